@@ -4,7 +4,7 @@ use deque::instruction_enum::{
 };
 use deque_client::{
     events::fetch_parsed_txn,
-    fuzz::fuzz,
+    fuzz::{fuzz, FuzzConfig, RpcBackend},
     initialize::initialize_market_and_event_authority,
     tokens::generate_market,
     transactions::{fund_account, send_deposit_or_withdraw, send_txn},
@@ -74,10 +74,7 @@ fn test_market_escrow(rpc: &RpcClient, payer: &Keypair) -> anyhow::Result<()> {
         payer_base_ata,
         ctx.base_mint,
         ctx.vault_base_ata,
-        &DequeInstruction::Deposit(DepositInstructionData {
-            amount: 1000,
-            choice: MarketChoice::Base,
-        }),
+        &DequeInstruction::Deposit(DepositInstructionData::new(1000, MarketChoice::Base)),
     )
     .and_then(|sig| fetch_parsed_txn(rpc, sig))?;
 
@@ -92,9 +89,7 @@ fn test_market_escrow(rpc: &RpcClient, payer: &Keypair) -> anyhow::Result<()> {
         payer_base_ata,
         ctx.base_mint,
         ctx.vault_base_ata,
-        &DequeInstruction::Withdraw(WithdrawInstructionData {
-            choice: MarketChoice::Base,
-        }),
+        &DequeInstruction::Withdraw(WithdrawInstructionData::new(MarketChoice::Base)),
     )
     .map(|sig| fetch_parsed_txn(rpc, sig))??;
 
@@ -109,10 +104,7 @@ fn test_market_escrow(rpc: &RpcClient, payer: &Keypair) -> anyhow::Result<()> {
         payer_base_ata,
         ctx.base_mint,
         ctx.vault_base_ata,
-        &DequeInstruction::Deposit(DepositInstructionData {
-            amount: 1000,
-            choice: MarketChoice::Base,
-        }),
+        &DequeInstruction::Deposit(DepositInstructionData::new(1000, MarketChoice::Base)),
     )
     .map(|sig| fetch_parsed_txn(rpc, sig))??;
 
@@ -121,7 +113,15 @@ fn test_market_escrow(rpc: &RpcClient, payer: &Keypair) -> anyhow::Result<()> {
 
     // ------------------------------------------- Fuzz --------------------------------------------
     const ROUNDS: u64 = 0;
-    fuzz(rpc, payer, &ctx, ROUNDS, None)?;
+    let mut backend = RpcBackend {
+        rpc,
+        market: ctx.clone(),
+    };
+    let config = FuzzConfig {
+        rounds: ROUNDS,
+        seed: 0,
+    };
+    fuzz(&mut backend, std::slice::from_ref(payer), config)?;
 
     Ok(())
 }
@@ -0,0 +1,135 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use deque::instruction_enum::{DepositInstructionData, MarketChoice, WithdrawInstructionData};
+use deque_client::{
+    replay::{
+        program_accounts, record_fixture, record_simulated, replay, snapshot_market,
+        AccountSnapshot, FixtureSource, ReplayFixture,
+    },
+    simulator::MarketSimulator,
+    tokens::DepositOrWithdraw,
+};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature, Signer},
+};
+
+const USAGE: &str = "Usage:
+  replay snapshot <rpc url> <deque> <snapshot> [<account>...]
+  replay record <rpc url> <deque> <snapshot> <fixture> <signature>...
+  replay run <fixture>
+  replay simulate <fixture>";
+
+/// Records transactions from a cluster into a fixture, or replays a fixture against the locally
+/// built program and prints where it diverges from the chain. Snapshot the market, and any other
+/// accounts the transactions will read, before sending the transactions to record.
+/// Usage: `cargo run --example replay -- <command>`, see [`USAGE`].
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["snapshot", rpc_url, deque, path, others @ ..] => {
+            let rpc =
+                RpcClient::new_with_commitment(rpc_url.to_string(), CommitmentConfig::confirmed());
+            let deque = Pubkey::from_str(deque).context("Invalid deque pubkey")?;
+            let others = others
+                .iter()
+                .map(|key| Pubkey::from_str(key).context("Invalid account pubkey"))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let snapshot = snapshot_market(&rpc, &deque, &others)?;
+            snapshot.save(path)?;
+            println!(
+                "Snapshotted {} accounts in slot {} to {path}",
+                snapshot.accounts.len(),
+                snapshot.slot
+            );
+        }
+        ["record", rpc_url, deque, snapshot, path, signatures @ ..] if !signatures.is_empty() => {
+            let rpc =
+                RpcClient::new_with_commitment(rpc_url.to_string(), CommitmentConfig::confirmed());
+            let signatures = signatures
+                .iter()
+                .map(|sig| Signature::from_str(sig).context("Invalid signature"))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let deque = Pubkey::from_str(deque).context("Invalid deque pubkey")?;
+            let snapshot = AccountSnapshot::load(snapshot)?;
+            let fixture = record_fixture(&rpc, deque, &signatures, &snapshot)?;
+            fixture.save(path)?;
+            println!("Recorded {} transactions to {path}", signatures.len());
+        }
+        ["run", path] => {
+            let report = replay(&ReplayFixture::load(path)?)?;
+            for mismatch in &report.mismatches {
+                println!("❌ {mismatch}");
+            }
+            println!(
+                "Replayed {} transactions, {} mismatches",
+                report.transactions,
+                report.mismatches.len()
+            );
+            if !report.is_match() {
+                bail!("The replay diverged from the chain");
+            }
+        }
+        ["simulate", path] => {
+            simulated_fixture()?.save(path)?;
+            println!("Wrote a simulated fixture to {path}");
+        }
+        _ => bail!("{USAGE}"),
+    }
+    Ok(())
+}
+
+/// A few deposits and withdrawals on a simulated market, including a failed withdrawal and the
+/// deposits that grow its single sector deque.
+fn simulated_fixture() -> anyhow::Result<ReplayFixture> {
+    let mut sim = MarketSimulator::new(1)?.with_event_history(16)?;
+    let traders: Vec<Keypair> = (0..3).map(|_| sim.add_trader(1_000, 1_000)).collect();
+    let initial_accounts = program_accounts(&sim);
+
+    let steps: [(usize, DepositOrWithdraw); 7] = [
+        (
+            0,
+            DepositInstructionData::new(100, MarketChoice::Base).into(),
+        ),
+        (1, WithdrawInstructionData::new(MarketChoice::Quote).into()),
+        (
+            1,
+            DepositInstructionData::new(250, MarketChoice::Quote).into(),
+        ),
+        (
+            2,
+            DepositInstructionData::new(30, MarketChoice::Base).into(),
+        ),
+        (
+            0,
+            DepositInstructionData::new(5, MarketChoice::Quote).into(),
+        ),
+        (0, WithdrawInstructionData::new(MarketChoice::Base).into()),
+        (1, WithdrawInstructionData::new(MarketChoice::Quote).into()),
+    ];
+    let mut transactions = vec![];
+    for (i, (trader, instruction)) in steps.into_iter().enumerate() {
+        sim.set_clock(100 + i as u64, 1_700_000_000 + 4 * i as i64);
+        let ixn = sim
+            .market
+            .deposit_or_withdraw_ixn(&traders[trader], instruction);
+        let signature = format!("simulated-{i}-{}", traders[trader].pubkey());
+        transactions.push(record_simulated(&mut sim, &ixn, signature));
+    }
+
+    Ok(ReplayFixture {
+        deque: sim.market.deque_pubkey,
+        source: FixtureSource::Simulated,
+        program_accounts: initial_accounts,
+        transactions,
+        final_accounts: program_accounts(&sim),
+    })
+}
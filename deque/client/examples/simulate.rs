@@ -0,0 +1,49 @@
+use std::time::Instant;
+
+use deque::instruction_enum::MarketChoice;
+use deque_client::simulator::MarketSimulator;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use solana_sdk::signature::Keypair;
+
+/// Runs random deposits and withdrawals against an in-memory market and reports the throughput.
+/// Usage: `cargo run --release --example simulate -- [steps] [traders]`.
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let steps: u64 = args.next().map_or(Ok(1_000_000), |arg| arg.parse())?;
+    let num_traders: usize = args.next().map_or(Ok(100), |arg| arg.parse())?;
+
+    let mut sim = MarketSimulator::new(num_traders as u16)?;
+    let traders: Vec<Keypair> = (0..num_traders)
+        .map(|_| sim.add_trader(u64::MAX / 2, u64::MAX / 2))
+        .collect();
+    let mut rng = SmallRng::seed_from_u64(0);
+
+    let start = Instant::now();
+    let (mut events, mut failures) = (0, 0);
+    for _ in 0..steps {
+        let trader = &traders[rng.random_range(0..num_traders)];
+        let choice = match rng.random_bool(0.5) {
+            true => MarketChoice::Base,
+            false => MarketChoice::Quote,
+        };
+        let processed = match rng.random_bool(0.7) {
+            true => sim.deposit(trader, rng.random_range(1..1_000), choice),
+            false => sim.withdraw(trader, choice),
+        };
+        events += processed.events.len();
+        failures += processed.result.is_err() as u64;
+    }
+
+    let elapsed = start.elapsed();
+    println!(
+        "{steps} steps in {elapsed:.2?} ({:.0} steps/s), {events} events, {failures} failed",
+        steps as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "Vaults: base {}, quote {}, {} escrows",
+        sim.vault_balance(MarketChoice::Base),
+        sim.vault_balance(MarketChoice::Quote),
+        sim.escrows().len()
+    );
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use deque::{
+    instruction_enum::InstructionTag,
+    shared::{
+        error::DequeError,
+        error_context::{ErrorContext, UNKNOWN},
+    },
+};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_request::{RpcError, RpcResponseErrorData},
+    rpc_response::RpcSimulateTransactionResult,
+};
+use solana_sdk::{instruction::InstructionError, transaction::TransactionError};
+
+/// A deque program error extracted from a failed transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FailedInstruction {
+    /// The index of the failed instruction in the transaction.
+    pub instruction_index: u8,
+    pub error: DequeError,
+}
+
+impl FailedInstruction {
+    pub fn message(&self) -> &'static str {
+        self.error.into()
+    }
+}
+
+impl core::fmt::Display for FailedInstruction {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Instruction {} failed with {:?}: {}",
+            self.instruction_index,
+            self.error,
+            self.message()
+        )
+    }
+}
+
+/// Extracts the deque error from a transaction error, if an instruction failed with a custom error
+/// code that's a valid [`DequeError`]. Note that other programs' custom errors can't be told apart
+/// from the deque program's by their code alone, so only pass errors from transactions whose
+/// failing instruction could only have been a deque instruction.
+pub fn deque_error_from_transaction_error(err: &TransactionError) -> Option<FailedInstruction> {
+    match err {
+        TransactionError::InstructionError(instruction_index, InstructionError::Custom(code)) => {
+            Some(FailedInstruction {
+                instruction_index: *instruction_index,
+                error: DequeError::try_from(*code).ok()?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Extracts the deque error from an RPC client error, including preflight simulation failures.
+pub fn deque_error_from_client_error(err: &ClientError) -> Option<FailedInstruction> {
+    deque_error_from_transaction_error(&err.get_transaction_error()?)
+}
+
+/// Extracts the deque error from the result of simulating a transaction.
+pub fn deque_error_from_simulation(
+    result: &RpcSimulateTransactionResult,
+) -> Option<FailedInstruction> {
+    deque_error_from_transaction_error(result.err.as_ref()?)
+}
+
+/// Extracts the deque error from an error returned by a helper like
+/// [`crate::transactions::send_txn`], which wraps the underlying RPC client error.
+pub fn deque_error_from_anyhow(err: &anyhow::Error) -> Option<FailedInstruction> {
+    deque_error_from_client_error(err.downcast_ref::<ClientError>()?)
+}
+
+/// Decodes the error context record the deque program logs when it fails. Returns the last one,
+/// since a failed transaction stops at the first failing instruction.
+pub fn error_context_from_logs(logs: &[String]) -> anyhow::Result<Option<ErrorContext>> {
+    Ok(crate::events::deque_program_data(logs)?
+        .iter()
+        .rev()
+        .find_map(|data| ErrorContext::unpack(data)))
+}
+
+/// Decodes the error context record from the logs of a failed preflight simulation.
+pub fn error_context_from_client_error(err: &ClientError) -> Option<ErrorContext> {
+    match err.kind() {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(result),
+            ..
+        }) => error_context_from_logs(result.logs.as_deref()?).ok()?,
+        _ => None,
+    }
+}
+
+/// Describes an error context with the names of its error, instruction and account.
+pub fn describe_error_context(ctx: &ErrorContext) -> String {
+    let error = match ctx.deque_error() {
+        Some(error) => format!("{error:?}: {}", <&str>::from(error)),
+        None => ctx.error().to_string(),
+    };
+    let Ok(tag) = InstructionTag::try_from(ctx.instruction_tag) else {
+        return format!("Unknown instruction failed with {error}");
+    };
+    match ctx.account_index {
+        UNKNOWN => format!("{tag:?} failed with {error}"),
+        index => {
+            let indices = (0..=index).collect::<Vec<_>>();
+            let label = tag
+                .label_accounts(&indices)
+                .last()
+                .map_or("unknown", |(label, _)| label);
+            format!("{tag:?} failed with {error} at account {index} ({label})")
+        }
+    }
+}
+
+#[test]
+fn test_deque_error_from_transaction_error() {
+    let err = TransactionError::InstructionError(
+        2,
+        InstructionError::Custom(DequeError::NoActiveEscrow as u32),
+    );
+    let failed = deque_error_from_transaction_error(&err).expect("Should be a deque error");
+    assert_eq!(failed.instruction_index, 2);
+    assert_eq!(failed.error, DequeError::NoActiveEscrow);
+    assert_eq!(failed.message(), <&str>::from(DequeError::NoActiveEscrow));
+
+    let anyhow_err = anyhow::Error::new(ClientError::from(err)).context("Failed to call");
+    assert_eq!(deque_error_from_anyhow(&anyhow_err), Some(failed));
+
+    assert!(
+        deque_error_from_transaction_error(&TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(u32::MAX),
+        ))
+        .is_none()
+    );
+    assert!(deque_error_from_transaction_error(&TransactionError::AccountNotFound).is_none());
+}
+
+#[test]
+fn test_error_context_from_logs() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use solana_sdk::program_error::ProgramError;
+
+    let error = ProgramError::from(DequeError::InvalidTokenProgram);
+    let ctx = ErrorContext::new(&error, &[InstructionTag::Deposit as u8]);
+    let logs = [
+        format!("Program {} invoke [1]", deque::id_str()),
+        format!("Program data: {}", STANDARD.encode(ctx.pack())),
+        format!(
+            "Program {} failed: custom program error: 0x24",
+            deque::id_str()
+        ),
+    ];
+
+    let decoded = error_context_from_logs(&logs)
+        .expect("Should decode")
+        .expect("Should have an error context");
+    assert_eq!(decoded, ctx);
+    assert_eq!(
+        describe_error_context(&decoded),
+        "Deposit failed with InvalidTokenProgram: Invalid token program ID at account 7 \
+         (token_program)"
+    );
+    assert_eq!(error_context_from_logs(&logs[..1]).unwrap(), None);
+}
@@ -1,16 +1,23 @@
 use anyhow::Context;
+#[cfg(test)]
+use deque::events::{DepositEventData, EmittableEvent, HeaderEventData, WithdrawEventData};
 use deque::{
-    events::{
-        DepositEventData, DequeEvent, EmittableEvent, EventTag, HeaderEventData, WithdrawEventData,
-    },
+    events::{split_event_record, DequeEvent},
     instruction_enum::InstructionTag,
+    seeds::event_history::find_event_history_address,
+    shared::error_context::ERROR_CONTEXT_TAG,
+    state::{
+        EventHistoryHeader, EventRecord, EVENT_HISTORY_DISCRIMINANT, EVENT_HISTORY_HEADER_SIZE,
+        EVENT_RECORD_SIZES,
+    },
 };
 use itertools::Itertools;
-use solana_sdk::{commitment_config::CommitmentConfig, signature::Signature};
-use solana_transaction_status::UiTransactionEncoding;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature};
+use solana_transaction_status::{EncodedConfirmedTransactionWithStatusMeta, UiTransactionEncoding};
 
-use crate::ellipsis_transaction_utils::{
-    parse_transaction, ParsedInnerInstruction, ParsedTransaction,
+use crate::{
+    ellipsis_transaction_utils::{parse_transaction, ParsedInnerInstruction, ParsedTransaction},
+    owned_events::{OwnedDequeEvent, ToOwnedEvent},
 };
 
 impl ParsedTransaction {
@@ -25,24 +32,87 @@ impl ParsedTransaction {
 
         Ok(events)
     }
+
+    /// The events logged with `sol_log_data` when the program is built with the `log-events`
+    /// feature, in the order they were emitted.
+    pub fn get_logged_deque_events(&self) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+        unpack_logged_events(&deque_program_data(&self.logs)?)
+    }
+}
+
+/// Decodes the events in data the deque program logged with `sol_log_data`, skipping its error
+/// context records. Owned events are returned since the logged data is usually decoded from the
+/// logs on the fly.
+pub fn unpack_logged_events(logged_data: &[Vec<u8>]) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+    let mut res = vec![];
+    for data in logged_data
+        .iter()
+        .filter(|data| data.first() != Some(&ERROR_CONTEXT_TAG))
+    {
+        res.extend(
+            unpack_event_bytes(data)?
+                .iter()
+                .map(ToOwnedEvent::to_owned_event),
+        );
+    }
+
+    Ok(res)
+}
+
+/// Extracts and base64 decodes the `Program data:` log lines emitted directly by the deque
+/// program, skipping any logged by the programs it invokes or that invoke it.
+pub fn deque_program_data(logs: &[String]) -> anyhow::Result<Vec<Vec<u8>>> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let mut invoke_stack: Vec<&str> = vec![];
+    let mut res = vec![];
+    for log in logs {
+        if let Some(data) = log.strip_prefix("Program data: ") {
+            if invoke_stack.last() == Some(&deque::id_str()) {
+                // `sol_log_data` is only ever called with a single slice.
+                res.push(
+                    STANDARD
+                        .decode(data)
+                        .context("Invalid base64 program data")?,
+                );
+            }
+        } else if let Some(rest) = log.strip_prefix("Program ") {
+            let mut words = rest.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some(program_id), Some("invoke")) => invoke_stack.push(program_id),
+                (Some(_), Some("success" | "failed:")) => {
+                    invoke_stack.pop();
+                }
+                _ => (),
+            }
+        }
+    }
+
+    Ok(res)
 }
 
 pub fn fetch_parsed_txn(
     rpc: &solana_client::rpc_client::RpcClient,
     sig: Signature,
 ) -> anyhow::Result<ParsedTransaction> {
-    let txn = rpc
-        .get_transaction_with_config(
-            &sig,
-            solana_client::rpc_config::RpcTransactionConfig {
-                encoding: Some(UiTransactionEncoding::Json),
-                commitment: Some(CommitmentConfig::confirmed()),
-                max_supported_transaction_version: Some(0),
-            },
-        )
-        .context("Failed to get txn from rpc")?;
+    Ok(parse_transaction(fetch_encoded_txn(rpc, sig)?))
+}
 
-    Ok(parse_transaction(txn))
+/// Fetches a transaction JSON encoded with its status meta, for when the balances or account
+/// privileges that [`ParsedTransaction`] drops are needed.
+pub fn fetch_encoded_txn(
+    rpc: &solana_client::rpc_client::RpcClient,
+    sig: Signature,
+) -> anyhow::Result<EncodedConfirmedTransactionWithStatusMeta> {
+    rpc.get_transaction_with_config(
+        &sig,
+        solana_client::rpc_config::RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Json),
+            commitment: Some(CommitmentConfig::confirmed()),
+            max_supported_transaction_version: Some(0),
+        },
+    )
+    .context("Failed to get txn from rpc")
 }
 
 pub fn maybe_unpack_events(inner_ixn: &ParsedInnerInstruction) -> Option<Vec<DequeEvent<'_>>> {
@@ -65,38 +135,295 @@ pub fn try_unpack_event_bytes_with_tag(data_with_tag: &[u8]) -> Option<Vec<Deque
 /// Unpacks a slab of bytes into deque events.
 /// Note that the data here is expected to start at the *first* byte of the event data.
 /// That is, `all_data` should start *at* the event tag/discriminant.
+/// Events with tags this decoder doesn't know about are skipped, as are any fields appended to
+/// known events by newer versions.
 pub fn unpack_event_bytes(all_data: &[u8]) -> anyhow::Result<Vec<DequeEvent<'_>>> {
-    let mut i = 0;
+    let mut rest = all_data;
     let mut res = vec![];
-    loop {
-        let data = &all_data[i..];
-        if data.is_empty() {
-            break;
-        }
+    while !rest.is_empty() {
+        let (_, record, next) = split_event_record(rest)?;
+        rest = next;
+        res.extend(DequeEvent::unpack_known(record)?);
+    }
 
-        let event_tag = EventTag::try_from(data[0])?;
-        let (event, len) = match event_tag {
-            EventTag::Header => (
-                DequeEvent::Header(HeaderEventData::try_from_slice(data)?),
-                HeaderEventData::LEN,
-            ),
-            EventTag::Initialize => todo!(),
-            EventTag::Deposit => (
-                DequeEvent::Deposit(DepositEventData::try_from_slice(data)?),
-                DepositEventData::LEN,
-            ),
-            EventTag::Withdraw => (
-                DequeEvent::Withdraw(WithdrawEventData::try_from_slice(data)?),
-                WithdrawEventData::LEN,
-            ),
-            EventTag::Resize => todo!(),
-        };
+    Ok(res)
+}
+
+/// An event read back from a market's event history account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoricalEvent<'p> {
+    pub seq: u64,
+    pub slot: u64,
+    pub event: DequeEvent<'p>,
+}
+
+/// Decodes the events held in an event history account's data, from oldest to newest.
+/// The data doesn't need to be aligned, so it can be passed straight from an RPC response.
+/// Like [`unpack_event_bytes`], records with tags this decoder doesn't know about are skipped.
+/// Histories that haven't been migrated yet are decoded with their own version's record size.
+pub fn decode_event_history(data: &[u8]) -> anyhow::Result<Vec<HistoricalEvent<'_>>> {
+    anyhow::ensure!(
+        data.len() >= EVENT_HISTORY_HEADER_SIZE,
+        "Event history data is too short"
+    );
+    let (header_data, records_data) = data.split_at(EVENT_HISTORY_HEADER_SIZE);
+    let header: EventHistoryHeader = bytemuck::pod_read_unaligned(header_data);
+    anyhow::ensure!(
+        header.discriminant == EVENT_HISTORY_DISCRIMINANT,
+        "Invalid event history discriminant"
+    );
+
+    let record_size = *EVENT_RECORD_SIZES
+        .get(header.version as usize)
+        .with_context(|| format!("Unknown event history version {}", header.version))?;
+    let records = records_data.chunks_exact(record_size).collect_vec();
+    anyhow::ensure!(
+        records.len() == header.capacity as usize,
+        "Expected {} records, found {}",
+        header.capacity,
+        records.len()
+    );
+
+    let capacity = header.capacity as u64;
+    let len = header.next_seq.min(capacity);
+    (header.next_seq - len..header.next_seq)
+        .filter_map(|seq| {
+            let record = records[(seq % capacity) as usize];
+            // Every version starts its records with the sequence number and slot.
+            let field = |offset: usize| bytemuck::pod_read_unaligned::<u64>(&record[offset..][..8]);
+            let (seq, slot) = (
+                field(core::mem::offset_of!(EventRecord, seq)),
+                field(core::mem::offset_of!(EventRecord, slot)),
+            );
+            DequeEvent::unpack_known(&record[core::mem::offset_of!(EventRecord, data)..])
+                .map_err(anyhow::Error::from)
+                .transpose()
+                .map(|event| {
+                    Ok(HistoricalEvent {
+                        seq,
+                        slot,
+                        event: event?,
+                    })
+                })
+        })
+        .collect()
+}
+
+/// Fetches a market's event history account and returns an owned copy of its data, to be decoded
+/// with [`decode_event_history`].
+pub fn fetch_event_history(
+    rpc: &solana_client::rpc_client::RpcClient,
+    market: &Pubkey,
+) -> anyhow::Result<Vec<u8>> {
+    rpc.get_account_data(&find_event_history_address(market).0)
+        .context("Failed to fetch the event history account")
+}
+
+#[test]
+fn test_deque_program_data_logs() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use deque::instruction_enum::MarketChoice;
+
+    let (trader, market) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let mut payload = Vec::with_capacity(HeaderEventData::LEN + WithdrawEventData::LEN);
+    HeaderEventData::new(InstructionTag::Withdraw, &market, &trader, 1, 1)
+        .write(&mut payload)
+        .expect("Should write");
+    WithdrawEventData::new(&trader, 10, MarketChoice::Base, 1)
+        .write(&mut payload)
+        .expect("Should write");
+
+    let token_program = spl_token::id().to_string();
+    let logs = [
+        format!("Program {} invoke [1]", deque::id_str()),
+        format!("Program {token_program} invoke [2]"),
+        "Program data: AAEC".to_string(),
+        format!("Program {token_program} success"),
+        format!("Program data: {}", STANDARD.encode(&payload)),
+        format!("Program {} success", deque::id_str()),
+    ];
+
+    let payloads = deque_program_data(&logs).expect("Should decode");
+    assert_eq!(payloads, vec![payload]);
+    let events = unpack_event_bytes(&payloads[0]).expect("Should unpack");
+    assert_eq!(
+        events[1],
+        DequeEvent::Withdraw(WithdrawEventData::new(&trader, 10, MarketChoice::Base, 1))
+    );
+}
 
-        i += len;
-        res.push(event);
+#[test]
+fn test_event_history_wraps_around() {
+    use deque::{instruction_enum::MarketChoice, state::EventHistory};
+
+    let market = Pubkey::new_unique();
+    let traders = (0..5).map(|_| Pubkey::new_unique()).collect_vec();
+    let capacity = 3;
+
+    // Back the account data with u64s so the records are aligned.
+    let mut buf = vec![0u64; EventHistory::account_size(capacity) / 8];
+    let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+    let mut history = EventHistory::init(data, &market, 255).expect("Should init");
+    for (i, trader) in traders.iter().enumerate() {
+        let mut packed = Vec::with_capacity(DepositEventData::LEN);
+        DepositEventData::new(trader, i as u64, MarketChoice::Base, 100 + i as u64)
+            .write(&mut packed)
+            .expect("Should write");
+        history
+            .append(100 + i as u64, &packed)
+            .expect("Should append");
     }
 
-    Ok(res)
+    let events = decode_event_history(bytemuck::cast_slice(&buf)).expect("Should decode");
+    assert_eq!(events.len(), capacity as usize);
+    for (event, i) in events.iter().zip(2..5) {
+        assert_eq!(event.seq, i as u64);
+        assert_eq!(event.slot, 100 + i as u64);
+        assert_eq!(
+            event.event,
+            DequeEvent::Deposit(DepositEventData::new(
+                &traders[i],
+                i as u64,
+                MarketChoice::Base,
+                100 + i as u64
+            ))
+        );
+    }
+}
+
+#[test]
+fn test_decode_version_1_fixtures() {
+    use deque::instruction_enum::MarketChoice;
+
+    let trader = Pubkey::new_from_array([7; 32]);
+    let market = Pubkey::new_from_array([9; 32]);
+
+    // Version 1 events, frozen as they were first emitted. These must always decode.
+    #[rustfmt::skip]
+    let fixture = [
+        // Header: tag, version, body length, instruction tag, market, sender, nonce, emitted count.
+        &[0, 1, 75, 0, 4][..], &[9; 32], &[7; 32], &5u64.to_le_bytes(), &2u16.to_le_bytes(),
+        // Deposit: tag, version, body length, trader, amount, side.
+        &[2, 1, 41, 0], &[7; 32], &100u64.to_le_bytes(), &[1],
+        // Withdraw: tag, version, body length, trader, amount, side.
+        &[3, 1, 41, 0], &[7; 32], &50u64.to_le_bytes(), &[0],
+    ]
+    .concat();
+
+    assert_eq!(
+        unpack_event_bytes(&fixture).expect("Should decode"),
+        vec![
+            DequeEvent::Header(HeaderEventData::new(
+                InstructionTag::Deposit,
+                &market,
+                &trader,
+                5,
+                2
+            )),
+            // Version 1 events don't carry a slot.
+            DequeEvent::Deposit(DepositEventData::new(&trader, 100, MarketChoice::Quote, 0)),
+            DequeEvent::Withdraw(WithdrawEventData::new(&trader, 50, MarketChoice::Base, 0)),
+        ]
+    );
+}
+
+#[test]
+fn test_decode_skips_unknown_tags_and_trailing_fields() {
+    use deque::instruction_enum::MarketChoice;
+
+    let trader = Pubkey::new_from_array([7; 32]);
+
+    #[rustfmt::skip]
+    let data = [
+        // An event with a tag this decoder doesn't know about.
+        &[200, 1, 3, 0, 0xaa, 0xbb, 0xcc][..],
+        // A newer version of the deposit event with an extra u64 field appended.
+        &[2, 3, 57, 0], &[7; 32], &100u64.to_le_bytes(), &[1], &8u64.to_le_bytes(),
+        &u64::MAX.to_le_bytes(),
+        // A version 1 withdraw event.
+        &[3, 1, 41, 0], &[7; 32], &50u64.to_le_bytes(), &[0],
+    ]
+    .concat();
+
+    assert_eq!(
+        unpack_event_bytes(&data).expect("Should decode"),
+        vec![
+            DequeEvent::Deposit(DepositEventData::new(&trader, 100, MarketChoice::Quote, 8)),
+            DequeEvent::Withdraw(WithdrawEventData::new(&trader, 50, MarketChoice::Base, 0)),
+        ]
+    );
+
+    // A record whose body length runs past the end of the data can't be skipped.
+    assert!(unpack_event_bytes(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn test_event_history_skips_unknown_tags() {
+    use deque::{instruction_enum::MarketChoice, state::EventHistory};
+
+    let (market, trader) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let mut buf = vec![0u64; EventHistory::account_size(3) / 8];
+    let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+    let mut history = EventHistory::init(data, &market, 255).expect("Should init");
+    let mut deposit = Vec::with_capacity(DepositEventData::LEN);
+    DepositEventData::new(&trader, 10, MarketChoice::Base, 7)
+        .write(&mut deposit)
+        .expect("Should write");
+    history
+        .append(6, &[200, 1, 3, 0, 0xaa, 0xbb, 0xcc])
+        .expect("Should append");
+    history.append(7, &deposit).expect("Should append");
+
+    let events = decode_event_history(bytemuck::cast_slice(&buf)).expect("Should decode");
+    assert_eq!(
+        events,
+        [HistoricalEvent {
+            seq: 1,
+            slot: 7,
+            event: DequeEvent::Deposit(DepositEventData::new(&trader, 10, MarketChoice::Base, 7)),
+        }]
+    );
+}
+
+#[test]
+fn test_event_history_decodes_unmigrated_records() {
+    use deque::{
+        events::ReconcileEventData,
+        instruction_enum::MarketChoice,
+        state::{EventHistory, EVENT_HISTORY_VERSION_OFFSET, EVENT_RECORD_SIZE},
+    };
+
+    let market = Pubkey::new_unique();
+    let mut buf = vec![0u64; EventHistory::account_size(2) / 8];
+    let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+    let mut history = EventHistory::init(data, &market, 255).expect("Should init");
+    let mut reconcile = Vec::with_capacity(ReconcileEventData::LEN);
+    ReconcileEventData::new(MarketChoice::Quote, 5, 5, 8)
+        .write(&mut reconcile)
+        .expect("Should write");
+    history.append(3, &reconcile).expect("Should append");
+
+    // Version 0 records are 64 bytes, with room for 48 bytes of event.
+    let data: &[u8] = bytemuck::cast_slice(&buf);
+    let (header, records) = data.split_at(EVENT_HISTORY_HEADER_SIZE);
+    let mut old = header.to_vec();
+    old[EVENT_HISTORY_VERSION_OFFSET] = 0;
+    for record in records.chunks_exact(EVENT_RECORD_SIZE) {
+        old.extend(&record[..64]);
+    }
+
+    let events = decode_event_history(&old).expect("Should decode");
+    assert_eq!(
+        events,
+        [HistoricalEvent {
+            seq: 0,
+            slot: 3,
+            event: DequeEvent::Reconcile(ReconcileEventData::new(MarketChoice::Quote, 5, 5, 8)),
+        }]
+    );
+
+    old[EVENT_HISTORY_VERSION_OFFSET] = u8::MAX;
+    assert!(decode_event_history(&old).is_err());
 }
 
 #[test]
@@ -118,11 +445,11 @@ fn test_multiple_events_in_slab() {
         u64::MAX - 1,
         u16::MAX - 1,
     );
-    let deposit_1 = DepositEventData::new(&trader_1, amount_1, MarketChoice::Base);
-    let deposit_2 = DepositEventData::new(&trader_2, amount_2, MarketChoice::Quote);
-    let deposit_3 = DepositEventData::new(&trader_2, amount_2, MarketChoice::Quote);
-    let withdraw_1 = WithdrawEventData::new(&trader_2, amount_2, MarketChoice::Quote);
-    let withdraw_2 = WithdrawEventData::new(&trader_2, amount_2, MarketChoice::Quote);
+    let deposit_1 = DepositEventData::new(&trader_1, amount_1, MarketChoice::Base, 1);
+    let deposit_2 = DepositEventData::new(&trader_2, amount_2, MarketChoice::Quote, 1);
+    let deposit_3 = DepositEventData::new(&trader_2, amount_2, MarketChoice::Quote, 1);
+    let withdraw_1 = WithdrawEventData::new(&trader_2, amount_2, MarketChoice::Quote, 1);
+    let withdraw_2 = WithdrawEventData::new(&trader_2, amount_2, MarketChoice::Quote, 1);
 
     let events = [
         DequeEvent::Header(header),
@@ -140,6 +467,7 @@ fn test_multiple_events_in_slab() {
             DequeEvent::Header(header) => header.write(&mut buf).expect("Should write"),
             DequeEvent::Deposit(deposit) => deposit.write(&mut buf).expect("Should write"),
             DequeEvent::Withdraw(withdraw) => withdraw.write(&mut buf).expect("Should write"),
+            DequeEvent::Reconcile(reconcile) => reconcile.write(&mut buf).expect("Should write"),
         };
     }
 
@@ -151,3 +479,36 @@ fn test_multiple_events_in_slab() {
         .zip(parsed_events)
         .for_each(|(e1, e2)| assert_eq!(e1, e2));
 }
+
+#[test]
+fn test_validate_event_payload() {
+    use deque::{events::validate_event_payload, instruction_enum::MarketChoice};
+
+    let (trader, market) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let mut buf = Vec::with_capacity(HeaderEventData::LEN + 2 * DepositEventData::LEN);
+    HeaderEventData::new(InstructionTag::Deposit, &market, &trader, 1, 1)
+        .write(&mut buf)
+        .expect("Should write");
+    DepositEventData::new(&trader, 10, MarketChoice::Quote, 1)
+        .write(&mut buf)
+        .expect("Should write");
+    assert_eq!(validate_event_payload(&buf), Ok(market));
+
+    // A truncated event.
+    assert!(validate_event_payload(&buf[..buf.len() - 1]).is_err());
+
+    // An invalid market choice.
+    let mut bad_side = buf.clone();
+    // The side is followed by the slot.
+    let side = bad_side.len() - 9;
+    bad_side[side] = 2;
+    assert!(validate_event_payload(&bad_side).is_err());
+
+    // Events without a header.
+    assert!(validate_event_payload(&buf[HeaderEventData::LEN..]).is_err());
+
+    // A second header.
+    let mut two_headers = buf.clone();
+    two_headers.extend_from_slice(&buf[..HeaderEventData::LEN]);
+    assert!(validate_event_payload(&two_headers).is_err());
+}
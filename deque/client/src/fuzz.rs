@@ -1,142 +1,480 @@
-use anyhow::Context;
-use deque::instruction_enum::{
-    DepositInstructionData, DequeInstruction, MarketChoice, WithdrawInstructionData,
+use std::collections::HashMap;
+
+use anyhow::{ensure, Context};
+use deque::{
+    instruction_enum::{
+        DepositInstructionData, InstructionTag, MarketChoice, WithdrawInstructionData,
+    },
+    state::MarketEscrow,
 };
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use solana_client::rpc_client::RpcClient;
-use solana_sdk::{signature::Keypair, signer::Signer};
+use solana_sdk::{program_pack::Pack, pubkey::Pubkey, signature::Keypair, signer::Signer};
 
 use crate::{
-    tokens::{MarketContext, INITIAL_MINT_AMOUNT},
-    transactions::send_deposit_or_withdraw,
+    events::fetch_parsed_txn,
+    owned_events::{OwnedDepositEvent, OwnedDequeEvent, OwnedWithdrawEvent, ToOwnedEvent},
+    simulator::MarketSimulator,
+    tokens::{DepositOrWithdraw, MarketContext},
+    transactions::send_txn,
+    views::fetch_escrows,
 };
 
-/// Run a randomized deposit/withdraw fuzz test against the deque program.
-///
-/// This function simulates a trader interacting with the on-chain deque by
-/// performing a sequence of deposits and withdrawals of the base token.
-/// It maintains a local model of the trader’s wallet balance and escrow
-/// balance to ensure that no invalid transactions are ever sent:
-/// - Deposits are only attempted when the wallet has available tokens.
-/// - Withdrawals are only attempted when there are tokens in escrow.
-/// - Withdrawals always empty the entire escrow (matching program semantics).
+/// Where the fuzzer sends its instructions and reads the market's state back from.
+pub trait FuzzBackend {
+    fn market(&self) -> &MarketContext;
+
+    /// Deposits and returns the events the instruction emitted.
+    fn deposit(
+        &mut self,
+        trader: &Keypair,
+        amount: u64,
+        choice: MarketChoice,
+    ) -> anyhow::Result<Vec<OwnedDequeEvent>>;
+
+    /// Withdraws and returns the events the instruction emitted.
+    fn withdraw(
+        &mut self,
+        trader: &Keypair,
+        choice: MarketChoice,
+    ) -> anyhow::Result<Vec<OwnedDequeEvent>>;
+
+    /// Grows the deque by `num_sectors`, paid for by `payer`.
+    fn resize(&mut self, payer: &Keypair, num_sectors: u16) -> anyhow::Result<()>;
+
+    /// The balance of `owner`'s associated token account for `choice`.
+    fn token_balance(&self, owner: &Pubkey, choice: MarketChoice) -> anyhow::Result<u64>;
+
+    /// Every escrow in the deque, from front to back.
+    fn escrows(&self) -> anyhow::Result<Vec<MarketEscrow>>;
+}
+
+/// Fuzzes a market on a live cluster.
+pub struct RpcBackend<'a> {
+    pub rpc: &'a RpcClient,
+    pub market: MarketContext,
+}
+
+impl RpcBackend<'_> {
+    fn send(
+        &mut self,
+        signer: &Keypair,
+        instruction: DepositOrWithdraw,
+        label: &str,
+    ) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+        let ixn = self.market.deposit_or_withdraw_ixn(signer, instruction);
+        let sig = send_txn(self.rpc, signer, &[signer], vec![ixn], label.to_string())?;
+        let events = fetch_parsed_txn(self.rpc, sig)?
+            .get_inner_deque_events()?
+            .iter()
+            .map(ToOwnedEvent::to_owned_event)
+            .collect();
+        self.market.refresh_shards(self.rpc)?;
+        Ok(events)
+    }
+}
+
+impl FuzzBackend for RpcBackend<'_> {
+    fn market(&self) -> &MarketContext {
+        &self.market
+    }
+
+    fn deposit(
+        &mut self,
+        trader: &Keypair,
+        amount: u64,
+        choice: MarketChoice,
+    ) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+        let data = DepositInstructionData::new(amount, choice);
+        self.send(trader, data.into(), "deposit")
+    }
+
+    fn withdraw(
+        &mut self,
+        trader: &Keypair,
+        choice: MarketChoice,
+    ) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+        let data = WithdrawInstructionData::new(choice);
+        self.send(trader, data.into(), "withdraw")
+    }
+
+    fn resize(&mut self, payer: &Keypair, num_sectors: u16) -> anyhow::Result<()> {
+        let ixn = self.market.resize_ixn(payer, num_sectors);
+        send_txn(self.rpc, payer, &[payer], vec![ixn], "resize".to_string())?;
+        self.market.refresh_shards(self.rpc)?;
+        Ok(())
+    }
+
+    fn token_balance(&self, owner: &Pubkey, choice: MarketChoice) -> anyhow::Result<u64> {
+        let (base_ata, quote_ata) = self.market.get_atas(owner);
+        let ata = match choice {
+            MarketChoice::Base => base_ata,
+            MarketChoice::Quote => quote_ata,
+        };
+        let data = self.rpc.get_account_data(&ata)?;
+        Ok(spl_token::state::Account::unpack(&data[..spl_token::state::Account::LEN])?.amount)
+    }
+
+    fn escrows(&self) -> anyhow::Result<Vec<MarketEscrow>> {
+        fetch_escrows(self.rpc, &self.market.deque_pubkey)
+    }
+}
+
+impl FuzzBackend for MarketSimulator {
+    fn market(&self) -> &MarketContext {
+        &self.market
+    }
+
+    fn deposit(
+        &mut self,
+        trader: &Keypair,
+        amount: u64,
+        choice: MarketChoice,
+    ) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+        MarketSimulator::deposit(self, trader, amount, choice).into_result()
+    }
+
+    fn withdraw(
+        &mut self,
+        trader: &Keypair,
+        choice: MarketChoice,
+    ) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+        MarketSimulator::withdraw(self, trader, choice).into_result()
+    }
+
+    fn resize(&mut self, _payer: &Keypair, num_sectors: u16) -> anyhow::Result<()> {
+        MarketSimulator::resize(self, num_sectors)
+            .into_result()
+            .map(|_| ())
+    }
+
+    fn token_balance(&self, owner: &Pubkey, choice: MarketChoice) -> anyhow::Result<u64> {
+        Ok(MarketSimulator::token_balance(self, owner, choice))
+    }
+
+    fn escrows(&self) -> anyhow::Result<Vec<MarketEscrow>> {
+        Ok(MarketSimulator::escrows(self))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FuzzConfig {
+    pub rounds: u64,
+    /// Seeds the sequence of traders and actions, so a failing run can be replayed.
+    pub seed: u64,
+}
+
+/// How many of each action the fuzzer took.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FuzzStats {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub resizes: u64,
+    pub skips: u64,
+}
+
+/// The fuzzer's model of a single trader.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct TraderModel {
+    wallet: [u64; 2],
+    escrow: [u64; 2],
+}
+
+const CHOICES: [MarketChoice; 2] = [MarketChoice::Base, MarketChoice::Quote];
+
+/// Run a randomized deposit/withdraw/resize fuzz test against the deque program.
 ///
-/// Each fuzz round randomly chooses one of three actions:
-/// - **Deposit:** transfer a random, valid amount from wallet → escrow.
-/// - **Withdraw:** withdraw the full escrow balance back to wallet.
+/// Each round picks one of `traders` and one of these actions for it:
+/// - **Deposit:** transfer a random amount of the base or quote token from wallet → escrow.
+/// - **Withdraw:** withdraw the trader's whole escrow of one side back to its wallet. The program
+///   has no partial withdrawals.
+/// - **Resize:** grow the deque by a few sectors, paid for by the trader.
 /// - **Skip:** take no action this round.
 ///
-/// The sequence of actions (e.g. `D, D, W, D, W, W`) is randomized using
-/// a seeded RNG, making runs reproducible per payer pubkey while still
-/// providing varied coverage across rounds. Balances are tracked locally,
-/// so deposits and withdrawals are always valid and the fuzzer never fails
-/// due to insufficient funds or empty escrow.
+/// The fuzzer keeps a model of every trader's wallet and escrow balances and only sends valid
+/// instructions, so any failure is a bug. After every round it checks the backend against the
+/// model:
+/// - The instruction emitted a header for the trader followed by the expected deposit or
+///   withdrawal event.
+/// - The trader's token balances and both vault balances match.
+/// - The deque holds exactly the expected escrows. Escrows of traders that aren't being fuzzed
+///   must be left untouched.
+///
+/// The fuzzer must be the only one using the market while it runs, since other traders would
+/// change the vault balances.
 ///
 /// # Arguments
-/// * `rpc` – Solana RPC client used to submit transactions.
-/// * `payer` – Keypair of the trader executing deposits/withdrawals.
-/// * `ctx` – Context object providing program/mint/vault addresses.
-/// * `rounds` – Number of fuzz rounds to execute.
-/// * `num_payers` – Optional number of concurrent payers to divide the
-///   initial mint amount between (ensures fair balance distribution).
+/// * `backend` – The live cluster or in-memory simulator to run against.
+/// * `traders` – Funded wallets with associated token accounts for both mints.
+/// * `config` – The number of rounds and the seed that picks each round's trader and action.
 ///
 /// # Errors
-/// Returns an error if any underlying deposit or withdraw transaction
-/// submission fails.
-pub fn fuzz(
-    rpc: &RpcClient,
-    payer: &Keypair,
-    ctx: &MarketContext,
-    rounds: u64,
-    num_payers: Option<usize>,
-) -> anyhow::Result<()> {
-    let (payer_base_ata, _payer_quote_ata) = ctx.get_atas(&payer.pubkey());
-
-    let per_fuzzer_mint = INITIAL_MINT_AMOUNT / (num_payers.unwrap_or(1) as u64);
-
-    // Local model of balances
-    let mut wallet_base: u64 = per_fuzzer_mint;
-    let mut escrow_base: u64 = 0;
-
-    // Deterministic randomness per payer
-    let mut seed = [0u8; 32];
-    let pk = payer.pubkey().to_bytes();
-    for (i, b) in pk.iter().enumerate() {
-        seed[i % 32] ^= *b;
-    }
-    seed[0] ^= (rounds as u8).wrapping_mul(31);
-    let mut rng = SmallRng::from_seed(seed);
-
-    for round in 0..rounds {
-        println!("---------------- Fuzz round: {} ----------------", round);
-
-        // Decide action: 0 = deposit, 1 = withdraw, 2 = skip
-        let action = rng.random_range(0..=2);
-
-        match action {
-            0 => {
-                if wallet_base > 0 {
-                    // Random deposit amount, capped so it's nonzero and sane
-                    let max_amt = wallet_base.min(per_fuzzer_mint / 4).max(1);
-                    let amount = rng.random_range(1..=max_amt);
-
-                    send_deposit_or_withdraw(
-                        rpc,
-                        payer,
-                        ctx.deque_pubkey,
-                        payer_base_ata,
-                        ctx.base_mint,
-                        ctx.vault_base_ata,
-                        &DequeInstruction::Deposit(DepositInstructionData::new(
-                            amount,
-                            MarketChoice::Base,
-                        )),
-                    )
-                    .with_context(|| format!("Couldn't deposit base (amt={amount})"))?;
-
-                    wallet_base -= amount;
-                    escrow_base += amount;
-                    println!(
-                        "Deposited {} (wallet={}, escrow={})",
-                        amount, wallet_base, escrow_base
-                    );
-                } else {
-                    println!("Wanted to deposit, but wallet is empty. Skipping.");
-                }
+/// Returns an error if an instruction fails or the backend's state diverges from the model.
+pub fn fuzz<B: FuzzBackend>(
+    backend: &mut B,
+    traders: &[Keypair],
+    config: FuzzConfig,
+) -> anyhow::Result<FuzzStats> {
+    ensure!(!traders.is_empty(), "Need at least one trader to fuzz");
+
+    let market = backend.market().deque_pubkey;
+    let mut models = traders
+        .iter()
+        .map(|trader| {
+            let pubkey = trader.pubkey();
+            let escrow = backend
+                .escrows()?
+                .into_iter()
+                .find(|escrow| escrow.trader == pubkey)
+                .map_or([0, 0], |escrow| [escrow.base, escrow.quote]);
+            Ok(TraderModel {
+                wallet: [
+                    backend.token_balance(&pubkey, MarketChoice::Base)?,
+                    backend.token_balance(&pubkey, MarketChoice::Quote)?,
+                ],
+                escrow,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut vaults = [
+        backend.token_balance(&market, MarketChoice::Base)?,
+        backend.token_balance(&market, MarketChoice::Quote)?,
+    ];
+    // Escrows that belong to other traders and must be left as they are.
+    let untouched: Vec<MarketEscrow> = backend
+        .escrows()?
+        .into_iter()
+        .filter(|escrow| {
+            !traders
+                .iter()
+                .any(|trader| trader.pubkey() == escrow.trader)
+        })
+        .collect();
+
+    let mut rng = SmallRng::seed_from_u64(config.seed);
+    let mut stats = FuzzStats::default();
+    for round in 0..config.rounds {
+        let i = rng.random_range(0..traders.len());
+        let (trader, model) = (&traders[i], &mut models[i]);
+        let side = rng.random_range(0..2);
+        let choice = CHOICES[side];
+
+        // Decide action: 0..=3 = deposit, 4..=5 = withdraw, 6 = resize, 7 = skip
+        let expected = match rng.random_range(0..8) {
+            0..=3 if model.wallet[side] > 0 => {
+                let amount = rng.random_range(1..=model.wallet[side]);
+                let events = backend
+                    .deposit(trader, amount, choice)
+                    .with_context(|| format!("Round {round}: couldn't deposit {amount}"))?;
+                model.wallet[side] -= amount;
+                model.escrow[side] += amount;
+                vaults[side] += amount;
+                stats.deposits += 1;
+                Some((events, InstructionTag::Deposit, amount))
+            }
+            4..=5 if model.escrow != [0, 0] => {
+                let events = backend
+                    .withdraw(trader, choice)
+                    .with_context(|| format!("Round {round}: couldn't withdraw"))?;
+                let amount = std::mem::take(&mut model.escrow[side]);
+                model.wallet[side] += amount;
+                vaults[side] -= amount;
+                stats.withdrawals += 1;
+                Some((events, InstructionTag::Withdraw, amount))
             }
-            1 => {
-                if escrow_base > 0 {
-                    send_deposit_or_withdraw(
-                        rpc,
-                        payer,
-                        ctx.deque_pubkey,
-                        payer_base_ata,
-                        ctx.base_mint,
-                        ctx.vault_base_ata,
-                        &DequeInstruction::Withdraw(WithdrawInstructionData::new(
-                            MarketChoice::Base,
-                        )),
-                    )
-                    .context(format!("Couldn't withdraw base for: {:?}", &payer.pubkey()))?;
-
-                    wallet_base += escrow_base;
-                    println!(
-                        "Withdrew {} (wallet={}, escrow=0)",
-                        escrow_base, wallet_base
-                    );
-                    escrow_base = 0;
-                } else {
-                    println!("Wanted to withdraw, but escrow is empty. Skipping.");
-                }
+            6 => {
+                let num_sectors = rng.random_range(1..=4);
+                backend
+                    .resize(trader, num_sectors)
+                    .with_context(|| format!("Round {round}: couldn't resize"))?;
+                stats.resizes += 1;
+                None
             }
             _ => {
-                println!(
-                    "Skipping action this round. (wallet={}, escrow={})",
-                    wallet_base, escrow_base
-                );
+                stats.skips += 1;
+                continue;
             }
+        };
+
+        if let Some((events, tag, amount)) = expected {
+            check_events(&events, &market, &trader.pubkey(), tag, amount, choice)
+                .with_context(|| format!("Round {round}: unexpected events"))?;
+        }
+        check_state(backend, traders, &models, vaults, &untouched)
+            .with_context(|| format!("Round {round}: diverged from the model"))?;
+    }
+
+    Ok(stats)
+}
+
+fn check_events(
+    events: &[OwnedDequeEvent],
+    market: &Pubkey,
+    trader: &Pubkey,
+    tag: InstructionTag,
+    amount: u64,
+    side: MarketChoice,
+) -> anyhow::Result<()> {
+    let [OwnedDequeEvent::Header(header), event] = events else {
+        anyhow::bail!("Expected a header and one event, got {events:?}");
+    };
+    ensure!(
+        (header.instruction_tag, &header.market, &header.sender) == (tag, market, trader),
+        "Unexpected header {header:?}"
+    );
+    // The slot depends on the backend's clock, so only the rest of the event is checked.
+    let slot = match event {
+        OwnedDequeEvent::Deposit(OwnedDepositEvent { slot, .. })
+        | OwnedDequeEvent::Withdraw(OwnedWithdrawEvent { slot, .. }) => *slot,
+        _ => anyhow::bail!("Expected a deposit or a withdrawal, got {event:?}"),
+    };
+    let expected = match tag {
+        InstructionTag::Deposit => OwnedDequeEvent::Deposit(OwnedDepositEvent {
+            trader: *trader,
+            amount,
+            side,
+            slot,
+        }),
+        _ => OwnedDequeEvent::Withdraw(OwnedWithdrawEvent {
+            trader: *trader,
+            amount,
+            side,
+            slot,
+        }),
+    };
+    ensure!(event == &expected, "Expected {expected:?}, got {event:?}");
+    Ok(())
+}
+
+fn check_state<B: FuzzBackend>(
+    backend: &B,
+    traders: &[Keypair],
+    models: &[TraderModel],
+    vaults: [u64; 2],
+    untouched: &[MarketEscrow],
+) -> anyhow::Result<()> {
+    let market = backend.market().deque_pubkey;
+    for (choice, expected) in CHOICES.into_iter().zip(vaults) {
+        let actual = backend.token_balance(&market, choice)?;
+        ensure!(
+            actual == expected,
+            "{choice:?} vault holds {actual}, expected {expected}"
+        );
+    }
+
+    let mut expected: HashMap<Pubkey, MarketEscrow> = untouched
+        .iter()
+        .map(|escrow| (escrow.trader, *escrow))
+        .collect();
+    for (trader, model) in traders.iter().zip(models) {
+        let pubkey = trader.pubkey();
+        for (choice, expected) in CHOICES.into_iter().zip(model.wallet) {
+            let actual = backend.token_balance(&pubkey, choice)?;
+            ensure!(
+                actual == expected,
+                "{pubkey}'s {choice:?} wallet holds {actual}, expected {expected}"
+            );
+        }
+        if model.escrow != [0, 0] {
+            let [base, quote] = model.escrow;
+            expected.insert(pubkey, MarketEscrow::new(pubkey, base, quote));
         }
     }
 
+    let escrows = backend.escrows()?;
+    ensure!(
+        escrows.len() == expected.len(),
+        "The deque holds {} escrows, expected {}",
+        escrows.len(),
+        expected.len()
+    );
+    for escrow in escrows {
+        let pubkey = escrow.trader;
+        // The model doesn't follow the backend's clock, so the escrows it expects are compared
+        // without their slots and timestamps.
+        let escrow = match untouched.contains(&escrow) {
+            true => escrow,
+            false => MarketEscrow::new(pubkey, escrow.base, escrow.quote),
+        };
+        ensure!(
+            expected.get(&pubkey) == Some(&escrow),
+            "Unexpected escrow {escrow:?}, expected {:?}",
+            expected.get(&pubkey)
+        );
+    }
     Ok(())
 }
+
+#[test]
+fn test_fuzz_against_the_simulator() {
+    let mut sim = MarketSimulator::new(1).expect("Should initialize");
+    let bystander = sim.add_trader(100, 100);
+    sim.deposit(&bystander, 40, MarketChoice::Quote)
+        .into_result()
+        .expect("Should deposit");
+    let traders: Vec<Keypair> = (0..4).map(|_| sim.add_trader(1_000, 500)).collect();
+
+    for seed in 0..4 {
+        let config = FuzzConfig { rounds: 200, seed };
+        let stats = fuzz(&mut sim, &traders, config).expect("Should match the model");
+        assert!(stats.deposits > 0 && stats.withdrawals > 0 && stats.resizes > 0);
+    }
+}
+
+#[test]
+fn test_fuzz_catches_a_diverging_backend() {
+    /// Reports one less token in the base vault than there is.
+    struct Skimming(MarketSimulator);
+
+    impl FuzzBackend for Skimming {
+        fn market(&self) -> &MarketContext {
+            &self.0.market
+        }
+
+        fn deposit(
+            &mut self,
+            trader: &Keypair,
+            amount: u64,
+            choice: MarketChoice,
+        ) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+            FuzzBackend::deposit(&mut self.0, trader, amount, choice)
+        }
+
+        fn withdraw(
+            &mut self,
+            trader: &Keypair,
+            choice: MarketChoice,
+        ) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+            FuzzBackend::withdraw(&mut self.0, trader, choice)
+        }
+
+        fn resize(&mut self, payer: &Keypair, num_sectors: u16) -> anyhow::Result<()> {
+            FuzzBackend::resize(&mut self.0, payer, num_sectors)
+        }
+
+        fn token_balance(&self, owner: &Pubkey, choice: MarketChoice) -> anyhow::Result<u64> {
+            let balance = self.0.token_balance(owner, choice);
+            match (owner == &self.0.market.deque_pubkey, choice) {
+                (true, MarketChoice::Base) => Ok(balance.saturating_sub(1)),
+                _ => Ok(balance),
+            }
+        }
+
+        fn escrows(&self) -> anyhow::Result<Vec<MarketEscrow>> {
+            Ok(self.0.escrows())
+        }
+    }
+
+    let mut sim = MarketSimulator::new(1).expect("Should initialize");
+    let traders: Vec<Keypair> = (0..2).map(|_| sim.add_trader(1_000, 1_000)).collect();
+    let err = fuzz(
+        &mut Skimming(sim),
+        &traders,
+        FuzzConfig {
+            rounds: 100,
+            seed: 0,
+        },
+    )
+    .expect_err("Should notice the missing token");
+    assert!(format!("{err:#}").contains("Base vault holds"));
+}
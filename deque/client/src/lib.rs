@@ -1,8 +1,12 @@
 pub mod ellipsis_transaction_utils;
+pub mod errors;
 pub mod events;
 pub mod fuzz;
 pub mod initialize;
 pub mod logs;
+pub mod owned_events;
+pub mod replay;
+pub mod simulator;
 pub mod tokens;
 pub mod transactions;
 pub mod views;
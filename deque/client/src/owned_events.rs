@@ -0,0 +1,204 @@
+//! Owned counterparts of the borrowed [`DequeEvent`] types, so that events can outlive the buffer
+//! they were decoded from, be sent across channels and be (de)serialized with serde.
+//! Pubkeys serialize as base58 strings and enums as their variant names.
+
+use deque::{
+    events::{
+        DepositEventData, DequeEvent, HeaderEventData, ReconcileEventData, WithdrawEventData,
+    },
+    instruction_enum::{InstructionTag, MarketChoice},
+};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OwnedDequeEvent {
+    Header(OwnedHeaderEvent),
+    Deposit(OwnedDepositEvent),
+    Withdraw(OwnedWithdrawEvent),
+    Reconcile(OwnedReconcileEvent),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnedHeaderEvent {
+    #[serde(with = "InstructionTagDef")]
+    pub instruction_tag: InstructionTag,
+    #[serde(with = "pubkey_str")]
+    pub market: Pubkey,
+    #[serde(with = "pubkey_str")]
+    pub sender: Pubkey,
+    pub nonce: u64,
+    pub emitted_count: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnedDepositEvent {
+    #[serde(with = "pubkey_str")]
+    pub trader: Pubkey,
+    pub amount: u64,
+    #[serde(with = "MarketChoiceDef")]
+    pub side: MarketChoice,
+    pub slot: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnedWithdrawEvent {
+    #[serde(with = "pubkey_str")]
+    pub trader: Pubkey,
+    pub amount: u64,
+    #[serde(with = "MarketChoiceDef")]
+    pub side: MarketChoice,
+    pub slot: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnedReconcileEvent {
+    #[serde(with = "MarketChoiceDef")]
+    pub side: MarketChoice,
+    pub escrowed: u64,
+    pub recorded: u64,
+    pub vault_balance: u64,
+    pub surplus: u64,
+    pub deficit: u64,
+}
+
+/// Converts a borrowed event into its owned counterpart.
+pub trait ToOwnedEvent {
+    fn to_owned_event(&self) -> OwnedDequeEvent;
+}
+
+impl ToOwnedEvent for DequeEvent<'_> {
+    fn to_owned_event(&self) -> OwnedDequeEvent {
+        match self {
+            DequeEvent::Header(header) => OwnedDequeEvent::Header(header.into()),
+            DequeEvent::Deposit(deposit) => OwnedDequeEvent::Deposit(deposit.into()),
+            DequeEvent::Withdraw(withdraw) => OwnedDequeEvent::Withdraw(withdraw.into()),
+            DequeEvent::Reconcile(reconcile) => OwnedDequeEvent::Reconcile(reconcile.into()),
+        }
+    }
+}
+
+impl From<DequeEvent<'_>> for OwnedDequeEvent {
+    fn from(event: DequeEvent<'_>) -> Self {
+        event.to_owned_event()
+    }
+}
+
+impl From<&HeaderEventData<'_>> for OwnedHeaderEvent {
+    fn from(header: &HeaderEventData<'_>) -> Self {
+        Self {
+            instruction_tag: header.instruction_tag,
+            market: *header.market,
+            sender: *header.sender,
+            nonce: header.nonce,
+            emitted_count: header.emitted_count,
+        }
+    }
+}
+
+impl From<&DepositEventData<'_>> for OwnedDepositEvent {
+    fn from(deposit: &DepositEventData<'_>) -> Self {
+        Self {
+            trader: *deposit.trader,
+            amount: deposit.amount,
+            side: deposit.side,
+            slot: deposit.slot,
+        }
+    }
+}
+
+impl From<&WithdrawEventData<'_>> for OwnedWithdrawEvent {
+    fn from(withdraw: &WithdrawEventData<'_>) -> Self {
+        Self {
+            trader: *withdraw.trader,
+            amount: withdraw.amount,
+            side: withdraw.side,
+            slot: withdraw.slot,
+        }
+    }
+}
+
+impl From<&ReconcileEventData> for OwnedReconcileEvent {
+    fn from(reconcile: &ReconcileEventData) -> Self {
+        Self {
+            side: reconcile.side,
+            escrowed: reconcile.escrowed,
+            recorded: reconcile.recorded,
+            vault_balance: reconcile.vault_balance,
+            surplus: reconcile.surplus,
+            deficit: reconcile.deficit,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "InstructionTag")]
+enum InstructionTagDef {
+    InitializeDeque,
+    InitializeEventAuthority,
+    ResizeEventAuthority,
+    Resize,
+    Deposit,
+    Withdraw,
+    FlushEventLog,
+    Defragment,
+    InitializeEventHistory,
+    Reconcile,
+    MigrateDeque,
+    CloseTraderRecord,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "MarketChoice")]
+enum MarketChoiceDef {
+    Base,
+    Quote,
+}
+
+pub(crate) mod pubkey_str {
+    use std::str::FromStr;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use solana_sdk::pubkey::Pubkey;
+
+    pub fn serialize<S: Serializer>(pubkey: &Pubkey, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(pubkey)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pubkey, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Pubkey::from_str(&s).map_err(D::Error::custom)
+    }
+}
+
+#[test]
+fn test_owned_event_json_round_trip() {
+    let (trader, market) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let events = [
+        DequeEvent::Header(HeaderEventData::new(
+            InstructionTag::Deposit,
+            &market,
+            &trader,
+            7,
+            1,
+        )),
+        DequeEvent::Deposit(DepositEventData::new(&trader, 100, MarketChoice::Quote, 1)),
+        DequeEvent::Withdraw(WithdrawEventData::new(&trader, 50, MarketChoice::Base, 1)),
+        DequeEvent::Reconcile(ReconcileEventData::new(MarketChoice::Base, 50, 50, 60)),
+    ];
+    let owned = events
+        .iter()
+        .map(ToOwnedEvent::to_owned_event)
+        .collect::<Vec<_>>();
+
+    let json = serde_json::to_string(&owned).expect("Should serialize");
+    assert!(json.contains(&format!("\"trader\":\"{trader}\"")));
+    assert!(json.contains("\"instruction_tag\":\"Deposit\""));
+    assert!(json.contains("\"type\":\"deposit\""));
+    assert!(json.contains("\"side\":\"Quote\""));
+    assert!(json.contains("\"surplus\":10"));
+
+    let deserialized: Vec<OwnedDequeEvent> = serde_json::from_str(&json).expect("Should parse");
+    assert_eq!(deserialized, owned);
+}
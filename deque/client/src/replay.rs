@@ -0,0 +1,1061 @@
+//! Replays recorded transactions against a [`MarketSimulator`] running the locally built program,
+//! and diffs the events and state they produce against what the chain recorded.
+//!
+//! A [`ReplayFixture`] holds everything a replay needs, so it can be saved as JSON and replayed
+//! offline. [`record_fixture`] builds one from a cluster. Past account data isn't available over
+//! RPC, so the market's accounts have to be snapshotted with [`snapshot_market`] before the first
+//! transaction is sent, and the transactions recorded once they've landed. The deque program's own
+//! accounts start from the snapshot and are carried over from one replayed transaction to the
+//! next, so every transaction on the market after the snapshot has to be recorded, which
+//! [`record_fixture`] checks. Every other account is loaded before each transaction with the
+//! lamports and token balance its status meta recorded, and otherwise as it was in the snapshot.
+//!
+//! Only the deque program's instructions are replayed. Compute budget instructions are skipped and
+//! any other instruction is reported as a mismatch, since the simulator can't run it.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+use deque::{
+    seeds::{
+        event_authority::find_event_authority_address, event_history::find_event_history_address,
+        shard::find_shard_address, trader::find_trader_address,
+    },
+    state::{Deque, MarketEscrow, DEQUE_HEADER_SIZE},
+};
+use deque_svm::{token_account, PROGRAMS};
+use serde::{Deserialize, Serialize};
+use solana_client::rpc_client::{GetConfirmedSignaturesForAddress2Config, RpcClient};
+use solana_program::{program_pack::Pack, system_program, sysvar};
+use solana_sdk::{
+    account::Account,
+    compute_budget::check_id as is_compute_budget_program,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::Signature,
+};
+use solana_transaction_status::{
+    option_serializer::OptionSerializer, EncodedConfirmedTransactionWithStatusMeta,
+    EncodedTransaction, UiMessage, UiTransactionTokenBalance,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token::state::Account as TokenAccount;
+
+use crate::{
+    ellipsis_transaction_utils::parse_transaction,
+    events::fetch_encoded_txn,
+    owned_events::{pubkey_str, OwnedDequeEvent, ToOwnedEvent},
+    simulator::MarketSimulator,
+    views::sharded_escrows,
+};
+
+/// Everything needed to replay a run of transactions against a market.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReplayFixture {
+    #[serde(with = "pubkey_str")]
+    pub deque: Pubkey,
+    pub source: FixtureSource,
+    /// The deque program's accounts before the first transaction. Empty if the first transaction
+    /// initializes the market.
+    pub program_accounts: Vec<FixtureAccount>,
+    pub transactions: Vec<RecordedTransaction>,
+    /// The deque program's accounts after the last transaction, or empty to skip comparing them.
+    pub final_accounts: Vec<FixtureAccount>,
+}
+
+/// Where a fixture's transactions were recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FixtureSource {
+    /// Sent to a cluster after its accounts were snapshotted at `snapshot_slot`.
+    Cluster { snapshot_slot: u64 },
+    /// Processed by a [`MarketSimulator`], see [`record_simulated`].
+    Simulated,
+}
+
+/// Accounts as a cluster held them at `slot`, to record a fixture's transactions from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccountSnapshot {
+    pub slot: u64,
+    pub accounts: Vec<FixtureAccount>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedTransaction {
+    pub signature: String,
+    pub slot: u64,
+    pub block_time: Option<i64>,
+    /// Every account the transaction references that the deque program doesn't own and that
+    /// wasn't created by an earlier transaction in the fixture, as it was before the transaction.
+    pub accounts: Vec<FixtureAccount>,
+    pub instructions: Vec<RecordedInstruction>,
+    pub is_err: bool,
+    /// The events the transaction emitted, in order.
+    pub events: Vec<OwnedDequeEvent>,
+    /// The balance of every token account the transaction references, after it.
+    pub post_token_balances: Vec<TokenBalance>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedInstruction {
+    #[serde(with = "pubkey_str")]
+    pub program_id: Pubkey,
+    pub accounts: Vec<RecordedAccountMeta>,
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedAccountMeta {
+    #[serde(with = "pubkey_str")]
+    pub pubkey: Pubkey,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FixtureAccount {
+    #[serde(with = "pubkey_str")]
+    pub pubkey: Pubkey,
+    pub lamports: u64,
+    #[serde(with = "pubkey_str")]
+    pub owner: Pubkey,
+    pub executable: bool,
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenBalance {
+    #[serde(with = "pubkey_str")]
+    pub account: Pubkey,
+    pub amount: u64,
+}
+
+impl ReplayFixture {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        load_json(path.as_ref())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        save_json(self, path.as_ref())
+    }
+}
+
+impl AccountSnapshot {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        load_json(path.as_ref())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        save_json(self, path.as_ref())
+    }
+}
+
+fn load_json<T: serde::de::DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("Invalid JSON in {}", path.display()))
+}
+
+fn save_json<T: Serialize>(value: &T, path: &Path) -> anyhow::Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(value)? + "\n")
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+impl From<&Instruction> for RecordedInstruction {
+    fn from(ixn: &Instruction) -> Self {
+        Self {
+            program_id: ixn.program_id,
+            accounts: ixn
+                .accounts
+                .iter()
+                .map(|meta| RecordedAccountMeta {
+                    pubkey: meta.pubkey,
+                    is_signer: meta.is_signer,
+                    is_writable: meta.is_writable,
+                })
+                .collect(),
+            data: ixn.data.clone(),
+        }
+    }
+}
+
+impl From<&RecordedInstruction> for Instruction {
+    fn from(ixn: &RecordedInstruction) -> Self {
+        Self {
+            program_id: ixn.program_id,
+            accounts: ixn
+                .accounts
+                .iter()
+                .map(|meta| AccountMeta {
+                    pubkey: meta.pubkey,
+                    is_signer: meta.is_signer,
+                    is_writable: meta.is_writable,
+                })
+                .collect(),
+            data: ixn.data.clone(),
+        }
+    }
+}
+
+impl FixtureAccount {
+    pub fn new(pubkey: Pubkey, account: &Account) -> Self {
+        Self {
+            pubkey,
+            lamports: account.lamports,
+            owner: account.owner,
+            executable: account.executable,
+            data: account.data.clone(),
+        }
+    }
+
+    pub fn to_account(&self) -> Account {
+        Account {
+            lamports: self.lamports,
+            data: self.data.clone(),
+            owner: self.owner,
+            executable: self.executable,
+            rent_epoch: 0,
+        }
+    }
+}
+
+/// A difference between a replayed transaction, or the final state, and what the chain recorded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The transaction that diverged, or `None` for the final state.
+    pub signature: Option<String>,
+    pub description: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.signature {
+            Some(signature) => write!(f, "{signature}: {}", self.description),
+            None => write!(f, "final state: {}", self.description),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub transactions: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    pub fn is_match(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Replays every transaction in `fixture` in order and collects where it diverges from the chain.
+pub fn replay(fixture: &ReplayFixture) -> anyhow::Result<ReplayReport> {
+    let mut sim = MarketSimulator::empty(fixture.deque);
+    for account in &fixture.program_accounts {
+        sim.set_account(account.pubkey, account.to_account());
+    }
+
+    let mut report = ReplayReport::default();
+    for txn in &fixture.transactions {
+        let mut mismatch = |description: String| {
+            report.mismatches.push(Mismatch {
+                signature: Some(txn.signature.clone()),
+                description,
+            })
+        };
+
+        for account in &txn.accounts {
+            sim.set_account(account.pubkey, account.to_account());
+        }
+        sim.set_clock(txn.slot, txn.block_time.unwrap_or_default());
+
+        // A failed instruction rolls back the whole transaction.
+        let before = sim.accounts().clone();
+        let mut events = vec![];
+        let mut failure = None;
+        for ixn in &txn.instructions {
+            match ixn.program_id {
+                id if id == deque::ID => {}
+                id if is_compute_budget_program(&id) => continue,
+                id => {
+                    mismatch(format!("Can't replay an instruction to {id}"));
+                    continue;
+                }
+            }
+            match sim.process(&ixn.into()).into_result() {
+                Ok(emitted) => events.extend(emitted),
+                Err(err) => {
+                    failure = Some(err);
+                    break;
+                }
+            }
+        }
+        if failure.is_some() {
+            sim.restore_accounts(before);
+        }
+
+        match (txn.is_err, failure) {
+            (false, Some(err)) => mismatch(format!("Succeeded on-chain but failed: {err}")),
+            (true, None) => mismatch("Failed on-chain but succeeded".to_string()),
+            (true, Some(_)) => {}
+            (false, None) => {
+                if let Some(description) = diff_events(&txn.events, &events) {
+                    mismatch(description);
+                }
+            }
+        }
+
+        for balance in &txn.post_token_balances {
+            let replayed = sim
+                .account(&balance.account)
+                .and_then(|account| account.data.get(..TokenAccount::LEN))
+                .and_then(|data| TokenAccount::unpack(data).ok())
+                .map_or(0, |token_account| token_account.amount);
+            if replayed != balance.amount {
+                mismatch(format!(
+                    "Token account {} holds {replayed}, {} on-chain",
+                    balance.account, balance.amount
+                ));
+            }
+        }
+        report.transactions += 1;
+    }
+
+    let recorded: HashMap<Pubkey, Account> = fixture
+        .final_accounts
+        .iter()
+        .map(|account| (account.pubkey, account.to_account()))
+        .collect();
+    for account in &fixture.final_accounts {
+        let mut mismatch = |description: String| {
+            report.mismatches.push(Mismatch {
+                signature: None,
+                description,
+            })
+        };
+        let Some(replayed) = sim.account(&account.pubkey) else {
+            mismatch(format!("{} doesn't exist", account.pubkey));
+            continue;
+        };
+        if replayed.lamports != account.lamports || replayed.owner != account.owner {
+            mismatch(format!(
+                "{} has {} lamports and owner {}, {} and {} on-chain",
+                account.pubkey, replayed.lamports, replayed.owner, account.lamports, account.owner
+            ));
+        }
+        if replayed.data != account.data {
+            mismatch(format!("{}'s data differs", account.pubkey));
+        }
+    }
+    if recorded.contains_key(&fixture.deque) {
+        let replayed = escrows(&fixture.deque, sim.accounts())?;
+        let expected = escrows(&fixture.deque, &recorded)?;
+        report.mismatches.extend(
+            diff_escrows(&expected, &replayed).map(|description| Mismatch {
+                signature: None,
+                description,
+            }),
+        );
+    }
+    Ok(report)
+}
+
+/// Describes the first difference between the recorded and replayed events, if there is one.
+fn diff_events(recorded: &[OwnedDequeEvent], replayed: &[OwnedDequeEvent]) -> Option<String> {
+    if let Some((i, (expected, actual))) = recorded
+        .iter()
+        .zip(replayed)
+        .enumerate()
+        .find(|(_, (expected, actual))| expected != actual)
+    {
+        return Some(format!("Event {i} is {actual:?}, {expected:?} on-chain"));
+    }
+    (recorded.len() != replayed.len()).then(|| {
+        format!(
+            "Emitted {} events, {} on-chain",
+            replayed.len(),
+            recorded.len()
+        )
+    })
+}
+
+/// Describes every escrow that differs between the recorded and replayed deques, and whether
+/// their order does.
+fn diff_escrows<'a>(
+    recorded: &'a [MarketEscrow],
+    replayed: &'a [MarketEscrow],
+) -> impl Iterator<Item = String> + 'a {
+    let by_trader = |escrows: &'a [MarketEscrow]| -> BTreeMap<Pubkey, &'a MarketEscrow> {
+        escrows
+            .iter()
+            .map(|escrow| (escrow.trader, escrow))
+            .collect()
+    };
+    let (expected, actual) = (by_trader(recorded), by_trader(replayed));
+    let mut traders: Vec<Pubkey> = expected.keys().chain(actual.keys()).copied().collect();
+    traders.sort();
+    traders.dedup();
+
+    let differences: Vec<String> = traders
+        .into_iter()
+        .filter_map(
+            |trader| match (expected.get(&trader), actual.get(&trader)) {
+                (Some(expected), Some(actual)) if expected != actual => Some(format!(
+                    "{trader}'s escrow holds {} base and {} quote, {} and {} on-chain",
+                    actual.base, actual.quote, expected.base, expected.quote
+                )),
+                (Some(_), None) => Some(format!("{trader} has no escrow, but does on-chain")),
+                (None, Some(_)) => Some(format!("{trader} has an escrow, but not on-chain")),
+                _ => None,
+            },
+        )
+        .collect();
+    let reordered = differences.is_empty() && recorded != replayed;
+    differences
+        .into_iter()
+        .chain(reordered.then(|| "The escrows are in a different order".to_string()))
+}
+
+/// Every escrow in the deque held in `accounts`, from front to back.
+fn escrows(
+    deque: &Pubkey,
+    accounts: &HashMap<Pubkey, Account>,
+) -> anyhow::Result<Vec<MarketEscrow>> {
+    let data = |key: &Pubkey| {
+        accounts
+            .get(key)
+            .map(|account| account.data.clone())
+            .with_context(|| format!("{key} doesn't exist"))
+    };
+    let mut deque_data = data(deque)?;
+    let last_shard = Deque::from_bytes(&mut deque_data[..DEQUE_HEADER_SIZE])
+        .map_err(|e| anyhow::anyhow!("{e}"))?
+        .header
+        .last_shard;
+    let mut shard_data = (1..=last_shard)
+        .map(|shard| data(&find_shard_address(deque, shard).0))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    sharded_escrows(&mut deque_data, &mut shard_data)
+}
+
+/// Fetches `signatures` and records them as a fixture for the market at `deque`, starting from
+/// `snapshot`.
+///
+/// Fails if a transaction landed at or before the snapshot's slot, or if a transaction on the
+/// market that isn't in `signatures` landed between the snapshot and the last one. The final
+/// accounts are fetched as they are now, and left empty if the market has moved on since.
+pub fn record_fixture(
+    rpc: &RpcClient,
+    deque: Pubkey,
+    signatures: &[Signature],
+    snapshot: &AccountSnapshot,
+) -> anyhow::Result<ReplayFixture> {
+    let txns = signatures
+        .iter()
+        .map(|sig| fetch_encoded_txn(rpc, *sig))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if let Some(txn) = txns.iter().find(|txn| txn.slot <= snapshot.slot) {
+        bail!(
+            "A transaction landed in slot {}, but the accounts were snapshotted in slot {}",
+            txn.slot,
+            snapshot.slot
+        );
+    }
+    let keys: Vec<Vec<(Pubkey, bool, bool)>> = txns
+        .iter()
+        .map(account_keys)
+        .collect::<anyhow::Result<_>>()?;
+
+    let mut unique: Vec<Pubkey> = keys.iter().flatten().map(|(key, ..)| *key).collect();
+    unique.sort();
+    unique.dedup();
+    let current = snapshot_accounts(rpc, &unique)?;
+    let last_slot = txns
+        .iter()
+        .map(|txn| txn.slot)
+        .max()
+        .unwrap_or(snapshot.slot);
+    let unrecorded = unrecorded_market_slots(rpc, &deque, signatures, snapshot.slot)?;
+    if let Some(slot) = unrecorded.iter().find(|slot| **slot <= last_slot) {
+        bail!("A transaction on the market in slot {slot} isn't one of the recorded transactions");
+    }
+
+    let mut pre_state = PreState::new(snapshot);
+    let transactions = txns
+        .into_iter()
+        .zip(&keys)
+        .map(|(txn, keys)| record_transaction(txn, keys, &mut pre_state))
+        .collect::<anyhow::Result<_>>()?;
+    let mut final_accounts: Vec<FixtureAccount> =
+        match unrecorded.iter().any(|slot| *slot <= current.slot) {
+            true => vec![],
+            false => current
+                .accounts
+                .into_iter()
+                .filter(|account| account.owner == deque::ID)
+                .collect(),
+        };
+    final_accounts.sort_by_key(|account| account.pubkey);
+
+    Ok(ReplayFixture {
+        deque,
+        source: FixtureSource::Cluster {
+            snapshot_slot: snapshot.slot,
+        },
+        program_accounts: snapshot
+            .accounts
+            .iter()
+            .filter(|account| account.owner == deque::ID)
+            .cloned()
+            .collect(),
+        transactions,
+        final_accounts,
+    })
+}
+
+/// Fetches every account in `keys` that exists. The snapshot's slot is the latest slot any of them
+/// was fetched at.
+pub fn snapshot_accounts(rpc: &RpcClient, keys: &[Pubkey]) -> anyhow::Result<AccountSnapshot> {
+    let mut snapshot = AccountSnapshot {
+        slot: 0,
+        accounts: vec![],
+    };
+    for keys in keys.chunks(100) {
+        let response = rpc.get_multiple_accounts_with_commitment(keys, rpc.commitment())?;
+        snapshot.slot = snapshot.slot.max(response.context.slot);
+        for (account, key) in response.value.into_iter().zip(keys) {
+            if let Some(account) = account {
+                snapshot.accounts.push(FixtureAccount::new(*key, &account));
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Snapshots the market at `deque`: the deque and its shards, its event authority and history, its
+/// mints and vaults, and `others`, e.g. the traders whose transactions will be recorded, along
+/// with the trader record of each of `others` that has one.
+pub fn snapshot_market(
+    rpc: &RpcClient,
+    deque: &Pubkey,
+    others: &[Pubkey],
+) -> anyhow::Result<AccountSnapshot> {
+    let mut data = rpc.get_account_data(deque)?;
+    let header = Deque::from_bytes(&mut data[..DEQUE_HEADER_SIZE])
+        .map_err(|e| anyhow::anyhow!("{e}"))?
+        .header;
+    let mints = [header.base_mint, header.quote_mint];
+    let mut keys = vec![
+        *deque,
+        find_event_authority_address(deque).0,
+        find_event_history_address(deque).0,
+    ];
+    keys.extend((1..=header.last_shard).map(|shard| find_shard_address(deque, shard).0));
+    for (mint, account) in mints.iter().zip(rpc.get_multiple_accounts(&mints)?) {
+        let account = account.with_context(|| format!("Mint {mint} doesn't exist"))?;
+        keys.push(*mint);
+        keys.push(get_associated_token_address_with_program_id(
+            deque,
+            mint,
+            &account.owner,
+        ));
+    }
+    keys.extend(others);
+    keys.extend(
+        others
+            .iter()
+            .map(|other| find_trader_address(deque, other).0),
+    );
+    keys.sort();
+    keys.dedup();
+    snapshot_accounts(rpc, &keys)
+}
+
+/// The slots of every transaction on the market after `after_slot` that isn't in `signatures`.
+fn unrecorded_market_slots(
+    rpc: &RpcClient,
+    deque: &Pubkey,
+    signatures: &[Signature],
+    after_slot: u64,
+) -> anyhow::Result<Vec<u64>> {
+    let recorded: Vec<String> = signatures.iter().map(Signature::to_string).collect();
+    let mut slots = vec![];
+    let mut before = None;
+    loop {
+        let page = rpc.get_signatures_for_address_with_config(
+            deque,
+            GetConfirmedSignaturesForAddress2Config {
+                before,
+                commitment: Some(rpc.commitment()),
+                ..Default::default()
+            },
+        )?;
+        let Some(last) = page.last() else {
+            return Ok(slots);
+        };
+        before = Some(Signature::from_str(&last.signature)?);
+        for status in &page {
+            if status.slot <= after_slot {
+                return Ok(slots);
+            }
+            if !recorded.contains(&status.signature) {
+                slots.push(status.slot);
+            }
+        }
+    }
+}
+
+/// The transaction's account keys, including those loaded from lookup tables, with whether each
+/// one is a signer and writable.
+fn account_keys(
+    txn: &EncodedConfirmedTransactionWithStatusMeta,
+) -> anyhow::Result<Vec<(Pubkey, bool, bool)>> {
+    let EncodedTransaction::Json(ui_txn) = &txn.transaction.transaction else {
+        bail!("Expected a JSON encoded transaction");
+    };
+    let UiMessage::Raw(message) = &ui_txn.message else {
+        bail!("Expected a raw message");
+    };
+    let header = &message.header;
+    let num_signers = header.num_required_signatures as usize;
+    let num_writable_signers = num_signers - header.num_readonly_signed_accounts as usize;
+    let num_writable_unsigned =
+        message.account_keys.len() - num_signers - header.num_readonly_unsigned_accounts as usize;
+
+    let mut keys = message
+        .account_keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| {
+            let is_writable = match i < num_signers {
+                true => i < num_writable_signers,
+                false => i - num_signers < num_writable_unsigned,
+            };
+            Ok((Pubkey::from_str(key)?, i < num_signers, is_writable))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if let Some(OptionSerializer::Some(loaded)) = txn
+        .transaction
+        .meta
+        .as_ref()
+        .map(|meta| &meta.loaded_addresses)
+    {
+        for (addresses, is_writable) in [(&loaded.writable, true), (&loaded.readonly, false)] {
+            for key in addresses {
+                keys.push((Pubkey::from_str(key)?, false, is_writable));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// What each account held before a transaction, apart from what its status meta recorded.
+struct PreState {
+    snapshot: HashMap<Pubkey, Account>,
+    /// Accounts that didn't exist before an earlier recorded transaction and did after it. The
+    /// replay creates them, so they're carried over instead of loaded.
+    created: HashSet<Pubkey>,
+}
+
+impl PreState {
+    fn new(snapshot: &AccountSnapshot) -> Self {
+        Self {
+            snapshot: snapshot
+                .accounts
+                .iter()
+                .map(|account| (account.pubkey, account.to_account()))
+                .collect(),
+            created: HashSet::new(),
+        }
+    }
+
+    /// The account at `key`, the transaction's `index`th, to load before replaying the
+    /// transaction, or `None` to leave it to the replay.
+    fn account(
+        &self,
+        key: &Pubkey,
+        index: usize,
+        lamports: u64,
+        token_balance: Option<&UiTransactionTokenBalance>,
+    ) -> anyhow::Result<Option<Account>> {
+        let snapshotted = self.snapshot.get(key);
+        if *key == sysvar::instructions::ID
+            || PROGRAMS.contains(key)
+            || is_compute_budget_program(key)
+            || snapshotted.is_some_and(|account| account.owner == deque::ID)
+        {
+            return Ok(None);
+        }
+        if let Some(balance) = token_balance {
+            let owner = match &balance.program_id {
+                OptionSerializer::Some(program_id) => Pubkey::from_str(program_id)?,
+                _ => spl_token::ID,
+            };
+            let amount: u64 = balance.ui_token_amount.amount.parse()?;
+            let mut account = match snapshotted {
+                Some(account) if account.owner == owner => account.clone(),
+                _ => token_account(
+                    &owner,
+                    &Pubkey::from_str(&balance.mint)?,
+                    &Pubkey::from_str(
+                        Option::<&String>::from(balance.owner.as_ref())
+                            .context("Token balance has no owner")?,
+                    )?,
+                    amount,
+                ),
+            };
+            // Both token programs keep the amount at the same offset.
+            account.data[64..72].copy_from_slice(&amount.to_le_bytes());
+            account.lamports = lamports;
+            return Ok(Some(account));
+        }
+        if self.created.contains(key) || lamports == 0 {
+            return Ok(None);
+        }
+        match snapshotted {
+            Some(account) => Ok(Some(Account {
+                lamports,
+                ..account.clone()
+            })),
+            // The fee payer has to be a system account without any data.
+            None if index == 0 => Ok(Some(Account {
+                lamports,
+                owner: system_program::ID,
+                ..Default::default()
+            })),
+            None => bail!("{key} existed before the transaction but isn't in the snapshot"),
+        }
+    }
+}
+
+fn record_transaction(
+    txn: EncodedConfirmedTransactionWithStatusMeta,
+    keys: &[(Pubkey, bool, bool)],
+    pre_state: &mut PreState,
+) -> anyhow::Result<RecordedTransaction> {
+    let meta = txn
+        .transaction
+        .meta
+        .clone()
+        .context("Transaction has no status meta")?;
+    let token_balances = |balances: OptionSerializer<Vec<UiTransactionTokenBalance>>| match balances
+    {
+        OptionSerializer::Some(balances) => balances,
+        _ => vec![],
+    };
+    let pre_token_balances = token_balances(meta.pre_token_balances);
+    let post_token_balances = token_balances(meta.post_token_balances);
+
+    let mut accounts = vec![];
+    for (i, (key, ..)) in keys.iter().enumerate() {
+        let token_balance = pre_token_balances
+            .iter()
+            .find(|balance| balance.account_index as usize == i);
+        if let Some(account) = pre_state.account(key, i, meta.pre_balances[i], token_balance)? {
+            accounts.push(FixtureAccount::new(*key, &account));
+        }
+        if meta.pre_balances[i] == 0 && meta.post_balances[i] != 0 {
+            pre_state.created.insert(*key);
+        }
+    }
+
+    let parsed = parse_transaction(txn);
+    let instructions = parsed
+        .instructions
+        .iter()
+        .map(|ixn| {
+            let accounts = ixn
+                .accounts
+                .iter()
+                .map(|key| {
+                    let pubkey = Pubkey::from_str(key)?;
+                    let (_, is_signer, is_writable) = keys
+                        .iter()
+                        .find(|(k, ..)| *k == pubkey)
+                        .context("Instruction account isn't in the transaction")?;
+                    Ok(RecordedAccountMeta {
+                        pubkey,
+                        is_signer: *is_signer,
+                        is_writable: *is_writable,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(RecordedInstruction {
+                program_id: Pubkey::from_str(&ixn.program_id)?,
+                accounts,
+                data: ixn.data.clone(),
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    // Programs built with `log-events` log their events instead of flushing them with a CPI.
+    let mut events: Vec<OwnedDequeEvent> = parsed
+        .get_inner_deque_events()?
+        .iter()
+        .map(ToOwnedEvent::to_owned_event)
+        .collect();
+    events.extend(parsed.get_logged_deque_events()?);
+
+    let post_token_balances = post_token_balances
+        .iter()
+        .map(|balance| {
+            Ok(TokenBalance {
+                account: keys[balance.account_index as usize].0,
+                amount: balance.ui_token_amount.amount.parse()?,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(RecordedTransaction {
+        signature: parsed.signature,
+        slot: parsed.slot,
+        block_time: parsed.block_time,
+        accounts,
+        instructions,
+        is_err: parsed.is_err,
+        events,
+        post_token_balances,
+    })
+}
+
+/// Processes `ixn` as a transaction of its own and records it the way [`record_fixture`] records
+/// a transaction from a cluster, e.g. to save a simulated reproduction of a bug as a fixture.
+pub fn record_simulated(
+    sim: &mut MarketSimulator,
+    ixn: &Instruction,
+    signature: String,
+) -> RecordedTransaction {
+    let mut keys: Vec<Pubkey> = ixn.accounts.iter().map(|meta| meta.pubkey).collect();
+    keys.sort();
+    keys.dedup();
+    let accounts: Vec<FixtureAccount> = keys
+        .iter()
+        .filter(|key| **key != sysvar::instructions::ID)
+        .filter_map(|key| Some(FixtureAccount::new(*key, sim.account(key)?)))
+        .filter(|account| account.owner != deque::ID)
+        .collect();
+    let token_accounts: Vec<Pubkey> = accounts
+        .iter()
+        .filter(|account| account.owner == spl_token::ID && account.data.len() == TokenAccount::LEN)
+        .map(|account| account.pubkey)
+        .collect();
+
+    let processed = sim.process(ixn);
+    let post_token_balances = token_accounts
+        .into_iter()
+        .map(|account| TokenBalance {
+            account,
+            amount: TokenAccount::unpack(&sim.account(&account).expect("Should exist").data)
+                .map_or(0, |token_account| token_account.amount),
+        })
+        .collect();
+    RecordedTransaction {
+        signature,
+        slot: sim.slot(),
+        block_time: Some(sim.unix_timestamp()),
+        accounts,
+        instructions: vec![ixn.into()],
+        is_err: processed.result.is_err(),
+        events: processed.events,
+        post_token_balances,
+    }
+}
+
+/// The deque program's accounts in `sim`, to start or end a fixture with.
+pub fn program_accounts(sim: &MarketSimulator) -> Vec<FixtureAccount> {
+    let mut accounts: Vec<FixtureAccount> = sim
+        .accounts()
+        .iter()
+        .filter(|(_, account)| account.owner == deque::ID)
+        .map(|(key, account)| FixtureAccount::new(*key, account))
+        .collect();
+    accounts.sort_by_key(|account| account.pubkey);
+    accounts
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        STANDARD.decode(s).map_err(D::Error::custom)
+    }
+}
+
+#[test]
+fn test_replay_matches_a_simulated_recording() {
+    use deque::instruction_enum::{DepositInstructionData, MarketChoice, WithdrawInstructionData};
+    use solana_sdk::signer::Signer;
+
+    let mut sim = MarketSimulator::new(1)
+        .and_then(|sim| sim.with_event_history(8))
+        .expect("Should initialize");
+    let traders = [sim.add_trader(100, 100), sim.add_trader(100, 100)];
+    let initial_accounts = program_accounts(&sim);
+
+    let ixns = [
+        (
+            &traders[0],
+            DepositInstructionData::new(40, MarketChoice::Base).into(),
+        ),
+        // Fails, the trader has nothing to withdraw.
+        (
+            &traders[1],
+            WithdrawInstructionData::new(MarketChoice::Base).into(),
+        ),
+        (
+            &traders[1],
+            DepositInstructionData::new(25, MarketChoice::Quote).into(),
+        ),
+        (
+            &traders[0],
+            WithdrawInstructionData::new(MarketChoice::Base).into(),
+        ),
+        (
+            &traders[1],
+            DepositInstructionData::new(5, MarketChoice::Base).into(),
+        ),
+    ];
+    let transactions: Vec<RecordedTransaction> = ixns
+        .into_iter()
+        .enumerate()
+        .map(|(i, (trader, instruction))| {
+            sim.set_clock(10 + i as u64, 1_700_000_000 + i as i64);
+            let ixn = sim.market.deposit_or_withdraw_ixn(trader, instruction);
+            record_simulated(&mut sim, &ixn, format!("{}-{i}", trader.pubkey()))
+        })
+        .collect();
+    assert!(transactions[1].is_err);
+
+    let fixture = ReplayFixture {
+        deque: sim.market.deque_pubkey,
+        source: FixtureSource::Simulated,
+        program_accounts: initial_accounts,
+        transactions,
+        final_accounts: program_accounts(&sim),
+    };
+    let json = serde_json::to_string(&fixture).expect("Should serialize");
+    let fixture: ReplayFixture = serde_json::from_str(&json).expect("Should deserialize");
+    let report = replay(&fixture).expect("Should replay");
+    assert_eq!(report.transactions, 5);
+    assert_eq!(report.mismatches, vec![]);
+
+    // What the replay would see if the chain had recorded something else.
+    let mut diverged = fixture.clone();
+    let OwnedDequeEvent::Deposit(deposit) = &mut diverged.transactions[0].events[1] else {
+        panic!("Should be a deposit");
+    };
+    deposit.amount = 41;
+    diverged.transactions[1].is_err = false;
+    diverged.transactions[2].post_token_balances[0].amount += 1;
+    let report = replay(&diverged).expect("Should replay");
+    let signatures: Vec<Option<&str>> = report
+        .mismatches
+        .iter()
+        .map(|mismatch| mismatch.signature.as_deref())
+        .collect();
+    let expected: Vec<Option<&str>> = (0..3)
+        .map(|i| Some(fixture.transactions[i].signature.as_str()))
+        .collect();
+    assert_eq!(signatures, expected);
+
+    // Replaying from the wrong starting state leaves the deque with a different escrow.
+    let mut diverged = fixture.clone();
+    diverged.transactions.remove(4);
+    let report = replay(&diverged).expect("Should replay");
+    assert!(report
+        .mismatches
+        .iter()
+        .any(|mismatch| mismatch.signature.is_none()
+            && mismatch.description
+                == format!(
+                    "{}'s escrow holds 0 base and 25 quote, 5 and 25 on-chain",
+                    traders[1].pubkey()
+                )));
+}
+
+/// The fixture is simulated, since recording from a cluster needs the network. Regenerate it with
+/// `cargo run --example replay -- simulate fixtures/replay/simulated.json` only in the change that
+/// alters what the program stores or emits; everything else has to keep replaying it as it is.
+#[test]
+fn test_replay_saved_fixture() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/replay/simulated.json");
+    let fixture = ReplayFixture::load(path).expect("Should load");
+    let report = replay(&fixture).expect("Should replay");
+    assert_eq!(report.transactions, fixture.transactions.len());
+    assert_eq!(report.mismatches, vec![]);
+}
+
+#[test]
+fn test_pre_state_comes_from_the_snapshot_and_the_status_meta() {
+    use solana_account_decoder_client_types::token::UiTokenAmount;
+
+    let (mint, trader, vault, other) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    let deque_account = Account {
+        owner: deque::ID,
+        ..Default::default()
+    };
+    let snapshot = AccountSnapshot {
+        slot: 7,
+        accounts: vec![
+            FixtureAccount::new(Pubkey::new_unique(), &deque_account),
+            FixtureAccount::new(vault, &token_account(&spl_token::ID, &mint, &trader, 500)),
+        ],
+    };
+    let mut pre_state = PreState::new(&snapshot);
+    let balance = |amount: u64| UiTransactionTokenBalance {
+        account_index: 1,
+        mint: mint.to_string(),
+        ui_token_amount: UiTokenAmount {
+            ui_amount: None,
+            decimals: 6,
+            amount: amount.to_string(),
+            ui_amount_string: amount.to_string(),
+        },
+        owner: OptionSerializer::Some(trader.to_string()),
+        program_id: OptionSerializer::Some(spl_token::ID.to_string()),
+    };
+
+    // The deque program's accounts are carried over by the replay.
+    let deque = snapshot.accounts[0].pubkey;
+    assert_eq!(pre_state.account(&deque, 2, 10, None).unwrap(), None);
+
+    // A token account holds the balance the status meta recorded, not the snapshot's.
+    let account = pre_state
+        .account(&vault, 1, 20, Some(&balance(300)))
+        .unwrap()
+        .expect("Should be loaded");
+    assert_eq!(account.lamports, 20);
+    assert_eq!(TokenAccount::unpack(&account.data).unwrap().amount, 300);
+
+    // Anything else that existed has to be in the snapshot, except the fee payer.
+    let fee_payer = pre_state
+        .account(&other, 0, 30, None)
+        .unwrap()
+        .expect("Should be loaded");
+    assert_eq!(
+        (fee_payer.owner, fee_payer.lamports),
+        (system_program::ID, 30)
+    );
+    assert!(pre_state.account(&other, 3, 30, None).is_err());
+    assert_eq!(pre_state.account(&other, 3, 0, None).unwrap(), None);
+    pre_state.created.insert(other);
+    assert_eq!(pre_state.account(&other, 3, 30, None).unwrap(), None);
+}
@@ -0,0 +1,569 @@
+//! An in-memory market that runs the deque program's [`process_instruction`] natively, for testing
+//! deposit and withdraw semantics without a validator.
+//!
+//! Instructions are built with the same [`MarketContext`] methods used against a real cluster and
+//! processed by [`deque_svm`], which captures the events of the event log flush directly.
+//!
+//! Each thread has its own clock and captured logs and events, so simulators can run in parallel on
+//! separate threads.
+
+use std::collections::HashMap;
+
+use deque::{
+    instruction_enum::{DepositInstructionData, MarketChoice, WithdrawInstructionData},
+    pack::Pack,
+    seeds::{
+        event_authority::find_event_authority_address, event_history::find_event_history_address,
+        market::find_market_address,
+    },
+    shared::error_context::ErrorContext,
+    state::{Deque, MarketEscrow, DEQUE_HEADER_SIZE},
+};
+use deque_svm::{mint_account, token_account};
+use solana_program::{
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program_pack::Pack as SolanaPack,
+    system_program,
+};
+use solana_sdk::{
+    account::Account,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token::state::Account as TokenAccount;
+
+use crate::{
+    errors::describe_error_context,
+    events::{unpack_event_bytes, unpack_logged_events},
+    owned_events::{OwnedDequeEvent, ToOwnedEvent},
+    tokens::{DepositOrWithdraw, MarketContext},
+    views::sharded_escrows,
+};
+
+/// The lamports every simulated wallet starts with.
+pub const WALLET_LAMPORTS: u64 = 1_000_000_000_000;
+
+/// The outcome of a single simulated instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulatedInstruction {
+    pub result: ProgramResult,
+    /// The messages the program logged with `sol_log`.
+    pub logs: Vec<String>,
+    /// The header and events the instruction emitted, in order.
+    pub events: Vec<OwnedDequeEvent>,
+    /// The error context the program logs when it fails.
+    pub error_context: Option<ErrorContext>,
+}
+
+impl SimulatedInstruction {
+    /// The emitted events, or an error describing why the instruction failed.
+    pub fn into_result(self) -> anyhow::Result<Vec<OwnedDequeEvent>> {
+        match (self.result, self.error_context) {
+            (Ok(()), _) => Ok(self.events),
+            (Err(_), Some(ctx)) => Err(anyhow::anyhow!(describe_error_context(&ctx))),
+            (Err(err), None) => Err(anyhow::anyhow!("Instruction failed with {err}")),
+        }
+    }
+}
+
+/// A market with its event authority and deque initialized, held entirely in memory.
+///
+/// Account changes are only kept when an instruction succeeds, like a transaction with a single
+/// instruction.
+pub struct MarketSimulator {
+    pub market: MarketContext,
+    pub payer: Keypair,
+    accounts: HashMap<Pubkey, Account>,
+    /// The accounts of each trader's deposits and withdrawals, per side and instruction tag.
+    trader_accounts: HashMap<(Pubkey, u8, u8), Vec<AccountMeta>>,
+    /// The aligned input buffer the accounts are deserialized from.
+    input: Vec<u128>,
+    slot: u64,
+    unix_timestamp: i64,
+}
+
+impl MarketSimulator {
+    /// Creates the market's mints and initializes its event authority and a deque with
+    /// `num_sectors` sectors.
+    pub fn new(num_sectors: u16) -> anyhow::Result<Self> {
+        let mut sim = Self::with_market(MarketContext {
+            base_mint: Pubkey::new_unique(),
+            quote_mint: Pubkey::new_unique(),
+            deque_pubkey: Pubkey::default(),
+            vault_base_ata: Pubkey::default(),
+            vault_quote_ata: Pubkey::default(),
+            base_token_program: spl_token::id(),
+            quote_token_program: spl_token::id(),
+            ata_program: spl_associated_token_account::id(),
+            event_authority: Pubkey::default(),
+            last_shard: 0,
+            event_history: None,
+        });
+
+        let market = &mut sim.market;
+        for mint in [market.base_mint, market.quote_mint] {
+            sim.accounts.insert(mint, mint_account(&spl_token::ID, 6));
+        }
+        market.deque_pubkey = find_market_address(&market.base_mint, &market.quote_mint).0;
+        market.vault_base_ata = token_address(&market.deque_pubkey, &market.base_mint);
+        market.vault_quote_ata = token_address(&market.deque_pubkey, &market.quote_mint);
+        market.event_authority = find_event_authority_address(&market.deque_pubkey).0;
+
+        let ixn = sim.market.initialize_event_authority_ixn(&sim.payer);
+        sim.process(&ixn).result?;
+        let ixn = sim
+            .market
+            .initialize_deque_market_ixn(&sim.payer, num_sectors);
+        sim.process(&ixn).result?;
+        Ok(sim)
+    }
+
+    /// A simulator holding nothing but the programs, for the market at `deque_pubkey` whose
+    /// accounts are set with [`Self::set_account`]. Its mints and vaults are left unset, so only
+    /// instructions built elsewhere can be processed.
+    pub fn empty(deque_pubkey: Pubkey) -> Self {
+        Self::with_market(MarketContext {
+            base_mint: Pubkey::default(),
+            quote_mint: Pubkey::default(),
+            deque_pubkey,
+            vault_base_ata: Pubkey::default(),
+            vault_quote_ata: Pubkey::default(),
+            base_token_program: spl_token::id(),
+            quote_token_program: spl_token::id(),
+            ata_program: spl_associated_token_account::id(),
+            event_authority: find_event_authority_address(&deque_pubkey).0,
+            last_shard: 0,
+            event_history: None,
+        })
+    }
+
+    fn with_market(market: MarketContext) -> Self {
+        let mut sim = MarketSimulator {
+            market,
+            payer: Keypair::new(),
+            accounts: deque_svm::PROGRAMS
+                .into_iter()
+                .map(|program| (program, deque_svm::program_account()))
+                .collect(),
+            trader_accounts: HashMap::new(),
+            input: vec![],
+            slot: 1,
+            unix_timestamp: 0,
+        };
+        sim.airdrop(&sim.payer.pubkey(), WALLET_LAMPORTS);
+        sim
+    }
+
+    /// Initializes the market's event history, which every later instruction records its events in.
+    pub fn with_event_history(mut self, capacity: u16) -> anyhow::Result<Self> {
+        let ixn = self
+            .market
+            .initialize_event_history_ixn(&self.payer, capacity);
+        self.process(&ixn).result?;
+        self.market.event_history = Some(find_event_history_address(&self.market.deque_pubkey).0);
+        self.trader_accounts.clear();
+        Ok(self)
+    }
+
+    pub fn slot(&self) -> u64 {
+        self.slot
+    }
+
+    pub fn unix_timestamp(&self) -> i64 {
+        self.unix_timestamp
+    }
+
+    /// Sets the slot and unix timestamp the program reads from the clock sysvar.
+    pub fn set_clock(&mut self, slot: u64, unix_timestamp: i64) {
+        self.slot = slot;
+        self.unix_timestamp = unix_timestamp;
+    }
+
+    pub fn account(&self, key: &Pubkey) -> Option<&Account> {
+        self.accounts.get(key)
+    }
+
+    pub fn set_account(&mut self, key: Pubkey, account: Account) {
+        self.accounts.insert(key, account);
+        if key == self.market.deque_pubkey {
+            self.sync_last_shard();
+        }
+    }
+
+    /// Every account the simulator holds, by address.
+    pub fn accounts(&self) -> &HashMap<Pubkey, Account> {
+        &self.accounts
+    }
+
+    /// Replaces every account, e.g. to roll back to a copy of [`Self::accounts`].
+    pub fn restore_accounts(&mut self, accounts: HashMap<Pubkey, Account>) {
+        self.accounts = accounts;
+        self.sync_last_shard();
+    }
+
+    pub fn airdrop(&mut self, key: &Pubkey, lamports: u64) {
+        let account = self.accounts.entry(*key).or_insert_with(|| Account {
+            owner: system_program::ID,
+            ..Default::default()
+        });
+        account.lamports += lamports;
+    }
+
+    /// Creates a funded wallet holding `base` and `quote` tokens in its associated token accounts.
+    pub fn add_trader(&mut self, base: u64, quote: u64) -> Keypair {
+        let trader = Keypair::new();
+        self.airdrop(&trader.pubkey(), WALLET_LAMPORTS);
+        let (base_ata, quote_ata) = self.market.get_atas(&trader.pubkey());
+        for (ata, mint, amount) in [
+            (base_ata, self.market.base_mint, base),
+            (quote_ata, self.market.quote_mint, quote),
+        ] {
+            self.accounts.insert(
+                ata,
+                token_account(&spl_token::ID, &mint, &trader.pubkey(), amount),
+            );
+        }
+        trader
+    }
+
+    /// Sets the balance of `owner`'s token account, creating it if it doesn't exist.
+    pub fn set_token_balance(&mut self, owner: &Pubkey, choice: MarketChoice, amount: u64) {
+        let mint = self.mint(choice);
+        self.accounts.insert(
+            token_address(owner, &mint),
+            token_account(&spl_token::ID, &mint, owner, amount),
+        );
+    }
+
+    /// The balance of `owner`'s associated token account, or zero if it doesn't exist.
+    pub fn token_balance(&self, owner: &Pubkey, choice: MarketChoice) -> u64 {
+        self.account(&token_address(owner, &self.mint(choice)))
+            .and_then(|account| TokenAccount::unpack(&account.data[..TokenAccount::LEN]).ok())
+            .map_or(0, |token_account| token_account.amount)
+    }
+
+    /// The balance of the market's vault for `choice`.
+    pub fn vault_balance(&self, choice: MarketChoice) -> u64 {
+        self.token_balance(&self.market.deque_pubkey, choice)
+    }
+
+    /// Every escrow in the deque, from front to back.
+    pub fn escrows(&self) -> Vec<MarketEscrow> {
+        let mut deque_data = self
+            .account(&self.market.deque_pubkey)
+            .expect("Deque should exist")
+            .data
+            .clone();
+        let mut shard_data: Vec<Vec<u8>> = (1..=self.market.last_shard)
+            .map(|shard| {
+                self.account(&self.market.shard_pubkey(shard))
+                    .expect("Shard should exist")
+                    .data
+                    .clone()
+            })
+            .collect();
+        sharded_escrows(&mut deque_data, &mut shard_data).expect("Should be a valid deque")
+    }
+
+    pub fn escrow(&self, trader: &Pubkey) -> Option<MarketEscrow> {
+        self.escrows()
+            .into_iter()
+            .find(|escrow| escrow.trader.as_ref() == trader.as_ref())
+    }
+
+    pub fn deposit(
+        &mut self,
+        trader: &Keypair,
+        amount: u64,
+        choice: MarketChoice,
+    ) -> SimulatedInstruction {
+        self.deposit_or_withdraw(trader, DepositInstructionData::new(amount, choice).into())
+    }
+
+    pub fn withdraw(&mut self, trader: &Keypair, choice: MarketChoice) -> SimulatedInstruction {
+        self.deposit_or_withdraw(trader, WithdrawInstructionData::new(choice).into())
+    }
+
+    /// Deriving the trader's and the deque's PDAs dominates the cost of building the instruction,
+    /// so each trader's accounts are cached until the deque gets a new shard.
+    fn deposit_or_withdraw(
+        &mut self,
+        trader: &Keypair,
+        instruction: DepositOrWithdraw,
+    ) -> SimulatedInstruction {
+        let (data, choice) = match &instruction {
+            DepositOrWithdraw::Deposit(deposit) => (deposit.pack().to_vec(), deposit.choice),
+            DepositOrWithdraw::Withdraw(withdraw) => (withdraw.pack().to_vec(), withdraw.choice),
+        };
+        // Only deposits pass the trader's record, so each instruction has its own accounts.
+        let key = (trader.pubkey(), choice as u8, data[0]);
+        let accounts = match self.trader_accounts.get(&key) {
+            Some(accounts) => accounts.clone(),
+            None => {
+                let accounts = self
+                    .market
+                    .deposit_or_withdraw_ixn(trader, instruction)
+                    .accounts;
+                self.trader_accounts.insert(key, accounts.clone());
+                accounts
+            }
+        };
+        self.process(&Instruction {
+            program_id: deque::ID,
+            accounts,
+            data,
+        })
+    }
+
+    /// Grows the deque by `num_sectors`, paid for by the simulator's payer.
+    pub fn resize(&mut self, num_sectors: u16) -> SimulatedInstruction {
+        let ixn = self.market.resize_ixn(&self.payer, num_sectors);
+        self.process(&ixn)
+    }
+
+    pub fn defragment(&mut self, max_moves: u16) -> SimulatedInstruction {
+        let ixn = self.market.defragment_ixn(max_moves);
+        self.process(&ixn)
+    }
+
+    pub fn reconcile(&mut self, fail_on_deficit: bool) -> SimulatedInstruction {
+        let ixn = self.market.reconcile_ixn(fail_on_deficit);
+        self.process(&ixn)
+    }
+
+    /// Processes `ixn` as the only instruction of a transaction. Signatures aren't checked, every
+    /// signer in the instruction's accounts is treated as having signed.
+    pub fn process(&mut self, ixn: &Instruction) -> SimulatedInstruction {
+        deque_svm::set_clock(self.slot, self.unix_timestamp);
+
+        // The buffer is reused between instructions, allocating it dominates otherwise.
+        let mut input = std::mem::take(&mut self.input);
+        deque_svm::serialize(ixn, |key| self.accounts.get(key), &mut input);
+        let executed = deque_svm::execute(&mut input, |accounts| {
+            for info in accounts.iter().filter(|info| info.is_writable) {
+                let account = self.accounts.entry(*info.key).or_default();
+                account.lamports = info.lamports();
+                account.owner = *info.owner;
+                account.data.clear();
+                account.data.extend_from_slice(&info.data.borrow());
+            }
+        });
+        self.input = input;
+        if executed.result.is_ok() {
+            self.sync_last_shard();
+        }
+
+        let mut events: Vec<_> = executed
+            .event_payloads
+            .iter()
+            .flat_map(|payload| unpack_event_bytes(payload).expect("Should decode events"))
+            .map(|event| event.to_owned_event())
+            .collect();
+        // Programs built with `log-events` log their events instead of flushing them with a CPI.
+        events.extend(unpack_logged_events(&executed.logged_data).expect("Should decode events"));
+        SimulatedInstruction {
+            result: executed.result,
+            logs: executed.logs,
+            events,
+            error_context: executed
+                .logged_data
+                .iter()
+                .find_map(|data| ErrorContext::unpack(data)),
+        }
+    }
+
+    /// Picks up a new shard from the deque's header, which changes every trader's accounts.
+    fn sync_last_shard(&mut self) {
+        let Some(deque) = self.account(&self.market.deque_pubkey) else {
+            return;
+        };
+        let Some(header) = deque.data.get(..DEQUE_HEADER_SIZE) else {
+            return;
+        };
+        let mut header = header.to_vec();
+        if let Ok(deque) = Deque::from_bytes(&mut header) {
+            if deque.header.last_shard != self.market.last_shard {
+                self.market.last_shard = deque.header.last_shard;
+                self.trader_accounts.clear();
+            }
+        }
+    }
+
+    fn mint(&self, choice: MarketChoice) -> Pubkey {
+        match choice {
+            MarketChoice::Base => self.market.base_mint,
+            MarketChoice::Quote => self.market.quote_mint,
+        }
+    }
+}
+
+fn token_address(owner: &Pubkey, mint: &Pubkey) -> Pubkey {
+    get_associated_token_address_with_program_id(owner, mint, &spl_token::ID)
+}
+
+#[test]
+fn test_simulator_matches_a_model_of_balances_and_escrows() {
+    use deque::shared::error::DequeError;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+    use solana_program::program_error::ProgramError;
+
+    use crate::owned_events::{OwnedDepositEvent, OwnedReconcileEvent, OwnedWithdrawEvent};
+
+    const INITIAL_BALANCE: u64 = 1_000;
+    let mut sim = MarketSimulator::new(1).expect("Should initialize");
+    let traders: Vec<Keypair> = (0..4)
+        .map(|_| sim.add_trader(INITIAL_BALANCE, INITIAL_BALANCE))
+        .collect();
+    // `(base, quote)` escrowed per trader.
+    let mut model = vec![(0u64, 0u64); traders.len()];
+    // The slots each trader's escrow was opened and last updated in.
+    let mut slots = vec![(0u64, 0u64); traders.len()];
+    let timestamp = |slot: u64| 1_700_000_000 + 2 * slot as i64;
+    let mut rng = SmallRng::seed_from_u64(7);
+
+    for round in 0..500 {
+        let slot = 10 + round;
+        sim.set_clock(slot, timestamp(slot));
+        let i = rng.random_range(0..traders.len());
+        let trader = traders[i].pubkey();
+        let choice = match rng.random_bool(0.5) {
+            true => MarketChoice::Base,
+            false => MarketChoice::Quote,
+        };
+        let has_escrow = model[i] != (0, 0);
+        let escrowed = match choice {
+            MarketChoice::Base => &mut model[i].0,
+            MarketChoice::Quote => &mut model[i].1,
+        };
+
+        if rng.random_bool(0.6) {
+            let amount = rng.random_range(0..=INITIAL_BALANCE / 2);
+            let processed = sim.deposit(&traders[i], amount, choice);
+            match INITIAL_BALANCE - *escrowed >= amount {
+                true => {
+                    assert_eq!(processed.result, Ok(()));
+                    assert_eq!(
+                        processed.events[1],
+                        OwnedDequeEvent::Deposit(OwnedDepositEvent {
+                            trader,
+                            amount,
+                            side: choice,
+                            slot,
+                        })
+                    );
+                    *escrowed += amount;
+                    if !has_escrow {
+                        slots[i].0 = slot;
+                    }
+                    slots[i].1 = slot;
+                }
+                // `TokenError::InsufficientFunds`, from the token program.
+                false => assert_eq!(processed.result, Err(ProgramError::Custom(1))),
+            }
+        } else {
+            let processed = sim.withdraw(&traders[i], choice);
+            match has_escrow {
+                false => {
+                    assert_eq!(processed.result, Err(DequeError::NoActiveEscrow.into()));
+                    let ctx = processed
+                        .error_context
+                        .expect("Should log an error context");
+                    assert_eq!(ctx.deque_error(), Some(DequeError::NoActiveEscrow));
+                }
+                true => {
+                    assert_eq!(processed.result, Ok(()));
+                    assert_eq!(
+                        processed.events[1],
+                        OwnedDequeEvent::Withdraw(OwnedWithdrawEvent {
+                            trader,
+                            amount: *escrowed,
+                            side: choice,
+                            slot,
+                        })
+                    );
+                    *escrowed = 0;
+                    slots[i].1 = slot;
+                }
+            }
+        }
+
+        let (base, quote) = model[i];
+        assert_eq!(
+            sim.token_balance(&trader, MarketChoice::Base),
+            INITIAL_BALANCE - base
+        );
+        assert_eq!(
+            sim.token_balance(&trader, MarketChoice::Quote),
+            INITIAL_BALANCE - quote
+        );
+        let escrow = sim.escrow(&trader);
+        match (base, quote) {
+            (0, 0) => assert_eq!(escrow, None),
+            _ => {
+                let (opened, updated) = slots[i];
+                let expected = MarketEscrow {
+                    first_deposit_slot: opened,
+                    first_deposit_timestamp: timestamp(opened),
+                    last_update_slot: updated,
+                    last_update_timestamp: timestamp(updated),
+                    ..MarketEscrow::new(trader, base, quote)
+                };
+                assert_eq!(escrow, Some(expected));
+            }
+        }
+    }
+
+    let (base, quote): (Vec<u64>, Vec<u64>) = model.into_iter().unzip();
+    assert_eq!(
+        sim.vault_balance(MarketChoice::Base),
+        base.iter().sum::<u64>()
+    );
+    assert_eq!(
+        sim.vault_balance(MarketChoice::Quote),
+        quote.iter().sum::<u64>()
+    );
+
+    let processed = sim.reconcile(true);
+    assert_eq!(processed.result, Ok(()));
+    for (event, side, escrowed) in [
+        (&processed.events[1], MarketChoice::Base, base.iter().sum()),
+        (
+            &processed.events[2],
+            MarketChoice::Quote,
+            quote.iter().sum(),
+        ),
+    ] {
+        assert_eq!(
+            event,
+            &OwnedDequeEvent::Reconcile(OwnedReconcileEvent {
+                side,
+                escrowed,
+                recorded: escrowed,
+                vault_balance: escrowed,
+                surplus: 0,
+                deficit: 0,
+            })
+        );
+    }
+}
+
+#[test]
+fn test_simulator_records_events_in_the_event_history() {
+    use crate::events::decode_event_history;
+
+    let mut sim = MarketSimulator::new(1)
+        .and_then(|sim| sim.with_event_history(8))
+        .expect("Should initialize");
+    let trader = sim.add_trader(100, 100);
+    sim.set_clock(42, 1_700_000_000);
+    assert_eq!(sim.deposit(&trader, 10, MarketChoice::Base).result, Ok(()));
+
+    let history = sim
+        .account(&sim.market.event_history.unwrap())
+        .expect("Event history should exist");
+    let events = decode_event_history(&history.data).expect("Should decode");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].slot, 42);
+}
@@ -1,12 +1,19 @@
 use anyhow::Context;
 use deque::{
     instruction_enum::{
-        DepositInstructionData, InitializeDequeInstructionData,
-        InitializeEventAuthorityInstructionData, MarketChoice, ResizeEventAuthorityInstructionData,
+        CloseTraderRecordInstructionData, DefragmentInstructionData, DepositInstructionData,
+        InitializeDequeInstructionData, InitializeEventAuthorityInstructionData,
+        InitializeEventHistoryInstructionData, MarketChoice, MigrateDequeInstructionData,
+        ReconcileInstructionData, ResizeEventAuthorityInstructionData, ResizeInstructionData,
         WithdrawInstructionData,
     },
     pack::Pack,
-    seeds::{self, event_authority},
+    seeds::{
+        self, event_authority::find_event_authority_address,
+        event_history::find_event_history_address, trader::find_trader_address,
+    },
+    state::{shard_of, Deque, ShardIndex},
+    utils::NIL,
 };
 use solana_client::rpc_client::RpcClient;
 use solana_sdk::{
@@ -14,6 +21,7 @@ use solana_sdk::{
     program_pack::Pack as SolanaPack,
     pubkey::Pubkey,
     signature::{Keypair, Signer},
+    sysvar,
 };
 
 #[allow(deprecated)]
@@ -22,7 +30,7 @@ use solana_sdk::{system_instruction, system_program};
 use spl_associated_token_account::get_associated_token_address;
 use spl_token::state::Mint;
 
-use crate::transactions::send_txn;
+use crate::{transactions::send_txn, views::EscrowLocation};
 
 /// Returns the mint pubkey and the token account pubkey.
 pub fn create_token(
@@ -109,7 +117,12 @@ pub struct MarketContext {
     pub base_token_program: Pubkey,
     pub quote_token_program: Pubkey,
     pub ata_program: Pubkey,
+    /// The market's own event authority PDA.
     pub event_authority: Pubkey,
+    /// The deque's last shard as of the last [`MarketContext::refresh_shards`].
+    pub last_shard: ShardIndex,
+    /// The market's event history PDA, if it has been initialized.
+    pub event_history: Option<Pubkey>,
 }
 
 pub enum DepositOrWithdraw {
@@ -130,6 +143,35 @@ impl From<WithdrawInstructionData> for DepositOrWithdraw {
 }
 
 impl MarketContext {
+    /// Re-read the deque header so instructions pass every shard the deque currently has.
+    pub fn refresh_shards(&mut self, rpc: &RpcClient) -> anyhow::Result<ShardIndex> {
+        let mut data = rpc.get_account_data(&self.deque_pubkey)?;
+        let deque = Deque::from_bytes(&mut data).map_err(|e| anyhow::anyhow!("{e}"))?;
+        self.last_shard = deque.header.last_shard;
+        Ok(self.last_shard)
+    }
+
+    pub fn shard_pubkey(&self, shard: ShardIndex) -> Pubkey {
+        seeds::shard::find_shard_address(&self.deque_pubkey, shard).0
+    }
+
+    /// Every shard after the deque account itself, followed by the next shard's PDA so that the
+    /// program can allocate it if the deque has to grow.
+    pub fn shard_metas(&self) -> Vec<AccountMeta> {
+        (1..=self.last_shard.saturating_add(1))
+            .map(|shard| AccountMeta::new(self.shard_pubkey(shard), false))
+            .collect()
+    }
+
+    /// The event history account passed to event emitting instructions, or the program ID as a
+    /// placeholder when the market doesn't record its events.
+    pub fn event_history_meta(&self) -> AccountMeta {
+        match self.event_history {
+            Some(event_history) => AccountMeta::new(event_history, false),
+            None => AccountMeta::new_readonly(deque::ID, false),
+        }
+    }
+
     pub fn get_atas(&self, owner: &Pubkey) -> (Pubkey, Pubkey) {
         (
             get_associated_token_address(owner, &self.base_mint),
@@ -158,7 +200,9 @@ impl MarketContext {
                 .to_vec(),
             accounts: vec![
                 AccountMeta::new_readonly(deque::ID, false),
-                AccountMeta::new_readonly(seeds::event_authority::ID, false),
+                AccountMeta::new_readonly(self.event_authority, false),
+                self.event_history_meta(),
+                AccountMeta::new_readonly(sysvar::instructions::ID, false),
                 AccountMeta::new(payer.pubkey(), true),
                 AccountMeta::new(self.deque_pubkey, false),
                 AccountMeta::new_readonly(self.base_mint, false),
@@ -173,40 +217,199 @@ impl MarketContext {
         }
     }
 
+    /// The mints are passed so the program can check that the deque is their market PDA.
+    fn event_authority_metas(&self, payer: &Keypair) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(self.event_authority, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(self.deque_pubkey, false),
+            AccountMeta::new_readonly(self.base_mint, false),
+            AccountMeta::new_readonly(self.quote_mint, false),
+        ]
+    }
+
     pub fn initialize_event_authority_ixn(&self, payer: &Keypair) -> Instruction {
         Instruction {
             program_id: deque::ID,
             data: InitializeEventAuthorityInstructionData {}.pack().to_vec(),
+            accounts: self.event_authority_metas(payer),
+        }
+    }
+
+    pub fn resize_event_authority_ixn(&self, payer: &Keypair) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            data: ResizeEventAuthorityInstructionData {}.pack().to_vec(),
+            accounts: self.event_authority_metas(payer),
+        }
+    }
+
+    /// Create the market's event history. Set [`MarketContext::event_history`] afterwards so that
+    /// later instructions record their events in it.
+    pub fn initialize_event_history_ixn(&self, payer: &Keypair, capacity: u16) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            data: InitializeEventHistoryInstructionData { capacity }
+                .pack()
+                .to_vec(),
             accounts: vec![
                 AccountMeta::new(payer.pubkey(), true),
-                AccountMeta::new(seeds::event_authority::ID, false),
+                AccountMeta::new(find_event_history_address(&self.deque_pubkey).0, false),
                 AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new(self.deque_pubkey, false),
             ],
         }
     }
 
-    pub fn resize_event_authority_ixn(&self, payer: &Keypair) -> Instruction {
+    pub fn resize_ixn(&self, payer: &Keypair, num_sectors: u16) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(deque::ID, false),
+            AccountMeta::new_readonly(self.event_authority, false),
+            self.event_history_meta(),
+            AccountMeta::new_readonly(sysvar::instructions::ID, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(self.deque_pubkey, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+        accounts.extend(self.shard_metas());
+
         Instruction {
             program_id: deque::ID,
-            data: ResizeEventAuthorityInstructionData {}.pack().to_vec(),
+            data: ResizeInstructionData { num_sectors }.pack().to_vec(),
+            accounts,
+        }
+    }
+
+    /// Every shard is passed writable, since nodes are moved across all of them.
+    pub fn defragment_ixn(&self, max_moves: u16) -> Instruction {
+        let mut accounts = vec![AccountMeta::new(self.deque_pubkey, false)];
+        accounts.extend(
+            (1..=self.last_shard).map(|shard| AccountMeta::new(self.shard_pubkey(shard), false)),
+        );
+
+        Instruction {
+            program_id: deque::ID,
+            data: DefragmentInstructionData { max_moves }.pack().to_vec(),
+            accounts,
+        }
+    }
+
+    /// Rewrite a deque created by an older version of the program in the current layout. A large
+    /// deque is grown and then rewritten over several calls, so send it until the deque has the
+    /// current version.
+    pub fn migrate_deque_ixn(&self, payer: &Keypair) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            data: MigrateDequeInstructionData {}.pack().to_vec(),
             accounts: vec![
                 AccountMeta::new(payer.pubkey(), true),
-                AccountMeta::new(seeds::event_authority::ID, false),
-                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new(self.deque_pubkey, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new(find_event_history_address(&self.deque_pubkey).0, false),
             ],
         }
     }
 
+    /// Compare the escrows with the vault balances. Every shard is passed read-only, since the
+    /// program walks the whole deque.
+    pub fn reconcile_ixn(&self, fail_on_deficit: bool) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(deque::ID, false),
+            AccountMeta::new(self.event_authority, false),
+            self.event_history_meta(),
+            AccountMeta::new_readonly(sysvar::instructions::ID, false),
+            AccountMeta::new_readonly(self.deque_pubkey, false),
+            AccountMeta::new_readonly(self.vault_base_ata, false),
+            AccountMeta::new_readonly(self.vault_quote_ata, false),
+        ];
+        accounts.extend(
+            (1..=self.last_shard)
+                .map(|shard| AccountMeta::new_readonly(self.shard_pubkey(shard), false)),
+        );
+
+        Instruction {
+            program_id: deque::ID,
+            data: ReconcileInstructionData { fail_on_deficit }.pack().to_vec(),
+            accounts,
+        }
+    }
+
+    /// Close the trader's record on the market and refund its rent, once they hold no escrow. Every
+    /// shard is passed read-only, since the program searches the whole deque for the trader.
+    pub fn close_trader_record_ixn(&self, trader: &Keypair) -> Instruction {
+        let (trader_record, _) = find_trader_address(&self.deque_pubkey, &trader.pubkey());
+        let mut accounts = vec![
+            AccountMeta::new(trader.pubkey(), true),
+            AccountMeta::new_readonly(self.deque_pubkey, false),
+            AccountMeta::new(trader_record, false),
+        ];
+        accounts.extend(
+            (1..=self.last_shard)
+                .map(|shard| AccountMeta::new_readonly(self.shard_pubkey(shard), false)),
+        );
+
+        Instruction {
+            program_id: deque::ID,
+            data: CloseTraderRecordInstructionData {}.pack().to_vec(),
+            accounts,
+        }
+    }
+
+    /// Deposits pass the trader's record after the system program and before the shards, which
+    /// the program requires since the record was added. Withdrawals don't pass it.
     pub fn deposit_or_withdraw_ixn(
         &self,
         payer: &Keypair,
         instruction: DepositOrWithdraw,
+    ) -> Instruction {
+        self.deposit_or_withdraw_with_shards_ixn(payer, instruction, self.shard_metas())
+    }
+
+    /// Like [`MarketContext::deposit_or_withdraw_ixn`] for a trader whose escrow is at `escrow`,
+    /// e.g. from [`crate::views::sharded_escrow_location`]. The program is pointed straight at the
+    /// escrow, so only the shards it writes to are passed instead of every shard.
+    pub fn deposit_or_withdraw_at_ixn(
+        &self,
+        payer: &Keypair,
+        instruction: DepositOrWithdraw,
+        escrow: &EscrowLocation,
+    ) -> Instruction {
+        let hint = Some(escrow.sector);
+        let instruction = match instruction {
+            DepositOrWithdraw::Deposit(deposit) => deposit.with_escrow_hint(hint).into(),
+            DepositOrWithdraw::Withdraw(withdraw) => withdraw.with_escrow_hint(hint).into(),
+        };
+        // A withdrawal that empties the escrow also relinks its neighbors.
+        let mut shards: Vec<ShardIndex> = [escrow.sector, escrow.prev, escrow.next]
+            .into_iter()
+            .filter(|sector| *sector != NIL)
+            .map(shard_of)
+            .filter(|shard| *shard != 0)
+            .collect();
+        shards.sort_unstable();
+        shards.dedup();
+        let shard_metas = shards
+            .into_iter()
+            .map(|shard| AccountMeta::new(self.shard_pubkey(shard), false))
+            .collect();
+
+        self.deposit_or_withdraw_with_shards_ixn(payer, instruction, shard_metas)
+    }
+
+    fn deposit_or_withdraw_with_shards_ixn(
+        &self,
+        payer: &Keypair,
+        instruction: DepositOrWithdraw,
+        shard_metas: Vec<AccountMeta>,
     ) -> Instruction {
         let (base_ata, quote_ata) = self.get_atas(&payer.pubkey());
 
-        let (data, choice) = match instruction {
-            DepositOrWithdraw::Deposit(deposit) => (deposit.pack().to_vec(), deposit.choice),
-            DepositOrWithdraw::Withdraw(withdraw) => (withdraw.pack().to_vec(), withdraw.choice),
+        let (data, choice, is_deposit) = match instruction {
+            DepositOrWithdraw::Deposit(deposit) => (deposit.pack().to_vec(), deposit.choice, true),
+            DepositOrWithdraw::Withdraw(withdraw) => {
+                (withdraw.pack().to_vec(), withdraw.choice, false)
+            }
         };
 
         let (payer_ata, mint, vault_ata) = match choice {
@@ -214,20 +417,29 @@ impl MarketContext {
             MarketChoice::Quote => (quote_ata, self.quote_mint, self.vault_quote_ata),
         };
 
+        let mut accounts = vec![
+            AccountMeta::new_readonly(deque::ID, false),
+            AccountMeta::new(self.event_authority, false),
+            self.event_history_meta(),
+            AccountMeta::new_readonly(sysvar::instructions::ID, false),
+            AccountMeta::new(self.deque_pubkey, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(payer_ata, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new(vault_ata, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+        if is_deposit {
+            let (trader_record, _) = find_trader_address(&self.deque_pubkey, &payer.pubkey());
+            accounts.push(AccountMeta::new(trader_record, false));
+        }
+        accounts.extend(shard_metas);
+
         Instruction {
             program_id: deque::ID,
             data,
-            accounts: vec![
-                AccountMeta::new_readonly(deque::ID, false),
-                AccountMeta::new(seeds::event_authority::ID, false),
-                AccountMeta::new(self.deque_pubkey, false),
-                AccountMeta::new(payer.pubkey(), true),
-                AccountMeta::new(payer_ata, false),
-                AccountMeta::new_readonly(spl_token::id(), false),
-                AccountMeta::new_readonly(mint, false),
-                AccountMeta::new(vault_ata, false),
-                AccountMeta::new_readonly(system_program::id(), false),
-            ],
+            accounts,
         }
     }
 }
@@ -259,6 +471,8 @@ pub fn generate_market(rpc: &RpcClient, payer: &Keypair) -> anyhow::Result<Marke
         base_token_program: spl_token::id(),
         quote_token_program: spl_token::id(),
         ata_program: spl_associated_token_account::id(),
-        event_authority: event_authority::ID,
+        event_authority: find_event_authority_address(&deque_pubkey).0,
+        last_shard: 0,
+        event_history: None,
     })
 }
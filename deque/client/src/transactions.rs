@@ -1,5 +1,10 @@
+use crate::errors::{
+    deque_error_from_client_error, describe_error_context, error_context_from_client_error,
+};
 use anyhow::Context;
-use deque::{instruction_enum::DequeInstruction, seeds};
+use deque::{
+    instruction_enum::DequeInstruction, seeds::event_authority::find_event_authority_address,
+};
 use solana_client::rpc_client::RpcClient;
 use solana_program::system_program;
 use solana_sdk::{
@@ -8,6 +13,7 @@ use solana_sdk::{
     message::Message,
     pubkey::Pubkey,
     signature::{Keypair, Signature, Signer},
+    sysvar,
     transaction::Transaction,
 };
 
@@ -85,6 +91,11 @@ pub fn send_txn(
                 payer.pubkey(),
                 e
             );
+            if let Some(ctx) = error_context_from_client_error(&e) {
+                eprintln!("   {}", describe_error_context(&ctx));
+            } else if let Some(failed) = deque_error_from_client_error(&e) {
+                eprintln!("   {failed}");
+            }
             Err(e).context("Failed to call")
         }
     }
@@ -116,7 +127,10 @@ pub fn send_deposit_or_withdraw(
         data: deque_instruction.pack(),
         accounts: vec![
             AccountMeta::new_readonly(deque::ID, false),
-            AccountMeta::new(seeds::event_authority::ID, false),
+            AccountMeta::new(find_event_authority_address(&deque_pubkey).0, false),
+            // No event history.
+            AccountMeta::new_readonly(deque::ID, false),
+            AccountMeta::new_readonly(sysvar::instructions::ID, false),
             AccountMeta::new(deque_pubkey, false),
             AccountMeta::new(payer.pubkey(), true),
             AccountMeta::new(payer_ata, false),
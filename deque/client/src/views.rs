@@ -1,10 +1,115 @@
 use deque::{
-    state::{Deque, DequeNode, MarketEscrow},
-    utils::from_sector_idx,
+    seeds,
+    state::{
+        find_escrow, Deque, DequeNode, MarketEscrow, ShardIndex, ShardedDeque, ShardedSectors,
+        DEQUE_SHARD_HEADER_SIZE,
+    },
+    utils::SectorIndex,
 };
 use solana_client::rpc_client::RpcClient;
 use solana_sdk::pubkey::Pubkey;
 
+/// Fetch the data of every shard in `1..=last_shard`, in order.
+pub fn fetch_shard_data(
+    client: &RpcClient,
+    deque_pubkey: &Pubkey,
+    last_shard: ShardIndex,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let shard_keys: Vec<Pubkey> = (1..=last_shard)
+        .map(|shard| seeds::shard::find_shard_address(deque_pubkey, shard).0)
+        .collect();
+
+    let mut shard_data = Vec::with_capacity(shard_keys.len());
+    for keys in shard_keys.chunks(100) {
+        for (account, key) in client.get_multiple_accounts(keys)?.into_iter().zip(keys) {
+            let account = account.ok_or_else(|| anyhow::anyhow!("Shard {key} doesn't exist"))?;
+            shard_data.push(account.data);
+        }
+    }
+    Ok(shard_data)
+}
+
+/// Fetch a deque and its shards and return every escrow in it, from front to back.
+pub fn fetch_escrows(
+    client: &RpcClient,
+    deque_pubkey: &Pubkey,
+) -> anyhow::Result<Vec<MarketEscrow>> {
+    let mut data = client.get_account_data(deque_pubkey)?;
+    let last_shard = Deque::from_bytes(&mut data)
+        .map_err(|e| anyhow::anyhow!("{e}"))?
+        .header
+        .last_shard;
+    let mut shard_data = fetch_shard_data(client, deque_pubkey, last_shard)?;
+    sharded_escrows(&mut data, &mut shard_data)
+}
+
+/// Every escrow in a deque from front to back, given the deque account's data followed by the data
+/// of each of its shards in order.
+pub fn sharded_escrows(
+    deque_data: &mut [u8],
+    shard_data: &mut [Vec<u8>],
+) -> anyhow::Result<Vec<MarketEscrow>> {
+    with_sharded_deque(deque_data, shard_data, |deque| {
+        deque
+            .iter_indices()
+            .map(|idx| {
+                deque
+                    .node(idx)
+                    .map(|node: &DequeNode<MarketEscrow>| node.inner)
+                    .map_err(|e| anyhow::anyhow!("{e}"))
+            })
+            .collect()
+    })
+}
+
+/// Where a trader's escrow is in the deque, and the sectors linked to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EscrowLocation {
+    pub sector: SectorIndex,
+    pub prev: SectorIndex,
+    pub next: SectorIndex,
+}
+
+/// Find `trader`'s escrow, given the same data as [`sharded_escrows`].
+pub fn sharded_escrow_location(
+    deque_data: &mut [u8],
+    shard_data: &mut [Vec<u8>],
+    trader: &Pubkey,
+) -> anyhow::Result<Option<EscrowLocation>> {
+    with_sharded_deque(deque_data, shard_data, |deque| {
+        let Some(sector) = find_escrow(deque, trader, None).map_err(|e| anyhow::anyhow!("{e}"))?
+        else {
+            return Ok(None);
+        };
+        let node = deque.node(sector).map_err(|e| anyhow::anyhow!("{e}"))?;
+        Ok(Some(EscrowLocation {
+            sector,
+            prev: node.prev,
+            next: node.next,
+        }))
+    })
+}
+
+fn with_sharded_deque<R>(
+    deque_data: &mut [u8],
+    shard_data: &mut [Vec<u8>],
+    f: impl FnOnce(&ShardedDeque) -> anyhow::Result<R>,
+) -> anyhow::Result<R> {
+    let Deque { header, sectors } =
+        Deque::from_bytes(deque_data).map_err(|e| anyhow::anyhow!("{e}"))?;
+    let mut shards = ShardedSectors::new();
+    shards
+        .insert(0, sectors)
+        .map_err(|e| anyhow::anyhow!("{e}"))?;
+    for (shard, data) in (1..).zip(shard_data.iter_mut()) {
+        shards
+            .insert(shard, &mut data[DEQUE_SHARD_HEADER_SIZE..])
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+    }
+    let deque = ShardedDeque::from_parts(header, shards).map_err(|e| anyhow::anyhow!("{e}"))?;
+    f(&deque)
+}
+
 pub fn inspect_account(client: &RpcClient, account_pubkey: &Pubkey, verbose: bool) {
     match client.get_account(account_pubkey) {
         Ok(account) => {
@@ -26,29 +131,38 @@ pub fn inspect_account(client: &RpcClient, account_pubkey: &Pubkey, verbose: boo
                 }
             }
 
-            let cloned_data = &mut account.data.clone();
-            let deque = Deque::from_bytes(cloned_data).expect("Should be able to cast directly.");
+            let mut data = account.data.clone();
+            let header = *Deque::from_bytes(&mut data)
+                .expect("Should be able to cast directly.")
+                .header;
             if verbose {
                 println!(
-                    "len: {}, deque_head: {:#?}, deque_tail: {:#?}, free_head: {:#?}",
-                    deque.header.len,
-                    deque.header.deque_head,
-                    deque.header.deque_tail,
-                    deque.header.free_head,
+                    "len: {}, deque_head: {:#?}, deque_tail: {:#?}, free_head: {:#?}, last_shard: {}",
+                    header.links.len,
+                    header.links.deque_head,
+                    header.links.deque_tail,
+                    header.links.free_head,
+                    header.last_shard,
                 );
             }
-
-            let from_head = deque
-                .iter_indices::<MarketEscrow>()
-                .map(|it| {
-                    *from_sector_idx::<DequeNode<MarketEscrow>>(deque.sectors, it)
-                        .expect("Should be valid.")
-                })
-                .collect::<Vec<DequeNode<MarketEscrow>>>();
             println!(
-                "{:?}",
-                from_head.iter().map(|f| f.inner).collect::<Vec<_>>()
+                "escrowed: {} base, {} quote; deposited: {} base, {} quote; withdrawn: {} base, {} quote",
+                header.total_base,
+                header.total_quote,
+                header.deposited_base,
+                header.deposited_quote,
+                header.withdrawn_base,
+                header.withdrawn_quote,
             );
+            println!(
+                "unique traders: {}, peak len: {}, last activity slot: {}",
+                header.unique_traders, header.peak_len, header.last_activity_slot
+            );
+
+            let mut shard_data = fetch_shard_data(client, account_pubkey, header.last_shard)
+                .expect("Should be able to fetch the deque's shards");
+            let escrows = sharded_escrows(&mut data, &mut shard_data).expect("Should be valid.");
+            println!("{:?}", escrows);
         }
         Err(e) => {
             println!("Failed to get account: {}", e);
@@ -17,13 +17,14 @@ use solana_client::{
     rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
     rpc_filter::{Memcmp, RpcFilterType},
 };
-use solana_sdk::{commitment_config::CommitmentConfig, signature::Keypair, signer::Signer};
+use solana_sdk::{commitment_config::CommitmentConfig, signature::Keypair};
 use tokio_stream::StreamExt;
 
 use crate::{
     events::unpack_event_bytes,
-    fuzz::fuzz,
+    fuzz::{fuzz, FuzzConfig, RpcBackend},
     initialize::{init_atas_and_send_tokens_to_acc, initialize_market_and_event_authority},
+    owned_events::ToOwnedEvent,
     tokens::{generate_market, MarketContext},
     transactions::fund_account,
 };
@@ -109,9 +110,15 @@ pub async fn subscribe_program_and_send() -> Result<()> {
                 let (event_bytes, _garbage) = rest.split_at(header.get_written_bytes() as usize);
 
                 let events = unpack_event_bytes(event_bytes)
-                    .expect("Should be able to unpack event bytes in event account data");
-
-                println!("events: {events:#?}");
+                    .expect("Should be able to unpack event bytes in event account data")
+                    .iter()
+                    .map(ToOwnedEvent::to_owned_event)
+                    .collect::<Vec<_>>();
+
+                println!(
+                    "events: {}",
+                    serde_json::to_string_pretty(&events).expect("Events should serialize")
+                );
             }
         }
     });
@@ -127,24 +134,25 @@ pub async fn subscribe_program_and_send() -> Result<()> {
     let payers =
         create_and_fund_payers(primary_payer.to_base58_string().as_str(), &ctx, num_payers).await;
 
-    // Then spawn fuzz tests for each of them concurrently.
-    let payer_fuzzes: Vec<_> = payers
-        .into_iter()
-        .map(|payer| {
-            let rpc = Arc::new(create_client());
-            let ctx = ctx.clone();
-            tokio::spawn(async move {
-                let err_msg = format!("Fuzz test failed for payer: {}", payer.pubkey());
-                fuzz(&rpc, &payer, &ctx, 10, Some(num_payers)).context(err_msg)
-            })
-        })
-        .collect();
+    // Then fuzz them all, interleaved, in a single run so the vault balances can be checked.
+    let fuzz_run = tokio::task::spawn_blocking(move || {
+        let rpc = create_client();
+        let mut backend = RpcBackend {
+            rpc: &rpc,
+            market: ctx,
+        };
+        let config = FuzzConfig {
+            rounds: 10 * num_payers as u64,
+            seed: 0,
+        };
+        fuzz(&mut backend, &payers, config).context("Fuzz test failed")
+    });
 
     tokio::select! {
         result1 = program_subscription => {
             println!("Program subscription errored out!, {result1:?}");
         },
-        result2 = join_all(payer_fuzzes) => {
+        result2 = fuzz_run => {
             println!("Market escrow complete! {result2:?}");
         },
         result3 = event_authority_account_subscription => {
@@ -0,0 +1,56 @@
+//! Casts arbitrary bytes to each of the program's account types and walks whatever it finds.
+
+#![no_main]
+
+use deque::{
+    state::{Deque, DequeShard, EventHistory},
+    utils::{from_slab_bytes, NIL},
+};
+use libfuzzer_sys::fuzz_target;
+use solana_program::pubkey::Pubkey;
+
+/// Copies the bytes into a `u64` backed buffer, since account data is always 8 byte aligned.
+fn aligned(data: &[u8]) -> Vec<u64> {
+    let mut buf = vec![0u64; data.len().div_ceil(8)];
+    bytemuck::cast_slice_mut::<u64, u8>(&mut buf)[..data.len()].copy_from_slice(data);
+    buf
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = aligned(data);
+    let bytes: &mut [u8] = &mut bytemuck::cast_slice_mut(&mut buf)[..data.len()];
+
+    if let Ok(deque) = Deque::from_bytes(bytes) {
+        let capacity = deque.capacity();
+        // Only corrupt data can have a length past the capacity, and walking a cycle that long
+        // times out instead of failing.
+        deque.header.links.len = deque.len().min(capacity);
+
+        let forward = deque.iter_indices().count();
+        let backward = deque.iter_indices_rev().count();
+        assert!(forward <= deque.len() as usize && backward <= deque.len() as usize);
+        assert!(deque.iter_nodes().count() <= forward);
+        let _ = deque.find_index(|escrow| escrow.base == u64::MAX);
+        if let Some(nodes) = deque.contiguous_nodes() {
+            assert_eq!(nodes.len(), deque.len() as usize);
+        }
+        for idx in (0..=capacity).chain([NIL]) {
+            assert_eq!(deque.node(idx).is_ok(), idx < capacity);
+        }
+    }
+
+    let bytes: &mut [u8] = &mut bytemuck::cast_slice_mut(&mut buf)[..data.len()];
+    if let Ok(history) = EventHistory::from_bytes(bytes) {
+        assert!(history.len() <= history.capacity());
+        assert_eq!(history.iter().count() as u64, history.len());
+    }
+
+    let bytes: &[u8] = &bytemuck::cast_slice(&buf)[..data.len()];
+    if let Ok(shard) = from_slab_bytes::<DequeShard>(bytes, 0) {
+        let deque = Pubkey::new_from_array(*bytes.first_chunk().unwrap_or(&[0; 32]));
+        if shard.verify(&deque, u16::MAX).is_ok() {
+            assert_eq!(shard.deque, deque);
+            assert_ne!(shard.shard, 0);
+        }
+    }
+});
@@ -0,0 +1,196 @@
+//! Runs arbitrary operations on a market's deque, either starting from a freshly initialized
+//! account and checked against a `VecDeque` model, or starting from arbitrary account bytes where
+//! the operations only need to fail gracefully.
+
+#![no_main]
+
+use std::collections::VecDeque;
+
+use arbitrary::Arbitrary;
+use deque::{
+    state::{Deque, DequeHeader, MarketEscrow, DEQUE_HEADER_SIZE},
+    utils::SECTOR_SIZE,
+};
+use libfuzzer_sys::fuzz_target;
+use slab_deque::{DequeLinks, SlabError};
+use solana_program::pubkey::Pubkey;
+
+const MAX_SECTORS: u32 = 64;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    PushFront(u8, u64, u64),
+    PushBack(u8, u64, u64),
+    RemoveAtLogicalIdx(u32),
+    RemoveAtSectorIdx(u32),
+    /// Grow the account like `inline_deque_resize` does and free the new sectors.
+    Grow(u8),
+    Defragment(u16),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Start {
+    Initialized { num_sectors: u8 },
+    Corrupt { links: [u32; 4], sectors: Vec<u8> },
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    start: Start,
+    ops: Vec<Op>,
+}
+
+fn escrow(trader: u8, base: u64, quote: u64) -> MarketEscrow {
+    MarketEscrow::new(Pubkey::new_from_array([trader; 32]), base, quote)
+}
+
+fn buffer(num_sectors: u32) -> Vec<u64> {
+    vec![0u64; (DEQUE_HEADER_SIZE + num_sectors as usize * SECTOR_SIZE) / 8]
+}
+
+fn deque(buf: &mut [u64]) -> Deque<'_> {
+    Deque::from_bytes(bytemuck::cast_slice_mut(buf)).expect("The header is always valid")
+}
+
+fn grow(buf: &mut Vec<u64>, num_sectors: u8) -> Option<(u32, u32)> {
+    let from = deque(buf).capacity();
+    let to = (from + num_sectors as u32).min(MAX_SECTORS);
+    buf.resize(buffer(to).len(), 0);
+    (from < to).then_some((from, to))
+}
+
+/// Runs the operations against a well-formed deque and checks it against the model.
+fn run_model(num_sectors: u8, ops: Vec<Op>) {
+    let mut buf = buffer(num_sectors as u32 % MAX_SECTORS);
+    let header = DequeHeader::init(0, &Pubkey::default(), &Pubkey::default());
+    Deque::init(bytemuck::cast_slice_mut(&mut buf), header).expect("Should init");
+    let mut model: VecDeque<MarketEscrow> = VecDeque::new();
+
+    for op in ops {
+        if let Op::Grow(n) = op {
+            if let Some((from, to)) = grow(&mut buf, n) {
+                deque(&mut buf).extend_free_stack(from, to).unwrap();
+            }
+            continue;
+        }
+
+        let mut deque = deque(&mut buf);
+        let full = model.len() as u32 == deque.capacity();
+        match op {
+            Op::PushFront(trader, base, quote) => {
+                let value = escrow(trader, base, quote);
+                match full {
+                    true => assert_eq!(deque.push_front(value), Err(SlabError::NoFreeSectors)),
+                    false => {
+                        deque.push_front(value).unwrap();
+                        model.push_front(value);
+                    }
+                }
+            }
+            Op::PushBack(trader, base, quote) => {
+                let value = escrow(trader, base, quote);
+                match full {
+                    true => assert_eq!(deque.push_back(value), Err(SlabError::NoFreeSectors)),
+                    false => {
+                        deque.push_back(value).unwrap();
+                        model.push_back(value);
+                    }
+                }
+            }
+            Op::RemoveAtLogicalIdx(idx) => match model.remove(idx as usize) {
+                Some(value) => assert_eq!(deque.remove_at_logical_idx(idx), Ok(value)),
+                None => assert_eq!(
+                    deque.remove_at_logical_idx(idx),
+                    Err(SlabError::OutOfBounds)
+                ),
+            },
+            Op::RemoveAtSectorIdx(idx) => {
+                if model.is_empty() {
+                    continue;
+                }
+                let idx = idx as usize % model.len();
+                let sector = deque.iter_indices().nth(idx).unwrap();
+                assert_eq!(
+                    deque.remove_at_sector_idx(sector),
+                    Ok(model.remove(idx).unwrap())
+                );
+            }
+            Op::Defragment(max_moves) => {
+                let progress = deque.defragment(max_moves).unwrap();
+                assert!(progress.moves <= max_moves);
+                assert_eq!(progress.complete, deque.contiguous_nodes().is_some());
+            }
+            Op::Grow(_) => unreachable!(),
+        }
+
+        assert_eq!(deque.len() as usize, model.len());
+        assert!(deque
+            .iter_nodes()
+            .map(|(escrow, _)| escrow)
+            .eq(model.iter()));
+        assert!(deque.iter_indices_rev().eq(deque
+            .iter_indices()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()));
+        assert_eq!(
+            deque.has_free_sector(),
+            model.len() as u32 != deque.capacity()
+        );
+    }
+}
+
+/// Runs the operations against arbitrary sectors and links. Any error is fine, panics aren't.
+fn run_corrupt(links: [u32; 4], sectors: Vec<u8>, ops: Vec<Op>) {
+    let num_sectors = (sectors.len() / SECTOR_SIZE) as u32 % MAX_SECTORS;
+    let mut buf = buffer(num_sectors);
+    let header = DequeHeader::init(0, &Pubkey::default(), &Pubkey::default());
+    {
+        let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+        bytes[DEQUE_HEADER_SIZE..].copy_from_slice(&sectors[..num_sectors as usize * SECTOR_SIZE]);
+        let mut deque = Deque::from_bytes_unchecked(bytes).unwrap();
+        *deque.header = header;
+        // Only corrupt data can have a length past the capacity, and walking a cycle that long
+        // times out instead of failing.
+        *deque.links_mut() = DequeLinks {
+            len: links[0] % (num_sectors + 1),
+            free_head: links[1],
+            deque_head: links[2],
+            deque_tail: links[3],
+        };
+    }
+
+    for op in ops {
+        if let Op::Grow(n) = op {
+            if let Some((from, to)) = grow(&mut buf, n) {
+                let _ = deque(&mut buf).extend_free_stack(from, to);
+            }
+            continue;
+        }
+
+        let mut deque = deque(&mut buf);
+        let _ = match op {
+            Op::PushFront(trader, base, quote) => {
+                deque.push_front(escrow(trader, base, quote)).map(|_| ())
+            }
+            Op::PushBack(trader, base, quote) => {
+                deque.push_back(escrow(trader, base, quote)).map(|_| ())
+            }
+            Op::RemoveAtLogicalIdx(idx) => deque.remove_at_logical_idx(idx).map(|_| ()),
+            Op::RemoveAtSectorIdx(idx) => deque.remove_at_sector_idx(idx).map(|_| ()),
+            Op::Defragment(max_moves) => deque.defragment(max_moves).map(|_| ()),
+            Op::Grow(_) => unreachable!(),
+        };
+        let len = deque.len();
+        deque.links_mut().len = len.min(deque.capacity());
+        let _ = deque.iter_nodes().count();
+        let _ = deque.find_index(|escrow| escrow.quote == u64::MAX);
+    }
+}
+
+fuzz_target!(|input: Input| {
+    match input.start {
+        Start::Initialized { num_sectors } => run_model(num_sectors, input.ops),
+        Start::Corrupt { links, sectors } => run_corrupt(links, sectors, input.ops),
+    }
+});
@@ -0,0 +1,74 @@
+//! Feeds arbitrary bytes to the event record decoders and to the flush payload validation.
+
+#![no_main]
+
+use deque::{
+    events::{
+        split_event_record, validate_event_payload, DequeEvent, EmittableEvent, EventTag,
+        EVENT_PREFIX_LEN,
+    },
+    shared::error_context::ErrorContext,
+};
+use libfuzzer_sys::fuzz_target;
+
+/// Checks that re-encoding a decoded event reproduces the fields it was decoded from. Newer
+/// versions of an event may have appended fields, so only the ones the decoder knows are compared.
+fn assert_repacks<T: EmittableEvent>(event: &T, record: &[u8]) {
+    let mut buf = Vec::with_capacity(T::LEN);
+    event.write(&mut buf).expect("Should write");
+    assert_eq!(buf[0], record[0]);
+    // Older versions end before the fields appended to the event since, which decode as zeroes.
+    let len = record.len().min(T::LEN);
+    assert_eq!(&buf[EVENT_PREFIX_LEN..len], &record[EVENT_PREFIX_LEN..len]);
+    assert!(buf[len..].iter().all(|byte| *byte == 0));
+}
+
+fn assert_decodes(record: &[u8]) -> DequeEvent<'_> {
+    let event = DequeEvent::unpack(record).expect("A validated record should decode");
+    match &event {
+        DequeEvent::Header(header) => assert_repacks(header, record),
+        DequeEvent::Deposit(deposit) => assert_repacks(deposit, record),
+        DequeEvent::Withdraw(withdraw) => assert_repacks(withdraw, record),
+        DequeEvent::Reconcile(reconcile) => assert_repacks(reconcile, record),
+    }
+    event
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(&byte) = data.first() {
+        if let Ok(tag) = EventTag::try_from(byte) {
+            assert_eq!(tag as u8, byte);
+        }
+    }
+
+    if let Ok((prefix, record, rest)) = split_event_record(data) {
+        assert_eq!(record.len(), prefix.record_len());
+        assert_eq!([record, rest].concat(), data);
+    }
+
+    if let Ok(event) = DequeEvent::unpack(data) {
+        assert_eq!(assert_decodes(data), event);
+    }
+
+    // A payload the flush instruction accepts must be fully decodable by indexers.
+    if let Ok(market) = validate_event_payload(data) {
+        let mut rest = data;
+        let mut first = true;
+        while !rest.is_empty() {
+            let (_, record, next) = split_event_record(rest).expect("Should split");
+            match assert_decodes(record) {
+                DequeEvent::Header(header) => {
+                    assert!(first);
+                    assert_eq!(header.market, &market);
+                }
+                _ => assert!(!first),
+            }
+            first = false;
+            rest = next;
+        }
+    }
+
+    if let Some(ctx) = ErrorContext::unpack(data) {
+        assert_eq!(&data[..ErrorContext::LEN], ctx.pack().as_slice());
+    }
+});
@@ -0,0 +1,34 @@
+//! Feeds arbitrary bytes to every instruction data decoder.
+
+#![no_main]
+
+use deque::{
+    instruction_enum::{DepositInstructionData, DequeInstruction, InstructionTag, MarketChoice},
+    pack::Pack,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(&byte) = data.first() {
+        // Enums are transmuted from their discriminant, so only valid ones may convert.
+        if let Ok(tag) = InstructionTag::try_from(byte) {
+            assert_eq!(tag as u8, byte);
+        }
+        if let Ok(choice) = MarketChoice::try_from(byte) {
+            assert_eq!(choice as u8, byte);
+        }
+    }
+
+    // Anything that decodes must encode back to the bytes it was decoded from.
+    if let Ok(instruction) = DequeInstruction::unpack(data) {
+        let packed = instruction.pack();
+        assert_eq!(instruction.tag() as u8, data[0]);
+        assert_eq!(&data[..packed.len()], packed.as_slice());
+    }
+
+    // Decoded directly, without dispatching on the tag first.
+    if let Ok(deposit) = DepositInstructionData::unpack(data) {
+        let packed = deposit.pack();
+        assert_eq!(&data[..packed.len()], packed.as_slice());
+    }
+});
@@ -1,10 +1,13 @@
-use deque::seeds::event_authority;
+use deque::state::EVENT_ACCOUNT_DISCRIMINANT;
 use futures::StreamExt;
 use grpc_stream::parse_update::parse_update;
 use std::collections::HashMap;
 use tokio::time::{sleep, Duration};
 use yellowstone_grpc_client::GeyserGrpcClient;
-use yellowstone_grpc_proto::prelude::*;
+use yellowstone_grpc_proto::prelude::{
+    subscribe_request_filter_accounts_filter::Filter as AccountsFilterOneof,
+    subscribe_request_filter_accounts_filter_memcmp::Data as AccountsFilterMemcmpOneof, *,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -18,10 +21,20 @@ async fn main() -> anyhow::Result<()> {
         .subscribe_once(SubscribeRequest {
             accounts: HashMap::from([(
                 "event authority pda account data".to_string(),
+                // Every market has its own event authority, so match all of them by discriminant.
                 SubscribeRequestFilterAccounts {
-                    account: vec![event_authority::ID.to_string()],
-                    owner: vec![],
-                    filters: vec![],
+                    account: vec![],
+                    owner: vec![deque::ID.to_string()],
+                    filters: vec![SubscribeRequestFilterAccountsFilter {
+                        filter: Some(AccountsFilterOneof::Memcmp(
+                            SubscribeRequestFilterAccountsFilterMemcmp {
+                                offset: 0,
+                                data: Some(AccountsFilterMemcmpOneof::Bytes(
+                                    EVENT_ACCOUNT_DISCRIMINANT.to_vec(),
+                                )),
+                            },
+                        )),
+                    }],
                     nonempty_txn_signature: Some(true),
                 },
             )]),
@@ -34,7 +47,7 @@ async fn main() -> anyhow::Result<()> {
                     vote: None,
                     account_exclude: vec![],
                     account_include: vec![],
-                    account_required: vec![event_authority::ID.to_string()],
+                    account_required: vec![deque::ID.to_string()],
                 },
             )]),
             transactions_status: HashMap::new(),
@@ -1,5 +1,8 @@
-use deque::events::DequeEvent;
-use deque_client::events::try_unpack_event_bytes_with_tag;
+use deque::instruction_enum::DequeInstruction;
+use deque_client::{
+    events::try_unpack_event_bytes_with_tag,
+    owned_events::{OwnedDequeEvent, ToOwnedEvent},
+};
 use solana_sdk::pubkey::Pubkey;
 use yellowstone_grpc_proto::{
     geyser::{subscribe_update::UpdateOneof, SubscribeUpdateTransactionInfo},
@@ -13,11 +16,15 @@ pub struct ParsedInnerInstruction {
 }
 
 impl ParsedInnerInstruction {
-    fn parse_deque_events(&self) -> Vec<DequeEvent<'_>> {
+    fn parse_deque_events(&self) -> Vec<OwnedDequeEvent> {
         if self.program_id.as_ref() != deque::ID.as_ref() {
             return vec![];
         }
-        try_unpack_event_bytes_with_tag(&self.inner_instruction.data).unwrap_or_default()
+        try_unpack_event_bytes_with_tag(&self.inner_instruction.data)
+            .unwrap_or_default()
+            .iter()
+            .map(ToOwnedEvent::to_owned_event)
+            .collect()
     }
 
     fn from_inner_instructions(accounts: &[Pubkey], inner_ixns: InnerInstructions) -> Vec<Self> {
@@ -43,6 +50,7 @@ pub fn parse_update(update: UpdateOneof) {
         UpdateOneof::Transaction(update) => {
             if let Some(txn) = update.transaction {
                 let account_keys = get_flattened_accounts_in_txn_update(&txn);
+                print_deque_instructions(&txn, &account_keys);
                 let (logs, parsed_inner_instructions) = if let Some(meta) = txn.meta {
                     meta.compute_units_consumed
                         .inspect(|cu| println!("CU consumed: {}", cu));
@@ -73,7 +81,10 @@ pub fn parse_update(update: UpdateOneof) {
                     if !events.is_empty() {
                         println!("----- EVENTS ------");
                         println!("Parent index: {}", inner_ixn.parent_index);
-                        println!("{:?}", events);
+                        match serde_json::to_string(&events) {
+                            Ok(json) => println!("{json}"),
+                            Err(e) => println!("Failed to serialize events: {e}"),
+                        }
                     }
                 }
             }
@@ -82,8 +93,14 @@ pub fn parse_update(update: UpdateOneof) {
     }
 }
 
+/// The transaction's account keys in the order instructions index them: the message's static keys,
+/// then the writable and readonly keys loaded from address lookup tables.
 fn get_flattened_accounts_in_txn_update(txn: &SubscribeUpdateTransactionInfo) -> Vec<Pubkey> {
     [
+        txn.transaction
+            .as_ref()
+            .and_then(|txn| txn.message.as_ref())
+            .map_or(vec![], |msg| msg.account_keys.clone()),
         txn.meta.as_ref().map_or(vec![], |meta| {
             [
                 meta.loaded_writable_addresses.clone(),
@@ -91,13 +108,41 @@ fn get_flattened_accounts_in_txn_update(txn: &SubscribeUpdateTransactionInfo) ->
             ]
             .concat()
         }),
-        txn.transaction
-            .as_ref()
-            .and_then(|txn| txn.message.as_ref())
-            .map_or(vec![], |msg| msg.account_keys.clone()),
     ]
     .concat()
     .into_iter()
     .filter_map(|vec| Pubkey::try_from(vec).ok())
     .collect::<Vec<Pubkey>>()
 }
+
+/// Prints each top-level deque instruction in the transaction with its accounts' roles.
+fn print_deque_instructions(txn: &SubscribeUpdateTransactionInfo, account_keys: &[Pubkey]) {
+    let Some(msg) = txn
+        .transaction
+        .as_ref()
+        .and_then(|txn| txn.message.as_ref())
+    else {
+        return;
+    };
+    for ixn in msg.instructions.iter() {
+        let program_id = account_keys.get(ixn.program_id_index as usize);
+        if program_id.is_none_or(|id| id.as_ref() != deque::ID.as_ref()) {
+            continue;
+        }
+        match DequeInstruction::unpack(&ixn.data) {
+            Ok(instruction) => {
+                println!("---- INSTRUCTION ----");
+                println!("{instruction:?}");
+                let accounts = ixn
+                    .accounts
+                    .iter()
+                    .filter_map(|idx| account_keys.get(*idx as usize))
+                    .collect::<Vec<_>>();
+                for (label, account) in instruction.tag().label_accounts(&accounts) {
+                    println!("  {label}: {account}");
+                }
+            }
+            Err(e) => println!("Failed to decode deque instruction: {e}"),
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Derives the deque program's `Pack` and `EmittableEvent` traits from a struct's field
+//! declarations, so that byte offsets and lengths never have to be written by hand.
+//!
+//! Every field's type must implement the program's `PackField` trait, which is implemented for
+//! `u8`, `u16`, `u32`, `u64`, `bool`, `Pubkey`, `&Pubkey`, `Option<u32>` and the program's
+//! `#[repr(u8)]` enums. Fields are packed in declaration order with no padding.
+//!
+//! The generated code refers to the traits through `crate::`, so these derives are only meant to
+//! be used inside the deque program crate.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Field, Fields, Ident, LitInt, Type};
+
+/// Derives `Pack<LEN>` for an instruction's data, where `LEN` is one byte for the instruction tag
+/// followed by each of the fields. The struct must also implement `Tagged`.
+///
+/// `unpack` checks the length, the tag and any fields with invalid bit patterns (enums) before
+/// calling `unpack_unchecked`, which reads every field without any checks.
+///
+/// Fields marked `#[pack(trailing)]` must come after every other field and may be left off the end
+/// of the data, in which case they're set to their `Default`. This lets an instruction grow a field
+/// without breaking callers that were built before it.
+#[proc_macro_derive(Pack, attributes(pack))]
+pub fn derive_pack(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_pack(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives `EmittableEvent` for an event. The event's version is set with
+/// `#[pack(version = N)]` on the struct, and the field holding the event's tag is marked with
+/// `#[pack(tag)]`. The tag is written as part of the event's prefix rather than as a field.
+///
+/// Fields appended in a later version are marked with `#[pack(since = N)]` and must come after
+/// every other field. Records from older versions, which end before them, still decode, with those
+/// fields set to their `Default`.
+#[proc_macro_derive(EmittableEvent, attributes(pack))]
+pub fn derive_emittable_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_emittable_event(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// A packed field and the expression for its offset in the packed data.
+struct PackedField<'a> {
+    ident: &'a Ident,
+    ty: &'a Type,
+    offset: TokenStream2,
+}
+
+/// Lays out `fields` one after another, starting at `start`. Returns the packed fields and the
+/// expression for the total length.
+fn layout<'a>(
+    fields: impl Iterator<Item = &'a Field>,
+    start: TokenStream2,
+) -> (Vec<PackedField<'a>>, TokenStream2) {
+    let mut offset = start;
+    let packed = fields
+        .map(|field| {
+            let ty = &field.ty;
+            let packed = PackedField {
+                ident: field.ident.as_ref().expect("Fields are named"),
+                ty,
+                offset: offset.clone(),
+            };
+            offset = quote!(#offset + <#ty as crate::pack::PackField>::SIZE);
+            packed
+        })
+        .collect();
+    (packed, offset)
+}
+
+fn named_fields(input: &DeriveInput) -> syn::Result<Vec<&Field>> {
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(fields.named.iter().collect()),
+            Fields::Unit => Ok(vec![]),
+            Fields::Unnamed(_) => Err(syn::Error::new_spanned(
+                &input.ident,
+                "Tuple structs can't be packed, name their fields",
+            )),
+        },
+        _ => Err(syn::Error::new_spanned(
+            &input.ident,
+            "Only structs can be packed",
+        )),
+    }
+}
+
+fn expand_pack(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = named_fields(input)?;
+    // The number of fields that must always be present, the rest are trailing.
+    let mut num_required = 0;
+    for (i, field) in fields.iter().enumerate() {
+        let attrs = field_attrs(field)?;
+        if attrs.is_tag || attrs.since.is_some() {
+            return Err(syn::Error::new_spanned(
+                field,
+                "Only `#[pack(trailing)]` applies to instruction data",
+            ));
+        }
+        match attrs.trailing {
+            false if num_required < i => {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "Fields marked `#[pack(trailing)]` must come after every other field",
+                ));
+            }
+            false => num_required += 1,
+            true => {}
+        }
+    }
+    let (packed, len) = layout(fields.into_iter(), quote!(1));
+    let (required, trailing) = packed.split_at(num_required);
+    let min_len = match trailing.first() {
+        Some(PackedField { offset, .. }) => offset.clone(),
+        None => len.clone(),
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let pack_fields = packed.iter().map(|PackedField { ident, ty, offset }| {
+        quote! {
+            <#ty as crate::pack::PackField>::pack_field(&self.#ident, &mut dst[#offset..]);
+        }
+    });
+    let validate_fields = required.iter().map(|PackedField { ty, offset, .. }| {
+        quote! {
+            // SAFETY: The length was just checked.
+            <#ty as crate::pack::PackField>::validate_field(unsafe { data.get_unchecked(#offset..) })?;
+        }
+    });
+    let validate_trailing_fields = trailing.iter().map(|PackedField { ty, offset, .. }| {
+        quote! {
+            if data.len() >= #offset + <#ty as crate::pack::PackField>::SIZE {
+                // SAFETY: The data was just checked to hold the field.
+                <#ty as crate::pack::PackField>::validate_field(unsafe { data.get_unchecked(#offset..) })?;
+            }
+        }
+    });
+    let unpack_fields = required.iter().map(|PackedField { ident, ty, offset }| {
+        quote! {
+            // SAFETY: Caller guarantees the data holds every field and that each is valid.
+            #ident: unsafe {
+                <#ty as crate::pack::PackField>::unpack_field_unchecked(
+                    instruction_data.as_ptr().add(#offset),
+                )
+            },
+        }
+    });
+    let unpack_trailing_fields = trailing.iter().map(|PackedField { ident, ty, offset }| {
+        quote! {
+            #ident: match instruction_data.len() >= #offset + <#ty as crate::pack::PackField>::SIZE {
+                // SAFETY: Caller guarantees the field is valid if the data is long enough to hold it.
+                true => unsafe {
+                    <#ty as crate::pack::PackField>::unpack_field_unchecked(
+                        instruction_data.as_ptr().add(#offset),
+                    )
+                },
+                false => ::core::default::Default::default(),
+            },
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics crate::pack::Pack<{ #len }> for #name #ty_generics #where_clause {
+            const MIN_LEN: usize = #min_len;
+
+            #[inline(always)]
+            fn pack_into_slice(&self, dst: &mut [::core::mem::MaybeUninit<u8>; { #len }]) {
+                dst[0].write(<Self as crate::pack::Tagged>::TAG);
+                #(#pack_fields)*
+            }
+
+            #[inline(always)]
+            fn unpack(
+                data: &[u8],
+            ) -> ::core::result::Result<Self, ::solana_program::program_error::ProgramError> {
+                <Self as crate::pack::Pack<{ #len }>>::check_len(data)?;
+                <Self as crate::pack::PackWithTag<{ #len }>>::check_tag(data)?;
+                #(#validate_fields)*
+                #(#validate_trailing_fields)*
+                // SAFETY: The length, tag and every field were just verified.
+                Ok(unsafe { <Self as crate::pack::Pack<{ #len }>>::unpack_unchecked(data) })
+            }
+
+            #[inline(always)]
+            unsafe fn unpack_unchecked(instruction_data: &[u8]) -> Self {
+                Self {
+                    #(#unpack_fields)*
+                    #(#unpack_trailing_fields)*
+                }
+            }
+        }
+    })
+}
+
+/// A field's `#[pack(...)]` attributes.
+#[derive(Default)]
+struct FieldAttrs {
+    is_tag: bool,
+    /// The version the field was appended in, if it wasn't part of the first one. Events only.
+    since: Option<LitInt>,
+    /// Whether the field may be left off the end of the data. Instruction data only.
+    trailing: bool,
+}
+
+fn field_attrs(field: &Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs::default();
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("pack"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                attrs.is_tag = true;
+                Ok(())
+            } else if meta.path.is_ident("since") {
+                attrs.since = Some(meta.value()?.parse::<LitInt>()?);
+                Ok(())
+            } else if meta.path.is_ident("trailing") {
+                attrs.trailing = true;
+                Ok(())
+            } else {
+                Err(meta.error("Expected `tag`, `since = N` or `trailing`"))
+            }
+        })?;
+    }
+    Ok(attrs)
+}
+
+fn version_attr(input: &DeriveInput) -> syn::Result<LitInt> {
+    let mut version = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("pack"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("version") {
+                version = Some(meta.value()?.parse::<LitInt>()?);
+                Ok(())
+            } else {
+                Err(meta.error("Expected `version = N`"))
+            }
+        })?;
+    }
+    version.ok_or_else(|| {
+        syn::Error::new_spanned(&input.ident, "Missing `#[pack(version = N)]` attribute")
+    })
+}
+
+fn expand_emittable_event(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let version = version_attr(input)?;
+    let fields = named_fields(input)?;
+
+    let mut tag_field = None;
+    let mut body_fields = vec![];
+    // The number of fields every version of the event has, the rest were appended later.
+    let mut num_required = 0;
+    for field in fields {
+        let attrs = field_attrs(field)?;
+        if attrs.trailing {
+            return Err(syn::Error::new_spanned(
+                field,
+                "Events mark appended fields with `#[pack(since = N)]` instead",
+            ));
+        }
+        if attrs.is_tag {
+            if tag_field.replace(field).is_some() {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "Only one field can be marked `#[pack(tag)]`",
+                ));
+            }
+            continue;
+        }
+        match attrs.since {
+            Some(since) if since.base10_parse::<u8>()? > version.base10_parse::<u8>()? => {
+                return Err(syn::Error::new_spanned(
+                    since,
+                    "A field can't be newer than the event's version",
+                ));
+            }
+            Some(_) => {}
+            None if num_required < body_fields.len() => {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "Fields marked `#[pack(since = N)]` must come after every other field",
+                ));
+            }
+            None => num_required += 1,
+        }
+        body_fields.push(field);
+    }
+    let tag_ident = tag_field
+        .and_then(|field| field.ident.as_ref())
+        .ok_or_else(|| {
+            syn::Error::new_spanned(&input.ident, "Missing a field marked `#[pack(tag)]`")
+        })?;
+
+    let (packed, len) = layout(
+        body_fields.into_iter(),
+        quote!(crate::events::EVENT_PREFIX_LEN),
+    );
+    let (required, appended) = packed.split_at(num_required);
+    let min_len = match appended.first() {
+        Some(PackedField { offset, .. }) => offset.clone(),
+        None => len.clone(),
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let write_fields = packed.iter().map(|PackedField { ident, ty, .. }| {
+        quote! {
+            <#ty as crate::pack::PackField>::pack_field(&self.#ident, buf.spare_capacity_mut());
+            // SAFETY: The field was just written to the caller guaranteed spare capacity.
+            unsafe { buf.set_len(buf.len() + <#ty as crate::pack::PackField>::SIZE) };
+        }
+    });
+    let validate_fields = required.iter().map(|PackedField { ty, offset, .. }| {
+        quote! {
+            <#ty as crate::pack::PackField>::validate_field(&data[#offset..])?;
+        }
+    });
+    let validate_appended_fields = appended.iter().map(|PackedField { ty, offset, .. }| {
+        quote! {
+            if data.len() >= #offset + <#ty as crate::pack::PackField>::SIZE {
+                <#ty as crate::pack::PackField>::validate_field(&data[#offset..])?;
+            }
+        }
+    });
+    let unpack_fields = required.iter().map(|PackedField { ident, ty, offset }| {
+        quote! {
+            // SAFETY: The caller guarantees the data was validated.
+            #ident: unsafe {
+                <#ty as crate::pack::PackField>::unpack_field_unchecked(data.as_ptr().add(#offset))
+            },
+        }
+    });
+    let unpack_appended_fields = appended.iter().map(|PackedField { ident, ty, offset }| {
+        quote! {
+            #ident: match data.len() >= #offset + <#ty as crate::pack::PackField>::SIZE {
+                // SAFETY: The caller guarantees the data was validated, and it's long enough to
+                // hold the field.
+                true => unsafe {
+                    <#ty as crate::pack::PackField>::unpack_field_unchecked(
+                        data.as_ptr().add(#offset),
+                    )
+                },
+                false => ::core::default::Default::default(),
+            },
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics crate::events::EmittableEvent for #name #ty_generics #where_clause {
+            const VERSION: u8 = #version;
+            const LEN: usize = #len;
+            const MIN_LEN: usize = #min_len;
+
+            #[inline(always)]
+            fn validate(data: &[u8]) -> ::solana_program::entrypoint::ProgramResult {
+                Self::check_len(data)?;
+                #(#validate_fields)*
+                #(#validate_appended_fields)*
+                Ok(())
+            }
+
+            unsafe fn write_unchecked(&self, buf: &mut Vec<u8>) {
+                Self::write_prefix(buf);
+                #(#write_fields)*
+            }
+
+            #[cfg(not(target_os = "solana"))]
+            unsafe fn from_slice_unchecked(data: &[u8]) -> Self {
+                Self {
+                    #tag_ident: data[0],
+                    #(#unpack_fields)*
+                    #(#unpack_appended_fields)*
+                }
+            }
+        }
+    })
+}
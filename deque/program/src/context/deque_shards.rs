@@ -0,0 +1,151 @@
+use solana_program::{account_info::AccountInfo, program_error::ProgramError};
+
+use crate::{
+    shared::error::{DequeError, DequeProgramResult},
+    state::{
+        load_deque, shard_of, Deque, DequeShard, ShardIndex, ShardedDeque, ShardedSectors,
+        DEQUE_SHARD_HEADER_SIZE,
+    },
+    utils::{check_owned, check_owned_and_writable, from_slab_bytes, SectorIndex, NIL},
+};
+
+/// The deque account and whichever of its shard accounts were passed as remaining accounts.
+///
+/// Only the shards an instruction actually touches need to be passed. Reaching a sector in a shard
+/// that wasn't passed fails with [`DequeError::MissingShard`] rather than being skipped over.
+///
+/// Instructions that only write to a few sectors load the shards with
+/// [`Self::load_with_read_only_shards`] and check the ones they write to with
+/// [`Self::check_writable`], so that shards which are only read don't have to be write locked.
+#[derive(Clone)]
+pub struct DequeShardsContext<'a, 'info> {
+    pub deque_account: &'a AccountInfo<'info>,
+    pub shards: Vec<(ShardIndex, &'a AccountInfo<'info>)>,
+    /// Remaining accounts without any data, e.g. the PDA for the next shard if the deque may need
+    /// to grow.
+    pub uninitialized: Vec<&'a AccountInfo<'info>>,
+}
+
+impl<'a, 'info> DequeShardsContext<'a, 'info> {
+    pub fn load(
+        deque_account: &'a AccountInfo<'info>,
+        shard_accounts: &'a [AccountInfo<'info>],
+    ) -> Result<DequeShardsContext<'a, 'info>, ProgramError> {
+        Self::load_with(deque_account, shard_accounts, check_owned_and_writable)
+    }
+
+    /// Like [`Self::load`], but only the deque account has to be writable. Shards that the
+    /// instruction writes to must be checked with [`Self::check_writable`] before they're written.
+    pub fn load_with_read_only_shards(
+        deque_account: &'a AccountInfo<'info>,
+        shard_accounts: &'a [AccountInfo<'info>],
+    ) -> Result<DequeShardsContext<'a, 'info>, ProgramError> {
+        check_owned_and_writable(deque_account)?;
+        Self::load_with(deque_account, shard_accounts, check_owned)
+    }
+
+    /// Like [`Self::load`], but for instructions that only read the deque, so none of its
+    /// accounts need to be writable.
+    pub fn load_read_only(
+        deque_account: &'a AccountInfo<'info>,
+        shard_accounts: &'a [AccountInfo<'info>],
+    ) -> Result<DequeShardsContext<'a, 'info>, ProgramError> {
+        Self::load_with(deque_account, shard_accounts, check_owned)
+    }
+
+    fn load_with(
+        deque_account: &'a AccountInfo<'info>,
+        shard_accounts: &'a [AccountInfo<'info>],
+        check: fn(&AccountInfo) -> DequeProgramResult,
+    ) -> Result<DequeShardsContext<'a, 'info>, ProgramError> {
+        check(deque_account)?;
+        let last_shard = {
+            let mut data = deque_account.data.borrow_mut();
+            load_deque(&mut data)?.header.last_shard
+        };
+
+        let mut shards = Vec::with_capacity(shard_accounts.len());
+        let mut uninitialized = vec![];
+        for account in shard_accounts {
+            if account.data_is_empty() {
+                uninitialized.push(account);
+                continue;
+            }
+            check(account)?;
+            let data = account.data.borrow();
+            let shard =
+                from_slab_bytes::<DequeShard>(&data, 0).or(Err(DequeError::InvalidShardAccount))?;
+            shard.verify(deque_account.key, last_shard)?;
+            shards.push((shard.shard, account));
+        }
+
+        Ok(DequeShardsContext {
+            deque_account,
+            shards,
+            uninitialized,
+        })
+    }
+
+    pub fn shard(&self, shard: ShardIndex) -> Result<&'a AccountInfo<'info>, DequeError> {
+        match shard {
+            0 => Ok(self.deque_account),
+            _ => self
+                .shards
+                .iter()
+                .find(|(idx, _)| *idx == shard)
+                .map(|(_, account)| *account)
+                .ok_or(DequeError::MissingShard),
+        }
+    }
+
+    /// Check that every shard the deque has was passed, for instructions that touch all of them.
+    pub fn check_all_shards_loaded(&self) -> Result<(), ProgramError> {
+        let last_shard = self.with_deque(|deque| Ok(deque.header.last_shard))?;
+        for shard in 1..=last_shard {
+            self.shard(shard)?;
+        }
+        Ok(())
+    }
+
+    /// Check that the shards holding `sectors` were passed writable. `NIL` sectors are skipped, so
+    /// a node's links can be passed as is.
+    pub fn check_writable(&self, sectors: &[SectorIndex]) -> Result<(), DequeError> {
+        for &sector in sectors.iter().filter(|sector| **sector != NIL) {
+            if !self.shard(shard_of(sector))?.is_writable {
+                return Err(DequeError::AccountIsNotWritable);
+            }
+        }
+        Ok(())
+    }
+
+    /// Borrow the deque account and every loaded shard's data for the duration of `f`.
+    pub fn with_deque<R>(
+        &self,
+        f: impl FnOnce(&mut ShardedDeque) -> Result<R, DequeError>,
+    ) -> Result<R, ProgramError> {
+        let mut deque_data = self.deque_account.try_borrow_mut_data()?;
+        let mut shard_data = self
+            .shards
+            .iter()
+            .map(|(shard, account)| Ok((*shard, account.try_borrow_mut_data()?)))
+            .collect::<Result<Vec<_>, ProgramError>>()?;
+
+        // The header is verified below, once the sectors are loaded.
+        let Deque {
+            header,
+            sectors: root_sectors,
+        } = Deque::from_bytes_unchecked(&mut deque_data).map_err(DequeError::from)?;
+
+        let mut sectors = ShardedSectors::new();
+        sectors.insert(0, root_sectors).map_err(DequeError::from)?;
+        for (shard, data) in shard_data.iter_mut() {
+            let bytes = data
+                .get_mut(DEQUE_SHARD_HEADER_SIZE..)
+                .ok_or(DequeError::InvalidShardAccount)?;
+            sectors.insert(*shard, bytes).map_err(DequeError::from)?;
+        }
+
+        let mut deque = ShardedDeque::from_parts(header, sectors).map_err(DequeError::from)?;
+        Ok(f(&mut deque)?)
+    }
+}
@@ -3,13 +3,19 @@ use solana_program::{
     program_error::ProgramError,
 };
 
-use crate::validation::{event_authority::EventAuthorityInfo, system_program::SystemProgramInfo};
+use crate::{
+    utils::check_derivations_and_get_bump,
+    validation::{event_authority::EventAuthorityInfo, system_program::SystemProgramInfo},
+};
 
 #[derive(Clone)]
 pub struct EventAuthorityContext<'a, 'info> {
     pub payer: &'a AccountInfo<'info>,
     pub event_authority: EventAuthorityInfo<'a, 'info>,
     pub system_program: SystemProgramInfo<'a, 'info>,
+    /// The deque (market) account the event authority belongs to. It doesn't need to exist yet,
+    /// but it must be the market PDA of the two mints that follow it.
+    pub market: &'a AccountInfo<'info>,
 }
 
 impl<'a, 'info> EventAuthorityContext<'a, 'info> {
@@ -18,13 +24,20 @@ impl<'a, 'info> EventAuthorityContext<'a, 'info> {
     ) -> Result<EventAuthorityContext<'a, 'info>, ProgramError> {
         let accounts_iter = &mut accounts.iter();
         let payer = next_account_info(accounts_iter)?;
-        let event_authority = EventAuthorityInfo::new_checked(next_account_info(accounts_iter)?)?;
+        let event_authority_info = next_account_info(accounts_iter)?;
         let system_program = SystemProgramInfo::new_checked(next_account_info(accounts_iter)?)?;
+        let market = next_account_info(accounts_iter)?;
+        let base_mint = next_account_info(accounts_iter)?;
+        let quote_mint = next_account_info(accounts_iter)?;
+        check_derivations_and_get_bump(market, base_mint.key, quote_mint.key)?;
+        let event_authority =
+            EventAuthorityInfo::new_pda_checked(event_authority_info, market.key)?;
 
         Ok(EventAuthorityContext {
             payer,
             event_authority,
             system_program,
+            market,
         })
     }
 }
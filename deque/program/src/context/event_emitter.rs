@@ -3,19 +3,59 @@ use solana_program::{
     program_error::ProgramError,
 };
 
-use crate::validation::{event_authority::EventAuthorityInfo, self_program::SelfProgramInfo};
+use crate::{
+    require,
+    shared::error::DequeError,
+    state::DequeHeader,
+    validation::{
+        event_authority::EventAuthorityInfo, event_history::EventHistoryInfo,
+        instructions_sysvar::InstructionsSysvarInfo, self_program::SelfProgramInfo,
+    },
+};
+
+/// The number of accounts that precede every event emitting instruction's own accounts.
+pub(crate) const NUM_EVENT_EMITTER_ACCOUNTS: usize = 4;
 
 pub(crate) struct EventEmitterContext<'a, 'info> {
     pub self_program: SelfProgramInfo<'a, 'info>,
     pub event_authority: EventAuthorityInfo<'a, 'info>,
+    /// The market's event history. The program ID is passed in its place until the market has one.
+    pub event_history: Option<EventHistoryInfo<'a, 'info>>,
+    /// Passed through to the flush CPI so it can check who invoked it.
+    pub instructions_sysvar: InstructionsSysvarInfo<'a, 'info>,
 }
 
 impl<'a, 'info> EventEmitterContext<'a, 'info> {
-    pub fn load(accounts: &'a [AccountInfo<'info>]) -> Result<Self, ProgramError> {
+    pub fn load(
+        accounts: &'a [AccountInfo<'info>],
+        market: &AccountInfo,
+    ) -> Result<Self, ProgramError> {
         let accounts_iter = &mut accounts.iter();
         Ok(EventEmitterContext {
             self_program: SelfProgramInfo::new_checked(next_account_info(accounts_iter)?)?,
-            event_authority: EventAuthorityInfo::new_checked(next_account_info(accounts_iter)?)?,
+            event_authority: EventAuthorityInfo::new_checked(
+                next_account_info(accounts_iter)?,
+                market.key,
+            )?,
+            event_history: match next_account_info(accounts_iter)? {
+                info if info.key.as_ref() == crate::ID.as_ref() => {
+                    require!(
+                        !market_has_event_history(market)?,
+                        DequeError::InvalidEventHistory,
+                        "The market has an event history, so it must be passed"
+                    )?;
+                    None
+                }
+                info => Some(EventHistoryInfo::new_checked(info, market.key)?),
+            },
+            instructions_sysvar: InstructionsSysvarInfo::new_checked(next_account_info(
+                accounts_iter,
+            )?)?,
         })
     }
 }
+
+fn market_has_event_history(market: &AccountInfo) -> Result<bool, ProgramError> {
+    Ok(market.owner.as_ref() == crate::ID.as_ref()
+        && DequeHeader::has_event_history(&market.try_borrow_data()?))
+}
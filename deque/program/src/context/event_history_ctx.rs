@@ -0,0 +1,56 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    program_error::ProgramError,
+};
+
+use crate::{
+    require, seeds::event_history::find_event_history_address, shared::error::DequeError,
+    state::load_deque, utils::check_owned_and_writable,
+    validation::system_program::SystemProgramInfo,
+};
+
+#[derive(Clone)]
+pub struct EventHistoryContext<'a, 'info> {
+    pub payer: &'a AccountInfo<'info>,
+    /// The uninitialized event history PDA.
+    pub event_history: &'a AccountInfo<'info>,
+    pub event_history_bump: u8,
+    pub system_program: SystemProgramInfo<'a, 'info>,
+    /// The deque (market) account the event history belongs to. It's written to record that the
+    /// market now has an event history.
+    pub market: &'a AccountInfo<'info>,
+}
+
+impl<'a, 'info> EventHistoryContext<'a, 'info> {
+    pub fn load(
+        accounts: &'a [AccountInfo<'info>],
+    ) -> Result<EventHistoryContext<'a, 'info>, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let payer = next_account_info(accounts_iter)?;
+        let event_history = next_account_info(accounts_iter)?;
+        let system_program = SystemProgramInfo::new_checked(next_account_info(accounts_iter)?)?;
+        let market = next_account_info(accounts_iter)?;
+
+        let (expected_address, event_history_bump) = find_event_history_address(market.key);
+        require!(
+            event_history.key.as_ref() == expected_address.as_ref(),
+            DequeError::InvalidEventHistory,
+            "Event history isn't the market's event history PDA"
+        )?;
+        require!(
+            market.owner.as_ref() == crate::ID.as_ref(),
+            DequeError::AccountNotOwnedByProgram,
+            "The market must be initialized before its event history"
+        )?;
+        check_owned_and_writable(market)?;
+        load_deque(&mut market.try_borrow_mut_data()?)?;
+
+        Ok(EventHistoryContext {
+            payer,
+            event_history,
+            event_history_bump,
+            system_program,
+            market,
+        })
+    }
+}
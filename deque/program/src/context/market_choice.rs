@@ -4,8 +4,10 @@ use solana_program::{
 };
 
 use crate::{
+    context::deque_shards::DequeShardsContext,
     instruction_enum::MarketChoice,
-    state::Deque,
+    shared::error::DequeError,
+    state::load_deque,
     utils::check_owned_and_writable,
     validation::token_accounts::{TokenAccountInfo, TokenMintInfo, TokenProgramInfo},
 };
@@ -20,12 +22,19 @@ pub struct MarketChoiceContext<'a, 'info> {
     pub system_program: &'a AccountInfo<'info>,
     pub mint_info: TokenMintInfo<'a, 'info>,
     pub choice: MarketChoice,
+    /// The payer's trader record on this market, which only deposits pass, after the system
+    /// program. It isn't checked until a deposit opens a new escrow.
+    pub trader_record: Option<&'a AccountInfo<'info>>,
+    /// The deque's shards, passed after the fixed accounts. Only the shards a deposit or withdrawal
+    /// writes to have to be writable, the ones it only searches can be read-only.
+    pub shards: DequeShardsContext<'a, 'info>,
 }
 
 impl<'a, 'info> MarketChoiceContext<'a, 'info> {
     pub fn load(
         accounts: &'a [AccountInfo<'info>],
         choice: MarketChoice,
+        with_trader_record: bool,
     ) -> Result<MarketChoiceContext<'a, 'info>, ProgramError> {
         let accounts_iter = &mut accounts.iter();
         let deque_account = next_account_info(accounts_iter)?;
@@ -35,19 +44,29 @@ impl<'a, 'info> MarketChoiceContext<'a, 'info> {
         let mint_in = next_account_info(accounts_iter)?;
         let vault_ata = next_account_info(accounts_iter)?;
         let system_program = next_account_info(accounts_iter)?;
+        let trader_record = match with_trader_record {
+            true => Some(next_account_info(accounts_iter)?),
+            false => None,
+        };
 
-        let mut data = deque_account.data.borrow_mut();
-        let deque = Deque::from_bytes(&mut data)?;
-        check_owned_and_writable(deque_account)?;
+        let mint = {
+            let mut data = deque_account.data.borrow_mut();
+            let deque = load_deque(&mut data)?;
+            check_owned_and_writable(deque_account)?;
 
-        let mint = match choice {
-            MarketChoice::Base => deque.header.base_mint,
-            MarketChoice::Quote => deque.header.quote_mint,
+            match choice {
+                MarketChoice::Base => deque.header.base_mint,
+                MarketChoice::Quote => deque.header.quote_mint,
+            }
         };
+        let shards = DequeShardsContext::load_with_read_only_shards(
+            deque_account,
+            accounts_iter.as_slice(),
+        )?;
 
         // Ensure the mint pubkey passed into account data matches the mint in header data.
         if mint_in.key.as_ref() != mint.as_ref() {
-            return Err(ProgramError::InvalidInstructionData);
+            return Err(DequeError::MarketMintMismatch.into());
         }
 
         let (payer_ata, vault_ata, token_program, mint_info) = (
@@ -66,6 +85,8 @@ impl<'a, 'info> MarketChoiceContext<'a, 'info> {
             system_program,
             mint_info,
             choice,
+            trader_record,
+            shards,
         })
     }
 }
@@ -1,4 +1,7 @@
+pub mod deque_shards;
 pub mod event_authority_ctx;
 pub mod event_emitter;
+pub mod event_history_ctx;
 pub mod initialize_deque;
 pub mod market_choice;
+pub mod reconcile;
@@ -0,0 +1,58 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+
+use crate::{
+    context::deque_shards::DequeShardsContext, state::load_deque, utils::check_owned,
+    validation::token_accounts::TokenAccountInfo,
+};
+
+#[derive(Clone)]
+pub struct ReconcileContext<'a, 'info> {
+    pub deque_account: &'a AccountInfo<'info>,
+    pub vault_base_ata: TokenAccountInfo<'a, 'info>,
+    pub vault_quote_ata: TokenAccountInfo<'a, 'info>,
+    /// The deque's shards, passed after the fixed accounts. Every shard must be passed, since the
+    /// whole deque is walked.
+    pub shards: DequeShardsContext<'a, 'info>,
+}
+
+impl<'a, 'info> ReconcileContext<'a, 'info> {
+    pub fn load(
+        accounts: &'a [AccountInfo<'info>],
+    ) -> Result<ReconcileContext<'a, 'info>, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let deque_account = next_account_info(accounts_iter)?;
+        let vault_base_ata = next_account_info(accounts_iter)?;
+        let vault_quote_ata = next_account_info(accounts_iter)?;
+
+        let (base_mint, quote_mint) = {
+            check_owned(deque_account)?;
+            let mut data = deque_account.data.borrow_mut();
+            let deque = load_deque(&mut data)?;
+            (deque.header.base_mint, deque.header.quote_mint)
+        };
+        let shards = DequeShardsContext::load_read_only(deque_account, accounts_iter.as_slice())?;
+
+        Ok(ReconcileContext {
+            deque_account,
+            vault_base_ata: load_vault(vault_base_ata, &base_mint, deque_account.key)?,
+            vault_quote_ata: load_vault(vault_quote_ata, &quote_mint, deque_account.key)?,
+            shards,
+        })
+    }
+}
+
+/// Any token account owned by the deque holds the right mint, so the vault's address is checked
+/// too. Otherwise a second, fuller token account could stand in for an underfunded vault.
+fn load_vault<'a, 'info>(
+    info: &'a AccountInfo<'info>,
+    mint: &Pubkey,
+    deque: &Pubkey,
+) -> Result<TokenAccountInfo<'a, 'info>, ProgramError> {
+    let expected = get_associated_token_address_with_program_id(deque, mint, info.owner);
+    TokenAccountInfo::new_checked_owners_with_key(info, mint, deque, &expected)
+}
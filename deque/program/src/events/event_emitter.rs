@@ -1,20 +1,21 @@
 use solana_program::{
     entrypoint::ProgramResult,
     instruction::{AccountMeta, Instruction},
-    program::invoke_signed,
     program_error::ProgramError,
     pubkey::Pubkey,
     syscalls::MAX_CPI_INSTRUCTION_DATA_LEN,
+    sysvar::{clock::Clock, Sysvar},
 };
 
 use crate::{
     context::event_emitter::EventEmitterContext,
     events::{EmittableEvent, HeaderEventData},
     instruction_enum::InstructionTag,
-    seeds,
-    shared::error::DequeError,
-    state::EphemeralEventLog,
-    validation::{event_authority::EventAuthorityInfo, self_program::SelfProgramInfo},
+    state::{EphemeralEventLog, EventHistory},
+    validation::{
+        event_authority::EventAuthorityInfo, event_history::EventHistoryInfo,
+        instructions_sysvar::InstructionsSysvarInfo, self_program::SelfProgramInfo,
+    },
 };
 
 const MAX_CPI_DATA_LEN: usize = MAX_CPI_INSTRUCTION_DATA_LEN as usize;
@@ -25,6 +26,10 @@ pub struct EventEmitter<'a, 'info> {
     pub emit_instruction: Instruction,
     pub self_program: SelfProgramInfo<'a, 'info>,
     pub event_authority: EventAuthorityInfo<'a, 'info>,
+    pub event_history: Option<EventHistoryInfo<'a, 'info>>,
+    pub instructions_sysvar: InstructionsSysvarInfo<'a, 'info>,
+    /// The current slot, only read if there's an event history to record it in.
+    pub slot: u64,
 }
 
 impl<'a, 'info> EventEmitter<'a, 'info> {
@@ -63,18 +68,46 @@ impl<'a, 'info> EventEmitter<'a, 'info> {
         Ok(Self {
             emit_instruction: Instruction {
                 program_id: crate::ID,
-                accounts: vec![AccountMeta::new_readonly(
-                    *ctx.event_authority.info.key,
-                    true,
-                )],
+                accounts: vec![
+                    AccountMeta::new_readonly(*ctx.event_authority.info.key, true),
+                    AccountMeta::new_readonly(*ctx.instructions_sysvar.info.key, false),
+                ],
                 data,
             },
             self_program: ctx.self_program.clone(),
             event_authority: ctx.event_authority.clone(),
+            slot: match ctx.event_history {
+                Some(_) => Clock::get()?.slot,
+                None => 0,
+            },
+            event_history: ctx.event_history,
+            instructions_sysvar: ctx.instructions_sysvar,
         })
     }
 
     pub fn flush(&mut self) -> ProgramResult {
+        #[cfg(feature = "log-events")]
+        self.log_events();
+        #[cfg(not(feature = "log-events"))]
+        self.emit_events_via_cpi()?;
+
+        self.emit_instruction.data.truncate(FULL_HEADER_SIZE);
+        Ok(())
+    }
+
+    /// Log the header and events with `sol_log_data`. This skips the CPI and the event authority's
+    /// ephemeral event log, at the cost of the events being lost if the logs are truncated.
+    #[cfg(feature = "log-events")]
+    fn log_events(&self) {
+        // Skip the `FlushEventLog` instruction tag, the rest is exactly what the CPI would emit.
+        solana_program::log::sol_log_data(&[&self.emit_instruction.data[1..]]);
+    }
+
+    #[cfg(not(feature = "log-events"))]
+    fn emit_events_via_cpi(&mut self) -> ProgramResult {
+        use crate::{event_authority_seeds_with_bump, log_debug, shared::error::DequeError};
+        use solana_program::program::invoke_signed;
+
         // Cast the event authority's account data to a mutable ephemeral event log.
         let mut event_authority_data = self
             .event_authority
@@ -86,18 +119,17 @@ impl<'a, 'info> EventEmitter<'a, 'info> {
         let mut ephemeral_event_log =
             EphemeralEventLog::from_bytes_unchecked(&mut event_authority_data)?;
 
-        solana_program::msg!("writing {} bytes to the ephemeral event log");
-        {
-            solana_program::msg!(
-                "bytes bein---------------------------------------------------g: {:?}",
-                unsafe {
-                    core::slice::from_raw_parts(
-                        self.emit_instruction.data.as_ptr().add(FULL_HEADER_SIZE),
-                        self.emit_instruction.data.len() - FULL_HEADER_SIZE,
-                    )
-                }
-            );
-        }
+        log_debug!(
+            "Writing {} bytes to the ephemeral event log: {:?}",
+            self.emit_instruction.data.len() - FULL_HEADER_SIZE,
+            // SAFETY: The instruction data always starts with the full header.
+            unsafe {
+                core::slice::from_raw_parts(
+                    self.emit_instruction.data.as_ptr().add(FULL_HEADER_SIZE),
+                    self.emit_instruction.data.len() - FULL_HEADER_SIZE,
+                )
+            }
+        );
 
         // TODO: As it stands, the data in the header is *not* actually duplicated.
         // This would have to be refactored to make sure that the CPI header data is always synced
@@ -118,11 +150,14 @@ impl<'a, 'info> EventEmitter<'a, 'info> {
             &[
                 self.self_program.info.clone(),
                 self.event_authority.info.clone(),
+                self.instructions_sysvar.info.clone(),
             ],
-            &[seeds::event_authority::SEEDS],
+            event_authority_seeds_with_bump!(
+                self.event_authority.market,
+                self.event_authority.bump
+            ),
         )?;
 
-        self.emit_instruction.data.truncate(FULL_HEADER_SIZE);
         Ok(())
     }
 
@@ -134,6 +169,15 @@ impl<'a, 'info> EventEmitter<'a, 'info> {
         }
 
         event.write(&mut self.emit_instruction.data)?;
+
+        // Persist a copy of the packed event that was just written in the market's history.
+        if let Some(event_history) = &self.event_history {
+            let data = &self.emit_instruction.data;
+            let mut history_data = event_history.info.try_borrow_mut_data()?;
+            EventHistory::from_bytes(&mut history_data)?
+                .append(self.slot, &data[data.len() - T::LEN..])?;
+        }
+
         Ok(())
     }
 }
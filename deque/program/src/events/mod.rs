@@ -1,3 +1,4 @@
+use arrayref::array_ref;
 use solana_program::program_error::ProgramError;
 use solana_program::{entrypoint::ProgramResult, pubkey::Pubkey};
 
@@ -8,6 +9,8 @@ use crate::{impl_tags, require};
 
 pub(crate) mod event_emitter;
 
+pub use deque_pack_derive::EmittableEvent;
+
 #[repr(u8)]
 #[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(test, derive(strum_macros::FromRepr, strum_macros::EnumIter))]
@@ -17,6 +20,7 @@ pub enum EventTag {
     Deposit,
     Withdraw,
     Resize,
+    Reconcile,
 }
 
 impl TryFrom<u8> for EventTag {
@@ -25,7 +29,7 @@ impl TryFrom<u8> for EventTag {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             // SAFETY: A valid enum variant is guaranteed with the match pattern.
-            0..5 => Ok(unsafe { core::mem::transmute::<u8, Self>(value) }),
+            0..6 => Ok(unsafe { core::mem::transmute::<u8, Self>(value) }),
             _ => Err(DequeError::InvalidDiscriminant.into()),
         }
     }
@@ -39,32 +43,119 @@ pub enum DequeEvent<'p> {
     Deposit(DepositEventData<'p>),
     Withdraw(WithdrawEventData<'p>),
     // Resize(ResizeEventData),
+    Reconcile(ReconcileEventData),
 }
 
 #[cfg(not(target_os = "solana"))]
 impl<'p> DequeEvent<'p> {
+    /// Unpacks the event record at the start of `data`, ignoring any fields appended to it by
+    /// newer versions of the event.
     pub fn unpack(data: &'p [u8]) -> Result<DequeEvent<'p>, ProgramError> {
-        let tag: EventTag = data[0].try_into()?;
-
-        Ok(match tag {
-            EventTag::Header => DequeEvent::Header(HeaderEventData::try_from_slice(data)?),
-            EventTag::Deposit => DequeEvent::Deposit(DepositEventData::try_from_slice(data)?),
-            EventTag::Withdraw => DequeEvent::Withdraw(WithdrawEventData::try_from_slice(data)?),
-            _ => todo!(),
-        })
+        Self::unpack_known(data)?.ok_or(DequeError::InvalidDiscriminant.into())
+    }
+
+    /// Like [`DequeEvent::unpack`], but returns `None` for a record whose tag this decoder doesn't
+    /// know about, so that the caller can skip it.
+    pub fn unpack_known(data: &'p [u8]) -> Result<Option<DequeEvent<'p>>, ProgramError> {
+        let (prefix, data, _rest) = split_event_record(data)?;
+
+        Ok(Some(match EventTag::try_from(prefix.tag) {
+            Ok(EventTag::Header) => DequeEvent::Header(HeaderEventData::try_from_slice(data)?),
+            Ok(EventTag::Deposit) => DequeEvent::Deposit(DepositEventData::try_from_slice(data)?),
+            Ok(EventTag::Withdraw) => {
+                DequeEvent::Withdraw(WithdrawEventData::try_from_slice(data)?)
+            }
+            Ok(EventTag::Reconcile) => {
+                DequeEvent::Reconcile(ReconcileEventData::try_from_slice(data)?)
+            }
+            Ok(EventTag::Initialize | EventTag::Resize) | Err(_) => return Ok(None),
+        }))
     }
 }
 
+/// The length of the prefix every packed event starts with: its tag, its version and the length
+/// of the rest of the event (its body) as a little endian u16.
+pub const EVENT_PREFIX_LEN: usize = 1 + 1 + 2;
+
+/// Events only ever evolve by appending fields and bumping their version, so a decoder that knows
+/// an older version can read the fields it knows and skip the rest with `body_len`. Decoders can
+/// also skip events with tags they don't know about entirely.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
+pub struct EventPrefix {
+    pub tag: u8,
+    pub version: u8,
+    pub body_len: u16,
+}
+
+impl EventPrefix {
+    #[inline(always)]
+    pub fn record_len(&self) -> usize {
+        EVENT_PREFIX_LEN + self.body_len as usize
+    }
+}
+
+/// Splits the event record at the start of `data` from the rest of the data, without checking its
+/// tag. Returns the record's prefix, the whole record including its prefix, and the rest.
+#[inline(always)]
+pub fn split_event_record(data: &[u8]) -> Result<(EventPrefix, &[u8], &[u8]), ProgramError> {
+    require!(
+        data.len() >= EVENT_PREFIX_LEN,
+        DequeError::InvalidPackedData
+    )?;
+    let prefix = EventPrefix {
+        tag: data[0],
+        version: data[1],
+        body_len: u16::from_le_bytes(*array_ref![data, 2, 2]),
+    };
+    let (record, rest) = data
+        .split_at_checked(prefix.record_len())
+        .ok_or(DequeError::InvalidPackedData)?;
+    Ok((prefix, record, rest))
+}
+
+/// Validates the payload of a flushed event log without decoding it: a single header event
+/// followed by any number of deposit, withdraw or reconcile events, each with a valid tag, length
+/// and enum fields. Returns the market in the header.
+pub fn validate_event_payload(data: &[u8]) -> Result<Pubkey, ProgramError> {
+    let (prefix, header, mut rest) = split_event_record(data)?;
+    require!(
+        EventTag::try_from(prefix.tag)? == EventTag::Header,
+        DequeError::InvalidDiscriminant,
+        "Event payload must start with a header event"
+    )?;
+    HeaderEventData::validate(header)?;
+
+    while !rest.is_empty() {
+        let (prefix, record, next) = split_event_record(rest)?;
+        match EventTag::try_from(prefix.tag)? {
+            EventTag::Deposit => DepositEventData::validate(record)?,
+            EventTag::Withdraw => WithdrawEventData::validate(record)?,
+            EventTag::Reconcile => ReconcileEventData::validate(record)?,
+            _ => return Err(DequeError::InvalidDiscriminant.into()),
+        };
+        rest = next;
+    }
+
+    Ok(Pubkey::new_from_array(*array_ref![header, 5, 32]))
+}
+
 impl_tags!(
     HeaderEventData<'_>       => EventTag::Header,
     // InitializeEventData    => EventTag::Initialize,
     DepositEventData<'_>      => EventTag::Deposit,
     WithdrawEventData<'_>     => EventTag::Withdraw,
     // ResizeEventData        => EventTag::Resize,
+    ReconcileEventData        => EventTag::Reconcile,
 );
 
-pub trait EmittableEvent: Sized {
+pub trait EmittableEvent: Tagged + Sized {
+    /// The version of the event's layout, bumped whenever a field is appended to it.
+    const VERSION: u8;
+    /// The length of the packed event, including its prefix.
     const LEN: usize;
+    /// The length of the event's first version, the shortest record that still decodes.
+    const MIN_LEN: usize;
 
     /// Writes the event bytes to a destination buffer, checking that it has enough spare capacity.
     #[inline(always)]
@@ -87,32 +178,56 @@ pub trait EmittableEvent: Sized {
     #[doc(hidden)]
     unsafe fn write_unchecked(&self, buf: &mut Vec<u8>);
 
+    /// Decodes a single event record, as split off by [`split_event_record`]. Fields appended in
+    /// later versions are read if the data is long enough to hold them, so it mustn't run on
+    /// into the next record.
     #[cfg(not(target_os = "solana"))]
     fn try_from_slice(data: &[u8]) -> Result<Self, ProgramError> {
         require!(
-            data.len() >= Self::LEN,
-            ProgramError::InvalidInstructionData,
-            "Expected data.len() >= Self::LEN aka {} >= {}, but data.len() == {}",
+            data.len() >= Self::MIN_LEN,
+            DequeError::InvalidPackedData,
+            "Expected data.len() >= Self::MIN_LEN aka {} >= {}, but data.len() == {}",
             data.len(),
-            Self::LEN,
+            Self::MIN_LEN,
             data.len()
         )?;
-        Ok(Self::from_slice_unchecked(data))
+        Self::validate(data)?;
+        // SAFETY: The data was just validated.
+        Ok(unsafe { Self::from_slice_unchecked(data) })
     }
 
+    /// # Safety
+    /// `data` must have passed [`EmittableEvent::validate`].
     #[cfg(not(target_os = "solana"))]
     #[doc(hidden)]
-    fn from_slice_unchecked(data: &[u8]) -> Self;
+    unsafe fn from_slice_unchecked(data: &[u8]) -> Self;
 
     #[inline(always)]
     fn check_len(data: &[u8]) -> ProgramResult {
-        require!(data.len() >= Self::LEN, DequeError::InvalidPackedData)
+        require!(data.len() >= Self::MIN_LEN, DequeError::InvalidPackedData)
+    }
+
+    /// # Safety
+    /// Caller must guarantee the vec has at least [`EVENT_PREFIX_LEN`] bytes of spare capacity.
+    #[inline(always)]
+    unsafe fn write_prefix(buf: &mut Vec<u8>) {
+        vec_append_bytes(buf, &[Self::TAG, Self::VERSION]);
+        vec_append_bytes(buf, &((Self::LEN - EVENT_PREFIX_LEN) as u16).to_le_bytes());
+    }
+
+    /// Checks that `data` holds a well-formed packed event, so that decoding it can't fail.
+    #[inline(always)]
+    fn validate(data: &[u8]) -> ProgramResult {
+        Self::check_len(data)
     }
 }
 
 #[repr(C)]
+#[derive(EmittableEvent)]
+#[pack(version = 1)]
 #[cfg_attr(not(target_os = "solana"), derive(Clone, Copy, Debug, Eq, PartialEq))]
 pub struct HeaderEventData<'p> {
+    #[pack(tag)]
     pub discriminant: u8,
     pub instruction_tag: InstructionTag,
     pub market: &'p Pubkey,
@@ -121,39 +236,6 @@ pub struct HeaderEventData<'p> {
     pub emitted_count: u16,
 }
 
-impl EmittableEvent for HeaderEventData<'_> {
-    const LEN: usize = 1 + 1 + 32 + 32 + 8 + 2;
-
-    unsafe fn write_unchecked(&self, buf: &mut Vec<u8>) {
-        vec_append_bytes(buf, &[Self::TAG]);
-        vec_append_bytes(buf, &[self.instruction_tag as u8]);
-        vec_append_bytes(buf, self.market.as_ref());
-        vec_append_bytes(buf, self.sender.as_ref());
-        vec_append_bytes(buf, &self.nonce.to_le_bytes());
-        vec_append_bytes(buf, &self.emitted_count.to_le_bytes());
-    }
-
-    #[cfg(not(target_os = "solana"))]
-    fn from_slice_unchecked<'p>(data: &[u8]) -> Self {
-        use arrayref::array_ref;
-
-        Self {
-            discriminant: data[0],
-            instruction_tag: data[1]
-                .try_into()
-                .expect("Instruction tag should have already been validated."),
-            // SAFETY: data[2..34] is exactly 32 bytes and Pubkey is repr(transparent) over [u8; 32]
-            // Casted from input slice to preserve the 'p lifetime.
-            market: unsafe { &*(data[2..34].as_ptr() as *const Pubkey) },
-            // SAFETY: data[34..66] is exactly 32 bytes and Pubkey is repr(transparent) over [u8; 32]
-            // Casted from input slice to preserve the 'p lifetime.
-            sender: unsafe { &*(data[34..66].as_ptr() as *const Pubkey) },
-            nonce: u64::from_le_bytes(*array_ref![data, 66, 8]),
-            emitted_count: u16::from_le_bytes(*array_ref![data, 74, 2]),
-        }
-    }
-}
-
 impl<'p> HeaderEventData<'p> {
     pub fn new(
         instruction_tag: InstructionTag,
@@ -174,91 +256,143 @@ impl<'p> HeaderEventData<'p> {
 }
 
 #[repr(C)]
+#[derive(EmittableEvent)]
+#[pack(version = 2)]
 #[cfg_attr(not(target_os = "solana"), derive(Clone, Copy, Debug, Eq, PartialEq))]
 pub struct DepositEventData<'p> {
+    #[pack(tag)]
     pub discriminant: u8,
     pub trader: &'p Pubkey,
     pub amount: u64,
     pub side: MarketChoice,
+    /// The slot the event was emitted in, or 0 in version 1 events.
+    #[pack(since = 2)]
+    pub slot: u64,
 }
 
 impl<'p> DepositEventData<'p> {
-    pub fn new(trader: &'p Pubkey, amount: u64, side: MarketChoice) -> Self {
+    pub fn new(trader: &'p Pubkey, amount: u64, side: MarketChoice, slot: u64) -> Self {
         Self {
             discriminant: Self::TAG,
             trader,
             amount,
             side,
-        }
-    }
-}
-
-impl EmittableEvent for DepositEventData<'_> {
-    const LEN: usize = 1 + 32 + 8 + 1;
-
-    unsafe fn write_unchecked(&self, buf: &mut Vec<u8>) {
-        vec_append_bytes(buf, &[Self::TAG]);
-        vec_append_bytes(buf, self.trader.as_ref());
-        vec_append_bytes(buf, &self.amount.to_le_bytes());
-        vec_append_bytes(buf, &[self.side as u8]);
-    }
-
-    #[cfg(not(target_os = "solana"))]
-    fn from_slice_unchecked(data: &[u8]) -> Self {
-        use arrayref::array_ref;
-
-        Self {
-            discriminant: data[0],
-            trader: unsafe { &*(data[1..33].as_ptr() as *const Pubkey) },
-            amount: u64::from_le_bytes(*array_ref![data, 33, 8]),
-            side: data[41]
-                .try_into()
-                .expect("Market choice enum should have been validated."),
+            slot,
         }
     }
 }
 
 #[repr(C)]
+#[derive(EmittableEvent)]
+#[pack(version = 2)]
 #[cfg_attr(not(target_os = "solana"), derive(Clone, Copy, Debug, Eq, PartialEq))]
 pub struct WithdrawEventData<'p> {
+    #[pack(tag)]
     pub discriminant: u8,
     pub trader: &'p Pubkey,
     pub amount: u64,
     pub side: MarketChoice,
+    /// The slot the event was emitted in, or 0 in version 1 events.
+    #[pack(since = 2)]
+    pub slot: u64,
 }
 
 impl<'p> WithdrawEventData<'p> {
-    pub fn new(trader: &'p Pubkey, amount: u64, side: MarketChoice) -> Self {
+    pub fn new(trader: &'p Pubkey, amount: u64, side: MarketChoice, slot: u64) -> Self {
         Self {
             discriminant: Self::TAG,
             trader,
             amount,
             side,
+            slot,
         }
     }
 }
 
-impl EmittableEvent for WithdrawEventData<'_> {
-    const LEN: usize = 1 + 32 + 8 + 1;
-
-    unsafe fn write_unchecked(&self, buf: &mut Vec<u8>) {
-        vec_append_bytes(buf, &[Self::TAG]);
-        vec_append_bytes(buf, self.trader.as_ref());
-        vec_append_bytes(buf, &self.amount.to_le_bytes());
-        vec_append_bytes(buf, &[self.side as u8]);
-    }
-
-    #[cfg(not(target_os = "solana"))]
-    fn from_slice_unchecked(data: &[u8]) -> Self {
-        use arrayref::array_ref;
+/// The result of reconciling one side of the market: the sum of every escrow's amount, the header's
+/// running total and the vault's balance. At most one of `surplus` and `deficit` is nonzero.
+#[repr(C)]
+#[derive(EmittableEvent)]
+#[pack(version = 1)]
+#[cfg_attr(not(target_os = "solana"), derive(Clone, Copy, Debug, Eq, PartialEq))]
+pub struct ReconcileEventData {
+    #[pack(tag)]
+    pub discriminant: u8,
+    pub side: MarketChoice,
+    /// The sum of every escrow's amount, counted by walking the deque.
+    pub escrowed: u64,
+    /// The running total kept in the deque's header.
+    pub recorded: u64,
+    pub vault_balance: u64,
+    /// How much more the vault holds than `escrowed`.
+    pub surplus: u64,
+    /// How much less the vault holds than `escrowed`.
+    pub deficit: u64,
+}
 
+impl ReconcileEventData {
+    pub fn new(side: MarketChoice, escrowed: u64, recorded: u64, vault_balance: u64) -> Self {
         Self {
-            discriminant: data[0],
-            trader: unsafe { &*(data[1..33].as_ptr() as *const Pubkey) },
-            amount: u64::from_le_bytes(*array_ref![data, 33, 8]),
-            side: data[41]
-                .try_into()
-                .expect("Market choice enum should have been validated."),
+            discriminant: Self::TAG,
+            side,
+            escrowed,
+            recorded,
+            vault_balance,
+            surplus: vault_balance.saturating_sub(escrowed),
+            deficit: escrowed.saturating_sub(vault_balance),
         }
     }
 }
+
+pub mod tests {
+    #[test]
+    pub fn derived_event_layouts() {
+        use super::*;
+
+        assert_eq!(HeaderEventData::LEN, EVENT_PREFIX_LEN + 1 + 32 + 32 + 8 + 2);
+        assert_eq!(DepositEventData::LEN, EVENT_PREFIX_LEN + 32 + 8 + 1 + 8);
+        assert_eq!(WithdrawEventData::LEN, EVENT_PREFIX_LEN + 32 + 8 + 1 + 8);
+        // Version 1 withdrawals, without the slot, still decode.
+        assert_eq!(WithdrawEventData::MIN_LEN, EVENT_PREFIX_LEN + 32 + 8 + 1);
+        assert_eq!(ReconcileEventData::LEN, EVENT_PREFIX_LEN + 1 + 8 * 5);
+
+        let reconcile = ReconcileEventData::new(MarketChoice::Base, 10, 10, 7);
+        assert_eq!((reconcile.surplus, reconcile.deficit), (0, 3));
+        let mut buf = Vec::with_capacity(ReconcileEventData::LEN);
+        reconcile.write(&mut buf).unwrap();
+        assert_eq!(ReconcileEventData::try_from_slice(&buf), Ok(reconcile));
+
+        let (market, trader) = (
+            Pubkey::new_from_array([1; 32]),
+            Pubkey::new_from_array([2; 32]),
+        );
+        let header = HeaderEventData::new(InstructionTag::Withdraw, &market, &trader, 3, 4);
+        let withdraw = WithdrawEventData::new(&trader, 0x0102, MarketChoice::Quote, 5);
+
+        let mut buf = Vec::with_capacity(HeaderEventData::LEN + WithdrawEventData::LEN);
+        header.write(&mut buf).unwrap();
+        withdraw.write(&mut buf).unwrap();
+
+        #[rustfmt::skip]
+        let expected = [
+            &[EventTag::Header as u8, 1, 75, 0, InstructionTag::Withdraw as u8][..],
+            &[1; 32], &[2; 32], &3u64.to_le_bytes(), &4u16.to_le_bytes(),
+            &[EventTag::Withdraw as u8, 2, 49, 0], &[2; 32], &0x0102u64.to_le_bytes(), &[1],
+            &5u64.to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(buf, expected);
+
+        let (_, header_record, rest) = split_event_record(&buf).unwrap();
+        assert_eq!(HeaderEventData::try_from_slice(header_record), Ok(header));
+        assert_eq!(WithdrawEventData::try_from_slice(rest), Ok(withdraw));
+
+        // Enum fields are validated.
+        let mut invalid_side = rest.to_vec();
+        invalid_side[EVENT_PREFIX_LEN + 32 + 8] = 2;
+        assert!(WithdrawEventData::validate(&invalid_side).is_err());
+        // Decoding invalid data returns an error rather than panicking.
+        assert!(WithdrawEventData::try_from_slice(&invalid_side).is_err());
+        assert!(DequeEvent::unpack(&invalid_side).is_err());
+    }
+}
@@ -1,15 +1,6 @@
-use core::mem::MaybeUninit;
-
-use crate::pack::Tagged;
 use solana_program::program_error::ProgramError;
 
-use crate::{
-    impl_tags,
-    pack::{Pack, PackWithTag, U16_BYTES},
-    require,
-    shared::error::DequeError,
-    utils::write_bytes,
-};
+use crate::{impl_tags, pack::Pack, shared::error::DequeError, utils::SectorIndex};
 
 #[repr(u8)]
 #[derive(Clone, Copy)]
@@ -27,7 +18,7 @@ impl TryFrom<u8> for MarketChoice {
         match value {
             // SAFETY: A valid enum variant is guaranteed with the match pattern.
             0..=1 => Ok(unsafe { core::mem::transmute::<u8, MarketChoice>(value) }),
-            _ => Err(ProgramError::InvalidInstructionData),
+            _ => Err(DequeError::InvalidMarketChoice.into()),
         }
     }
 }
@@ -40,9 +31,24 @@ pub enum InstructionTag {
     InitializeEventAuthority,
     ResizeEventAuthority,
     Resize,
+    /// Accounts: the event emitter's accounts, then `deque`, `payer`, `payer_ata`,
+    /// `token_program`, `mint`, `vault_ata`, `system_program`, `trader_record` and the deque's
+    /// shards.
+    ///
+    /// `trader_record` was added after the original release, before the shards, so callers built
+    /// against the original account list have to be updated to pass it. Without it, a deposit
+    /// fails with `NotEnoughAccountKeys`, or takes the first shard as the trader record.
     Deposit,
+    /// Accounts: the event emitter's accounts, then `deque`, `payer`, `payer_ata`,
+    /// `token_program`, `mint`, `vault_ata`, `system_program` and the deque's shards.
     Withdraw,
     FlushEventLog,
+    Defragment,
+    InitializeEventHistory,
+    Reconcile,
+    MigrateDeque,
+    /// Accounts: `trader`, `deque`, `trader_record` and every shard of the deque.
+    CloseTraderRecord,
 }
 
 impl_tags! {
@@ -53,16 +59,28 @@ impl_tags! {
     WithdrawInstructionData                  => InstructionTag::Withdraw,
     ResizeInstructionData                    => InstructionTag::Resize,
     FlushEventLogInstructionData             => InstructionTag::FlushEventLog,
+    DefragmentInstructionData                => InstructionTag::Defragment,
+    InitializeEventHistoryInstructionData    => InstructionTag::InitializeEventHistory,
+    ReconcileInstructionData                 => InstructionTag::Reconcile,
+    MigrateDequeInstructionData              => InstructionTag::MigrateDeque,
+    CloseTraderRecordInstructionData         => InstructionTag::CloseTraderRecord,
 }
 
 #[cfg(not(target_os = "solana"))]
+#[derive(Debug)]
 pub enum DequeInstruction {
     InitializeDeque(InitializeDequeInstructionData),
     InitializeEventAuthority(InitializeEventAuthorityInstructionData),
+    ResizeEventAuthority(ResizeEventAuthorityInstructionData),
     Deposit(DepositInstructionData),
     Withdraw(WithdrawInstructionData),
     Resize(ResizeInstructionData),
     FlushEventLog(FlushEventLogInstructionData),
+    Defragment(DefragmentInstructionData),
+    InitializeEventHistory(InitializeEventHistoryInstructionData),
+    Reconcile(ReconcileInstructionData),
+    MigrateDeque(MigrateDequeInstructionData),
+    CloseTraderRecord(CloseTraderRecordInstructionData),
 }
 
 #[cfg(not(target_os = "solana"))]
@@ -71,12 +89,228 @@ impl DequeInstruction {
         match self {
             DequeInstruction::InitializeDeque(data) => data.pack().to_vec(),
             DequeInstruction::InitializeEventAuthority(data) => data.pack().to_vec(),
+            DequeInstruction::ResizeEventAuthority(data) => data.pack().to_vec(),
             DequeInstruction::Deposit(data) => data.pack().to_vec(),
             DequeInstruction::Withdraw(data) => data.pack().to_vec(),
             DequeInstruction::Resize(data) => data.pack().to_vec(),
             DequeInstruction::FlushEventLog(data) => data.pack().to_vec(),
+            DequeInstruction::Defragment(data) => data.pack().to_vec(),
+            DequeInstruction::InitializeEventHistory(data) => data.pack().to_vec(),
+            DequeInstruction::Reconcile(data) => data.pack().to_vec(),
+            DequeInstruction::MigrateDeque(data) => data.pack().to_vec(),
+            DequeInstruction::CloseTraderRecord(data) => data.pack().to_vec(),
+        }
+    }
+
+    /// Decodes any instruction's data. Empty, short or otherwise malformed data is an error.
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        let tag = InstructionTag::try_from(*data.first().ok_or(DequeError::InvalidPackedData)?)?;
+        Ok(match tag {
+            InstructionTag::InitializeDeque => {
+                Self::InitializeDeque(InitializeDequeInstructionData::unpack(data)?)
+            }
+            InstructionTag::InitializeEventAuthority => Self::InitializeEventAuthority(
+                InitializeEventAuthorityInstructionData::unpack(data)?,
+            ),
+            InstructionTag::ResizeEventAuthority => {
+                Self::ResizeEventAuthority(ResizeEventAuthorityInstructionData::unpack(data)?)
+            }
+            InstructionTag::Resize => Self::Resize(ResizeInstructionData::unpack(data)?),
+            InstructionTag::Deposit => Self::Deposit(DepositInstructionData::unpack(data)?),
+            InstructionTag::Withdraw => Self::Withdraw(WithdrawInstructionData::unpack(data)?),
+            InstructionTag::FlushEventLog => {
+                Self::FlushEventLog(FlushEventLogInstructionData::unpack(data)?)
+            }
+            InstructionTag::Defragment => {
+                Self::Defragment(DefragmentInstructionData::unpack(data)?)
+            }
+            InstructionTag::InitializeEventHistory => {
+                Self::InitializeEventHistory(InitializeEventHistoryInstructionData::unpack(data)?)
+            }
+            InstructionTag::Reconcile => Self::Reconcile(ReconcileInstructionData::unpack(data)?),
+            InstructionTag::MigrateDeque => {
+                Self::MigrateDeque(MigrateDequeInstructionData::unpack(data)?)
+            }
+            InstructionTag::CloseTraderRecord => {
+                Self::CloseTraderRecord(CloseTraderRecordInstructionData::unpack(data)?)
+            }
+        })
+    }
+
+    pub fn tag(&self) -> InstructionTag {
+        match self {
+            DequeInstruction::InitializeDeque(_) => InstructionTag::InitializeDeque,
+            DequeInstruction::InitializeEventAuthority(_) => {
+                InstructionTag::InitializeEventAuthority
+            }
+            DequeInstruction::ResizeEventAuthority(_) => InstructionTag::ResizeEventAuthority,
+            DequeInstruction::Deposit(_) => InstructionTag::Deposit,
+            DequeInstruction::Withdraw(_) => InstructionTag::Withdraw,
+            DequeInstruction::Resize(_) => InstructionTag::Resize,
+            DequeInstruction::FlushEventLog(_) => InstructionTag::FlushEventLog,
+            DequeInstruction::Defragment(_) => InstructionTag::Defragment,
+            DequeInstruction::InitializeEventHistory(_) => InstructionTag::InitializeEventHistory,
+            DequeInstruction::Reconcile(_) => InstructionTag::Reconcile,
+            DequeInstruction::MigrateDeque(_) => InstructionTag::MigrateDeque,
+            DequeInstruction::CloseTraderRecord(_) => InstructionTag::CloseTraderRecord,
+        }
+    }
+}
+
+/// The accounts that precede the instruction's own accounts in every event emitting instruction.
+const EVENT_EMITTER_ACCOUNT_LABELS: [&str; 4] = [
+    "self_program",
+    "event_authority",
+    "event_history",
+    "instructions_sysvar",
+];
+
+/// The roles of an instruction's accounts, in order.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug, PartialEq, Eq))]
+pub struct AccountLabels {
+    /// Whether the accounts start with the event emitter's accounts.
+    pub emits_events: bool,
+    pub fixed: &'static [&'static str],
+    /// The role of every account after the fixed accounts, if the instruction takes any.
+    pub remaining: Option<&'static str>,
+}
+
+impl InstructionTag {
+    pub fn account_labels(&self) -> AccountLabels {
+        let (emits_events, fixed, remaining): (_, &'static [&'static str], _) = match self {
+            InstructionTag::InitializeDeque => (
+                true,
+                &[
+                    "payer",
+                    "deque",
+                    "base_mint",
+                    "quote_mint",
+                    "vault_base_ata",
+                    "vault_quote_ata",
+                    "base_token_program",
+                    "quote_token_program",
+                    "associated_token_program",
+                    "system_program",
+                ],
+                None,
+            ),
+            InstructionTag::InitializeEventAuthority | InstructionTag::ResizeEventAuthority => (
+                false,
+                &[
+                    "payer",
+                    "event_authority",
+                    "system_program",
+                    "deque",
+                    "base_mint",
+                    "quote_mint",
+                ],
+                None,
+            ),
+            InstructionTag::Resize => (
+                true,
+                &["payer", "deque", "system_program"],
+                Some("deque_shard"),
+            ),
+            InstructionTag::Deposit => (
+                true,
+                &[
+                    "deque",
+                    "payer",
+                    "payer_ata",
+                    "token_program",
+                    "mint",
+                    "vault_ata",
+                    "system_program",
+                    "trader_record",
+                ],
+                Some("deque_shard"),
+            ),
+            InstructionTag::Withdraw => (
+                true,
+                &[
+                    "deque",
+                    "payer",
+                    "payer_ata",
+                    "token_program",
+                    "mint",
+                    "vault_ata",
+                    "system_program",
+                ],
+                Some("deque_shard"),
+            ),
+            InstructionTag::FlushEventLog => {
+                (false, &["event_authority", "instructions_sysvar"], None)
+            }
+            InstructionTag::Defragment => (false, &["deque"], Some("deque_shard")),
+            InstructionTag::InitializeEventHistory => (
+                false,
+                &["payer", "event_history", "system_program", "deque"],
+                None,
+            ),
+            InstructionTag::Reconcile => (
+                true,
+                &["deque", "vault_base_ata", "vault_quote_ata"],
+                Some("deque_shard"),
+            ),
+            InstructionTag::MigrateDeque => (
+                false,
+                &["payer", "deque", "system_program", "event_history"],
+                None,
+            ),
+            InstructionTag::CloseTraderRecord => (
+                false,
+                &["trader", "deque", "trader_record"],
+                Some("deque_shard"),
+            ),
+        };
+        AccountLabels {
+            emits_events,
+            fixed,
+            remaining,
         }
     }
+
+    /// The index of the only account with the role `label`, if the instruction has exactly one.
+    pub fn account_index(&self, label: &str) -> Option<u8> {
+        let labels = self.account_labels();
+        let prefix: &[&'static str] = match labels.emits_events {
+            true => &EVENT_EMITTER_ACCOUNT_LABELS,
+            false => &[],
+        };
+        if labels.remaining == Some(label) {
+            return None;
+        }
+        let mut matches = prefix
+            .iter()
+            .chain(labels.fixed)
+            .enumerate()
+            .filter(|(_, account)| **account == label);
+        match (matches.next(), matches.next()) {
+            (Some((index, _)), None) => Some(index as u8),
+            _ => None,
+        }
+    }
+
+    /// Pairs each of an instruction's accounts with its role. Accounts the instruction doesn't
+    /// expect are labeled `"unknown"`.
+    #[cfg(not(target_os = "solana"))]
+    pub fn label_accounts<'a, T>(&self, accounts: &'a [T]) -> Vec<(&'static str, &'a T)> {
+        let labels = self.account_labels();
+        let prefix: &[&'static str] = match labels.emits_events {
+            true => &EVENT_EMITTER_ACCOUNT_LABELS,
+            false => &[],
+        };
+        let remaining = core::iter::repeat(labels.remaining.unwrap_or("unknown"));
+
+        prefix
+            .iter()
+            .chain(labels.fixed)
+            .copied()
+            .chain(remaining)
+            .zip(accounts)
+            .collect()
+    }
 }
 
 impl TryFrom<u8> for InstructionTag {
@@ -85,195 +319,134 @@ impl TryFrom<u8> for InstructionTag {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             // SAFETY: A valid enum variant is guaranteed with the match pattern.
-            0..7 => Ok(unsafe { core::mem::transmute::<u8, Self>(value) }),
+            0..12 => Ok(unsafe { core::mem::transmute::<u8, Self>(value) }),
             _ => Err(DequeError::InvalidInstructionTag.into()),
         }
     }
 }
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Pack)]
 #[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
 pub struct InitializeDequeInstructionData {
     pub num_sectors: u16,
 }
 
-impl Pack<3> for InitializeDequeInstructionData {
-    #[inline(always)]
-    fn pack_into_slice(&self, dst: &mut [MaybeUninit<u8>; 3]) {
-        dst[0].write(Self::TAG);
-        write_bytes(&mut dst[1..3], &self.num_sectors.to_le_bytes());
-    }
-
-    #[inline(always)]
-    unsafe fn unpack_unchecked(instruction_data: &[u8]) -> Self {
-        // SAFETY: Caller guarantees instruction data has at least 2 bytes at offset 1.
-        Self {
-            num_sectors: u16::from_le_bytes(unsafe {
-                *(instruction_data.get_unchecked(1..3).as_ptr() as *const [u8; U16_BYTES])
-            }),
-        }
-    }
-}
-
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Pack)]
 #[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
 pub struct ResizeInstructionData {
     pub num_sectors: u16,
 }
 
-impl Pack<3> for ResizeInstructionData {
-    #[inline(always)]
-    fn pack_into_slice(&self, dst: &mut [MaybeUninit<u8>; 3]) {
-        dst[0].write(Self::TAG);
-        write_bytes(&mut dst[1..3], &self.num_sectors.to_le_bytes());
-    }
+#[repr(C)]
+#[derive(Clone, Pack)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
+pub struct DefragmentInstructionData {
+    /// The maximum number of nodes to relocate in a single instruction.
+    pub max_moves: u16,
+}
 
-    #[inline(always)]
-    unsafe fn unpack_unchecked(instruction_data: &[u8]) -> Self {
-        // SAFETY: Caller guarantees instruction data has at least 2 bytes at offset 1.
-        let num_sectors = u16::from_le_bytes(unsafe {
-            *(instruction_data.get_unchecked(1..3).as_ptr() as *const [u8; U16_BYTES])
-        });
-        Self { num_sectors }
-    }
+#[repr(C)]
+#[derive(Clone, Pack)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
+pub struct InitializeEventHistoryInstructionData {
+    /// The number of events the history's ring buffer holds before overwriting the oldest.
+    pub capacity: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Pack)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
+pub struct ReconcileInstructionData {
+    /// Fail the instruction if either vault holds less than its escrows add up to, instead of
+    /// only reporting the deficit in an event.
+    pub fail_on_deficit: bool,
 }
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Pack)]
 #[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
 pub struct DepositInstructionData {
     pub choice: MarketChoice,
     pub amount: u64,
+    /// The sector holding the trader's escrow, if the caller knows it. The program then only
+    /// touches that sector's shard instead of searching the deque for the trader. A hint that no
+    /// longer points at the trader's escrow falls back to the search.
+    #[pack(trailing)]
+    pub escrow_hint: Option<SectorIndex>,
 }
 
 #[cfg(not(target_os = "solana"))]
 impl DepositInstructionData {
     pub fn new(amount: u64, choice: MarketChoice) -> Self {
-        DepositInstructionData { amount, choice }
+        DepositInstructionData {
+            amount,
+            choice,
+            escrow_hint: None,
+        }
+    }
+
+    pub fn with_escrow_hint(self, escrow_hint: Option<SectorIndex>) -> Self {
+        DepositInstructionData {
+            escrow_hint,
+            ..self
+        }
     }
 }
 
 #[cfg(not(target_os = "solana"))]
 impl WithdrawInstructionData {
     pub fn new(choice: MarketChoice) -> Self {
-        WithdrawInstructionData { choice }
-    }
-}
-
-// TODO: Check the actual sBPF output and see if the extensible impl we have here compiles to
-// roughly the same bytecode as inlining it with no extensibility/composition on the checks and
-// whatnot.
-impl Pack<10> for DepositInstructionData {
-    #[inline(always)]
-    fn pack_into_slice(&self, dst: &mut [MaybeUninit<u8>; 10]) {
-        dst[0].write(Self::TAG);
-        dst[1].write(self.choice as u8);
-        write_bytes(&mut dst[2..10], &self.amount.to_le_bytes());
-    }
-
-    #[inline(always)]
-    fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
-        Self::check_len(data)?;
-        Self::check_tag(data)?;
-        require!(
-            MarketChoice::try_from(unsafe { *(data.get_unchecked(1)) }).is_ok(),
-            DequeError::InvalidMarketChoice
-        )?;
-        // Safety: The length, tag, and choice enum were all just verified.
-        Ok(unsafe { Self::unpack_unchecked(data) })
+        WithdrawInstructionData {
+            choice,
+            escrow_hint: None,
+        }
     }
 
-    #[inline(always)]
-    unsafe fn unpack_unchecked(instruction_data: &[u8]) -> Self {
-        // SAFETY: Caller guarantees instruction data has 1 byte at offset 1.
-        let choice_byte = unsafe { *(instruction_data.get_unchecked(1)) };
-        // SAFETY: Caller must ensure that that byte is either 0 or 1.
-        let choice = unsafe { core::mem::transmute::<u8, MarketChoice>(choice_byte) };
-        // SAFETY: Caller guarantees instruction data has 8 bytes at offset 2.
-        let amount = u64::from_le_bytes(unsafe {
-            *(instruction_data.get_unchecked(2..10).as_ptr() as *const [u8; 8])
-        });
-        Self { choice, amount }
+    pub fn with_escrow_hint(self, escrow_hint: Option<SectorIndex>) -> Self {
+        WithdrawInstructionData {
+            escrow_hint,
+            ..self
+        }
     }
 }
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Pack)]
 #[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
 pub struct WithdrawInstructionData {
     pub choice: MarketChoice,
-}
-
-impl Pack<2> for WithdrawInstructionData {
-    #[inline(always)]
-    fn pack_into_slice(&self, dst: &mut [MaybeUninit<u8>; 2]) {
-        dst[0].write(Self::TAG);
-        dst[1].write(self.choice as u8);
-    }
-
-    #[inline(always)]
-    unsafe fn unpack_unchecked(instruction_data: &[u8]) -> Self {
-        // SAFETY: Caller guarantees instruction data has 1 byte at offset 1.
-        let choice_byte = unsafe { *(instruction_data.get_unchecked(1)) };
-        // SAFETY: Caller must ensure that that byte is either 0 or 1.
-        let choice = unsafe { core::mem::transmute::<u8, MarketChoice>(choice_byte) };
-        Self { choice }
-    }
+    /// See [`DepositInstructionData::escrow_hint`].
+    #[pack(trailing)]
+    pub escrow_hint: Option<SectorIndex>,
 }
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Pack)]
 #[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
 pub struct FlushEventLogInstructionData {}
 
-impl Pack<1> for FlushEventLogInstructionData {
-    #[inline(always)]
-    fn pack_into_slice(&self, dst: &mut [MaybeUninit<u8>; 1]) {
-        dst[0].write(Self::TAG);
-    }
-
-    #[inline(always)]
-    unsafe fn unpack_unchecked(_instruction_data: &[u8]) -> Self {
-        Self {}
-    }
-}
-
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Pack)]
 #[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
 pub struct InitializeEventAuthorityInstructionData {}
 
-impl Pack<1> for InitializeEventAuthorityInstructionData {
-    #[inline(always)]
-    fn pack_into_slice(&self, dst: &mut [MaybeUninit<u8>; 1]) {
-        dst[0].write(Self::TAG);
-    }
+#[repr(C)]
+#[derive(Clone, Pack)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
+pub struct MigrateDequeInstructionData {}
 
-    #[inline(always)]
-    unsafe fn unpack_unchecked(_instruction_data: &[u8]) -> Self {
-        Self {}
-    }
-}
+#[repr(C)]
+#[derive(Clone, Pack)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
+pub struct CloseTraderRecordInstructionData {}
 
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Pack)]
 #[cfg_attr(not(target_os = "solana"), derive(Debug, Eq, PartialEq))]
 pub struct ResizeEventAuthorityInstructionData {}
 
-impl Pack<1> for ResizeEventAuthorityInstructionData {
-    #[inline(always)]
-    fn pack_into_slice(&self, dst: &mut [MaybeUninit<u8>; 1]) {
-        dst[0].write(Self::TAG);
-    }
-
-    #[inline(always)]
-    unsafe fn unpack_unchecked(_instruction_data: &[u8]) -> Self {
-        Self {}
-    }
-}
-
 pub mod tests {
     #[test]
     pub fn u8_to_market_choice() {
@@ -290,4 +463,142 @@ pub mod tests {
         assert!(MarketChoice::try_from(unsafe { *(data.as_ptr().add(3)) }).is_err());
         assert!(MarketChoice::try_from(unsafe { *(data.as_ptr().add(4)) }).is_err());
     }
+
+    #[test]
+    pub fn derived_instruction_layouts() {
+        use super::*;
+
+        assert_eq!(
+            DepositInstructionData::new(0x0102030405060708, MarketChoice::Quote).pack()[..],
+            [
+                &[InstructionTag::Deposit as u8, 1, 8, 7, 6, 5, 4, 3, 2, 1][..],
+                &[0xff; 4]
+            ]
+            .concat()
+        );
+        assert_eq!(
+            WithdrawInstructionData::new(MarketChoice::Base)
+                .with_escrow_hint(Some(0x01020304))
+                .pack(),
+            [InstructionTag::Withdraw as u8, 0, 4, 3, 2, 1]
+        );
+        assert_eq!(
+            ResizeInstructionData {
+                num_sectors: 0x0102
+            }
+            .pack(),
+            [InstructionTag::Resize as u8, 2, 1]
+        );
+        assert_eq!(
+            InitializeEventHistoryInstructionData { capacity: 3 }.pack(),
+            [InstructionTag::InitializeEventHistory as u8, 3, 0]
+        );
+        assert_eq!(
+            FlushEventLogInstructionData {}.pack(),
+            [InstructionTag::FlushEventLog as u8]
+        );
+        assert_eq!(
+            ReconcileInstructionData {
+                fail_on_deficit: true
+            }
+            .pack(),
+            [InstructionTag::Reconcile as u8, 1]
+        );
+
+        let deposit = DepositInstructionData::new(u64::MAX - 1, MarketChoice::Quote);
+        assert_eq!(DepositInstructionData::unpack(&deposit.pack()), Ok(deposit));
+
+        // Trailing fields can be left off, e.g. by callers built before they were added.
+        let deposit = DepositInstructionData::new(7, MarketChoice::Base).with_escrow_hint(Some(3));
+        let packed = deposit.pack();
+        assert_eq!(DepositInstructionData::unpack(&packed), Ok(deposit.clone()));
+        assert_eq!(
+            DepositInstructionData::unpack(&packed[..10]),
+            Ok(deposit.with_escrow_hint(None))
+        );
+
+        // Trailing bytes are ignored.
+        let defragment = DefragmentInstructionData { max_moves: 9 };
+        let data = [defragment.pack().as_slice(), &[0xff]].concat();
+        assert_eq!(DefragmentInstructionData::unpack(&data), Ok(defragment));
+    }
+
+    #[test]
+    pub fn derived_unpack_rejects_invalid_data() {
+        use super::*;
+
+        let deposit = DepositInstructionData::new(1, MarketChoice::Base).pack();
+        assert_eq!(
+            DepositInstructionData::unpack(&deposit[..9]),
+            Err(DequeError::InvalidPackedData.into())
+        );
+
+        let mut invalid_choice = deposit;
+        invalid_choice[1] = 2;
+        assert_eq!(
+            DepositInstructionData::unpack(&invalid_choice),
+            Err(DequeError::InvalidMarketChoice.into())
+        );
+
+        assert_eq!(
+            ReconcileInstructionData::unpack(&[InstructionTag::Reconcile as u8, 2]),
+            Err(DequeError::InvalidPackedData.into())
+        );
+
+        let mut wrong_tag = deposit;
+        wrong_tag[0] = InstructionTag::Withdraw as u8;
+        assert_eq!(
+            DepositInstructionData::unpack(&wrong_tag),
+            Err(DequeError::InvalidDiscriminant.into())
+        );
+    }
+
+    #[test]
+    pub fn unpack_any_instruction() {
+        use super::*;
+
+        for ixn in [
+            DequeInstruction::Deposit(DepositInstructionData::new(5, MarketChoice::Quote)),
+            DequeInstruction::ResizeEventAuthority(ResizeEventAuthorityInstructionData {}),
+            DequeInstruction::Defragment(DefragmentInstructionData { max_moves: 3 }),
+            DequeInstruction::CloseTraderRecord(CloseTraderRecordInstructionData {}),
+        ] {
+            let unpacked = DequeInstruction::unpack(&ixn.pack()).unwrap();
+            assert_eq!(unpacked.tag() as u8, ixn.tag() as u8);
+            assert_eq!(unpacked.pack(), ixn.pack());
+        }
+
+        assert!(DequeInstruction::unpack(&[]).is_err());
+        assert!(DequeInstruction::unpack(&[InstructionTag::Resize as u8, 1]).is_err());
+        assert!(DequeInstruction::unpack(&[u8::MAX]).is_err());
+    }
+
+    #[test]
+    pub fn label_instruction_accounts() {
+        use super::*;
+
+        let accounts = (0..14).collect::<Vec<u8>>();
+        let labels = InstructionTag::Deposit.label_accounts(&accounts);
+        assert_eq!(labels.len(), accounts.len());
+        assert_eq!(labels[1], ("event_authority", &1));
+        assert_eq!(labels[4], ("deque", &4));
+        assert_eq!(labels[6], ("payer_ata", &6));
+        assert_eq!(labels[11], ("trader_record", &11));
+        assert_eq!(labels[13], ("deque_shard", &13));
+
+        let labels = InstructionTag::FlushEventLog.label_accounts(&accounts[..3]);
+        assert_eq!(
+            labels,
+            vec![
+                ("event_authority", &0),
+                ("instructions_sysvar", &1),
+                ("unknown", &2)
+            ]
+        );
+
+        assert_eq!(InstructionTag::Deposit.account_index("mint"), Some(8));
+        assert_eq!(InstructionTag::FlushEventLog.account_index("mint"), None);
+        // Accounts that aren't unique can't be pointed to by their role alone.
+        assert_eq!(InstructionTag::Resize.account_index("deque_shard"), None);
+    }
 }
@@ -0,0 +1,63 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program,
+};
+
+use crate::{
+    context::deque_shards::DequeShardsContext, log_info, require,
+    seeds::trader::find_trader_address, shared::error::DequeError, state::find_escrow,
+    utils::check_owned_and_writable,
+};
+
+/// Close the trader's record on a market and refund its rent to the trader, once they no longer
+/// hold an escrow on it.
+///
+/// The market's number of traders isn't decremented, so a trader who closes their record and
+/// deposits again is counted again.
+///
+/// The deque is searched for the trader from the front, so every shard of a sharded deque must be
+/// passed after the trader record. None of the deque's accounts need to be writable.
+pub fn process(_program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let trader = next_account_info(accounts_iter)?;
+    let deque_account = next_account_info(accounts_iter)?;
+    let trader_record = next_account_info(accounts_iter)?;
+    let shards = DequeShardsContext::load_read_only(deque_account, accounts_iter.as_slice())?;
+    shards.check_all_shards_loaded()?;
+
+    if !trader.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let (pda, _) = find_trader_address(deque_account.key, trader.key);
+    require!(
+        trader_record.key.as_ref() == pda.as_ref(),
+        DequeError::InvalidTraderRecord,
+        "Expected trader record {}, got {}",
+        pda,
+        trader_record.key
+    )?;
+    check_owned_and_writable(trader_record)?;
+
+    let has_escrow =
+        shards.with_deque(|deque| Ok(find_escrow(deque, trader.key, None)?.is_some()))?;
+    if has_escrow {
+        return Err(DequeError::TraderHasEscrow.into());
+    }
+
+    let lamports = trader_record.lamports();
+    **trader.try_borrow_mut_lamports()? = trader
+        .lamports()
+        .checked_add(lamports)
+        .ok_or(DequeError::ArithmetricError)?;
+    **trader_record.try_borrow_mut_lamports()? = 0;
+    // Hand the record back to the system program, so that a deposit in the same transaction
+    // recreates it instead of finding it still owned by the program.
+    trader_record.assign(&system_program::ID);
+
+    log_info!("Closed trader record, refunded {} lamports", lamports);
+
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    pubkey::Pubkey,
+};
+
+use crate::{context::deque_shards::DequeShardsContext, log_info, shared::error::DequeError};
+
+/// Permissionless crank that moves up to `max_moves` nodes into their logical position. Call it
+/// repeatedly until the deque reports that it's fully defragmented.
+///
+/// Nodes are packed into shard 0 first and then each shard in order, so every shard of a sharded
+/// deque must be passed after the deque account, writable.
+pub fn process(_program_id: &Pubkey, accounts: &[AccountInfo], max_moves: u16) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let deque_account = next_account_info(accounts_iter)?;
+    let shards = DequeShardsContext::load(deque_account, accounts_iter.as_slice())?;
+    shards.check_all_shards_loaded()?;
+
+    let (progress, len) = shards.with_deque(|deque| {
+        let progress = deque.defragment(max_moves).map_err(DequeError::from)?;
+        Ok((progress, deque.len()))
+    })?;
+
+    log_info!(
+        "Defragment moved {} node(s), {}/{} in place, complete: {}",
+        progress.moves,
+        progress.in_place,
+        len,
+        progress.complete
+    );
+
+    Ok(())
+}
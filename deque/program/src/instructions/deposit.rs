@@ -1,15 +1,25 @@
 use solana_program::{
-    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
     pubkey::Pubkey,
+    rent::Rent,
+    system_instruction,
+    sysvar::{clock::Clock, Sysvar},
 };
 
 use crate::{
     context::market_choice::MarketChoiceContext,
     events::{event_emitter::EventEmitter, DepositEventData},
     instruction_enum::MarketChoice,
+    log_debug, require,
+    seeds::trader::find_trader_address,
+    shared::error::DequeError,
     shared::token_utils::vault_transfers::deposit_to_vault,
-    state::{Deque, DequeNode, MarketEscrow},
-    utils::{from_sector_idx_mut, inline_deque_resize},
+    state::{find_escrow, MarketEscrow},
+    trader_seeds_with_bump,
+    utils::{inline_deque_resize, SectorIndex},
 };
 
 pub fn process(
@@ -17,78 +27,126 @@ pub fn process(
     accounts: &[AccountInfo],
     amount_in: u64,
     choice: MarketChoice,
+    escrow_hint: Option<SectorIndex>,
     event_emitter: &mut EventEmitter,
 ) -> ProgramResult {
-    let ctx = MarketChoiceContext::load(accounts, choice)?;
+    let mut ctx = MarketChoiceContext::load(accounts, choice, true)?;
 
     let amount = deposit_to_vault(&ctx, amount_in)?;
+    let clock = Clock::get()?;
 
     let MarketChoiceContext {
         deque_account,
         payer,
         system_program,
         choice,
+        trader_record,
         ..
     } = ctx;
 
     // Try to find the trader in existing nodes.
-    let mut data = deque_account.data.borrow_mut();
-    // The deque's account discriminant is checked in `load`.
-    let mut deque = Deque::from_bytes_unchecked(&mut data)?;
-    let maybe_idx = deque
-        .iter_nodes::<MarketEscrow>()
-        .find(|(node, _)| node.trader.as_ref() == payer.key.as_ref())
-        .map(|(_, idx)| idx);
-    let needs_resize = deque.header.len >= deque.get_capacity();
+    let (maybe_idx, has_free_sector) = ctx.shards.with_deque(|deque| {
+        let maybe_idx = find_escrow(deque, payer.key, escrow_hint)?;
+        Ok((maybe_idx, deque.has_free_sector()))
+    })?;
 
     match maybe_idx {
         // Mutate the node.
-        Some(idx) => {
-            let node = from_sector_idx_mut::<DequeNode<MarketEscrow>>(deque.sectors, idx)?;
-            match choice {
+        Some(idx) => ctx.shards.with_deque(|deque| {
+            ctx.shards.check_writable(&[idx])?;
+            let node = deque.node_mut(idx)?;
+            let amount_in_node = match choice {
                 // Update the base amount in the existing node.
-                MarketChoice::Base => {
-                    node.inner.base = node
-                        .inner
-                        .base
-                        .checked_add(amount)
-                        .ok_or(ProgramError::InvalidArgument)?;
-                }
+                MarketChoice::Base => &mut node.inner.base,
                 // Update the quote amount in the existing node.
-                MarketChoice::Quote => {
-                    node.inner.quote = node
-                        .inner
-                        .quote
-                        .checked_add(amount)
-                        .ok_or(ProgramError::InvalidArgument)?;
-                }
-            }
-        }
+                MarketChoice::Quote => &mut node.inner.quote,
+            };
+            *amount_in_node = amount_in_node
+                .checked_add(amount)
+                .ok_or(DequeError::EscrowAmountOverflow)?;
+            node.inner.touch(clock.slot, clock.unix_timestamp);
+            deque.header.record_deposit(&choice, amount, clock.slot)
+        })?,
         // Push a new node to the front of the deque.
         None => {
-            // drop(data);
-
-            // Resize (grow) the account if there's not enough space.
-            if needs_resize {
-                msg!("Growing account by 1 sector");
-                inline_deque_resize(deque_account, payer, system_program, 1)?;
+            // Grow the deque if there's not enough space.
+            if !has_free_sector {
+                log_debug!("Growing deque by 1 sector");
+                inline_deque_resize(&mut ctx.shards, payer, system_program, 1)?;
             }
-
-            // let mut data = deque_account.data.borrow_mut();
-            // let mut deque = Deque::from_bytes_unchecked(&mut data)?;
+            let first_deposit = create_trader_record(
+                deque_account,
+                payer,
+                system_program,
+                trader_record.ok_or(ProgramError::NotEnoughAccountKeys)?,
+            )?;
 
             let escrow = match choice {
                 MarketChoice::Base => MarketEscrow::new(*payer.key, amount, 0),
                 MarketChoice::Quote => MarketEscrow::new(*payer.key, 0, amount),
-            };
+            }
+            .opened_at(clock.slot, clock.unix_timestamp);
 
-            deque
-                .push_front(escrow)
-                .map_err(|_| ProgramError::InvalidAccountData)?;
+            ctx.shards.with_deque(|deque| {
+                // The new escrow takes the free stack's head and links to the current front.
+                let links = deque.links();
+                ctx.shards
+                    .check_writable(&[links.free_head, links.deque_head])?;
+                deque.push_front(escrow)?;
+                if first_deposit {
+                    deque.header.record_new_trader();
+                }
+                deque.header.record_deposit(&choice, amount, clock.slot)
+            })?;
         }
     }
 
-    event_emitter.add_event(DepositEventData::new(ctx.payer.key, amount, choice))?;
+    event_emitter.add_event(DepositEventData::new(
+        ctx.payer.key,
+        amount,
+        choice,
+        clock.slot,
+    ))?;
 
     Ok(())
 }
+
+/// Create the trader's record on the market if this is their first deposit. Returns whether it was.
+///
+/// The record holds no data, its only purpose is to exist once the trader has been counted. Anyone
+/// can send lamports to the PDA before it's created, so it's funded up to the rent exemption and
+/// assigned to the program rather than created outright.
+fn create_trader_record<'a, 'info>(
+    market: &'a AccountInfo<'info>,
+    payer: &'a AccountInfo<'info>,
+    system_program: &'a AccountInfo<'info>,
+    trader_record: &'a AccountInfo<'info>,
+) -> Result<bool, ProgramError> {
+    let (pda, bump) = find_trader_address(market.key, payer.key);
+    require!(
+        trader_record.key.as_ref() == pda.as_ref(),
+        DequeError::InvalidTraderRecord,
+        "Expected trader record {}, got {}",
+        pda,
+        trader_record.key
+    )?;
+    if trader_record.owner.as_ref() == crate::ID.as_ref() {
+        return Ok(false);
+    }
+
+    let lamports = Rent::get()?
+        .minimum_balance(0)
+        .saturating_sub(trader_record.lamports());
+    if lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, trader_record.key, lamports),
+            &[payer.clone(), trader_record.clone(), system_program.clone()],
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::assign(trader_record.key, &crate::ID),
+        &[trader_record.clone(), system_program.clone()],
+        trader_seeds_with_bump!(market.key, payer.key, bump),
+    )?;
+    Ok(true)
+}
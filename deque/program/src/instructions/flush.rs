@@ -1,26 +1,64 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
-    msg,
-    program_error::ProgramError,
+    instruction::{get_stack_height, TRANSACTION_LEVEL_STACK_HEIGHT},
+    sysvar::instructions::{load_current_index_checked, load_instruction_at_checked},
 };
 
-use crate::{require, seeds};
+use crate::{
+    events::validate_event_payload,
+    log_debug, require,
+    shared::error::DequeError,
+    validation::{
+        event_authority::EventAuthorityInfo, instructions_sysvar::InstructionsSysvarInfo,
+    },
+};
 
 /// This doesn't actually need to do anything- it merely flushes the passed instruction data.
-pub fn process(accounts: &[AccountInfo]) -> ProgramResult {
-    let authority = next_account_info(&mut accounts.iter())?;
+/// Since indexers treat these inner instructions as canonical, it rejects any flush that isn't
+/// a well-formed event log emitted by this program through a CPI.
+pub fn process(accounts: &[AccountInfo], instruction_data: &[u8]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let authority = next_account_info(accounts_iter)?;
+    let instructions_sysvar =
+        InstructionsSysvarInfo::new_checked(next_account_info(accounts_iter)?)?;
+
+    // Flushing is never a top-level instruction.
+    let stack_height = get_stack_height();
     require!(
-        authority.is_signer,
-        ProgramError::MissingRequiredSignature,
-        "Event authority must be a signer"
+        stack_height > TRANSACTION_LEVEL_STACK_HEIGHT,
+        DequeError::FlushNotInvokedBySelf,
+        "Flush must be invoked through a CPI"
     )?;
+
+    // The instructions sysvar only knows the top-level instruction, which is the direct caller
+    // when flushing from the first level of CPIs. Deeper than that, only the program could've
+    // signed for the event authority below.
+    if stack_height == TRANSACTION_LEVEL_STACK_HEIGHT + 1 {
+        let current_index = load_current_index_checked(instructions_sysvar.info)?;
+        let caller = load_instruction_at_checked(current_index as usize, instructions_sysvar.info)?;
+        require!(
+            caller.program_id.as_ref() == crate::ID.as_ref(),
+            DequeError::FlushNotInvokedBySelf,
+            "Flush must be invoked by the deque program"
+        )?;
+    }
+
+    let market = instruction_data
+        .get(1..)
+        .and_then(|payload| validate_event_payload(payload).ok())
+        .ok_or(DequeError::InvalidEventPayload)?;
+
+    // Only this program can sign for the event authority PDA, and checking it against the header's
+    // market ties the events to the market they were emitted for.
     require!(
-        authority.key.as_ref() == seeds::event_authority::ID.as_ref(),
-        ProgramError::IncorrectAuthority,
-        "Invalid event authority"
+        authority.is_signer,
+        DequeError::EventAuthorityNotSigner,
+        "Event authority must be a signer"
     )?;
-    msg!("Flushing! 🚽");
+    EventAuthorityInfo::new_checked(authority, &market)?;
+
+    log_debug!("Flushing the event log");
 
     Ok(())
 }
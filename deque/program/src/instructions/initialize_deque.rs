@@ -1,18 +1,19 @@
 use solana_program::{
-    account_info::AccountInfo, entrypoint::ProgramResult, msg, program::invoke_signed,
-    pubkey::Pubkey, rent::Rent, system_instruction, sysvar::Sysvar,
+    account_info::AccountInfo, entrypoint::ProgramResult, program::invoke_signed, pubkey::Pubkey,
+    rent::Rent, system_instruction, sysvar::Sysvar,
 };
 
 use crate::{
     context::initialize_deque::InitializeDequeContext,
-    market_seeds_with_bump,
+    log_info, market_seeds_with_bump,
+    shared::error::DequeError,
     shared::token_utils::create_vault::create_token_vault,
-    state::{Deque, DEQUE_HEADER_SIZE},
+    state::{Deque, DequeHeader, DEQUE_HEADER_SIZE},
     utils::SECTOR_SIZE,
 };
 
 pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], num_sectors: u16) -> ProgramResult {
-    msg!("Initialize deque with {:?} sector(s)", num_sectors);
+    log_info!("Initialize deque with {:?} sector(s)", num_sectors);
 
     let ctx = InitializeDequeContext::load(accounts)?;
 
@@ -53,16 +54,16 @@ pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], num_sectors: u16)
 
     {
         let mut data = ctx.deque_account.try_borrow_mut_data()?;
-        Deque::init(
-            &mut data,
-            num_sectors,
+        let header = DequeHeader::init(
             ctx.market_bump,
             ctx.base_mint.info.key,
             ctx.quote_mint.info.key,
-        )?;
+        );
+        let deque = Deque::init(&mut data, header).map_err(DequeError::from)?;
+        debug_assert_eq!(deque.capacity(), num_sectors as u32);
     }
 
-    msg!(
+    log_info!(
         "Deque initialized successfully (space = {:?} bytes).",
         account_space
     );
@@ -1,18 +1,18 @@
 use solana_program::{
-    account_info::AccountInfo, entrypoint::ProgramResult, msg, program::invoke_signed,
-    pubkey::Pubkey, rent::Rent, system_instruction, sysvar::Sysvar,
+    account_info::AccountInfo, entrypoint::ProgramResult, program::invoke_signed, pubkey::Pubkey,
+    rent::Rent, system_instruction, sysvar::Sysvar,
 };
 
 use crate::{
     context::event_authority_ctx::EventAuthorityContext,
-    seeds::event_authority,
+    event_authority_seeds_with_bump, log_info,
     shared::error::DequeError,
     state::{EphemeralEventLog, EVENT_DATA_ACCOUNT_SIZE},
 };
 
 /// This doesn't actually need to do anything- it merely flushes the passed instruction data.
 pub fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-    msg!("Initialize event authority!");
+    log_info!("Initialize event authority!");
     let ctx = EventAuthorityContext::load(accounts)?;
 
     // Skip initialized if it's already been done.
@@ -36,7 +36,7 @@ pub fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
             ctx.event_authority.info.clone(),
             ctx.system_program.info.clone(),
         ],
-        &[event_authority::SEEDS],
+        event_authority_seeds_with_bump!(ctx.market.key, ctx.event_authority.bump),
     )?;
 
     let mut data = ctx
@@ -49,7 +49,7 @@ pub fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
     // It'd be easy to call, would reduce boilerplate, and the lifetimes would automatically
     // protect against double borrows (I believe).
 
-    EphemeralEventLog::init(&mut data)?;
+    EphemeralEventLog::init(&mut data, ctx.event_authority.bump)?;
 
     Ok(())
 }
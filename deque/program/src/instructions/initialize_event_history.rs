@@ -0,0 +1,60 @@
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, program::invoke_signed, pubkey::Pubkey,
+    rent::Rent, system_instruction, sysvar::Sysvar,
+};
+
+use crate::{
+    context::event_history_ctx::EventHistoryContext,
+    event_history_seeds_with_bump, log_info, require,
+    shared::error::DequeError,
+    state::{Deque, EventHistory},
+};
+
+/// Create a market's event history with room for `capacity` events. Once it exists, it must be
+/// passed to every event emitting instruction on the market in place of the program ID placeholder.
+pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], capacity: u16) -> ProgramResult {
+    log_info!("Initialize event history!");
+    let ctx = EventHistoryContext::load(accounts)?;
+
+    require!(
+        ctx.event_history.data_is_empty(),
+        DequeError::InvalidEventHistory,
+        "Event history is already initialized"
+    )?;
+
+    let account_size = EventHistory::account_size(capacity);
+    require!(
+        (1..=EventHistory::max_capacity()).contains(&capacity),
+        DequeError::InvalidEventHistory,
+        "Capacity must be between 1 and {}, got: {}",
+        EventHistory::max_capacity(),
+        capacity
+    )?;
+
+    invoke_signed(
+        &system_instruction::create_account(
+            ctx.payer.key,
+            ctx.event_history.key,
+            Rent::get()?.minimum_balance(account_size),
+            account_size as u64,
+            program_id,
+        ),
+        &[
+            ctx.payer.clone(),
+            ctx.event_history.clone(),
+            ctx.system_program.info.clone(),
+        ],
+        event_history_seeds_with_bump!(ctx.market.key, ctx.event_history_bump),
+    )?;
+
+    let mut data = ctx.event_history.try_borrow_mut_data()?;
+    EventHistory::init(&mut data, ctx.market.key, ctx.event_history_bump)?;
+
+    let mut market_data = ctx.market.try_borrow_mut_data()?;
+    Deque::from_bytes(&mut market_data)
+        .map_err(DequeError::from)?
+        .header
+        .has_event_history = 1;
+
+    Ok(())
+}
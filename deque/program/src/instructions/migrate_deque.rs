@@ -0,0 +1,294 @@
+use core::ops::Range;
+
+use bytemuck::pod_read_unaligned;
+use slab_deque::DequeLinks;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use static_assertions::const_assert;
+
+use crate::{
+    log_info, require,
+    seeds::event_history::find_event_history_address,
+    shared::error::DequeError,
+    state::{
+        DequeHeader, DequeLayout, EventHistory, EventHistoryHeader, MarketEscrow, DEQUE_LAYOUTS,
+        DEQUE_VERSION, DEQUE_VERSION_OFFSET, EVENT_HISTORY_HEADER_SIZE, EVENT_HISTORY_VERSION,
+        EVENT_HISTORY_VERSION_OFFSET, EVENT_RECORD_SIZE, EVENT_RECORD_SIZES,
+    },
+    utils::{check_owned_and_writable, from_slab_bytes_mut, fund_then_resize, SectorIndex},
+    validation::system_program::SystemProgramInfo,
+};
+
+/// How many sectors are rewritten per call, to keep each call well within the default compute
+/// budget however large the deque is.
+pub const MIGRATE_SECTORS_PER_CALL: usize = 1024;
+
+/// Where the old header records the migration's progress, in padding of the baseline layout:
+/// how many sectors the deque held before it started, plus one so that 0 means not recorded yet,
+/// and how many of them have been rewritten since. Both are 3-byte little-endian counts, which
+/// hold the sector count of any account up to the 10 MiB limit.
+const ORIGINAL_SECTORS: Range<usize> = 90..93;
+const REWRITTEN_SECTORS: Range<usize> = 93..96;
+
+/// A node's links follow its escrow, in deque and free stack nodes alike.
+const LINKS_SIZE: usize = 2 * size_of::<SectorIndex>();
+
+// The escrows' running totals are kept between the sectors still to rewrite and the ones already
+// rewritten, which are always at least as far apart as the headers' sizes differ.
+const_assert!(
+    DEQUE_LAYOUTS[DEQUE_VERSION as usize].header_size
+        >= DEQUE_LAYOUTS[0].header_size + 2 * size_of::<u64>()
+);
+
+/// Rewrites a deque of an older version in the current layout. The fields the current layout adds
+/// are zeroed. Anyone can migrate a deque by paying for the rent of the extra space.
+///
+/// A large deque takes several calls, each doing a bounded amount of work:
+/// - An account can only grow by [`MAX_PERMITTED_DATA_INCREASE`] bytes per instruction, so the
+///   deque is grown first, without changing what it holds.
+/// - Then at most [`MIGRATE_SECTORS_PER_CALL`] sectors are rewritten per call, from the back, with
+///   the progress recorded in the old header.
+/// - The call that rewrites the last sector rebuilds the header and bumps the version.
+///
+/// Until then the deque can't be loaded, and once it has the current version calling this does
+/// nothing. Deques in the baseline layout have no shards, so only the deque account is rewritten.
+///
+/// The header's running totals are summed from the escrows as they're rewritten. Its peak length
+/// and number of unique traders start at the deque's length, since a trader holds at most one
+/// escrow. That's only a lower bound: traders who emptied their escrow before the migration can't
+/// be counted, and since the ones holding an escrow have no trader record yet, one that empties
+/// their escrow and deposits again is counted a second time.
+///
+/// An initialized event history of an older version is migrated by the first call, whatever the
+/// deque's version, see `migrate_event_history`.
+///
+/// Accounts: payer, deque, system program, the deque's event history PDA (initialized or not).
+pub fn process(_program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let payer = next_account_info(accounts_iter)?;
+    let deque_account = next_account_info(accounts_iter)?;
+    let system_program = SystemProgramInfo::new_checked(next_account_info(accounts_iter)?)?;
+    let event_history = next_account_info(accounts_iter)?;
+
+    check_owned_and_writable(deque_account)?;
+    require!(
+        event_history.key.as_ref() == find_event_history_address(deque_account.key).0.as_ref(),
+        DequeError::InvalidEventHistory,
+        "Event history isn't the market's event history PDA"
+    )?;
+    migrate_event_history(payer, event_history, system_program.info)?;
+
+    let version = match DequeHeader::check_version(&deque_account.try_borrow_data()?) {
+        Ok(()) => {
+            log_info!("The deque already has version {}", DEQUE_VERSION);
+            return Ok(());
+        }
+        Err(DequeError::DequeNeedsMigration) => {
+            deque_account.try_borrow_data()?[DEQUE_VERSION_OFFSET]
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let from = DEQUE_LAYOUTS[version as usize];
+    let to = DEQUE_LAYOUTS[DEQUE_VERSION as usize];
+
+    let num_sectors = original_num_sectors(&mut deque_account.try_borrow_mut_data()?, from)?;
+    let size = to.header_size + num_sectors * to.sector_size;
+    let missing = size.saturating_sub(deque_account.data_len());
+    if missing > 0 {
+        fund_then_resize(
+            deque_account,
+            payer,
+            system_program.info,
+            missing.min(MAX_PERMITTED_DATA_INCREASE),
+        )?;
+        if deque_account.data_len() < size {
+            log_info!("Grew the deque, call again to keep migrating");
+            return Ok(());
+        }
+    }
+
+    let mut data = deque_account.try_borrow_mut_data()?;
+    let rewritten = read_count(&data[REWRITTEN_SECTORS]);
+    let remaining = num_sectors
+        .checked_sub(rewritten)
+        .ok_or(DequeError::MalformedSlab)?;
+    let (mut total_base, mut total_quote) = match rewritten {
+        0 => (0, 0),
+        _ => read_totals(&data, from.header_size + remaining * from.sector_size),
+    };
+    let batch = remaining.min(MIGRATE_SECTORS_PER_CALL);
+    let (base, quote) = widen_sectors(&mut data, remaining - batch..remaining, from, to)?;
+    total_base = total_base
+        .checked_add(base)
+        .ok_or(DequeError::EscrowAmountOverflow)?;
+    total_quote = total_quote
+        .checked_add(quote)
+        .ok_or(DequeError::EscrowAmountOverflow)?;
+    if batch < remaining {
+        let offset = from.header_size + (remaining - batch) * from.sector_size;
+        data[offset..offset + 8].copy_from_slice(&total_base.to_le_bytes());
+        data[offset + 8..offset + 16].copy_from_slice(&total_quote.to_le_bytes());
+        data[REWRITTEN_SECTORS].copy_from_slice(&count_bytes(rewritten + batch));
+        log_info!(
+            "Rewrote {} of {} sectors, call again to keep migrating",
+            rewritten + batch,
+            num_sectors
+        );
+        return Ok(());
+    }
+
+    let links: DequeLinks = pod_read_unaligned(&data[8..24]);
+    let mut header = DequeHeader::init(
+        data[core::mem::offset_of!(DequeHeader, deque_bump)],
+        &pod_read_unaligned(&data[24..56]),
+        &pod_read_unaligned(&data[56..88]),
+    );
+    header.links = links;
+    header.has_event_history = (event_history.owner.as_ref() == crate::ID.as_ref()
+        && !event_history.data_is_empty()) as u8;
+    header.total_base = total_base;
+    header.total_quote = total_quote;
+    header.unique_traders = links.len as u64;
+    header.peak_len = links.len;
+    *from_slab_bytes_mut::<DequeHeader>(&mut data, 0).map_err(DequeError::from)? = header;
+
+    log_info!(
+        "Migrated the deque from version {} to {}",
+        version,
+        DEQUE_VERSION
+    );
+    Ok(())
+}
+
+/// Rewrites an initialized event history of an older version in the current layout, keeping every
+/// record and its sequence number. Records only ever grow their packed event's zero padding, so the
+/// events they hold still decode. The history was created in a single instruction and only grows by
+/// a fraction of its size, so it's always grown and rewritten in one call.
+fn migrate_event_history<'a, 'info>(
+    payer: &'a AccountInfo<'info>,
+    event_history: &'a AccountInfo<'info>,
+    system_program: &'a AccountInfo<'info>,
+) -> ProgramResult {
+    if event_history.owner.as_ref() != crate::ID.as_ref() || event_history.data_is_empty() {
+        return Ok(());
+    }
+    let version = match EventHistory::check_version(&event_history.try_borrow_data()?) {
+        Ok(()) => return Ok(()),
+        Err(DequeError::EventHistoryNeedsMigration) => {
+            event_history.try_borrow_data()?[EVENT_HISTORY_VERSION_OFFSET]
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let old_record_size = EVENT_RECORD_SIZES[version as usize];
+    let capacity = pod_read_unaligned::<EventHistoryHeader>(
+        &event_history.try_borrow_data()?[..EVENT_HISTORY_HEADER_SIZE],
+    )
+    .capacity as usize;
+    let size = EVENT_HISTORY_HEADER_SIZE + capacity * EVENT_RECORD_SIZE;
+    fund_then_resize(
+        event_history,
+        payer,
+        system_program,
+        size.saturating_sub(event_history.data_len()),
+    )?;
+
+    let mut data = event_history.try_borrow_mut_data()?;
+    let records = &mut data[EVENT_HISTORY_HEADER_SIZE..];
+    // Like sectors, records only move further into the account, so they're moved back to front.
+    for i in (0..capacity).rev() {
+        let (src, dst) = (i * old_record_size, i * EVENT_RECORD_SIZE);
+        records.copy_within(src..src + old_record_size, dst);
+        records[dst + old_record_size..dst + EVENT_RECORD_SIZE].fill(0);
+    }
+    data[EVENT_HISTORY_VERSION_OFFSET] = EVENT_HISTORY_VERSION;
+
+    log_info!(
+        "Migrated the event history from version {} to {}",
+        version,
+        EVENT_HISTORY_VERSION
+    );
+    Ok(())
+}
+
+fn read_count(bytes: &[u8]) -> usize {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) as usize
+}
+
+fn count_bytes(count: usize) -> [u8; 3] {
+    let bytes = (count as u32).to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+/// The running totals of the escrows rewritten so far, kept at `offset` in the gap that opens up
+/// between the sectors still to rewrite and the ones already rewritten.
+fn read_totals(data: &[u8], offset: usize) -> (u64, u64) {
+    (
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap()),
+        u64::from_le_bytes(data[offset + 8..offset + 16].try_into().unwrap()),
+    )
+}
+
+/// The number of sectors the deque held in the old layout, recorded the first time it's read.
+fn original_num_sectors(data: &mut [u8], from: DequeLayout) -> Result<usize, ProgramError> {
+    match read_count(&data[ORIGINAL_SECTORS]) {
+        0 => {
+            let num_sectors = data
+                .len()
+                .checked_sub(from.header_size)
+                .ok_or(DequeError::DequeAccountUnallocated)?
+                / from.sector_size;
+            data[ORIGINAL_SECTORS].copy_from_slice(&count_bytes(num_sectors + 1));
+            Ok(num_sectors)
+        }
+        recorded => Ok(recorded - 1),
+    }
+}
+
+/// Moves the `sectors` from the `from` layout to the `to` layout, zeroing the bytes the new layout
+/// adds to the escrow. Sectors only ever move further into the account, so they're moved back to
+/// front to never overwrite one that hasn't moved yet.
+///
+/// Returns the sums of the escrows' base and quote amounts. Free sectors have their escrow zeroed,
+/// so they add nothing.
+fn widen_sectors(
+    data: &mut [u8],
+    sectors: Range<usize>,
+    from: DequeLayout,
+    to: DequeLayout,
+) -> Result<(u64, u64), ProgramError> {
+    require!(
+        data.len() >= to.header_size + sectors.end * to.sector_size,
+        DequeError::InsufficientAccountSpace
+    )?;
+    let old_escrow_size = from.sector_size - LINKS_SIZE;
+    let new_escrow_size = to.sector_size - LINKS_SIZE;
+    let amount = |escrow: &[u8], offset: usize| {
+        u64::from_le_bytes(escrow[offset..offset + 8].try_into().unwrap())
+    };
+
+    let (mut base, mut quote) = (0u64, 0u64);
+    for i in sectors.rev() {
+        let src = from.header_size + i * from.sector_size;
+        let dst = to.header_size + i * to.sector_size;
+        let escrow = &data[src..src + old_escrow_size];
+        base = base
+            .checked_add(amount(escrow, core::mem::offset_of!(MarketEscrow, base)))
+            .ok_or(DequeError::EscrowAmountOverflow)?;
+        quote = quote
+            .checked_add(amount(escrow, core::mem::offset_of!(MarketEscrow, quote)))
+            .ok_or(DequeError::EscrowAmountOverflow)?;
+
+        // The links move the furthest, so moving them first never overwrites the escrow.
+        data.copy_within(
+            src + old_escrow_size..src + from.sector_size,
+            dst + new_escrow_size,
+        );
+        data.copy_within(src..src + old_escrow_size, dst);
+        data[dst + old_escrow_size..dst + new_escrow_size].fill(0);
+    }
+    Ok((base, quote))
+}
@@ -1,7 +1,12 @@
+pub mod close_trader_record;
+pub mod defragment;
 pub mod deposit;
 pub mod flush;
 pub mod initialize_deque;
 pub mod initialize_event_authority;
+pub mod initialize_event_history;
+pub mod migrate_deque;
+pub mod reconcile;
 pub mod resize;
 pub mod resize_event_authority;
 pub mod withdraw;
@@ -0,0 +1,65 @@
+use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, pubkey::Pubkey};
+
+use crate::{
+    context::reconcile::ReconcileContext,
+    events::{event_emitter::EventEmitter, ReconcileEventData},
+    instruction_enum::MarketChoice,
+    log_info,
+    shared::error::DequeError,
+};
+
+/// Permissionless, read-only check that each vault holds at least what the deque's escrows add up
+/// to. Emits one event per side with the sums, the header's running totals and the vault balances.
+pub fn process(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    fail_on_deficit: bool,
+    event_emitter: &mut EventEmitter,
+) -> ProgramResult {
+    let ctx = ReconcileContext::load(accounts)?;
+
+    let (escrowed, recorded) = ctx.shards.with_deque(|deque| {
+        // Walk the links explicitly rather than with `iter_indices`, which stops at the first
+        // sector in a shard that wasn't passed instead of failing.
+        let (mut base, mut quote) = (0u64, 0u64);
+        let mut idx = deque.links().deque_head;
+        for _ in 0..deque.len() {
+            let node = deque.node(idx)?;
+            base = base
+                .checked_add(node.inner.base)
+                .ok_or(DequeError::ArithmetricError)?;
+            quote = quote
+                .checked_add(node.inner.quote)
+                .ok_or(DequeError::ArithmetricError)?;
+            idx = node.next;
+        }
+        let header = &deque.header;
+        Ok(([base, quote], [header.total_base, header.total_quote]))
+    })?;
+
+    let mut has_deficit = false;
+    for (i, (side, vault)) in [
+        (MarketChoice::Base, &ctx.vault_base_ata),
+        (MarketChoice::Quote, &ctx.vault_quote_ata),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let event = ReconcileEventData::new(side, escrowed[i], recorded[i], vault.get_balance());
+        log_info!(
+            "Reconciled side {}: escrowed {}, recorded {}, vault {}",
+            i,
+            event.escrowed,
+            event.recorded,
+            event.vault_balance
+        );
+        has_deficit |= event.deficit > 0;
+        event_emitter.add_event(event)?;
+    }
+
+    if fail_on_deficit && has_deficit {
+        return Err(DequeError::VaultDeficit.into());
+    }
+
+    Ok(())
+}
@@ -1,21 +1,23 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
-    msg,
     pubkey::Pubkey,
 };
 
-use crate::utils::inline_deque_resize;
+use crate::{context::deque_shards::DequeShardsContext, log_info, utils::inline_deque_resize};
 
+/// Accounts: payer, deque, system program, then the deque's last shard if it has more than one,
+/// and the next shard PDA if the last shard can't fit `num_sectors` more sectors.
 pub fn process(_program_id: &Pubkey, accounts: &[AccountInfo], num_sectors: u16) -> ProgramResult {
-    msg!("Trying to add {} sectors.", num_sectors);
+    log_info!("Trying to add {} sectors.", num_sectors);
 
     let accounts_iter = &mut accounts.iter();
     let payer_account = next_account_info(accounts_iter)?;
     let deque_account = next_account_info(accounts_iter)?;
     let system_program = next_account_info(accounts_iter)?;
+    let mut shards = DequeShardsContext::load(deque_account, accounts_iter.as_slice())?;
 
-    inline_deque_resize(deque_account, payer_account, system_program, num_sectors)?;
+    inline_deque_resize(&mut shards, payer_account, system_program, num_sectors)?;
 
     Ok(())
 }
@@ -1,77 +1,87 @@
-use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, msg, pubkey::Pubkey};
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    pubkey::Pubkey,
+    sysvar::{clock::Clock, Sysvar},
+};
 
 use crate::{
     context::market_choice::MarketChoiceContext,
     events::{event_emitter::EventEmitter, WithdrawEventData},
     instruction_enum::MarketChoice,
+    log_debug, log_info,
     shared::{error::DequeError, token_utils::vault_transfers::withdraw_from_vault},
-    state::{Deque, DequeNode, MarketEscrow},
-    utils::from_sector_idx_mut,
+    state::find_escrow,
+    utils::SectorIndex,
 };
 
 pub fn process(
     _program_id: &Pubkey,
     accounts: &[AccountInfo],
     choice: MarketChoice,
+    escrow_hint: Option<SectorIndex>,
     event_emitter: &mut EventEmitter,
 ) -> ProgramResult {
-    let ctx = MarketChoiceContext::load(accounts, choice)?;
-
-    let MarketChoiceContext {
-        deque_account,
-        payer,
-        ..
-    } = ctx;
+    let ctx = MarketChoiceContext::load(accounts, choice, false)?;
 
-    let mut data = deque_account.data.borrow_mut();
-    // Deque discriminant is checked in `load`.
-    let deque = Deque::from_bytes_unchecked(&mut data)?;
+    let payer = ctx.payer;
 
     // Try to find a node with the payer.
-    let escrow_and_idx = deque
-        .iter_nodes::<MarketEscrow>()
-        .find(|(node, _)| node.trader.as_ref() == payer.key.as_ref())
-        .map(|(node, idx)| (*node, idx));
-
-    // Drop the deque account data ref so it's possible to call transfer.
-    drop(data);
+    let escrow_and_idx =
+        ctx.shards
+            .with_deque(|deque| match find_escrow(deque, payer.key, escrow_hint)? {
+                Some(idx) => Ok(Some((*deque.node(idx)?, idx))),
+                None => Ok(None),
+            })?;
 
-    let amount = match escrow_and_idx {
-        Some((escrow, idx)) => {
+    // The deque account data is no longer borrowed, so it's possible to call transfer.
+    let (amount, slot) = match escrow_and_idx {
+        Some((node, idx)) => {
+            let escrow = node.inner;
             let amount = escrow.amount_from_choice(&ctx.choice);
 
             if amount > 0 {
                 withdraw_from_vault(&ctx, amount)?;
             }
+            let clock = Clock::get()?;
 
-            let mut data = deque_account.data.borrow_mut();
-            let mut deque = Deque::from_bytes_unchecked(&mut data)?;
-
-            // Remove the node from the deque if the trader has no coins in either token.
-            if escrow.amount_of_opposite_choice(&ctx.choice) == 0 {
-                msg!("Both amounts are 0. Removing node from the deque!");
+            ctx.shards.with_deque(|deque| {
+                // Remove the node from the deque if the trader has no coins in either token.
+                if escrow.amount_of_opposite_choice(&ctx.choice) == 0 {
+                    // Unlinking the node also rewrites its neighbors' links.
+                    ctx.shards.check_writable(&[idx, node.prev, node.next])?;
+                    log_debug!("Both amounts are 0. Removing node from the deque!");
+                    deque.remove_at_sector_idx(idx)?;
+                } else {
+                    // Otherwise, just zero out the one that was just withdrawn.
+                    log_debug!("Zeroing out the token that was withdrawn.");
+                    ctx.shards.check_writable(&[idx])?;
+                    let node = deque.node_mut(idx)?;
+                    match choice {
+                        MarketChoice::Base => node.inner.base = 0,
+                        MarketChoice::Quote => node.inner.quote = 0,
+                    };
+                    node.inner.touch(clock.slot, clock.unix_timestamp);
+                }
                 deque
-                    .remove_at_sector_idx::<MarketEscrow>(idx)
-                    .expect("The deque node sector index was just found and should exist");
-            } else {
-                // Otherwise, just zero out the one that was just withdrawn.
-                msg!("Zeroing out the token that was withdrawn.");
-                let node = from_sector_idx_mut::<DequeNode<MarketEscrow>>(deque.sectors, idx)?;
-                match choice {
-                    MarketChoice::Base => node.inner.base = 0,
-                    MarketChoice::Quote => node.inner.quote = 0,
-                };
-            }
+                    .header
+                    .record_withdrawal(&ctx.choice, amount, clock.slot)
+            })?;
 
-            msg!("Withdrawing {} coins", amount);
-            amount
+            log_info!("Withdrawing {} coins", amount);
+            (amount, clock.slot)
         }
         None => {
             return Err(DequeError::NoActiveEscrow.into());
         }
     };
 
-    event_emitter.add_event(WithdrawEventData::new(ctx.payer.key, amount, ctx.choice))?;
+    event_emitter.add_event(WithdrawEventData::new(
+        ctx.payer.key,
+        amount,
+        ctx.choice,
+        slot,
+    ))?;
 
     Ok(())
 }
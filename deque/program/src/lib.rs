@@ -8,6 +8,7 @@ pub mod context;
 pub mod events;
 pub mod instruction_enum;
 pub mod instructions;
+pub mod logging;
 pub mod macros;
 pub mod pack;
 pub mod processor;
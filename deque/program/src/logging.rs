@@ -0,0 +1,47 @@
+//! Log macros that are compiled out unless the program is built with a log level feature:
+//!
+//! - `log-info`: high level messages about what each instruction did.
+//! - `log-debug`: everything in `log-info`, plus verbose internals and a `file:line` message for
+//!   every failed `require!`.
+//!
+//! Without either feature nothing is logged with `msg!` at all, and failures are only described by
+//! the [`crate::shared::error_context::ErrorContext`] record.
+//!
+//! The arguments are always type checked but only evaluated when the level is enabled. Since the
+//! features are checked with `cfg!` where the macros are expanded, they're only meant to be used
+//! inside this crate.
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => {
+        if cfg!(feature = "log-info") {
+            solana_program::msg!($($arg)+);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => {
+        if cfg!(feature = "log-debug") {
+            solana_program::msg!($($arg)+);
+        }
+    };
+}
+
+pub mod tests {
+    #[test]
+    pub fn disabled_log_levels_skip_their_arguments() {
+        let (mut info, mut debug) = (false, false);
+        crate::log_info!("{}", {
+            info = true;
+            "info"
+        });
+        crate::log_debug!("{}", {
+            debug = true;
+            "debug"
+        });
+        assert_eq!(info, cfg!(feature = "log-info"));
+        assert_eq!(debug, cfg!(feature = "log-debug"));
+    }
+}
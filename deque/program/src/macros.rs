@@ -21,6 +21,48 @@ macro_rules! market_seeds_with_bump {
     };
 }
 
+#[macro_export]
+macro_rules! event_authority_seeds {
+    ( $market:expr ) => {
+        &[
+            $crate::seeds::event_authority::EVENT_AUTHORITY_SEED_STR,
+            $market.as_ref(),
+        ]
+    };
+}
+
+#[macro_export]
+macro_rules! event_authority_seeds_with_bump {
+    ( $market:expr, $bump:expr ) => {
+        &[&[
+            $crate::seeds::event_authority::EVENT_AUTHORITY_SEED_STR,
+            $market.as_ref(),
+            &[$bump],
+        ]]
+    };
+}
+
+#[macro_export]
+macro_rules! event_history_seeds {
+    ( $market:expr ) => {
+        &[
+            $crate::seeds::event_history::EVENT_HISTORY_SEED_STR,
+            $market.as_ref(),
+        ]
+    };
+}
+
+#[macro_export]
+macro_rules! event_history_seeds_with_bump {
+    ( $market:expr, $bump:expr ) => {
+        &[&[
+            $crate::seeds::event_history::EVENT_HISTORY_SEED_STR,
+            $market.as_ref(),
+            &[$bump],
+        ]]
+    };
+}
+
 #[macro_export]
 macro_rules! impl_tags {
     ( $( $ty:ty => $tag:path ),+ $(,)? ) => {
@@ -31,3 +73,49 @@ macro_rules! impl_tags {
         )+
     };
 }
+
+#[macro_export]
+macro_rules! shard_seeds {
+    ( $deque:expr, $shard:expr ) => {
+        &[
+            $deque.as_ref(),
+            $crate::seeds::shard::SHARD_SEED_STR,
+            &$shard.to_le_bytes(),
+        ]
+    };
+}
+
+#[macro_export]
+macro_rules! shard_seeds_with_bump {
+    ( $deque:expr, $shard:expr, $bump:expr ) => {
+        &[&[
+            $deque.as_ref(),
+            $crate::seeds::shard::SHARD_SEED_STR,
+            &$shard.to_le_bytes(),
+            &[$bump],
+        ]]
+    };
+}
+
+#[macro_export]
+macro_rules! trader_seeds {
+    ( $market:expr, $trader:expr ) => {
+        &[
+            $crate::seeds::trader::TRADER_SEED_STR,
+            $market.as_ref(),
+            $trader.as_ref(),
+        ]
+    };
+}
+
+#[macro_export]
+macro_rules! trader_seeds_with_bump {
+    ( $market:expr, $trader:expr, $bump:expr ) => {
+        &[&[
+            $crate::seeds::trader::TRADER_SEED_STR,
+            $market.as_ref(),
+            $trader.as_ref(),
+            &[$bump],
+        ]]
+    };
+}
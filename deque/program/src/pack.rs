@@ -1,14 +1,21 @@
 use std::mem::MaybeUninit;
 
-use solana_program::{entrypoint::ProgramResult, program_error::ProgramError};
+use solana_program::{entrypoint::ProgramResult, program_error::ProgramError, pubkey::Pubkey};
 
 use crate::{
+    instruction_enum::{InstructionTag, MarketChoice},
     require,
     shared::error::DequeError,
     utils::{write_bytes, UNINIT_BYTE},
 };
 
+pub use deque_pack_derive::Pack;
+
 pub trait Pack<const LEN: usize>: Sized {
+    /// The shortest data that unpacks, which is less than `LEN` if the trailing fields are
+    /// optional.
+    const MIN_LEN: usize = LEN;
+
     fn pack(&self) -> [u8; LEN] {
         let mut dst = [UNINIT_BYTE; LEN];
         self.pack_into_slice(&mut dst);
@@ -28,11 +35,11 @@ pub trait Pack<const LEN: usize>: Sized {
 
     #[inline(always)]
     fn check_len(data: &[u8]) -> ProgramResult {
-        require!(data.len() >= LEN, DequeError::InvalidPackedData)
+        require!(data.len() >= Self::MIN_LEN, DequeError::InvalidPackedData)
     }
 
     /// # Safety:
-    /// The length of the instruction data must be verified before calling this.
+    /// The instruction data must be verified to be at least `MIN_LEN` bytes before calling this.
     #[doc(hidden)]
     unsafe fn unpack_unchecked(instruction_data: &[u8]) -> Self;
 }
@@ -66,6 +73,155 @@ impl<T, const N: usize> PackWithTag<N> for T where T: Pack<N> + Tagged {}
 pub const U16_BYTES: usize = core::mem::size_of::<u16>();
 pub const U64_BYTES: usize = core::mem::size_of::<u64>();
 
+/// A field of a packed instruction or event, as laid out by the `Pack` and `EmittableEvent`
+/// derives. Integers are little endian and enums are their `u8` discriminant.
+pub trait PackField: Sized {
+    const SIZE: usize;
+
+    /// Writes the field to the start of `dst`, which must be at least `SIZE` bytes long.
+    fn pack_field(&self, dst: &mut [MaybeUninit<u8>]);
+
+    /// Checks that the bytes at the start of `src` are a valid value. Only fields with invalid bit
+    /// patterns need to override this.
+    #[inline(always)]
+    fn validate_field(_src: &[u8]) -> ProgramResult {
+        Ok(())
+    }
+
+    /// # Safety
+    /// `src` must point to at least `SIZE` readable bytes that passed `validate_field`.
+    unsafe fn unpack_field_unchecked(src: *const u8) -> Self;
+}
+
+macro_rules! impl_pack_field_for_int {
+    ( $( $int:ty ),+ ) => {
+        $(
+            impl PackField for $int {
+                const SIZE: usize = core::mem::size_of::<$int>();
+
+                #[inline(always)]
+                fn pack_field(&self, dst: &mut [MaybeUninit<u8>]) {
+                    write_bytes(&mut dst[..Self::SIZE], &self.to_le_bytes());
+                }
+
+                #[inline(always)]
+                unsafe fn unpack_field_unchecked(src: *const u8) -> Self {
+                    <$int>::from_le_bytes(unsafe { *(src as *const [u8; core::mem::size_of::<$int>()]) })
+                }
+            }
+        )+
+    };
+}
+
+impl_pack_field_for_int!(u8, u16, u32, u64);
+
+/// Packed as a `u32`, with `u32::MAX` standing in for `None`. Optional sector indices use this, since
+/// `u32::MAX` is the `NIL` sector.
+impl PackField for Option<u32> {
+    const SIZE: usize = u32::SIZE;
+
+    #[inline(always)]
+    fn pack_field(&self, dst: &mut [MaybeUninit<u8>]) {
+        self.unwrap_or(u32::MAX).pack_field(dst);
+    }
+
+    #[inline(always)]
+    unsafe fn unpack_field_unchecked(src: *const u8) -> Self {
+        match unsafe { u32::unpack_field_unchecked(src) } {
+            u32::MAX => None,
+            value => Some(value),
+        }
+    }
+}
+
+/// Packed as a single `0` or `1` byte. Any other byte is invalid.
+impl PackField for bool {
+    const SIZE: usize = 1;
+
+    #[inline(always)]
+    fn pack_field(&self, dst: &mut [MaybeUninit<u8>]) {
+        dst[0].write(*self as u8);
+    }
+
+    #[inline(always)]
+    fn validate_field(src: &[u8]) -> ProgramResult {
+        require!(
+            src.first().is_some_and(|byte| *byte <= 1),
+            DequeError::InvalidPackedData
+        )
+    }
+
+    #[inline(always)]
+    unsafe fn unpack_field_unchecked(src: *const u8) -> Self {
+        unsafe { *src == 1 }
+    }
+}
+
+impl PackField for Pubkey {
+    const SIZE: usize = 32;
+
+    #[inline(always)]
+    fn pack_field(&self, dst: &mut [MaybeUninit<u8>]) {
+        write_bytes(&mut dst[..Self::SIZE], self.as_ref());
+    }
+
+    #[inline(always)]
+    unsafe fn unpack_field_unchecked(src: *const u8) -> Self {
+        Pubkey::new_from_array(unsafe { *(src as *const [u8; 32]) })
+    }
+}
+
+/// Borrowed pubkeys are read in place, which is how events avoid copying them.
+impl PackField for &Pubkey {
+    const SIZE: usize = 32;
+
+    #[inline(always)]
+    fn pack_field(&self, dst: &mut [MaybeUninit<u8>]) {
+        write_bytes(&mut dst[..Self::SIZE], self.as_ref());
+    }
+
+    #[inline(always)]
+    unsafe fn unpack_field_unchecked(src: *const u8) -> Self {
+        // SAFETY: Pubkey is repr(transparent) over [u8; 32], which has an alignment of 1. The
+        // caller must ensure the bytes outlive the returned reference.
+        unsafe { &*(src as *const Pubkey) }
+    }
+}
+
+macro_rules! impl_pack_field_for_u8_enum {
+    ( $( $enum:ty => $err:expr ),+ $(,)? ) => {
+        $(
+            impl PackField for $enum {
+                const SIZE: usize = 1;
+
+                #[inline(always)]
+                fn pack_field(&self, dst: &mut [MaybeUninit<u8>]) {
+                    dst[0].write(*self as u8);
+                }
+
+                #[inline(always)]
+                fn validate_field(src: &[u8]) -> ProgramResult {
+                    require!(
+                        src.first().is_some_and(|byte| <$enum>::try_from(*byte).is_ok()),
+                        $err
+                    )
+                }
+
+                #[inline(always)]
+                unsafe fn unpack_field_unchecked(src: *const u8) -> Self {
+                    // SAFETY: Caller guarantees the byte is a valid discriminant.
+                    unsafe { core::mem::transmute::<u8, $enum>(*src) }
+                }
+            }
+        )+
+    };
+}
+
+impl_pack_field_for_u8_enum!(
+    MarketChoice => DequeError::InvalidMarketChoice,
+    InstructionTag => DequeError::InvalidInstructionTag,
+);
+
 #[inline(always)]
 /// # Safety
 /// The caller must guarantee that `dst.capacity() >= new_len`.
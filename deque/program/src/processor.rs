@@ -1,16 +1,17 @@
 use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, pubkey::Pubkey};
 
 use crate::{
-    context::event_emitter::EventEmitterContext,
+    context::event_emitter::{EventEmitterContext, NUM_EVENT_EMITTER_ACCOUNTS},
     events::event_emitter::EventEmitter,
     instruction_enum::{
-        DepositInstructionData, InitializeDequeInstructionData, InstructionTag,
+        DefragmentInstructionData, DepositInstructionData, InitializeDequeInstructionData,
+        InitializeEventHistoryInstructionData, InstructionTag, ReconcileInstructionData,
         ResizeInstructionData, WithdrawInstructionData,
     },
     instructions,
     pack::Pack,
     require,
-    shared::error::DequeError,
+    shared::{error::DequeError, error_context::ErrorContext},
 };
 
 pub fn process_instruction(
@@ -18,16 +19,41 @@ pub fn process_instruction(
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    let instruction_tag: InstructionTag = instruction_data[0].try_into()?;
+    process(program_id, accounts, instruction_data)
+        .inspect_err(|err| ErrorContext::new(err, instruction_data).log())
+}
+
+fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let instruction_tag: InstructionTag = (*instruction_data
+        .first()
+        .ok_or(DequeError::InvalidInstructionTag)?)
+    .try_into()?;
 
     match instruction_tag {
-        InstructionTag::FlushEventLog => instructions::flush::process(accounts)?,
+        InstructionTag::FlushEventLog => instructions::flush::process(accounts, instruction_data)?,
         InstructionTag::InitializeEventAuthority => {
             instructions::initialize_event_authority::process(program_id, accounts)?
         }
         InstructionTag::ResizeEventAuthority => {
             instructions::resize_event_authority::process(program_id, accounts)?
         }
+        InstructionTag::InitializeEventHistory => {
+            let capacity =
+                InitializeEventHistoryInstructionData::unpack(instruction_data)?.capacity;
+            instructions::initialize_event_history::process(program_id, accounts, capacity)?
+        }
+        InstructionTag::Defragment => {
+            let max_moves = DefragmentInstructionData::unpack(instruction_data)?.max_moves;
+            instructions::defragment::process(program_id, accounts, max_moves)?
+        }
+        InstructionTag::MigrateDeque => instructions::migrate_deque::process(program_id, accounts)?,
+        InstructionTag::CloseTraderRecord => {
+            instructions::close_trader_record::process(program_id, accounts)?
+        }
         _ => handle_instructions_with_events(
             program_id,
             accounts,
@@ -47,25 +73,33 @@ fn handle_instructions_with_events(
 ) -> ProgramResult {
     debug_assert!(instruction_tag as u8 != InstructionTag::FlushEventLog as u8);
 
-    // Split [self program, event authority] from the rest of the accounts.
+    // Split [self program, event authority, event history, instructions sysvar] from the rest of
+    // the accounts.
     require!(
-        accounts.len() > 2,
+        accounts.len() > NUM_EVENT_EMITTER_ACCOUNTS,
         DequeError::InvalidNumberOfAccounts,
         "Expected at least {} accounts, got: {}",
-        2,
+        NUM_EVENT_EMITTER_ACCOUNTS + 1,
         accounts.len()
     )?;
 
-    let (event_emitter_accounts, accounts) = accounts.split_at(2);
-    let event_ctx = EventEmitterContext::load(event_emitter_accounts)?;
+    let (event_emitter_accounts, accounts) = accounts.split_at(NUM_EVENT_EMITTER_ACCOUNTS);
 
-    let mut event_emitter = EventEmitter::new(
-        event_ctx,
-        // TODO: Fix this logic here, these are *not* correct.
-        accounts[0].key,
-        accounts[1].key,
-        instruction_tag,
-    )?;
+    // The positions of the sender and the deque (market) account in each instruction's accounts.
+    let (sender_idx, market_idx) = match instruction_tag {
+        InstructionTag::InitializeDeque | InstructionTag::Resize => (0, 1),
+        InstructionTag::Deposit | InstructionTag::Withdraw => (1, 0),
+        // Anyone can reconcile a market, so the market is named as its own sender.
+        InstructionTag::Reconcile => (0, 0),
+        _ => unreachable!(),
+    };
+    let (sender, market) = match (accounts.get(sender_idx), accounts.get(market_idx)) {
+        (Some(sender), Some(market)) => (sender.key, market),
+        _ => return Err(DequeError::InvalidNumberOfAccounts.into()),
+    };
+    let event_ctx = EventEmitterContext::load(event_emitter_accounts, market)?;
+
+    let mut event_emitter = EventEmitter::new(event_ctx, sender, market.key, instruction_tag)?;
 
     match instruction_tag {
         InstructionTag::InitializeDeque => {
@@ -83,6 +117,7 @@ fn handle_instructions_with_events(
                 accounts,
                 deposit.amount,
                 deposit.choice,
+                deposit.escrow_hint,
                 &mut event_emitter,
             )?;
             event_emitter.flush()?;
@@ -93,6 +128,17 @@ fn handle_instructions_with_events(
                 program_id,
                 accounts,
                 withdraw.choice,
+                withdraw.escrow_hint,
+                &mut event_emitter,
+            )?;
+            event_emitter.flush()?;
+        }
+        InstructionTag::Reconcile => {
+            let reconcile = ReconcileInstructionData::unpack(instruction_data)?;
+            instructions::reconcile::process(
+                program_id,
+                accounts,
+                reconcile.fail_on_deficit,
                 &mut event_emitter,
             )?;
             event_emitter.flush()?;
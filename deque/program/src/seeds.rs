@@ -1,29 +1,36 @@
 pub mod event_authority {
     use solana_program::pubkey::Pubkey;
 
-    pub const SEEDS: &[&[u8]] = &[b"event_authority", &[BUMP]];
+    pub const EVENT_AUTHORITY_SEED_STR: &[u8] = b"event_authority";
 
-    /// Regenerate with `print_pda` helper below if the program ID changes.
-    pub const ID: Pubkey = Pubkey::from_str_const("FrEG6ig9wu4p75ZskAC8BA8DdfqFfmpWsubAE5yoVtvi");
-
-    /// Regenerate with `print_pda` helper below if the program ID changes.
-    pub const BUMP: u8 = 247;
-
-    #[test]
-    /// Helper function to print the PDA for easy copy/paste into the const values above.
-    pub fn print_pda() {
-        let (pda, bump) =
-            solana_program::pubkey::Pubkey::find_program_address(&[b"event_authority"], &crate::ID);
-        println!("pda: {pda}\nbump: {bump}");
+    /// Each market has its own event authority so that markets don't write-lock the same account.
+    pub fn find_event_authority_address(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(crate::event_authority_seeds!(market), &crate::ID)
     }
 
     #[test]
     pub fn check_pda() {
+        let market = Pubkey::new_unique();
+        let (pda, bump) = find_event_authority_address(&market);
         assert_eq!(
-            ID,
-            solana_program::pubkey::Pubkey::create_program_address(SEEDS, &crate::ID)
-                .expect("Should be OK")
+            pda,
+            Pubkey::create_program_address(
+                crate::event_authority_seeds_with_bump!(market, bump)[0],
+                &crate::ID
+            )
+            .expect("Should be OK")
         );
+        assert_ne!(pda, find_event_authority_address(&Pubkey::new_unique()).0);
+    }
+}
+
+pub mod event_history {
+    use solana_program::pubkey::Pubkey;
+
+    pub const EVENT_HISTORY_SEED_STR: &[u8] = b"event_history";
+
+    pub fn find_event_history_address(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(crate::event_history_seeds!(market), &crate::ID)
     }
 }
 
@@ -36,3 +43,27 @@ pub mod market {
         Pubkey::find_program_address(crate::market_seeds!(base_mint, quote_mint), &crate::ID)
     }
 }
+
+pub mod shard {
+    use slab_deque::ShardIndex;
+    use solana_program::pubkey::Pubkey;
+
+    pub const SHARD_SEED_STR: &[u8] = b"shard";
+
+    pub fn find_shard_address(deque: &Pubkey, shard: ShardIndex) -> (Pubkey, u8) {
+        Pubkey::find_program_address(crate::shard_seeds!(deque, shard), &crate::ID)
+    }
+}
+
+pub mod trader {
+    use solana_program::pubkey::Pubkey;
+
+    pub const TRADER_SEED_STR: &[u8] = b"trader";
+
+    /// Marks that `trader` has deposited on `market` at least once, so that each trader is only
+    /// counted once in the market's number of traders. The trader can close it for its rent once
+    /// they hold no escrow, see [`crate::instructions::close_trader_record::process`].
+    pub fn find_trader_address(market: &Pubkey, trader: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(crate::trader_seeds!(market, trader), &crate::ID)
+    }
+}
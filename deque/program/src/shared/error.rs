@@ -1,3 +1,4 @@
+use slab_deque::SlabError;
 use solana_program::program_error::ProgramError;
 
 #[macro_export]
@@ -8,8 +9,12 @@ macro_rules! require {
     } else {
         // Evaluate it once in case it's an expensive expression.
         let __err = ProgramError::from($err);
+        // Logging strings is expensive, so on-chain failures are only described by the error
+        // context record unless the program is built with `log-debug`.
         #[cfg(target_os = "solana")]
-        solana_program::msg!("[{}:{}] {}", std::file!(), std::line!(), __err);
+        if cfg!(feature = "log-debug") {
+            solana_program::msg!("[{}:{}] {}", std::file!(), std::line!(), __err);
+        }
         #[cfg(not(target_os = "solana"))]
         std::println!("[{}:{}] {}", std::file!(), std::line!(), __err);
         Err(__err)
@@ -21,7 +26,9 @@ macro_rules! require {
         Ok(())
     } else {
         #[cfg(target_os = "solana")]
-        solana_program::msg!("[{}:{}] {}", std::file!(), std::line!(), std::format_args!($($fmt_args)*));
+        if cfg!(feature = "log-debug") {
+            solana_program::msg!("[{}:{}] {}", std::file!(), std::line!(), std::format_args!($($fmt_args)*));
+        }
         #[cfg(not(target_os = "solana"))]
         std::println!("[{}:{}] {}", std::file!(), std::line!(), std::format_args!($($fmt_args)*));
         Err($err)
@@ -30,6 +37,7 @@ macro_rules! require {
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(test, derive(strum_macros::EnumIter))]
 #[repr(u8)]
 pub enum DequeError {
     InvalidDiscriminant,
@@ -55,6 +63,56 @@ pub enum DequeError {
     TransferError,
     RentGetError,
     ReallocError,
+    MissingShard,
+    DuplicateShard,
+    InvalidShard,
+    InvalidShardAccount,
+    InvalidEventHistory,
+    FlushNotInvokedBySelf,
+    InvalidEventPayload,
+    NoFreeSectors,
+    EscrowAmountOverflow,
+    VaultBalanceDecreased,
+    InvalidSelfProgram,
+    InvalidSystemProgram,
+    InvalidTokenProgram,
+    InvalidAssociatedTokenProgram,
+    InvalidInstructionsSysvar,
+    InvalidEventAuthority,
+    EventAuthorityNotSigner,
+    InvalidMintOwner,
+    MarketMintMismatch,
+    TokenAccountNotOwnedByTokenProgram,
+    TokenAccountOwnerMismatch,
+    TokenAccountMintMismatch,
+    UnexpectedTokenAccount,
+    AccountAlreadyInitialized,
+    AccountNotOwnedBySystemProgram,
+    VaultDeficit,
+    DequeNeedsMigration,
+    InvalidTraderRecord,
+    EventHistoryNeedsMigration,
+    TraderHasEscrow,
+}
+
+impl DequeError {
+    /// The last variant. New variants are only ever appended, so it must be kept up to date.
+    const LAST: DequeError = DequeError::TraderHasEscrow;
+}
+
+impl TryFrom<u32> for DequeError {
+    type Error = ProgramError;
+
+    /// Recovers the error from its `ProgramError::Custom` code.
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            // SAFETY: The enum is repr(u8) with contiguous discriminants up to `LAST`.
+            value if value <= DequeError::LAST as u32 => {
+                Ok(unsafe { core::mem::transmute::<u8, DequeError>(value as u8) })
+            }
+            _ => Err(ProgramError::Custom(value)),
+        }
+    }
 }
 
 impl From<DequeError> for ProgramError {
@@ -64,6 +122,24 @@ impl From<DequeError> for ProgramError {
     }
 }
 
+impl From<SlabError> for DequeError {
+    #[inline(always)]
+    fn from(e: SlabError) -> Self {
+        match e {
+            SlabError::HeaderTooSmall => DequeError::DequeAccountUnallocated,
+            SlabError::InvalidHeader => DequeError::InvalidDiscriminant,
+            SlabError::MalformedSlab => DequeError::MalformedSlab,
+            SlabError::InvalidSectorIndex => DequeError::InvalidSectorIndex,
+            SlabError::ArithmeticError => DequeError::ArithmetricError,
+            SlabError::OutOfBounds => DequeError::OutOfBounds,
+            SlabError::NoFreeSectors => DequeError::NoFreeSectors,
+            SlabError::MissingShard => DequeError::MissingShard,
+            SlabError::DuplicateShard => DequeError::DuplicateShard,
+            SlabError::InvalidShard => DequeError::InvalidShard,
+        }
+    }
+}
+
 impl From<DequeError> for &'static str {
     fn from(value: DequeError) -> Self {
         match value {
@@ -98,6 +174,50 @@ impl From<DequeError> for &'static str {
             DequeError::TransferError => "Couldn't invoke system transfer",
             DequeError::RentGetError => "Failed to get rent",
             DequeError::ReallocError => "Failed to realloc",
+            DequeError::MissingShard => "A needed deque shard wasn't passed",
+            DequeError::DuplicateShard => "The same deque shard was passed more than once",
+            DequeError::InvalidShard => "Invalid deque shard index",
+            DequeError::InvalidShardAccount => "Account isn't a shard of this deque",
+            DequeError::InvalidEventHistory => "Invalid event history account",
+            DequeError::FlushNotInvokedBySelf => {
+                "Flushing the event log must be a CPI from this program"
+            }
+            DequeError::InvalidEventPayload => "Malformed event log payload",
+            DequeError::NoFreeSectors => "The deque has no free sectors left",
+            DequeError::EscrowAmountOverflow => "Escrow amount would overflow",
+            DequeError::VaultBalanceDecreased => "Vault balance decreased during a deposit",
+            DequeError::InvalidSelfProgram => "Invalid self program ID",
+            DequeError::InvalidSystemProgram => "Invalid system program ID",
+            DequeError::InvalidTokenProgram => "Invalid token program ID",
+            DequeError::InvalidAssociatedTokenProgram => "Invalid associated token program ID",
+            DequeError::InvalidInstructionsSysvar => "Invalid instructions sysvar",
+            DequeError::InvalidEventAuthority => "Invalid event authority",
+            DequeError::EventAuthorityNotSigner => "Event authority must be a signer",
+            DequeError::InvalidMintOwner => "Mint account must be owned by a token program",
+            DequeError::MarketMintMismatch => "Mint doesn't match the market's mint",
+            DequeError::TokenAccountNotOwnedByTokenProgram => {
+                "Token account must be owned by a token program"
+            }
+            DequeError::TokenAccountOwnerMismatch => "Token account owner doesn't match",
+            DequeError::TokenAccountMintMismatch => "Token account mint doesn't match",
+            DequeError::UnexpectedTokenAccount => "Token account isn't the expected account",
+            DequeError::AccountAlreadyInitialized => "Account must be uninitialized",
+            DequeError::AccountNotOwnedBySystemProgram => {
+                "Account must be owned by the system program"
+            }
+            DequeError::VaultDeficit => "A vault holds less than its escrows add up to",
+            DequeError::DequeNeedsMigration => {
+                "The deque has an older layout and must be migrated first"
+            }
+            DequeError::InvalidTraderRecord => {
+                "Trader record isn't the trader's PDA on this market"
+            }
+            DequeError::EventHistoryNeedsMigration => {
+                "The event history has an older layout and must be migrated first"
+            }
+            DequeError::TraderHasEscrow => {
+                "The trader's record can't be closed while they hold an escrow"
+            }
         }
     }
 }
@@ -113,3 +233,17 @@ impl core::fmt::Display for DequeError {
 impl std::error::Error for DequeError {}
 
 pub type DequeProgramResult = Result<(), DequeError>;
+
+pub mod tests {
+    #[test]
+    pub fn deque_error_u32_round_trip() {
+        use super::*;
+        use strum::IntoEnumIterator;
+
+        assert_eq!(DequeError::iter().next_back(), Some(DequeError::LAST));
+        for error in DequeError::iter() {
+            assert_eq!(DequeError::try_from(error as u32), Ok(error));
+        }
+        assert!(DequeError::try_from(DequeError::LAST as u32 + 1).is_err());
+    }
+}
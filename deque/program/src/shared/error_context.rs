@@ -0,0 +1,142 @@
+use solana_program::program_error::ProgramError;
+
+use crate::{instruction_enum::InstructionTag, shared::error::DequeError};
+
+/// The first byte of a logged error context record. Logged event payloads always start with an
+/// event tag, which is never `u8::MAX`, so the two can't be confused.
+pub const ERROR_CONTEXT_TAG: u8 = u8::MAX;
+
+/// The instruction tag or account index in a record when it isn't known.
+pub const UNKNOWN: u8 = u8::MAX;
+
+/// A compact description of a failed instruction, logged with `sol_log_data` instead of a string
+/// message so that failures cost a handful of compute units and can be decoded by clients.
+///
+/// ```text
+/// [ERROR_CONTEXT_TAG u8, code u64 LE, instruction_tag u8, account_index u8]
+/// ```
+#[derive(Clone, Copy)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug, PartialEq, Eq))]
+pub struct ErrorContext {
+    /// The error's `u64` representation, which is the custom error code for a [`DequeError`].
+    pub code: u64,
+    /// The failed instruction's tag, or [`UNKNOWN`] if the instruction data had no valid tag.
+    pub instruction_tag: u8,
+    /// The index of the account that failed validation, or [`UNKNOWN`] if the error isn't about a
+    /// single account of the instruction.
+    pub account_index: u8,
+}
+
+impl ErrorContext {
+    pub const LEN: usize = 1 + 8 + 1 + 1;
+
+    pub fn new(error: &ProgramError, instruction_data: &[u8]) -> Self {
+        let tag = instruction_data
+            .first()
+            .and_then(|tag| InstructionTag::try_from(*tag).ok());
+        let deque_error = match error {
+            ProgramError::Custom(code) => DequeError::try_from(*code).ok(),
+            _ => None,
+        };
+        let account_index = match (tag, deque_error.and_then(account_label)) {
+            (Some(tag), Some(label)) => tag.account_index(label),
+            _ => None,
+        };
+
+        ErrorContext {
+            code: u64::from(error.clone()),
+            instruction_tag: tag.map_or(UNKNOWN, |tag| tag as u8),
+            account_index: account_index.unwrap_or(UNKNOWN),
+        }
+    }
+
+    pub fn pack(&self) -> [u8; Self::LEN] {
+        let mut data = [0; Self::LEN];
+        data[0] = ERROR_CONTEXT_TAG;
+        data[1..9].copy_from_slice(&self.code.to_le_bytes());
+        data[9] = self.instruction_tag;
+        data[10] = self.account_index;
+        data
+    }
+
+    #[inline(never)]
+    pub fn log(&self) {
+        solana_program::log::sol_log_data(&[&self.pack()]);
+    }
+
+    /// Unpacks a logged record, returning `None` if the data isn't an error context record.
+    #[cfg(not(target_os = "solana"))]
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        match data {
+            [ERROR_CONTEXT_TAG, code @ .., instruction_tag, account_index] if code.len() == 8 => {
+                Some(ErrorContext {
+                    code: u64::from_le_bytes(code.try_into().ok()?),
+                    instruction_tag: *instruction_tag,
+                    account_index: *account_index,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    #[cfg(not(target_os = "solana"))]
+    pub fn error(&self) -> ProgramError {
+        ProgramError::from(self.code)
+    }
+
+    #[cfg(not(target_os = "solana"))]
+    pub fn deque_error(&self) -> Option<DequeError> {
+        match self.error() {
+            ProgramError::Custom(code) => DequeError::try_from(code).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// The role of the account an error is about, matching the labels in
+/// [`InstructionTag::account_labels`].
+fn account_label(error: DequeError) -> Option<&'static str> {
+    Some(match error {
+        DequeError::InvalidSelfProgram => "self_program",
+        DequeError::InvalidEventAuthority
+        | DequeError::EventAuthorityNotSigner
+        | DequeError::EventAuthorityNotAllocated
+        | DequeError::EventAuthorityNotFullyAllocated
+        | DequeError::InvalidEventAuthorityBorrow => "event_authority",
+        DequeError::InvalidEventHistory | DequeError::EventHistoryNeedsMigration => "event_history",
+        DequeError::InvalidInstructionsSysvar => "instructions_sysvar",
+        DequeError::InvalidSystemProgram => "system_program",
+        DequeError::InvalidTokenProgram => "token_program",
+        DequeError::InvalidAssociatedTokenProgram => "associated_token_program",
+        DequeError::InvalidMintOwner | DequeError::MarketMintMismatch => "mint",
+        DequeError::DequeAccountUnallocated
+        | DequeError::InvalidPDA
+        | DequeError::DequeNeedsMigration => "deque",
+        DequeError::InvalidTraderRecord => "trader_record",
+        DequeError::TraderHasEscrow => "trader",
+        _ => return None,
+    })
+}
+
+pub mod tests {
+    #[test]
+    pub fn error_context_round_trip() {
+        use super::*;
+
+        let error = ProgramError::from(DequeError::MarketMintMismatch);
+        let ctx = ErrorContext::new(&error, &[InstructionTag::Withdraw as u8, 0]);
+        assert_eq!(ctx.account_index, 8);
+        assert_eq!(ctx.instruction_tag, InstructionTag::Withdraw as u8);
+        assert_eq!(ErrorContext::unpack(&ctx.pack()), Some(ctx));
+        assert_eq!(ctx.deque_error(), Some(DequeError::MarketMintMismatch));
+
+        // Errors that aren't about a single account, or aren't deque errors at all.
+        let ctx = ErrorContext::new(&DequeError::NoFreeSectors.into(), &[]);
+        assert_eq!((ctx.instruction_tag, ctx.account_index), (UNKNOWN, UNKNOWN));
+        let ctx = ErrorContext::new(&ProgramError::NotEnoughAccountKeys, &[0]);
+        assert_eq!(ctx.error(), ProgramError::NotEnoughAccountKeys);
+        assert_eq!(ctx.deque_error(), None);
+
+        assert_eq!(ErrorContext::unpack(&[0; ErrorContext::LEN]), None);
+    }
+}
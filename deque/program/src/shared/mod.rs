@@ -1,3 +1,4 @@
 pub mod error;
+pub mod error_context;
 pub mod pack_utils;
 pub mod token_utils;
@@ -5,8 +5,8 @@ use solana_program::{
 };
 
 use crate::{
-    context::market_choice::MarketChoiceContext, market_seeds_with_bump, state::Deque,
-    validation::token_accounts::TokenProgram,
+    context::market_choice::MarketChoiceContext, market_seeds_with_bump, shared::error::DequeError,
+    state::Deque, validation::token_accounts::TokenProgram,
 };
 
 pub fn deposit_to_vault<'a, 'info>(
@@ -51,6 +51,7 @@ pub fn deposit_to_vault<'a, 'info>(
                 &[
                     ctx.token_program.info.as_ref().clone(),
                     ctx.payer_ata.info.as_ref().clone(),
+                    ctx.mint_info.info.as_ref().clone(),
                     ctx.vault_ata.info.as_ref().clone(),
                     ctx.payer.as_ref().clone(),
                 ],
@@ -60,7 +61,7 @@ pub fn deposit_to_vault<'a, 'info>(
             // fees, and other misc extensions.
             let deposited = balance_after
                 .checked_sub(balance_before)
-                .ok_or(ProgramError::InvalidArgument)?;
+                .ok_or(DequeError::VaultBalanceDecreased)?;
             Ok(deposited)
         }
     }
@@ -72,7 +73,7 @@ pub fn withdraw_from_vault<'a, 'info>(
 ) -> ProgramResult {
     let (base_mint, quote_mint, deque_bump) = {
         let mut data = ctx.deque_account.data.borrow_mut();
-        let deque = Deque::from_bytes_unchecked(&mut data)?;
+        let deque = Deque::from_bytes_unchecked(&mut data).map_err(DequeError::from)?;
         (
             deque.header.base_mint,
             deque.header.quote_mint,
@@ -116,6 +117,7 @@ pub fn withdraw_from_vault<'a, 'info>(
                 &[
                     ctx.token_program.info.as_ref().clone(),
                     ctx.vault_ata.info.as_ref().clone(),
+                    ctx.mint_info.info.as_ref().clone(),
                     ctx.payer_ata.info.as_ref().clone(),
                     ctx.deque_account.as_ref().clone(),
                 ],
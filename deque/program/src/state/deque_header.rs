@@ -1,57 +1,229 @@
 use crate::{
+    instruction_enum::MarketChoice,
     shared::error::DequeError,
-    utils::{SectorIndex, Slab, NIL},
+    state::MarketEscrow,
+    utils::{SectorIndex, Slab, SECTOR_SIZE},
 };
 use bytemuck::{Pod, Zeroable};
-use solana_program::{entrypoint::ProgramResult, pubkey::Pubkey};
+use slab_deque::{DequeLinks, ShardIndex, SlabError, SlabHeader};
+use solana_program::pubkey::Pubkey;
 use static_assertions::const_assert_eq;
 
 pub const DEQUE_ACCOUNT_DISCRIMINANT: [u8; 8] = 0xd00d00b00b00f00du64.to_le_bytes();
-pub const DEQUE_HEADER_SIZE: usize = 96;
+pub const DEQUE_HEADER_SIZE: usize = 168;
+/// Bumped whenever the header's or the sectors' layout changes. Deques of an older version have to
+/// be migrated with [`crate::instruction_enum::InstructionTag::MigrateDeque`] before they're loaded.
+pub const DEQUE_VERSION: u8 = 1;
+
+/// The offset of the version byte, which is the same in every layout.
+pub const DEQUE_VERSION_OFFSET: usize = 88;
+
+/// How a deque account of a given version is laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DequeLayout {
+    pub header_size: usize,
+    /// The size of each sector, in the deque account after the header and in every shard.
+    pub sector_size: usize,
+}
+
+/// The layout of every version, indexed by version.
+pub const DEQUE_LAYOUTS: [DequeLayout; DEQUE_VERSION as usize + 1] = [
+    // The original header and 48-byte escrows, before shards, running totals, market statistics
+    // and escrow timestamps.
+    DequeLayout {
+        header_size: 96,
+        sector_size: 56,
+    },
+    DequeLayout {
+        header_size: DEQUE_HEADER_SIZE,
+        sector_size: SECTOR_SIZE,
+    },
+];
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Zeroable)]
 pub struct DequeHeader {
     pub discriminant: [u8; 8],
-    pub len: SectorIndex,
-    pub free_head: SectorIndex,
-    pub deque_head: SectorIndex,
-    pub deque_tail: SectorIndex,
+    /// The deque's `len`, `free_head`, `deque_head` and `deque_tail`.
+    pub links: DequeLinks,
     pub base_mint: Pubkey,
     pub quote_mint: Pubkey,
     pub version: u8,
     pub deque_bump: u8,
+    /// The index of the newest shard. The header's own account is shard 0, so this is 0 until the
+    /// deque outgrows it and every index in `1..=last_shard` is a [`crate::state::DequeShard`].
+    pub last_shard: ShardIndex,
+    /// 1 once the market's event history has been initialized. From then on every event emitting
+    /// instruction on the market must pass the history instead of the program ID placeholder.
+    pub has_event_history: u8,
     // Explicitly mark the padding that repr(C) will add implicitly.
-    pub _padding: [u8; 6],
+    pub _padding: [u8; 3],
+    /// The sum of every escrow's base amount, kept up to date by each deposit and withdrawal so
+    /// that it can be compared with the base vault's balance without walking the deque.
+    pub total_base: u64,
+    /// The sum of every escrow's quote amount.
+    pub total_quote: u64,
+    /// Every base amount ever deposited, saturating at `u64::MAX`.
+    pub deposited_base: u64,
+    pub deposited_quote: u64,
+    /// Every base amount ever withdrawn, saturating at `u64::MAX`.
+    pub withdrawn_base: u64,
+    pub withdrawn_quote: u64,
+    /// The number of unique traders that have ever deposited. A trader is counted when their first
+    /// deposit creates their trader record, see [`crate::seeds::trader`], and counted again if they
+    /// close the record and deposit after. Deques migrated from the baseline layout only
+    /// approximate it, see [`crate::instructions::migrate_deque::process`].
+    pub unique_traders: u64,
+    /// The slot of the last deposit or withdrawal.
+    pub last_activity_slot: u64,
+    /// The most escrows the deque has ever held at once.
+    pub peak_len: u32,
+    pub _stats_padding: [u8; 4],
 }
 
 unsafe impl Pod for DequeHeader {}
 
 impl Slab for DequeHeader {}
 
+impl SlabHeader for DequeHeader {
+    type Item = MarketEscrow;
+
+    #[inline(always)]
+    fn links(&self) -> &DequeLinks {
+        &self.links
+    }
+
+    #[inline(always)]
+    fn links_mut(&mut self) -> &mut DequeLinks {
+        &mut self.links
+    }
+
+    #[inline(always)]
+    fn verify(&self) -> Result<(), SlabError> {
+        if self.discriminant != DEQUE_ACCOUNT_DISCRIMINANT || self.version != DEQUE_VERSION {
+            return Err(SlabError::InvalidHeader);
+        }
+        Ok(())
+    }
+}
+
 impl DequeHeader {
     pub fn init(deque_bump: u8, base_mint: &Pubkey, quote_mint: &Pubkey) -> Self {
         DequeHeader {
             discriminant: DEQUE_ACCOUNT_DISCRIMINANT,
             base_mint: *base_mint,
             quote_mint: *quote_mint,
-            len: 0,
-            free_head: NIL,
-            deque_head: NIL,
-            deque_tail: NIL,
-            version: 0,
+            links: DequeLinks::empty(),
+            version: DEQUE_VERSION,
             deque_bump,
-            _padding: [0; 6],
+            last_shard: 0,
+            has_event_history: 0,
+            _padding: [0; 3],
+            total_base: 0,
+            total_quote: 0,
+            deposited_base: 0,
+            deposited_quote: 0,
+            withdrawn_base: 0,
+            withdrawn_quote: 0,
+            unique_traders: 0,
+            last_activity_slot: 0,
+            peak_len: 0,
+            _stats_padding: [0; 4],
+        }
+    }
+
+    /// Whether the deque in `data` is an initialized market with an event history. Anything else,
+    /// including a deque that isn't initialized yet, has no history to require.
+    pub fn has_event_history(data: &[u8]) -> bool {
+        data.get(..DEQUE_HEADER_SIZE)
+            .map(bytemuck::pod_read_unaligned::<DequeHeader>)
+            .is_some_and(|header| header.verify().is_ok() && header.has_event_history != 0)
+    }
+
+    /// Check that `data` holds a deque of the current version. A deque of an older version fails
+    /// with [`DequeError::DequeNeedsMigration`].
+    pub fn check_version(data: &[u8]) -> Result<(), DequeError> {
+        if data.get(..8) != Some(&DEQUE_ACCOUNT_DISCRIMINANT[..]) {
+            return Err(DequeError::InvalidDiscriminant);
+        }
+        match data.get(DEQUE_VERSION_OFFSET).copied() {
+            Some(DEQUE_VERSION) => Ok(()),
+            Some(version) if version < DEQUE_VERSION => Err(DequeError::DequeNeedsMigration),
+            _ => Err(DequeError::InvalidDiscriminant),
+        }
+    }
+
+    /// The running total of every escrow's amount of `choice`.
+    #[inline(always)]
+    pub fn total(&self, choice: &MarketChoice) -> u64 {
+        match choice {
+            MarketChoice::Base => self.total_base,
+            MarketChoice::Quote => self.total_quote,
         }
     }
 
+    /// The running total and the cumulative deposited and withdrawn volume of `choice`.
     #[inline(always)]
-    pub fn verify_discriminant(&self) -> ProgramResult {
-        if self.discriminant != DEQUE_ACCOUNT_DISCRIMINANT {
-            return Err(DequeError::InvalidDiscriminant.into());
+    fn stats_mut(&mut self, choice: &MarketChoice) -> (&mut u64, &mut u64, &mut u64) {
+        match choice {
+            MarketChoice::Base => (
+                &mut self.total_base,
+                &mut self.deposited_base,
+                &mut self.withdrawn_base,
+            ),
+            MarketChoice::Quote => (
+                &mut self.total_quote,
+                &mut self.deposited_quote,
+                &mut self.withdrawn_quote,
+            ),
         }
+    }
+
+    /// Adds a deposit to the running total and the deposited volume. Call it after any new escrow
+    /// was pushed, so that it counts towards the peak length.
+    #[inline(always)]
+    pub fn record_deposit(
+        &mut self,
+        choice: &MarketChoice,
+        amount: u64,
+        slot: u64,
+    ) -> Result<(), DequeError> {
+        let (total, deposited, _) = self.stats_mut(choice);
+        *total = total
+            .checked_add(amount)
+            .ok_or(DequeError::EscrowAmountOverflow)?;
+        *deposited = deposited.saturating_add(amount);
+        self.record_activity(slot);
         Ok(())
     }
+
+    /// Removes a withdrawal from the running total and adds it to the withdrawn volume.
+    #[inline(always)]
+    pub fn record_withdrawal(
+        &mut self,
+        choice: &MarketChoice,
+        amount: u64,
+        slot: u64,
+    ) -> Result<(), DequeError> {
+        let (total, _, withdrawn) = self.stats_mut(choice);
+        *total = total
+            .checked_sub(amount)
+            .ok_or(DequeError::ArithmetricError)?;
+        *withdrawn = withdrawn.saturating_add(amount);
+        self.record_activity(slot);
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn record_new_trader(&mut self) {
+        self.unique_traders = self.unique_traders.saturating_add(1);
+    }
+
+    #[inline(always)]
+    fn record_activity(&mut self, slot: u64) {
+        self.last_activity_slot = slot;
+        self.peak_len = self.peak_len.max(self.links.len);
+    }
 }
 
 const_assert_eq!(size_of::<DequeHeader>(), DEQUE_HEADER_SIZE);
@@ -67,5 +239,24 @@ const_assert_eq!(
     32 + // quote_mint
     1 + // version
     1 + // deque_bump
-    6 // _padding
+    2 + // last_shard
+    1 + // has_event_history
+    3 + // _padding
+    8 + // total_base
+    8 + // total_quote
+    8 + // deposited_base
+    8 + // deposited_quote
+    8 + // withdrawn_base
+    8 + // withdrawn_quote
+    8 + // unique_traders
+    8 + // last_activity_slot
+    4 + // peak_len
+    4 // _stats_padding
+);
+// The links must stay at the same offset they had before they were grouped together.
+const_assert_eq!(core::mem::offset_of!(DequeHeader, links), 8);
+const_assert_eq!(
+    core::mem::offset_of!(DequeHeader, version),
+    DEQUE_VERSION_OFFSET
 );
+const_assert_eq!(size_of::<DequeLinks>(), 4 * size_of::<SectorIndex>());
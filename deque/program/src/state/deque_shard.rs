@@ -0,0 +1,62 @@
+use bytemuck::{Pod, Zeroable};
+use slab_deque::ShardIndex;
+use solana_program::pubkey::Pubkey;
+use static_assertions::const_assert_eq;
+
+use crate::{shared::error::DequeError, utils::Slab};
+
+pub const DEQUE_SHARD_DISCRIMINANT: [u8; 8] = 0xd00d00b00b00f00eu64.to_le_bytes();
+pub const DEQUE_SHARD_HEADER_SIZE: usize = 48;
+
+/// The header of an account holding one shard of a deque's sectors, after the shard 0 sectors in
+/// the deque account itself. The sectors follow the header directly.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct DequeShard {
+    pub discriminant: [u8; 8],
+    /// The deque (market) account this shard belongs to.
+    pub deque: Pubkey,
+    pub shard: ShardIndex,
+    pub shard_bump: u8,
+    // Explicitly mark the padding that repr(C) will add implicitly.
+    pub _padding: [u8; 5],
+}
+
+impl Slab for DequeShard {}
+
+impl DequeShard {
+    pub fn init(deque: &Pubkey, shard: ShardIndex, shard_bump: u8) -> Self {
+        DequeShard {
+            discriminant: DEQUE_SHARD_DISCRIMINANT,
+            deque: *deque,
+            shard,
+            shard_bump,
+            _padding: [0; 5],
+        }
+    }
+
+    /// Check that this is an initialized shard of `deque` with an index the deque has allocated.
+    #[inline(always)]
+    pub fn verify(&self, deque: &Pubkey, last_shard: ShardIndex) -> Result<(), DequeError> {
+        if self.discriminant != DEQUE_SHARD_DISCRIMINANT || self.deque.as_ref() != deque.as_ref() {
+            return Err(DequeError::InvalidShardAccount);
+        }
+        if self.shard == 0 || self.shard > last_shard {
+            return Err(DequeError::InvalidShard);
+        }
+        Ok(())
+    }
+}
+
+const_assert_eq!(size_of::<DequeShard>(), DEQUE_SHARD_HEADER_SIZE);
+// Ensure the fixed size is exactly what's expected.
+const_assert_eq!(
+    DEQUE_SHARD_HEADER_SIZE,
+    8 + // discriminant
+    32 + // deque
+    2 + // shard
+    1 + // shard_bump
+    5 // _padding
+);
+// Keep the sectors after the header 8-byte aligned.
+const_assert_eq!(DEQUE_SHARD_HEADER_SIZE % 8, 0);
@@ -26,18 +26,25 @@ pub struct EphemeralEventHeader {
     discriminant: [u8; 8],
     /// Track the number of bytes to know where to append new events to.
     written_bytes_u32: [u8; 4],
-    _padding: [u8; 4],
+    /// The bump of the event authority PDA, which is derived from its market.
+    bump: u8,
+    _padding: [u8; 3],
 }
 
 impl EphemeralEventHeader {
-    pub fn init() -> Self {
+    pub fn init(bump: u8) -> Self {
         EphemeralEventHeader {
             discriminant: EVENT_ACCOUNT_DISCRIMINANT,
             written_bytes_u32: 0u32.to_le_bytes(),
-            _padding: [0u8; 4],
+            bump,
+            _padding: [0u8; 3],
         }
     }
 
+    pub fn get_bump(&self) -> u8 {
+        self.bump
+    }
+
     pub fn get_written_bytes(&self) -> u32 {
         u32::from_le_bytes(self.written_bytes_u32)
     }
@@ -75,7 +82,7 @@ pub struct EphemeralEventLog<'a> {
 
 impl<'a> EphemeralEventLog<'a> {
     /// Construct a new, empty EventData with allocated but uninitialized (zerod out) account data.
-    pub fn init(zerod_account_data: &'a mut [u8]) -> ProgramResult {
+    pub fn init(zerod_account_data: &'a mut [u8], bump: u8) -> ProgramResult {
         // Only needs to be at least the header size in order to init.
         if zerod_account_data.len() < EPHEMERAL_EVENT_LOG_HEADER_SIZE {
             return Err(DequeError::EventAuthorityNotAllocated.into());
@@ -84,8 +91,9 @@ impl<'a> EphemeralEventLog<'a> {
         let header = from_slab_bytes_mut(
             &mut zerod_account_data[0..EPHEMERAL_EVENT_LOG_HEADER_SIZE],
             0_usize,
-        )?;
-        *header = EphemeralEventHeader::init();
+        )
+        .map_err(DequeError::from)?;
+        *header = EphemeralEventHeader::init(bump);
 
         Ok(())
     }
@@ -100,7 +108,8 @@ impl<'a> EphemeralEventLog<'a> {
             return Err(DequeError::EventAuthorityNotFullyAllocated.into());
         }
         let (header_slab, event_data) = data.split_at_mut(EPHEMERAL_EVENT_LOG_HEADER_SIZE);
-        let header = from_slab_bytes_mut::<EphemeralEventHeader>(header_slab, 0_usize)?;
+        let header = from_slab_bytes_mut::<EphemeralEventHeader>(header_slab, 0_usize)
+            .map_err(DequeError::from)?;
         header.verify_discriminant()?;
         debug_assert!(header.get_written_bytes() as usize <= event_data.len());
         Ok(Self { header, event_data })
@@ -112,7 +121,8 @@ impl<'a> EphemeralEventLog<'a> {
             return Err(DequeError::EventAuthorityNotFullyAllocated.into());
         }
         let (header_slab, event_data) = data.split_at_mut(EPHEMERAL_EVENT_LOG_HEADER_SIZE);
-        let header = from_slab_bytes_mut::<EphemeralEventHeader>(header_slab, 0_usize)?;
+        let header = from_slab_bytes_mut::<EphemeralEventHeader>(header_slab, 0_usize)
+            .map_err(DequeError::from)?;
         debug_assert!(header.get_written_bytes() as usize <= event_data.len());
         Ok(Self { header, event_data })
     }
@@ -0,0 +1,194 @@
+use bytemuck::{Pod, Zeroable};
+use solana_program::{entrypoint::MAX_PERMITTED_DATA_INCREASE, pubkey::Pubkey};
+use static_assertions::{const_assert, const_assert_eq};
+
+use crate::{
+    events::{DepositEventData, EmittableEvent, ReconcileEventData, WithdrawEventData},
+    shared::error::DequeError,
+    utils::{from_slab_bytes_mut, Slab},
+};
+
+pub const EVENT_HISTORY_DISCRIMINANT: [u8; 8] = 0xbaadbaadf000000eu64.to_le_bytes();
+pub const EVENT_HISTORY_HEADER_SIZE: usize = 56;
+
+/// The maximum size of a single packed event stored in the history, tag included.
+pub const EVENT_RECORD_DATA_LEN: usize = 64;
+pub const EVENT_RECORD_SIZE: usize = 80;
+/// Bumped whenever the records' layout changes. Histories of an older version have to be migrated
+/// with [`crate::instruction_enum::InstructionTag::MigrateDeque`] before events are appended to
+/// them.
+pub const EVENT_HISTORY_VERSION: u8 = 1;
+/// The offset of the version byte in the header, which was padding before it was versioned.
+pub const EVENT_HISTORY_VERSION_OFFSET: usize = 53;
+
+/// The size of a record in every version, indexed by version. Every version keeps the sequence
+/// number and slot first and only grows the packed event's zero padding.
+pub const EVENT_RECORD_SIZES: [usize; EVENT_HISTORY_VERSION as usize + 1] = [
+    // Records held events of up to 48 bytes.
+    64,
+    // Deposit and withdrawal events gained their slot.
+    EVENT_RECORD_SIZE,
+];
+
+#[repr(C)]
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug))]
+pub struct EventHistoryHeader {
+    /// The account discriminant.
+    pub discriminant: [u8; 8],
+    /// The deque (market) account whose events are recorded.
+    pub market: Pubkey,
+    /// The sequence number the next event will be recorded with. Also the total number of events
+    /// ever recorded.
+    pub next_seq: u64,
+    /// The number of records in the ring buffer.
+    pub capacity: u32,
+    pub bump: u8,
+    pub version: u8,
+    // Explicitly mark the padding that repr(C) will add implicitly.
+    pub _padding: [u8; 2],
+}
+
+impl Slab for EventHistoryHeader {}
+
+const_assert_eq!(size_of::<EventHistoryHeader>(), EVENT_HISTORY_HEADER_SIZE);
+// Keep the records after the header 8-byte aligned.
+const_assert_eq!(EVENT_HISTORY_HEADER_SIZE % 8, 0);
+const_assert_eq!(
+    core::mem::offset_of!(EventHistoryHeader, version),
+    EVENT_HISTORY_VERSION_OFFSET
+);
+
+/// A single event in the history, in the same packed format it's emitted in.
+#[repr(C)]
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[cfg_attr(not(target_os = "solana"), derive(Debug))]
+pub struct EventRecord {
+    pub seq: u64,
+    /// The slot the event was recorded in.
+    pub slot: u64,
+    /// The packed event, starting at its tag and zero padded to the record size.
+    pub data: [u8; EVENT_RECORD_DATA_LEN],
+}
+
+const_assert_eq!(size_of::<EventRecord>(), EVENT_RECORD_SIZE);
+// Every event emitted after the header must fit in a record.
+const_assert!(DepositEventData::LEN <= EVENT_RECORD_DATA_LEN);
+const_assert!(WithdrawEventData::LEN <= EVENT_RECORD_DATA_LEN);
+const_assert!(ReconcileEventData::LEN <= EVENT_RECORD_DATA_LEN);
+
+/// A per-market, fixed-capacity ring buffer of the most recent events, stored in the event history
+/// account's data. Unlike the [`crate::state::EphemeralEventLog`], it's never reset, so clients can
+/// catch up on missed events by reading a single account.
+pub struct EventHistory<'a> {
+    pub header: &'a mut EventHistoryHeader,
+    pub records: &'a mut [EventRecord],
+}
+
+impl<'a> EventHistory<'a> {
+    pub const fn account_size(capacity: u16) -> usize {
+        EVENT_HISTORY_HEADER_SIZE + EVENT_RECORD_SIZE * capacity as usize
+    }
+
+    /// The largest capacity whose account can be created in a single instruction.
+    pub const fn max_capacity() -> u16 {
+        ((MAX_PERMITTED_DATA_INCREASE - EVENT_HISTORY_HEADER_SIZE) / EVENT_RECORD_SIZE) as u16
+    }
+
+    /// Initialize an empty history over allocated but uninitialized (zerod out) account data.
+    /// Every whole record after the header is part of the ring buffer.
+    pub fn init(
+        zerod_account_data: &'a mut [u8],
+        market: &Pubkey,
+        bump: u8,
+    ) -> Result<Self, DequeError> {
+        let history = Self::from_bytes_unchecked(zerod_account_data)?;
+        *history.header = EventHistoryHeader {
+            discriminant: EVENT_HISTORY_DISCRIMINANT,
+            market: *market,
+            next_seq: 0,
+            capacity: history.records.len() as u32,
+            bump,
+            version: EVENT_HISTORY_VERSION,
+            _padding: [0; 2],
+        };
+        Ok(history)
+    }
+
+    /// Cast account data to an event history and check the header's discriminant and version.
+    pub fn from_bytes(data: &'a mut [u8]) -> Result<Self, DequeError> {
+        Self::check_version(data)?;
+        let history = Self::from_bytes_unchecked(data)?;
+        if history.header.capacity as usize != history.records.len() {
+            return Err(DequeError::InvalidEventHistory);
+        }
+        Ok(history)
+    }
+
+    /// Check that `data` holds an event history of the current version. A history of an older
+    /// version fails with [`DequeError::EventHistoryNeedsMigration`].
+    pub fn check_version(data: &[u8]) -> Result<(), DequeError> {
+        if data.get(..8) != Some(&EVENT_HISTORY_DISCRIMINANT[..]) {
+            return Err(DequeError::InvalidDiscriminant);
+        }
+        match data.get(EVENT_HISTORY_VERSION_OFFSET).copied() {
+            Some(EVENT_HISTORY_VERSION) => Ok(()),
+            Some(version) if version < EVENT_HISTORY_VERSION => {
+                Err(DequeError::EventHistoryNeedsMigration)
+            }
+            _ => Err(DequeError::InvalidEventHistory),
+        }
+    }
+
+    fn from_bytes_unchecked(data: &'a mut [u8]) -> Result<Self, DequeError> {
+        if data.len() < EVENT_HISTORY_HEADER_SIZE + EVENT_RECORD_SIZE {
+            return Err(DequeError::InvalidEventHistory);
+        }
+        let (header_slab, records) = data.split_at_mut(EVENT_HISTORY_HEADER_SIZE);
+        let num_records = records.len() / EVENT_RECORD_SIZE;
+        let header = from_slab_bytes_mut::<EventHistoryHeader>(header_slab, 0)?;
+        let records = bytemuck::try_cast_slice_mut(&mut records[..num_records * EVENT_RECORD_SIZE])
+            .or(Err(DequeError::MalformedSlab))?;
+        Ok(Self { header, records })
+    }
+
+    #[inline(always)]
+    pub fn capacity(&self) -> u64 {
+        self.records.len() as u64
+    }
+
+    /// The number of events currently held, at most the capacity.
+    #[inline(always)]
+    pub fn len(&self) -> u64 {
+        self.header.next_seq.min(self.capacity())
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.header.next_seq == 0
+    }
+
+    /// Record a packed event, overwriting the oldest one if the buffer is full. Returns the event's
+    /// sequence number.
+    pub fn append(&mut self, slot: u64, packed_event: &[u8]) -> Result<u64, DequeError> {
+        if packed_event.len() > EVENT_RECORD_DATA_LEN {
+            return Err(DequeError::InvalidPackedData);
+        }
+
+        let seq = self.header.next_seq;
+        let record = &mut self.records[(seq % self.capacity()) as usize];
+        record.seq = seq;
+        record.slot = slot;
+        record.data[..packed_event.len()].copy_from_slice(packed_event);
+        record.data[packed_event.len()..].fill(0);
+
+        self.header.next_seq = seq.checked_add(1).ok_or(DequeError::ArithmetricError)?;
+        Ok(seq)
+    }
+
+    /// The recorded events, from oldest to newest.
+    pub fn iter(&self) -> impl Iterator<Item = &EventRecord> + '_ {
+        let next_seq = self.header.next_seq;
+        (next_seq - self.len()..next_seq).map(|seq| &self.records[(seq % self.capacity()) as usize])
+    }
+}
@@ -3,12 +3,20 @@ use solana_program::pubkey::Pubkey;
 
 use crate::instruction_enum::MarketChoice;
 
-#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Pod, Zeroable)]
 #[repr(C)]
 pub struct MarketEscrow {
     pub trader: Pubkey,
     pub base: u64,
     pub quote: u64,
+    /// The slot of the deposit that opened the escrow.
+    pub first_deposit_slot: u64,
+    /// The unix timestamp of the deposit that opened the escrow.
+    pub first_deposit_timestamp: i64,
+    /// The slot of the escrow's last deposit or withdrawal.
+    pub last_update_slot: u64,
+    /// The unix timestamp of the escrow's last deposit or withdrawal.
+    pub last_update_timestamp: i64,
 }
 
 impl MarketEscrow {
@@ -18,9 +26,31 @@ impl MarketEscrow {
             trader,
             base,
             quote,
+            first_deposit_slot: 0,
+            first_deposit_timestamp: 0,
+            last_update_slot: 0,
+            last_update_timestamp: 0,
         }
     }
 
+    /// Marks a new escrow as opened, and last updated, at `slot` and `unix_timestamp`.
+    #[inline(always)]
+    pub fn opened_at(self, slot: u64, unix_timestamp: i64) -> Self {
+        MarketEscrow {
+            first_deposit_slot: slot,
+            first_deposit_timestamp: unix_timestamp,
+            last_update_slot: slot,
+            last_update_timestamp: unix_timestamp,
+            ..self
+        }
+    }
+
+    #[inline(always)]
+    pub fn touch(&mut self, slot: u64, unix_timestamp: i64) {
+        self.last_update_slot = slot;
+        self.last_update_timestamp = unix_timestamp;
+    }
+
     #[inline(always)]
     pub fn amount_from_choice(&self, choice: &MarketChoice) -> u64 {
         match choice {
@@ -1,11 +1,13 @@
 pub mod deque;
 pub mod deque_header;
+pub mod deque_shard;
 pub mod event_data;
-pub mod free_stack;
+pub mod event_history;
 pub mod market;
 
 pub use deque::*;
 pub use deque_header::*;
+pub use deque_shard::*;
 pub use event_data::*;
-pub use free_stack::*;
+pub use event_history::*;
 pub use market::*;
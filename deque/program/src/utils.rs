@@ -1,20 +1,32 @@
 use core::mem::MaybeUninit;
 
 use solana_program::{
-    account_info::AccountInfo, entrypoint::ProgramResult, msg, program::invoke,
-    program_error::ProgramError, pubkey::Pubkey, rent::Rent, system_instruction, sysvar::Sysvar,
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction,
+    sysvar::Sysvar,
 };
 
 use crate::{
-    seeds,
+    context::deque_shards::DequeShardsContext,
+    seeds, shard_seeds_with_bump,
     shared::error::{DequeError, DequeProgramResult},
-    state::{Deque, DequeNode, MarketEscrow, Stack, DEQUE_HEADER_SIZE},
+    state::{
+        shard_sector_idx, DequeNode, DequeShard, MarketEscrow, ShardIndex, DEQUE_HEADER_SIZE,
+        DEQUE_SHARD_HEADER_SIZE, SECTORS_PER_SHARD,
+    },
+};
+
+pub use slab_deque::{
+    from_sector_idx, from_sector_idx_mut, from_slab_bytes, from_slab_bytes_mut, SectorIndex, Slab,
+    MAX_SHARDS, NIL,
 };
 
-/// The physical `sector` index in the slab of bytes dedicated to inner data for a type.
-/// That is, to get the raw bytes offset, it is multiplied by the sector type's sector size.
-pub type SectorIndex = u32;
-pub const NIL: SectorIndex = SectorIndex::MAX;
 pub const SECTOR_SIZE: usize = size_of::<DequeNode<MarketEscrow>>();
 
 /// Below is taken directly from:
@@ -36,67 +48,21 @@ impl From<bool> for PodBool {
     }
 }
 
-/// Marker trait to narrow an account data's Pod-based outer Node<T> type (used as a slice of
-/// mutable bytes: &mut [u8]) so that T can't also be passed.
-pub trait Slab: bytemuck::Pod {}
-
-#[inline(always)]
-pub fn from_slab_bytes<T: Slab>(data: &[u8], byte_offset: usize) -> Result<&T, ProgramError> {
-    // TODO: Optimize this to use `get_unchecked` with a prior safety check on the data/buffer size.
-    let size = core::mem::size_of::<T>();
-    let end = byte_offset
-        .checked_add(size)
-        .ok_or(DequeError::ArithmetricError)?;
-    let bytes = data.get(byte_offset..end).ok_or(DequeError::OutOfBounds)?;
-    bytemuck::try_from_bytes(bytes).map_err(|_| DequeError::MalformedSlab.into())
-}
-
-#[inline(always)]
-pub fn from_slab_bytes_mut<T: Slab>(
-    data: &mut [u8],
-    byte_offset: usize,
-) -> Result<&mut T, ProgramError> {
-    // TODO: Optimize this to use `get_unchecked` with a prior safety check on the data/buffer size.
-    let size = core::mem::size_of::<T>();
-    let end = byte_offset
-        .checked_add(size)
-        .ok_or(DequeError::ArithmetricError)?;
-    let bytes = data
-        .get_mut(byte_offset..end)
-        .ok_or(DequeError::OutOfBounds)?;
-    bytemuck::try_from_bytes_mut(bytes).map_err(|_| DequeError::MalformedSlab.into())
-}
-
-#[inline(always)]
-pub fn from_sector_idx<T: Slab>(sectors: &[u8], idx: SectorIndex) -> Result<&T, ProgramError> {
-    if idx == NIL {
-        return Err(DequeError::InvalidSectorIndex.into());
+/// Logs the bytes as hex at the `log-debug` level.
+pub fn log_bytes(bytes: &[u8]) {
+    if cfg!(feature = "log-debug") {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        msg!(&hex);
     }
-    let stride = size_of::<T>();
-    let start = (idx as usize)
-        .checked_mul(stride)
-        .ok_or(DequeError::ArithmetricError)?;
-    from_slab_bytes(sectors, start)
 }
 
 #[inline(always)]
-pub fn from_sector_idx_mut<T: Slab>(
-    sectors: &mut [u8],
-    idx: SectorIndex,
-) -> Result<&mut T, ProgramError> {
-    if idx == NIL {
-        return Err(DequeError::InvalidSectorIndex.into());
+pub fn check_owned(account: &AccountInfo) -> DequeProgramResult {
+    if account.owner.as_ref() != crate::ID.as_ref() {
+        Err(DequeError::AccountNotOwnedByProgram)
+    } else {
+        Ok(())
     }
-    let stride = size_of::<T>();
-    let start = (idx as usize)
-        .checked_mul(stride)
-        .ok_or(DequeError::ArithmetricError)?;
-    from_slab_bytes_mut(sectors, start)
-}
-
-pub fn log_bytes(bytes: &[u8]) {
-    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
-    msg!(&hex);
 }
 
 #[inline(always)]
@@ -159,9 +125,13 @@ pub fn fund_then_resize<'a, 'info>(
     Ok(())
 }
 
-#[inline(always)]
+/// Grow the deque by `num_sectors` free sectors.
+///
+/// The newest shard is reallocated in place while it has room for all of them. Otherwise a new
+/// shard holding all `num_sectors` sectors is created at the next shard PDA, which must be one of
+/// the uninitialized accounts in `ctx`.
 pub fn inline_deque_resize<'a, 'info>(
-    deque_account: &'a AccountInfo<'info>,
+    ctx: &mut DequeShardsContext<'a, 'info>,
     payer_account: &'a AccountInfo<'info>,
     system_program: &'a AccountInfo<'info>,
     num_sectors: u16,
@@ -170,31 +140,99 @@ pub fn inline_deque_resize<'a, 'info>(
         return Err(DequeError::MustBeGreaterThanOne.into());
     }
 
-    let current_size = deque_account.data_len();
+    let last_shard = ctx.with_deque(|deque| Ok(deque.header.last_shard))?;
+    let last_shard_account = ctx.shard(last_shard)?;
+    let header_size = match last_shard {
+        0 => DEQUE_HEADER_SIZE,
+        _ => DEQUE_SHARD_HEADER_SIZE,
+    };
+    let curr_n_sectors = (last_shard_account.data_len() - header_size) / SECTOR_SIZE;
+    let new_n_sectors = curr_n_sectors + num_sectors as usize;
 
-    fund_then_resize(
-        deque_account,
-        payer_account,
-        system_program,
-        SECTOR_SIZE * (num_sectors as usize),
-    )?;
+    let (shard, from, to) = if new_n_sectors <= SECTORS_PER_SHARD as usize {
+        fund_then_resize(
+            last_shard_account,
+            payer_account,
+            system_program,
+            SECTOR_SIZE * (num_sectors as usize),
+        )?;
+        (last_shard, curr_n_sectors, new_n_sectors)
+    } else {
+        let shard = create_next_shard(ctx, payer_account, system_program, num_sectors)?;
+        (shard, 0, num_sectors as usize)
+    };
 
-    // Now chain the old sectors to the new sectors in the stack of free nodes.
-    let mut deque_data = deque_account.data.borrow_mut();
-    let deque = Deque::from_bytes_unchecked(&mut deque_data)?;
+    // Now chain the new sectors to the existing ones in the stack of free nodes.
+    ctx.with_deque(|deque| {
+        deque
+            .extend_free_stack(
+                shard_sector_idx(shard, from as u32),
+                shard_sector_idx(shard, to as u32),
+            )
+            .map_err(DequeError::from)
+    })?;
 
-    let curr_n_sectors = (current_size - DEQUE_HEADER_SIZE) / SECTOR_SIZE;
-    let new_n_sectors = curr_n_sectors + num_sectors as usize;
+    Ok(())
+}
 
-    let mut free = Stack::<MarketEscrow>::new(deque.sectors, deque.header.free_head);
-    for i in curr_n_sectors..new_n_sectors {
-        free.push_to_free(i as SectorIndex)?;
-    }
-    deque.header.free_head = free.get_head();
+/// Create the shard after the deque's current last shard with room for `num_sectors` sectors and
+/// record it in the deque header. Returns the new shard's index.
+fn create_next_shard<'a, 'info>(
+    ctx: &mut DequeShardsContext<'a, 'info>,
+    payer_account: &'a AccountInfo<'info>,
+    system_program: &'a AccountInfo<'info>,
+    num_sectors: u16,
+) -> Result<ShardIndex, ProgramError> {
+    let shard = ctx.with_deque(|deque| {
+        deque
+            .header
+            .last_shard
+            .checked_add(1)
+            .filter(|shard| (*shard as u32) < MAX_SHARDS)
+            .ok_or(DequeError::InvalidShard)
+    })?;
 
-    drop(deque_data);
+    let deque_key = ctx.deque_account.key;
+    let (shard_pda, shard_bump) = seeds::shard::find_shard_address(deque_key, shard);
+    let shard_account = *ctx
+        .uninitialized
+        .iter()
+        .find(|account| account.key.as_ref() == shard_pda.as_ref())
+        .ok_or(DequeError::MissingShard)?;
 
-    Ok(())
+    let account_space = DEQUE_SHARD_HEADER_SIZE + SECTOR_SIZE * (num_sectors as usize);
+    let lamports_required = Rent::get()
+        .or(Err(DequeError::RentGetError))?
+        .minimum_balance(account_space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            payer_account.key,
+            shard_account.key,
+            lamports_required,
+            account_space as u64,
+            &crate::ID,
+        ),
+        &[
+            payer_account.clone(),
+            shard_account.clone(),
+            system_program.clone(),
+        ],
+        shard_seeds_with_bump!(deque_key, shard, shard_bump),
+    )?;
+
+    *from_slab_bytes_mut::<DequeShard>(&mut shard_account.try_borrow_mut_data()?, 0)
+        .map_err(DequeError::from)? = DequeShard::init(deque_key, shard, shard_bump);
+
+    ctx.with_deque(|deque| {
+        deque.header.last_shard = shard;
+        Ok(())
+    })?;
+    ctx.shards.push((shard, shard_account));
+    ctx.uninitialized
+        .retain(|account| account.key.as_ref() != shard_pda.as_ref());
+
+    Ok(shard)
 }
 
 pub const UNINIT_BYTE: MaybeUninit<u8> = MaybeUninit::uninit();
@@ -210,6 +248,7 @@ pub const UNINIT_BYTE: MaybeUninit<u8> = MaybeUninit::uninit();
 /// # Example
 /// ```
 /// use core::mem::MaybeUninit;
+/// use deque::utils::write_bytes;
 ///
 /// const UNINIT_BYTE: MaybeUninit<u8> = MaybeUninit::uninit();
 ///
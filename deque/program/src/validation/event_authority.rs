@@ -1,22 +1,80 @@
-use solana_program::{account_info::AccountInfo, program_error::ProgramError};
+use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
 
-use crate::{require, seeds};
+use crate::{
+    event_authority_seeds_with_bump, require,
+    seeds::event_authority::find_event_authority_address,
+    shared::error::DequeError,
+    state::{EphemeralEventHeader, EPHEMERAL_EVENT_LOG_HEADER_SIZE},
+    utils::from_slab_bytes,
+};
 
+/// The event authority PDA for a single market.
 #[derive(Clone)]
 pub struct EventAuthorityInfo<'a, 'info> {
     pub info: &'a AccountInfo<'info>,
+    /// The market the event authority is derived from.
+    pub market: Pubkey,
+    pub bump: u8,
 }
 
 impl<'a, 'info> EventAuthorityInfo<'a, 'info> {
+    /// Check an initialized event authority against the PDA derived from `market` and the bump
+    /// stored in its account data.
     pub fn new_checked(
         info: &'a AccountInfo<'info>,
+        market: &Pubkey,
     ) -> Result<EventAuthorityInfo<'a, 'info>, ProgramError> {
         require!(
-            info.key.as_ref() == seeds::event_authority::ID.as_ref(),
-            ProgramError::IncorrectAuthority,
+            info.owner.as_ref() == crate::ID.as_ref(),
+            DequeError::InvalidEventAuthority,
+            "Invalid event authority"
+        )?;
+        let bump = {
+            let data = info
+                .try_borrow_data()
+                .or(Err(DequeError::InvalidEventAuthorityBorrow))?;
+            let header = data
+                .get(..EPHEMERAL_EVENT_LOG_HEADER_SIZE)
+                .ok_or(DequeError::EventAuthorityNotAllocated)?;
+            let header =
+                from_slab_bytes::<EphemeralEventHeader>(header, 0).map_err(DequeError::from)?;
+            header.verify_discriminant()?;
+            header.get_bump()
+        };
+        let expected = Pubkey::create_program_address(
+            event_authority_seeds_with_bump!(market, bump)[0],
+            &crate::ID,
+        )
+        .or(Err(DequeError::InvalidEventAuthority))?;
+        require!(
+            info.key.as_ref() == expected.as_ref(),
+            DequeError::InvalidEventAuthority,
+            "Invalid event authority"
+        )?;
+
+        Ok(EventAuthorityInfo {
+            info,
+            market: *market,
+            bump,
+        })
+    }
+
+    /// Check a possibly uninitialized event authority by deriving the canonical PDA for `market`.
+    pub fn new_pda_checked(
+        info: &'a AccountInfo<'info>,
+        market: &Pubkey,
+    ) -> Result<EventAuthorityInfo<'a, 'info>, ProgramError> {
+        let (expected, bump) = find_event_authority_address(market);
+        require!(
+            info.key.as_ref() == expected.as_ref(),
+            DequeError::InvalidEventAuthority,
             "Invalid event authority"
         )?;
 
-        Ok(EventAuthorityInfo { info })
+        Ok(EventAuthorityInfo {
+            info,
+            market: *market,
+            bump,
+        })
     }
 }
@@ -0,0 +1,31 @@
+use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::{
+    require, shared::error::DequeError, state::EventHistory, utils::check_owned_and_writable,
+};
+
+/// An initialized event history for a single market.
+#[derive(Clone)]
+pub struct EventHistoryInfo<'a, 'info> {
+    pub info: &'a AccountInfo<'info>,
+}
+
+impl<'a, 'info> EventHistoryInfo<'a, 'info> {
+    /// Only this program can write the history's header, so it's enough to check the owner, the
+    /// discriminant and the market recorded in it.
+    pub fn new_checked(
+        info: &'a AccountInfo<'info>,
+        market: &Pubkey,
+    ) -> Result<EventHistoryInfo<'a, 'info>, ProgramError> {
+        check_owned_and_writable(info)?;
+        let mut data = info.try_borrow_mut_data()?;
+        let history = EventHistory::from_bytes(&mut data)?;
+        require!(
+            history.header.market.as_ref() == market.as_ref(),
+            DequeError::InvalidEventHistory,
+            "Event history belongs to a different market"
+        )?;
+
+        Ok(EventHistoryInfo { info })
+    }
+}
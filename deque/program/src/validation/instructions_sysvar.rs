@@ -0,0 +1,23 @@
+use solana_program::{
+    account_info::AccountInfo, program_error::ProgramError, sysvar::instructions,
+};
+
+use crate::{require, shared::error::DequeError};
+
+#[derive(Clone)]
+pub struct InstructionsSysvarInfo<'a, 'info> {
+    pub info: &'a AccountInfo<'info>,
+}
+
+impl<'a, 'info> InstructionsSysvarInfo<'a, 'info> {
+    pub fn new_checked(
+        info: &'a AccountInfo<'info>,
+    ) -> Result<InstructionsSysvarInfo<'a, 'info>, ProgramError> {
+        require!(
+            info.key.as_ref() == instructions::ID.as_ref(),
+            DequeError::InvalidInstructionsSysvar,
+            "Invalid instructions sysvar"
+        )?;
+        Ok(InstructionsSysvarInfo { info })
+    }
+}
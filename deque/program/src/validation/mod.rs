@@ -1,4 +1,6 @@
 pub mod event_authority;
+pub mod event_history;
+pub mod instructions_sysvar;
 pub mod self_program;
 pub mod system_program;
 pub mod token_accounts;
@@ -1,6 +1,6 @@
 use solana_program::{account_info::AccountInfo, program_error::ProgramError};
 
-use crate::require;
+use crate::{require, shared::error::DequeError};
 
 #[derive(Clone)]
 pub struct SelfProgramInfo<'a, 'info> {
@@ -13,7 +13,7 @@ impl<'a, 'info> SelfProgramInfo<'a, 'info> {
     ) -> Result<SelfProgramInfo<'a, 'info>, ProgramError> {
         require!(
             info.key.as_ref() == crate::ID.as_ref(),
-            ProgramError::IncorrectProgramId,
+            DequeError::InvalidSelfProgram,
             "Invalid self program ID"
         )?;
 
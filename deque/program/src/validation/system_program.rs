@@ -1,6 +1,6 @@
 use solana_program::{account_info::AccountInfo, program_error::ProgramError, system_program};
 
-use crate::require;
+use crate::{require, shared::error::DequeError};
 
 #[derive(Clone)]
 pub struct SystemProgramInfo<'a, 'info> {
@@ -13,7 +13,7 @@ impl<'a, 'info> SystemProgramInfo<'a, 'info> {
     ) -> Result<SystemProgramInfo<'a, 'info>, ProgramError> {
         require!(
             info.key.as_ref() == system_program::id().as_ref(),
-            ProgramError::IncorrectProgramId,
+            DequeError::InvalidSystemProgram,
             "Invalid system program ID"
         )?;
         Ok(SystemProgramInfo { info })
@@ -1,6 +1,6 @@
 use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
 
-use crate::require;
+use crate::{require, shared::error::DequeError};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TokenProgram {
@@ -28,20 +28,20 @@ impl<'a, 'info> TokenAccountInfo<'a, 'info> {
     ) -> Result<TokenAccountInfo<'a, 'info>, ProgramError> {
         require!(
             info.owner == &spl_token::id() || info.owner == &spl_token_2022::id(),
-            ProgramError::IllegalOwner,
+            DequeError::TokenAccountNotOwnedByTokenProgram,
             "Associated token account owner must be owned by a token program: {:?}",
             mint.as_ref()
         )?;
 
         require!(
             &info.try_borrow_data()?[32..64] == owner.as_ref(),
-            ProgramError::IllegalOwner,
+            DequeError::TokenAccountOwnerMismatch,
             "Token account owner doesn't match expected owner"
         )?;
 
         require!(
             &info.try_borrow_data()?[0..32] == mint.as_ref(),
-            ProgramError::InvalidInstructionData,
+            DequeError::TokenAccountMintMismatch,
             "Token account mint doesn't match expected mint"
         )?;
 
@@ -55,14 +55,14 @@ impl<'a, 'info> TokenAccountInfo<'a, 'info> {
         expected_pubkey: &Pubkey,
     ) -> Result<TokenAccountInfo<'a, 'info>, ProgramError> {
         if info.key != expected_pubkey {
-            return Err(ProgramError::InvalidInstructionData);
+            return Err(DequeError::UnexpectedTokenAccount.into());
         }
         Self::new_checked_owners(info, mint, owner)
     }
 
     /// Get an account's balance from the token amount in its associated token account data.
     ///
-    /// ```
+    /// ```text
     /// pub struct Account {
     ///     pub mint: Pubkey, // 32
     ///     pub owner: Pubkey, // 32
@@ -79,7 +79,7 @@ impl<'a, 'info> TokenAccountInfo<'a, 'info> {
 
     /// Get the mint for an associated token account.
     ///
-    /// ```
+    /// ```text
     /// pub struct Account {
     ///     pub mint: Pubkey, // 32
     ///     // ...
@@ -97,7 +97,7 @@ impl<'a, 'info> TokenAccountInfo<'a, 'info> {
     /// That is, get the nominal owner of the tokens, not the literal account owner of the
     /// associated token account, which is one of the two token programs.
     ///
-    /// ```
+    /// ```text
     /// pub struct Account {
     ///     pub mint: Pubkey, // 32
     ///     pub owner: Pubkey, // 32
@@ -125,7 +125,7 @@ impl<'a, 'info> TokenProgramInfo<'a, 'info> {
         let is_spl_token = info.key.as_ref() == spl_token::id().as_ref();
         require!(
             is_spl_token || info.key.as_ref() == spl_token_2022::id().as_ref(),
-            ProgramError::IncorrectProgramId,
+            DequeError::InvalidTokenProgram,
             "Token program isn't valid"
         )?;
 
@@ -153,7 +153,7 @@ impl<'a, 'info> AssociatedTokenProgramInfo<'a, 'info> {
     ) -> Result<AssociatedTokenProgramInfo<'a, 'info>, ProgramError> {
         require!(
             info.key.as_ref() == spl_associated_token_account::id().as_ref(),
-            ProgramError::IncorrectProgramId,
+            DequeError::InvalidAssociatedTokenProgram,
             "Associated token program isn't valid"
         )?;
 
@@ -178,7 +178,7 @@ impl<'a, 'info> TokenMintInfo<'a, 'info> {
         require!(
             info.owner.as_ref() == spl_token::id().as_ref()
                 || info.owner.as_ref() == spl_token_2022::id().as_ref(),
-            ProgramError::IncorrectProgramId,
+            DequeError::InvalidMintOwner,
             "Mint account must be owned by a valid token program"
         )?;
         Ok(TokenMintInfo { info })
@@ -186,7 +186,7 @@ impl<'a, 'info> TokenMintInfo<'a, 'info> {
 
     /// Get the `decimals` for a mint.
     ///
-    /// ```
+    /// ```text
     /// pub struct Mint {
     ///     pub mint_authority: COption<Pubkey>, // 36
     ///     pub supply: u64, // 8
@@ -1,6 +1,6 @@
 use solana_program::{account_info::AccountInfo, program_error::ProgramError, system_program};
 
-use crate::require;
+use crate::{require, shared::error::DequeError};
 
 /// Represents a completely uninitialized account.
 #[derive(Clone)]
@@ -14,12 +14,12 @@ impl<'a, 'info> UninitializedAccountInfo<'a, 'info> {
     ) -> Result<UninitializedAccountInfo<'a, 'info>, ProgramError> {
         require!(
             info.data_is_empty(),
-            ProgramError::InvalidAccountData,
+            DequeError::AccountAlreadyInitialized,
             "Account must be uninitialized"
         )?;
         require!(
             info.owner.as_ref() == system_program::id().as_ref(),
-            ProgramError::InvalidAccountOwner,
+            DequeError::AccountNotOwnedBySystemProgram,
             "Uninitialized accounts must be owned by the system program"
         )?;
         Ok(UninitializedAccountInfo { info })
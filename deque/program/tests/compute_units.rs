@@ -0,0 +1,265 @@
+//! Compares the compute units each instruction consumes in the built sBPF program against
+//! `snapshots/compute_units.snap`.
+//!
+//! Only the `sbf-tests` crate measures compute units, the native runner returns early. Run it with
+//! `UPDATE_COMPUTE_UNITS=1` to rewrite the snapshot after an intended change, and commit the
+//! snapshot with the change so the difference shows up in review.
+
+mod svm;
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use deque::instruction_enum::MarketChoice;
+use solana_program::pubkey::Pubkey;
+use svm::{market::TestMarket, Processed, TestSvm};
+
+const TRADER_COUNTS: [u16; 3] = [1, 100, 1000];
+/// How much an instruction may exceed its snapshot by before the test fails.
+const REGRESSION_THRESHOLD_PERCENT: u64 = 2;
+const UPDATE_ENV_VAR: &str = "UPDATE_COMPUTE_UNITS";
+
+/// The features the program was built with that change what it consumes, each measured against
+/// its own snapshot. Programs built with `log-events` don't flush their events with a CPI, and the
+/// log levels add `msg!` calls.
+fn feature_suffix(with_log_level: bool) -> String {
+    let mut suffix = String::new();
+    if cfg!(feature = "log-events") {
+        suffix.push_str(".log-events");
+    }
+    if with_log_level && cfg!(feature = "log-debug") {
+        suffix.push_str(".log-debug");
+    } else if with_log_level && cfg!(feature = "log-info") {
+        suffix.push_str(".log-info");
+    }
+    suffix
+}
+
+fn snapshot_path(suffix: &str) -> PathBuf {
+    // Relative to the manifest of either crate that runs these tests.
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../program/tests/snapshots")
+        .join(format!("compute_units{suffix}.snap"))
+}
+
+fn read_snapshot(path: &Path) -> Measurements {
+    parse_snapshot(&fs::read_to_string(path).unwrap_or_else(|err| {
+        panic!("Couldn't read {path:?}, record it with {UPDATE_ENV_VAR}=1: {err}")
+    }))
+}
+
+/// Every measurement, keyed by `<instruction>/<number of traders>`.
+type Measurements = BTreeMap<String, u64>;
+
+/// The compute units consumed by the `FlushEventLog` self-CPIs, read from the runtime's
+/// `Program <id> consumed <units> of <limit> compute units` logs of nested invocations.
+fn flush_units(logs: &[String]) -> Option<u64> {
+    let deque_id = deque::ID.to_string();
+    let mut invoke_stack: Vec<&str> = vec![];
+    let mut units = None;
+    for log in logs {
+        let Some(rest) = log.strip_prefix("Program ") else {
+            continue;
+        };
+        let mut words = rest.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some(program_id), Some("invoke"), _) => invoke_stack.push(program_id),
+            (Some(program_id), Some("consumed"), Some(consumed))
+                if program_id == deque_id && invoke_stack.len() > 1 =>
+            {
+                *units.get_or_insert(0) += consumed.parse::<u64>().expect("Should be a number");
+            }
+            (Some(_), Some("success" | "failed:"), _) => {
+                invoke_stack.pop();
+            }
+            _ => (),
+        }
+    }
+    units
+}
+
+fn record(measurements: &mut Measurements, name: &str, traders: u16, processed: Processed) {
+    let processed = processed.unwrap();
+    let units = processed
+        .compute_units
+        .expect("Only the sBPF backend measures compute units");
+    measurements.insert(format!("{name}/{traders}"), units);
+    if let Some(units) = flush_units(&processed.logs) {
+        measurements.insert(format!("{name}.flush/{traders}"), units);
+    }
+}
+
+/// Fills a deque with `num_traders` escrows holding both sides, then measures each trader
+/// instruction and a reconcile against it.
+fn measure_with_traders(measurements: &mut Measurements, num_traders: u16) {
+    let mut svm = TestSvm::new();
+    // Each new trader grows the deque by a sector, so it's always full.
+    let market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 1);
+    let traders: Vec<Pubkey> = (0..num_traders)
+        .map(|_| {
+            let trader = market.trader(&mut svm, 1_000);
+            for choice in [MarketChoice::Base, MarketChoice::Quote] {
+                svm.process(&market.deposit(&trader, 100, choice)).unwrap();
+            }
+            trader
+        })
+        .collect();
+    // The first trader is at the back of the deque, the furthest from the front.
+    let oldest = traders[0];
+    let newcomer = market.trader(&mut svm, 1_000);
+
+    let ixns = [
+        (
+            "deposit_existing",
+            market.deposit(&oldest, 1, MarketChoice::Base),
+        ),
+        // The deque is full, so this also grows it by a sector.
+        (
+            "deposit_new",
+            market.deposit(&newcomer, 1, MarketChoice::Base),
+        ),
+        ("reconcile", market.reconcile(true)),
+        (
+            "withdraw_one_side",
+            market.withdraw(&oldest, MarketChoice::Base),
+        ),
+        // Removes the escrow.
+        (
+            "withdraw_last_side",
+            market.withdraw(&oldest, MarketChoice::Quote),
+        ),
+    ];
+    for (name, ixn) in ixns {
+        record(measurements, name, num_traders, svm.process(&ixn));
+    }
+}
+
+fn parse_snapshot(contents: &str) -> Measurements {
+    contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (name, units) = line
+                .split_once(' ')
+                .unwrap_or_else(|| panic!("Malformed snapshot line {line:?}"));
+            (
+                name.to_string(),
+                units.trim().parse().expect("Should be a number"),
+            )
+        })
+        .collect()
+}
+
+fn format_snapshot(measurements: &Measurements) -> String {
+    let mut contents = format!(
+        "# Compute units per instruction in the sBPF program, see `tests/compute_units.rs`.\n\
+         # Regenerate with `{UPDATE_ENV_VAR}=1 cargo test --test compute_units` in `sbf-tests`.\n"
+    );
+    for (name, units) in measurements {
+        contents.push_str(&format!("{name} {units}\n"));
+    }
+    contents
+}
+
+/// Measures every instruction once for all the tests in this file.
+fn measurements() -> &'static Measurements {
+    static MEASUREMENTS: OnceLock<Measurements> = OnceLock::new();
+    MEASUREMENTS.get_or_init(|| {
+        let mut measurements = Measurements::new();
+        for num_traders in TRADER_COUNTS {
+            measure_with_traders(&mut measurements, num_traders);
+        }
+        measurements
+    })
+}
+
+#[test]
+fn compute_units_match_snapshot() {
+    if !cfg!(deque_sbf) {
+        // The native runner doesn't meter compute units.
+        return;
+    }
+
+    let measurements = measurements();
+    let path = snapshot_path(&feature_suffix(true));
+    if std::env::var_os(UPDATE_ENV_VAR).is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, format_snapshot(measurements)).unwrap();
+        return;
+    }
+
+    let snapshot = read_snapshot(&path);
+    let mut failures = vec![];
+    for (name, &units) in measurements {
+        match snapshot.get(name) {
+            None => failures.push(format!("{name}: {units} units, not in the snapshot")),
+            Some(&expected) if units * 100 > expected * (100 + REGRESSION_THRESHOLD_PERCENT) => {
+                failures.push(format!("{name}: {units} units, up from {expected}"))
+            }
+            Some(&expected) if units < expected => {
+                println!("{name}: {units} units, down from {expected}")
+            }
+            Some(_) => {}
+        }
+    }
+    for name in snapshot
+        .keys()
+        .filter(|name| !measurements.contains_key(*name))
+    {
+        failures.push(format!("{name}: in the snapshot but no longer measured"));
+    }
+    assert!(
+        failures.is_empty(),
+        "Compute units regressed by more than {REGRESSION_THRESHOLD_PERCENT}%, rerun with \
+         {UPDATE_ENV_VAR}=1 if this is intended:\n{}",
+        failures.join("\n")
+    );
+}
+
+/// Compares a program built with a log level against the snapshot of the same program without
+/// one, which is what production builds leave out.
+#[test]
+fn log_levels_cost_compute_units() {
+    if !cfg!(deque_sbf) || !cfg!(feature = "log-info") {
+        return;
+    }
+
+    let without_logs = read_snapshot(&snapshot_path(&feature_suffix(false)));
+    let mut cheaper = vec![];
+    for (name, &units) in measurements() {
+        let Some(&off) = without_logs.get(name) else {
+            continue;
+        };
+        println!("{name}: {off} units without logs, {units} with them");
+        if units < off {
+            cheaper.push(name.as_str());
+        }
+    }
+    assert!(
+        cheaper.is_empty(),
+        "Logging made these cheaper, rerecord the snapshot without logs: {cheaper:?}"
+    );
+}
+
+#[test]
+fn flush_units_are_read_from_nested_invocations() {
+    let deque_id = deque::ID;
+    let token_program = spl_token::ID;
+    let logs = [
+        format!("Program {deque_id} invoke [1]"),
+        format!("Program {token_program} invoke [2]"),
+        format!("Program {token_program} consumed 4645 of 195000 compute units"),
+        format!("Program {token_program} success"),
+        format!("Program {deque_id} invoke [2]"),
+        format!("Program {deque_id} consumed 1200 of 180000 compute units"),
+        format!("Program {deque_id} success"),
+        format!("Program {deque_id} consumed 21000 of 200000 compute units"),
+        format!("Program {deque_id} success"),
+    ];
+    assert_eq!(flush_units(&logs), Some(1200));
+    assert_eq!(flush_units(&logs[..4]), None);
+}
@@ -0,0 +1,285 @@
+mod svm;
+
+use deque::{
+    events::DequeEvent,
+    instruction_enum::{FlushEventLogInstructionData, InstructionTag, MarketChoice},
+    pack::Pack,
+    seeds::event_authority::find_event_authority_address,
+    shared::{error::DequeError, error_context::UNKNOWN},
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+use svm::{
+    market::{TestMarket, PAYER_LAMPORTS},
+    Processed, TestSvm,
+};
+
+fn setup() -> (TestSvm, TestMarket, Pubkey) {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 1);
+    let trader = market.trader(&mut svm, 100);
+    (svm, market, trader)
+}
+
+/// Asserts that the instruction failed with `error` and logged a matching error context.
+fn assert_fails_with(
+    processed: Processed,
+    tag: InstructionTag,
+    error: DequeError,
+    account_index: u8,
+) {
+    let ctx = processed
+        .error_context()
+        .expect("Should log an error context");
+    assert_eq!(processed.unwrap_err(), ProgramError::from(error));
+    assert_eq!(ctx.deque_error(), Some(error));
+    assert_eq!(ctx.instruction_tag, tag as u8);
+    assert_eq!(ctx.account_index, account_index);
+}
+
+#[test]
+fn deposit_with_the_other_mint() {
+    let (mut svm, market, trader) = setup();
+    let mut ixn = market.deposit(&trader, 10, MarketChoice::Base);
+    ixn.accounts[8].pubkey = market.quote_mint;
+
+    let processed = svm.process(&ixn);
+    assert_fails_with(
+        processed,
+        InstructionTag::Deposit,
+        DequeError::MarketMintMismatch,
+        8,
+    );
+    assert!(market.escrows(&svm).is_empty());
+}
+
+#[test]
+fn deposit_more_than_the_balance() {
+    let (mut svm, market, trader) = setup();
+    for choice in [MarketChoice::Base, MarketChoice::Quote] {
+        let processed = svm.process(&market.deposit(&trader, 101, choice));
+        // `TokenError::InsufficientFunds`, from the token program.
+        assert_eq!(processed.unwrap_err(), ProgramError::Custom(1));
+        assert_eq!(svm.token_balance(&market.ata(&trader, choice)), 100);
+    }
+    assert!(market.escrows(&svm).is_empty());
+}
+
+#[test]
+fn withdraw_without_an_escrow() {
+    let (mut svm, market, trader) = setup();
+    let processed = svm.process(&market.withdraw(&trader, MarketChoice::Quote));
+    assert_fails_with(
+        processed,
+        InstructionTag::Withdraw,
+        DequeError::NoActiveEscrow,
+        UNKNOWN,
+    );
+}
+
+#[test]
+fn deposit_with_the_wrong_token_program() {
+    let (mut svm, market, trader) = setup();
+    let mut ixn = market.deposit(&trader, 10, MarketChoice::Base);
+    ixn.accounts[7].pubkey = system_program::ID;
+
+    let processed = svm.process(&ixn);
+    assert_fails_with(
+        processed,
+        InstructionTag::Deposit,
+        DequeError::InvalidTokenProgram,
+        7,
+    );
+}
+
+#[test]
+fn deposit_with_the_wrong_event_authority() {
+    let (mut svm, market, trader) = setup();
+    let mut ixn = market.deposit(&trader, 10, MarketChoice::Base);
+    ixn.accounts[1].pubkey = Pubkey::new_unique();
+
+    let processed = svm.process(&ixn);
+    assert_fails_with(
+        processed,
+        InstructionTag::Deposit,
+        DequeError::InvalidEventAuthority,
+        1,
+    );
+}
+
+#[test]
+fn flush_as_a_top_level_instruction() {
+    let (mut svm, market, _) = setup();
+    let ixn = Instruction {
+        program_id: deque::ID,
+        accounts: vec![
+            AccountMeta::new_readonly(market.event_authority, false),
+            AccountMeta::new_readonly(sysvar::instructions::ID, false),
+        ],
+        data: FlushEventLogInstructionData {}.pack().to_vec(),
+    };
+
+    let processed = svm.process(&ixn);
+    assert_fails_with(
+        processed,
+        InstructionTag::FlushEventLog,
+        DequeError::FlushNotInvokedBySelf,
+        UNKNOWN,
+    );
+}
+
+#[test]
+fn initialize_twice() {
+    let (mut svm, market, _) = setup();
+    let lamports = svm.lamports(&market.payer);
+
+    let processed = svm.process(&market.initialize_deque(1));
+    assert_fails_with(
+        processed,
+        InstructionTag::InitializeDeque,
+        DequeError::AccountAlreadyInitialized,
+        UNKNOWN,
+    );
+    // Nothing is written back when an instruction fails.
+    assert_eq!(svm.lamports(&market.payer), lamports);
+    assert!(lamports < PAYER_LAMPORTS);
+}
+
+#[test]
+fn reconcile_a_vault_in_deficit() {
+    let (mut svm, market, trader) = setup();
+    svm.process(&market.deposit(&trader, 30, MarketChoice::Quote))
+        .unwrap();
+    let quote_vault = market.vault(MarketChoice::Quote);
+    svm.set_token_balance(&quote_vault, 20);
+
+    // Without `fail_on_deficit` the deficit is only reported.
+    let processed = svm.process(&market.reconcile(false)).unwrap();
+    let Some(DequeEvent::Reconcile(quote)) = processed.events().last().copied() else {
+        panic!("Expected the quote side to be reconciled last");
+    };
+    assert_eq!((quote.escrowed, quote.deficit), (30, 10));
+
+    let processed = svm.process(&market.reconcile(true));
+    assert_fails_with(
+        processed,
+        InstructionTag::Reconcile,
+        DequeError::VaultDeficit,
+        UNKNOWN,
+    );
+}
+
+#[test]
+fn reconcile_with_a_token_account_that_isnt_the_vault() {
+    let (mut svm, market, _) = setup();
+    // A token account owned by the deque, but not at the vault's address.
+    let decoy = svm.create_token_account(&market.base_mint, &Pubkey::new_unique(), 1_000);
+    let mut account = svm.account(&decoy).unwrap().clone();
+    account.data[32..64].copy_from_slice(market.deque.as_ref());
+    svm.set_account(decoy, account);
+
+    let mut ixn = market.reconcile(true);
+    ixn.accounts[5].pubkey = decoy;
+    assert_fails_with(
+        svm.process(&ixn),
+        InstructionTag::Reconcile,
+        DequeError::UnexpectedTokenAccount,
+        UNKNOWN,
+    );
+}
+
+#[test]
+fn deposit_into_a_read_only_shard() {
+    let (mut svm, mut market, trader) = setup();
+    market.add_shard(&mut svm, 1);
+    svm.process(&market.deposit(&trader, 10, MarketChoice::Base))
+        .unwrap();
+    let [(_, sector)] = market.escrow_sectors(&svm)[..] else {
+        panic!("Expected a single escrow");
+    };
+
+    let deposit = market.deposit(&trader, 10, MarketChoice::Base);
+    let processed = svm.process(&market.with_shards(deposit, Some(sector), &[(1, false)]));
+    assert_fails_with(
+        processed,
+        InstructionTag::Deposit,
+        DequeError::AccountIsNotWritable,
+        UNKNOWN,
+    );
+}
+
+#[test]
+fn defragment_without_every_shard() {
+    let (mut svm, mut market, _) = setup();
+    market.add_shard(&mut svm, 1);
+
+    let mut ixn = market.defragment(1);
+    ixn.accounts.pop();
+    assert_fails_with(
+        svm.process(&ixn),
+        InstructionTag::Defragment,
+        DequeError::MissingShard,
+        UNKNOWN,
+    );
+}
+
+#[test]
+fn initialize_an_event_authority_for_an_account_that_isnt_the_market() {
+    let mut svm = TestSvm::new();
+    let mut market = TestMarket::new(&mut svm, spl_token::ID, spl_token::ID);
+    market.deque = Pubkey::new_unique();
+    market.event_authority = find_event_authority_address(&market.deque).0;
+
+    assert_fails_with(
+        svm.process(&market.initialize_event_authority()),
+        InstructionTag::InitializeEventAuthority,
+        DequeError::InvalidPDA,
+        3,
+    );
+    assert_eq!(svm.account(&market.event_authority), None);
+}
+
+#[test]
+fn deposit_without_the_markets_event_history() {
+    let (mut svm, market, trader) = setup();
+    svm.process(&market.initialize_event_history(4)).unwrap();
+
+    // `market.event_history` is still `None`, so the program ID placeholder is passed.
+    assert_fails_with(
+        svm.process(&market.deposit(&trader, 10, MarketChoice::Base)),
+        InstructionTag::Deposit,
+        DequeError::InvalidEventHistory,
+        2,
+    );
+    assert!(market.escrows(&svm).is_empty());
+}
+
+#[test]
+fn close_a_trader_record_while_holding_an_escrow() {
+    let (mut svm, mut market, trader) = setup();
+    market.add_shard(&mut svm, 1);
+    svm.process(&market.deposit(&trader, 10, MarketChoice::Base))
+        .unwrap();
+
+    assert_fails_with(
+        svm.process(&market.close_trader_record(&trader)),
+        InstructionTag::CloseTraderRecord,
+        DequeError::TraderHasEscrow,
+        0,
+    );
+    assert!(svm.account(&market.trader_record(&trader)).is_some());
+
+    // Without every shard, the trader's escrow could be in one that wasn't searched.
+    let mut ixn = market.close_trader_record(&trader);
+    ixn.accounts.pop();
+    assert_fails_with(
+        svm.process(&ixn),
+        InstructionTag::CloseTraderRecord,
+        DequeError::MissingShard,
+        UNKNOWN,
+    );
+}
@@ -1,5 +1,651 @@
-#[cfg(test)]
-mod layout_tests {
-    #[test]
-    fn happy_path() {}
+mod svm;
+
+use deque::{
+    events::DequeEvent,
+    instruction_enum::{InstructionTag, MarketChoice},
+    instructions::migrate_deque::MIGRATE_SECTORS_PER_CALL,
+    seeds::{event_history::find_event_history_address, market::find_market_address},
+    shared::error::DequeError,
+    state::{
+        shard_of, shard_sector_idx, Deque, EventHistory, MarketEscrow, DEQUE_ACCOUNT_DISCRIMINANT,
+        DEQUE_LAYOUTS, DEQUE_VERSION, DEQUE_VERSION_OFFSET, EVENT_HISTORY_HEADER_SIZE,
+        EVENT_HISTORY_VERSION, EVENT_HISTORY_VERSION_OFFSET, EVENT_RECORD_SIZE,
+    },
+    utils::{NIL, SECTOR_SIZE},
+};
+use solana_program::{
+    entrypoint::MAX_PERMITTED_DATA_INCREASE, pubkey::Pubkey, rent::Rent, system_program,
+};
+use svm::{market::TestMarket, Account, TestSvm};
+
+const TOKEN_PROGRAMS: [Pubkey; 2] = [spl_token::ID, spl_token_2022::ID];
+
+fn deque_capacity(svm: &TestSvm, market: &TestMarket) -> u32 {
+    let mut data = svm.account(&market.deque).unwrap().data.clone();
+    Deque::from_bytes(&mut data).unwrap().capacity()
+}
+
+/// An escrow opened and last updated at the test svm's default clock.
+fn escrow(trader: Pubkey, base: u64, quote: u64) -> MarketEscrow {
+    MarketEscrow::new(trader, base, quote).opened_at(1, 0)
+}
+
+#[test]
+fn initialize_and_resize_event_authority() {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::new(&mut svm, spl_token::ID, spl_token::ID);
+
+    svm.process(&market.initialize_event_authority()).unwrap();
+    let event_authority = svm.account(&market.event_authority).unwrap().clone();
+    assert_eq!(event_authority.owner, deque::ID);
+    assert_eq!(event_authority.data.len(), MAX_PERMITTED_DATA_INCREASE);
+
+    svm.process(&market.resize_event_authority()).unwrap();
+    let resized = svm.account(&market.event_authority).unwrap();
+    assert_eq!(resized.data.len(), 2 * MAX_PERMITTED_DATA_INCREASE);
+    assert!(resized.lamports > event_authority.lamports);
+}
+
+#[test]
+fn market_lifecycle() {
+    for base_token_program in TOKEN_PROGRAMS {
+        for quote_token_program in TOKEN_PROGRAMS {
+            market_lifecycle_with(base_token_program, quote_token_program);
+        }
+    }
+}
+
+fn market_lifecycle_with(base_token_program: Pubkey, quote_token_program: Pubkey) {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::initialized(&mut svm, base_token_program, quote_token_program, 1);
+
+    let deque_account = svm.account(&market.deque).unwrap();
+    assert_eq!(deque_account.owner, deque::ID);
+    assert_eq!(deque_capacity(&svm, &market), 1);
+    for choice in [MarketChoice::Base, MarketChoice::Quote] {
+        let vault = svm.account(&market.vault(choice)).unwrap();
+        assert_eq!(vault.owner, market.mint(choice).1);
+        assert_eq!(svm.token_balance(&market.vault(choice)), 0);
+    }
+
+    let alice = market.trader(&mut svm, 1_000);
+    let bob = market.trader(&mut svm, 1_000);
+
+    // A deposit emits the header followed by the deposit event.
+    let processed = svm
+        .process(&market.deposit(&alice, 300, MarketChoice::Base))
+        .unwrap();
+    let events = processed.events();
+    assert_eq!(events.len(), 2);
+    let DequeEvent::Header(header) = &events[0] else {
+        panic!("Expected a header, got {:?}", events[0]);
+    };
+    assert_eq!(header.instruction_tag, InstructionTag::Deposit);
+    assert_eq!(header.market, &market.deque);
+    assert_eq!(header.sender, &alice);
+    let DequeEvent::Deposit(deposit) = &events[1] else {
+        panic!("Expected a deposit, got {:?}", events[1]);
+    };
+    assert_eq!(
+        (deposit.trader, deposit.amount, deposit.side),
+        (&alice, 300, MarketChoice::Base)
+    );
+
+    // Depositing the other side updates the trader's existing escrow.
+    svm.process(&market.deposit(&alice, 200, MarketChoice::Quote))
+        .unwrap();
+    assert_eq!(market.escrows(&svm), [escrow(alice, 300, 200)]);
+
+    // A new trader grows the full deque by a sector.
+    svm.process(&market.deposit(&bob, 50, MarketChoice::Quote))
+        .unwrap();
+    assert_eq!(deque_capacity(&svm, &market), 2);
+    assert_eq!(
+        market.escrows(&svm),
+        [escrow(bob, 0, 50), escrow(alice, 300, 200)]
+    );
+    assert_eq!(svm.token_balance(&market.vault(MarketChoice::Base)), 300);
+    assert_eq!(svm.token_balance(&market.vault(MarketChoice::Quote)), 250);
+    assert_eq!(
+        svm.token_balance(&market.ata(&alice, MarketChoice::Base)),
+        700
+    );
+
+    // Withdrawing one side zeroes it out and keeps the escrow.
+    let processed = svm
+        .process(&market.withdraw(&alice, MarketChoice::Base))
+        .unwrap();
+    let DequeEvent::Withdraw(withdraw) = &processed.events()[1] else {
+        panic!("Expected a withdrawal, got {:?}", processed.events());
+    };
+    assert_eq!(
+        (withdraw.trader, withdraw.amount, withdraw.side),
+        (&alice, 300, MarketChoice::Base)
+    );
+    assert_eq!(
+        svm.token_balance(&market.ata(&alice, MarketChoice::Base)),
+        1_000
+    );
+    assert_eq!(
+        market.escrows(&svm),
+        [escrow(bob, 0, 50), escrow(alice, 0, 200)]
+    );
+
+    // Withdrawing the last side removes the escrow.
+    svm.process(&market.withdraw(&alice, MarketChoice::Quote))
+        .unwrap();
+    assert_eq!(market.escrows(&svm), [escrow(bob, 0, 50)]);
+    assert_eq!(svm.token_balance(&market.vault(MarketChoice::Quote)), 50);
+    assert_eq!(
+        svm.token_balance(&market.ata(&alice, MarketChoice::Quote)),
+        1_000
+    );
+
+    // Only deposits and withdrawals flush their events.
+    let processed = svm.process(&market.resize(3)).unwrap();
+    assert!(processed.events().is_empty());
+    assert_eq!(deque_capacity(&svm, &market), 5);
+    assert_eq!(
+        svm.account(&market.deque).unwrap().data.len() % SECTOR_SIZE,
+        deque::state::DEQUE_HEADER_SIZE % SECTOR_SIZE
+    );
+
+    svm.process(&market.defragment(8)).unwrap();
+    assert_eq!(market.escrows(&svm), [escrow(bob, 0, 50)]);
+}
+
+#[test]
+fn event_history_records_emitted_events() {
+    let mut svm = TestSvm::new();
+    let mut market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 2);
+    svm.process(&market.initialize_event_history(4)).unwrap();
+    market.event_history = Some(find_event_history_address(&market.deque).0);
+
+    let trader = market.trader(&mut svm, 100);
+    svm.set_slot(42);
+    svm.process(&market.deposit(&trader, 10, MarketChoice::Base))
+        .unwrap();
+    svm.set_slot(43);
+    svm.process(&market.withdraw(&trader, MarketChoice::Base))
+        .unwrap();
+
+    let mut data = svm
+        .account(&market.event_history.unwrap())
+        .unwrap()
+        .data
+        .clone();
+    let history = EventHistory::from_bytes(&mut data).unwrap();
+    assert_eq!(history.len(), 2);
+    let slots: Vec<u64> = history.iter().map(|record| record.slot).collect();
+    assert_eq!(slots, [42, 43]);
+    let DequeEvent::Withdraw(withdraw) =
+        DequeEvent::unpack(&history.iter().last().unwrap().data).unwrap()
+    else {
+        panic!("Expected the withdrawal to be recorded last");
+    };
+    assert_eq!((withdraw.trader, withdraw.amount), (&trader, 10));
+}
+
+#[test]
+fn reconcile_escrows_with_the_vaults() {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 2);
+    let (alice, bob) = (market.trader(&mut svm, 100), market.trader(&mut svm, 100));
+    svm.process(&market.deposit(&alice, 40, MarketChoice::Base))
+        .unwrap();
+    svm.process(&market.deposit(&bob, 25, MarketChoice::Quote))
+        .unwrap();
+    svm.process(&market.deposit(&bob, 5, MarketChoice::Base))
+        .unwrap();
+    svm.process(&market.withdraw(&alice, MarketChoice::Base))
+        .unwrap();
+
+    let mut data = svm.account(&market.deque).unwrap().data.clone();
+    let header = *Deque::from_bytes(&mut data).unwrap().header;
+    assert_eq!((header.total_base, header.total_quote), (5, 25));
+
+    // Tokens sent straight to a vault show up as a surplus.
+    let base_vault = market.vault(MarketChoice::Base);
+    svm.set_token_balance(&base_vault, svm.token_balance(&base_vault) + 7);
+    let processed = svm.process(&market.reconcile(true)).unwrap();
+    let [DequeEvent::Header(header), DequeEvent::Reconcile(base), DequeEvent::Reconcile(quote)] =
+        processed.events()[..]
+    else {
+        panic!("Expected a header and an event for each side");
+    };
+    assert_eq!(header.instruction_tag, InstructionTag::Reconcile);
+    assert_eq!(header.sender, &market.deque);
+    assert_eq!(
+        (base.side, base.escrowed, base.recorded, base.vault_balance),
+        (MarketChoice::Base, 5, 5, 12)
+    );
+    assert_eq!((base.surplus, base.deficit), (7, 0));
+    assert_eq!(
+        (quote.escrowed, quote.recorded, quote.surplus, quote.deficit),
+        (25, 25, 0, 0)
+    );
+}
+
+#[test]
+fn header_tracks_market_statistics() {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 1);
+    let (alice, bob) = (market.trader(&mut svm, 100), market.trader(&mut svm, 100));
+    let header = |svm: &TestSvm| {
+        let mut data = svm.account(&market.deque).unwrap().data.clone();
+        *Deque::from_bytes(&mut data).unwrap().header
+    };
+
+    svm.set_slot(10);
+    svm.process(&market.deposit(&alice, 40, MarketChoice::Base))
+        .unwrap();
+    svm.process(&market.deposit(&bob, 25, MarketChoice::Quote))
+        .unwrap();
+    svm.set_slot(11);
+    svm.process(&market.withdraw(&alice, MarketChoice::Base))
+        .unwrap();
+    // Alice emptied her escrow, so depositing again opens a new one, but her trader record
+    // already exists and she isn't counted again.
+    svm.set_slot(12);
+    svm.process(&market.deposit(&alice, 5, MarketChoice::Quote))
+        .unwrap();
+
+    let header = header(&svm);
+    assert_eq!(header.version, deque::state::DEQUE_VERSION);
+    assert_eq!((header.total_base, header.total_quote), (0, 30));
+    assert_eq!((header.deposited_base, header.deposited_quote), (40, 30));
+    assert_eq!((header.withdrawn_base, header.withdrawn_quote), (40, 0));
+    assert_eq!(header.unique_traders, 2);
+    assert_eq!(header.peak_len, 2);
+    assert_eq!(header.last_activity_slot, 12);
+    assert_eq!(
+        svm.account(&market.trader_record(&alice)).unwrap().owner,
+        deque::ID
+    );
+}
+
+#[test]
+fn first_deposit_creates_a_trader_record_someone_already_funded() {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 1);
+    let trader = market.trader(&mut svm, 100);
+    let record = market.trader_record(&trader);
+    // Anyone can send lamports to the PDA, which would make creating it outright fail.
+    svm.set_account(
+        record,
+        Account {
+            lamports: 1,
+            owner: system_program::ID,
+            ..Default::default()
+        },
+    );
+
+    svm.process(&market.deposit(&trader, 10, MarketChoice::Base))
+        .unwrap();
+
+    let record = svm.account(&record).unwrap();
+    assert_eq!(record.owner, deque::ID);
+    assert_eq!(record.lamports, Rent::default().minimum_balance(0));
+    let mut data = svm.account(&market.deque).unwrap().data.clone();
+    assert_eq!(
+        Deque::from_bytes(&mut data).unwrap().header.unique_traders,
+        1
+    );
+}
+
+#[test]
+fn close_a_trader_record_for_its_rent() {
+    let mut svm = TestSvm::new();
+    let mut market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 1);
+    market.add_shard(&mut svm, 1);
+    let trader = market.trader(&mut svm, 100);
+    let record = market.trader_record(&trader);
+    let unique_traders = |svm: &TestSvm| {
+        let mut data = svm.account(&market.deque).unwrap().data.clone();
+        Deque::from_bytes(&mut data).unwrap().header.unique_traders
+    };
+
+    svm.process(&market.deposit(&trader, 10, MarketChoice::Base))
+        .unwrap();
+    svm.process(&market.withdraw(&trader, MarketChoice::Base))
+        .unwrap();
+    let lamports = svm.lamports(&trader);
+    svm.process(&market.close_trader_record(&trader)).unwrap();
+
+    assert_eq!(svm.account(&record), None);
+    assert_eq!(
+        svm.lamports(&trader),
+        lamports + Rent::default().minimum_balance(0)
+    );
+
+    // The count isn't decremented, so depositing again counts the trader again.
+    svm.process(&market.deposit(&trader, 10, MarketChoice::Base))
+        .unwrap();
+    assert_eq!(svm.account(&record).unwrap().owner, deque::ID);
+    assert_eq!(unique_traders(&svm), 2);
+}
+
+#[test]
+fn escrows_and_events_record_when_they_happened() {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 1);
+    let trader = market.trader(&mut svm, 100);
+
+    svm.set_clock(20, 1_700_000_000);
+    let processed = svm
+        .process(&market.deposit(&trader, 10, MarketChoice::Base))
+        .unwrap();
+    let DequeEvent::Deposit(deposit) = processed.events()[1] else {
+        panic!("Expected a deposit, got {:?}", processed.events());
+    };
+    assert_eq!(deposit.slot, 20);
+
+    svm.set_clock(30, 1_700_000_004);
+    svm.process(&market.deposit(&trader, 5, MarketChoice::Quote))
+        .unwrap();
+    svm.set_clock(45, 1_700_000_010);
+    let processed = svm
+        .process(&market.withdraw(&trader, MarketChoice::Base))
+        .unwrap();
+    let DequeEvent::Withdraw(withdraw) = processed.events()[1] else {
+        panic!("Expected a withdrawal, got {:?}", processed.events());
+    };
+    assert_eq!(withdraw.slot, 45);
+
+    let [escrow] = market.escrows(&svm)[..] else {
+        panic!("Expected a single escrow");
+    };
+    assert_eq!(
+        (escrow.first_deposit_slot, escrow.first_deposit_timestamp),
+        (20, 1_700_000_000)
+    );
+    assert_eq!(
+        (escrow.last_update_slot, escrow.last_update_timestamp),
+        (45, 1_700_000_010)
+    );
+}
+
+/// A deque account as the original program laid it out: a 96-byte header without any running
+/// totals, then 56-byte sectors holding 48-byte escrows.
+fn baseline_deque(
+    market: &TestMarket,
+    num_sectors: u32,
+    escrows: &[(u32, MarketEscrow)],
+) -> Vec<u8> {
+    let (_, bump) = find_market_address(&market.base_mint, &market.quote_mint);
+    let sector = |idx: u32| escrows.iter().position(|(sector, _)| *sector == idx);
+    let link = |i: Option<usize>| i.map_or(NIL, |i| escrows[i].0);
+    let free: Vec<u32> = (0..num_sectors)
+        .filter(|idx| sector(*idx).is_none())
+        .collect();
+
+    let mut data = DEQUE_ACCOUNT_DISCRIMINANT.to_vec();
+    let head = link((!escrows.is_empty()).then_some(0));
+    let tail = link(escrows.len().checked_sub(1));
+    for value in [
+        escrows.len() as u32,
+        free.first().copied().unwrap_or(NIL),
+        head,
+        tail,
+    ] {
+        data.extend(value.to_le_bytes());
+    }
+    data.extend(market.base_mint.as_ref());
+    data.extend(market.quote_mint.as_ref());
+    data.extend([0, bump]);
+    data.resize(96, 0);
+    for idx in 0..num_sectors {
+        let (inner, links) = match sector(idx) {
+            Some(i) => (
+                bytemuck::bytes_of(&escrows[i].1)[..48].to_vec(),
+                [
+                    link(i.checked_sub(1)),
+                    link(Some(i + 1).filter(|i| *i < escrows.len())),
+                ],
+            ),
+            None => {
+                let next = free.iter().find(|free| **free > idx).copied();
+                (vec![0; 48], [next.unwrap_or(NIL), 0])
+            }
+        };
+        data.extend(inner);
+        data.extend(links.iter().flat_map(|link| link.to_le_bytes()));
+    }
+    data
+}
+
+#[test]
+fn migrate_a_baseline_deque_then_withdraw() {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 1);
+    let (alice, bob, carol) = (
+        market.trader(&mut svm, 0),
+        market.trader(&mut svm, 0),
+        market.trader(&mut svm, 0),
+    );
+    // Enough sectors that growing the deque and rewriting its sectors both take several calls.
+    let num_sectors = 2500;
+    let data = baseline_deque(
+        &market,
+        num_sectors,
+        &[
+            (0, MarketEscrow::new(alice, 0, 40)),
+            (1500, MarketEscrow::new(carol, 3, 2)),
+            (2499, MarketEscrow::new(bob, 7, 0)),
+        ],
+    );
+    svm.set_account(
+        market.deque,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: deque::ID,
+            ..Default::default()
+        },
+    );
+    svm.set_token_balance(&market.vault(MarketChoice::Base), 10);
+    svm.set_token_balance(&market.vault(MarketChoice::Quote), 42);
+
+    let version = |svm: &TestSvm| svm.account(&market.deque).unwrap().data[DEQUE_VERSION_OFFSET];
+    let mut calls = 0;
+    while version(&svm) != DEQUE_VERSION {
+        let processed = svm.process(&market.withdraw(&alice, MarketChoice::Quote));
+        assert_eq!(
+            processed.unwrap_err(),
+            DequeError::DequeNeedsMigration.into()
+        );
+        svm.process(&market.migrate_deque()).unwrap();
+        calls += 1;
+    }
+    let [from, to] = DEQUE_LAYOUTS;
+    let growth = to.header_size - from.header_size
+        + num_sectors as usize * (to.sector_size - from.sector_size);
+    // The call that finishes growing the deque also rewrites the first batch of sectors.
+    assert_eq!(
+        calls,
+        growth.div_ceil(MAX_PERMITTED_DATA_INCREASE)
+            + (num_sectors as usize).div_ceil(MIGRATE_SECTORS_PER_CALL)
+            - 1
+    );
+    assert_eq!(deque_capacity(&svm, &market), num_sectors);
+    assert_eq!(
+        market.escrows(&svm),
+        [
+            MarketEscrow::new(alice, 0, 40),
+            MarketEscrow::new(carol, 3, 2),
+            MarketEscrow::new(bob, 7, 0)
+        ]
+    );
+    let mut data = svm.account(&market.deque).unwrap().data.clone();
+    let header = *Deque::from_bytes(&mut data).unwrap().header;
+    assert_eq!((header.total_base, header.total_quote), (10, 42));
+    assert_eq!((header.peak_len, header.unique_traders), (3, 3));
+
+    svm.process(&market.withdraw(&alice, MarketChoice::Quote))
+        .unwrap();
+    assert_eq!(
+        svm.token_balance(&market.ata(&alice, MarketChoice::Quote)),
+        40
+    );
+    assert_eq!(
+        market.escrows(&svm),
+        [MarketEscrow::new(carol, 3, 2), MarketEscrow::new(bob, 7, 0)]
+    );
+    let processed = svm.process(&market.reconcile(true)).unwrap();
+    assert!(processed.events().iter().all(
+        |event| !matches!(event, DequeEvent::Reconcile(side) if side.deficit + side.surplus != 0)
+    ));
+
+    // Migrating a deque that's already current does nothing.
+    let data = svm.account(&market.deque).unwrap().data.clone();
+    svm.process(&market.migrate_deque()).unwrap();
+    assert_eq!(svm.account(&market.deque).unwrap().data, data);
+}
+
+/// Rewrites a current event history's records the way version 0 of the history laid them out:
+/// 64-byte records holding version 1 deposit and withdrawal events without their slot.
+fn downgrade_event_history(svm: &mut TestSvm, market: &TestMarket) {
+    let key = market.event_history.unwrap();
+    let mut history = svm.account(&key).unwrap();
+    let (header, records) = history.data.split_at(EVENT_HISTORY_HEADER_SIZE);
+    let mut data = header.to_vec();
+    for record in records.chunks_exact(EVENT_RECORD_SIZE) {
+        let mut record = record[..64].to_vec();
+        if record[16] != 0 {
+            // The version and body length, then the slot's first bytes that no longer fit.
+            record[17..20].copy_from_slice(&[1, 41, 0]);
+            record[16 + 45..].fill(0);
+        }
+        data.extend(record);
+    }
+    data[EVENT_HISTORY_VERSION_OFFSET] = 0;
+    history.data = data;
+    svm.set_account(key, history);
+}
+
+#[test]
+fn migrate_an_older_event_history() {
+    let mut svm = TestSvm::new();
+    let mut market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 2);
+    svm.process(&market.initialize_event_history(4)).unwrap();
+    market.event_history = Some(find_event_history_address(&market.deque).0);
+    let (alice, bob) = (market.trader(&mut svm, 100), market.trader(&mut svm, 100));
+    svm.set_slot(10);
+    svm.process(&market.deposit(&alice, 40, MarketChoice::Base))
+        .unwrap();
+    svm.set_slot(11);
+    svm.process(&market.deposit(&bob, 25, MarketChoice::Quote))
+        .unwrap();
+    let deque = svm.account(&market.deque).unwrap().data;
+
+    downgrade_event_history(&mut svm, &market);
+    let processed = svm.process(&market.withdraw(&alice, MarketChoice::Base));
+    assert_eq!(
+        processed.unwrap_err(),
+        DequeError::EventHistoryNeedsMigration.into()
+    );
+
+    // The history only grows by a fraction of its size, so a single call migrates it. The deque is
+    // already current, so it's left as it is.
+    svm.process(&market.migrate_deque()).unwrap();
+    assert_eq!(svm.account(&market.deque).unwrap().data, deque);
+
+    svm.set_slot(12);
+    svm.process(&market.withdraw(&alice, MarketChoice::Base))
+        .unwrap();
+    let mut data = svm.account(&market.event_history.unwrap()).unwrap().data;
+    assert_eq!(data[EVENT_HISTORY_VERSION_OFFSET], EVENT_HISTORY_VERSION);
+    let history = EventHistory::from_bytes(&mut data).unwrap();
+    let events: Vec<(u64, u64, u64)> = history
+        .iter()
+        .map(|record| match DequeEvent::unpack(&record.data).unwrap() {
+            DequeEvent::Deposit(deposit) => (record.slot, deposit.amount, deposit.slot),
+            DequeEvent::Withdraw(withdraw) => (record.slot, withdraw.amount, withdraw.slot),
+            event => panic!("Unexpected event {event:?}"),
+        })
+        .collect();
+    // The events recorded before the migration are version 1 events, without their slot.
+    assert_eq!(events, [(10, 40, 0), (11, 25, 0), (12, 40, 12)]);
+}
+
+#[test]
+fn sharded_deque_only_locks_the_shards_it_touches() {
+    let mut svm = TestSvm::new();
+    let mut market = TestMarket::initialized(&mut svm, spl_token::ID, spl_token_2022::ID, 2);
+    market.add_shard(&mut svm, 2);
+    let traders: Vec<Pubkey> = (0..3).map(|_| market.trader(&mut svm, 100)).collect();
+    for trader in &traders {
+        svm.process(&market.deposit(trader, 10, MarketChoice::Base))
+            .unwrap();
+    }
+
+    // The new shard's sectors were freed last, so the first two escrows were opened in it.
+    let sectors = market.escrow_sectors(&svm);
+    let escrowed = |sectors: &[(MarketEscrow, u32)]| -> Vec<(Pubkey, u64)> {
+        sectors
+            .iter()
+            .map(|(escrow, _)| (escrow.trader, escrow.base))
+            .collect()
+    };
+    assert_eq!(
+        escrowed(&sectors),
+        [(traders[2], 10), (traders[1], 10), (traders[0], 10)]
+    );
+    let shards: Vec<_> = sectors.iter().map(|(_, idx)| shard_of(*idx)).collect();
+    assert_eq!(shards, [0, 1, 1]);
+    let hint = |i: usize| Some(sectors[i].1);
+
+    // An escrow in the deque account doesn't need any shard, one in a shard only needs that one.
+    let deposit = market.deposit(&traders[2], 1, MarketChoice::Base);
+    svm.process(&market.with_shards(deposit, hint(0), &[]))
+        .unwrap();
+    let deposit = market.deposit(&traders[0], 1, MarketChoice::Base);
+    svm.process(&market.with_shards(deposit, hint(2), &[(1, true)]))
+        .unwrap();
+
+    // Without a hint, or with a stale one, the deque is searched. Shards that are only searched can
+    // be read-only.
+    let deposit = market.deposit(&traders[2], 1, MarketChoice::Base);
+    svm.process(&market.with_shards(deposit, None, &[(1, false)]))
+        .unwrap();
+    let deposit = market.deposit(&traders[0], 1, MarketChoice::Base);
+    svm.process(&market.with_shards(deposit, hint(0), &[(1, true)]))
+        .unwrap();
+
+    // Emptying an escrow unlinks it, which writes to its neighbors in both shards.
+    let withdraw = market.withdraw(&traders[1], MarketChoice::Base);
+    svm.process(&market.with_shards(withdraw, hint(1), &[(1, true)]))
+        .unwrap();
+    assert_eq!(
+        escrowed(&market.escrow_sectors(&svm)),
+        [(traders[2], 12), (traders[0], 12)]
+    );
+
+    // Defragmenting packs the escrows into the deque account's sectors first.
+    svm.process(&market.defragment(8)).unwrap();
+    let sectors = market.escrow_sectors(&svm);
+    assert_eq!(escrowed(&sectors), [(traders[2], 12), (traders[0], 12)]);
+    let packed: Vec<_> = sectors.iter().map(|(_, idx)| *idx).collect();
+    assert_eq!(packed, [shard_sector_idx(0, 0), shard_sector_idx(0, 1)]);
+}
+
+/// Token-2022 transfers are `transfer_checked`, which takes the mint between the source and the
+/// destination. Both directions must pass it to the CPI.
+#[test]
+fn token_2022_vault_transfers_pass_the_mint() {
+    let mut svm = TestSvm::new();
+    let market = TestMarket::initialized(&mut svm, spl_token_2022::ID, spl_token_2022::ID, 1);
+    let trader = market.trader(&mut svm, 100);
+
+    for choice in [MarketChoice::Base, MarketChoice::Quote] {
+        svm.process(&market.deposit(&trader, 40, choice)).unwrap();
+        assert_eq!(svm.token_balance(&market.vault(choice)), 40);
+        assert_eq!(svm.token_balance(&market.ata(&trader, choice)), 60);
+    }
+    for choice in [MarketChoice::Base, MarketChoice::Quote] {
+        svm.process(&market.withdraw(&trader, choice)).unwrap();
+        assert_eq!(svm.token_balance(&market.vault(choice)), 0);
+        assert_eq!(svm.token_balance(&market.ata(&trader, choice)), 100);
+    }
+    assert!(market.escrows(&svm).is_empty());
 }
@@ -1 +0,0 @@
-pub mod happy_path;
@@ -0,0 +1,418 @@
+//! Builds the deque program's instructions for a market in a [`TestSvm`].
+
+use deque::{
+    instruction_enum::{
+        CloseTraderRecordInstructionData, DefragmentInstructionData, DepositInstructionData,
+        DequeInstruction, InitializeDequeInstructionData, InitializeEventAuthorityInstructionData,
+        InitializeEventHistoryInstructionData, MarketChoice, MigrateDequeInstructionData,
+        ReconcileInstructionData, ResizeEventAuthorityInstructionData, ResizeInstructionData,
+        WithdrawInstructionData,
+    },
+    pack::Pack,
+    seeds::{
+        event_authority::find_event_authority_address, event_history::find_event_history_address,
+        market::find_market_address, shard::find_shard_address, trader::find_trader_address,
+    },
+    state::{
+        shard_sector_idx, Deque, DequeShard, MarketEscrow, ShardIndex, ShardedDeque,
+        ShardedSectors, DEQUE_SHARD_HEADER_SIZE,
+    },
+    utils::{from_slab_bytes_mut, SectorIndex},
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    rent::Rent,
+    system_program, sysvar,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+
+use super::{Account, TestSvm};
+
+pub const PAYER_LAMPORTS: u64 = 100_000_000_000;
+
+pub struct TestMarket {
+    pub payer: Pubkey,
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub base_token_program: Pubkey,
+    pub quote_token_program: Pubkey,
+    pub deque: Pubkey,
+    pub event_authority: Pubkey,
+    /// Passed to event emitting instructions once it's set, otherwise the program ID is.
+    pub event_history: Option<Pubkey>,
+    /// Every shard in `1..=last_shard` is passed to the instructions that take shards.
+    pub last_shard: ShardIndex,
+}
+
+impl TestMarket {
+    /// Creates the market's mints without initializing anything in the deque program.
+    pub fn new(svm: &mut TestSvm, base_token_program: Pubkey, quote_token_program: Pubkey) -> Self {
+        let payer = Pubkey::new_unique();
+        svm.airdrop(&payer, PAYER_LAMPORTS);
+        let base_mint = svm.create_mint(&base_token_program, 6);
+        let quote_mint = svm.create_mint(&quote_token_program, 9);
+        let deque = find_market_address(&base_mint, &quote_mint).0;
+        TestMarket {
+            payer,
+            base_mint,
+            quote_mint,
+            base_token_program,
+            quote_token_program,
+            deque,
+            event_authority: find_event_authority_address(&deque).0,
+            event_history: None,
+            last_shard: 0,
+        }
+    }
+
+    /// Creates the market and initializes its event authority and deque.
+    pub fn initialized(
+        svm: &mut TestSvm,
+        base_token_program: Pubkey,
+        quote_token_program: Pubkey,
+        num_sectors: u16,
+    ) -> Self {
+        let market = Self::new(svm, base_token_program, quote_token_program);
+        svm.process(&market.initialize_event_authority()).unwrap();
+        svm.process(&market.initialize_deque(num_sectors)).unwrap();
+        market
+    }
+
+    pub fn mint(&self, choice: MarketChoice) -> (Pubkey, Pubkey) {
+        match choice {
+            MarketChoice::Base => (self.base_mint, self.base_token_program),
+            MarketChoice::Quote => (self.quote_mint, self.quote_token_program),
+        }
+    }
+
+    pub fn vault(&self, choice: MarketChoice) -> Pubkey {
+        let (mint, token_program) = self.mint(choice);
+        get_associated_token_address_with_program_id(&self.deque, &mint, &token_program)
+    }
+
+    pub fn ata(&self, owner: &Pubkey, choice: MarketChoice) -> Pubkey {
+        let (mint, token_program) = self.mint(choice);
+        get_associated_token_address_with_program_id(owner, &mint, &token_program)
+    }
+
+    fn event_emitter_metas(&self, event_authority_writable: bool) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(deque::ID, false),
+            match event_authority_writable {
+                true => AccountMeta::new(self.event_authority, false),
+                false => AccountMeta::new_readonly(self.event_authority, false),
+            },
+            match self.event_history {
+                Some(event_history) => AccountMeta::new(event_history, false),
+                None => AccountMeta::new_readonly(deque::ID, false),
+            },
+            AccountMeta::new_readonly(sysvar::instructions::ID, false),
+        ]
+    }
+
+    fn event_authority_metas(&self) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.payer, true),
+            AccountMeta::new(self.event_authority, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(self.deque, false),
+            AccountMeta::new_readonly(self.base_mint, false),
+            AccountMeta::new_readonly(self.quote_mint, false),
+        ]
+    }
+
+    pub fn initialize_event_authority(&self) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            accounts: self.event_authority_metas(),
+            data: InitializeEventAuthorityInstructionData {}.pack().to_vec(),
+        }
+    }
+
+    pub fn resize_event_authority(&self) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            accounts: self.event_authority_metas(),
+            data: ResizeEventAuthorityInstructionData {}.pack().to_vec(),
+        }
+    }
+
+    pub fn initialize_event_history(&self, capacity: u16) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            accounts: vec![
+                AccountMeta::new(self.payer, true),
+                AccountMeta::new(find_event_history_address(&self.deque).0, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new(self.deque, false),
+            ],
+            data: InitializeEventHistoryInstructionData { capacity }
+                .pack()
+                .to_vec(),
+        }
+    }
+
+    pub fn initialize_deque(&self, num_sectors: u16) -> Instruction {
+        let mut accounts = self.event_emitter_metas(false);
+        accounts.extend([
+            AccountMeta::new(self.payer, true),
+            AccountMeta::new(self.deque, false),
+            AccountMeta::new_readonly(self.base_mint, false),
+            AccountMeta::new_readonly(self.quote_mint, false),
+            AccountMeta::new(self.vault(MarketChoice::Base), false),
+            AccountMeta::new(self.vault(MarketChoice::Quote), false),
+            AccountMeta::new_readonly(self.base_token_program, false),
+            AccountMeta::new_readonly(self.quote_token_program, false),
+            AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]);
+        Instruction {
+            program_id: deque::ID,
+            accounts,
+            data: InitializeDequeInstructionData { num_sectors }
+                .pack()
+                .to_vec(),
+        }
+    }
+
+    pub fn resize(&self, num_sectors: u16) -> Instruction {
+        let mut accounts = self.event_emitter_metas(false);
+        accounts.extend([
+            AccountMeta::new(self.payer, true),
+            AccountMeta::new(self.deque, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]);
+        Instruction {
+            program_id: deque::ID,
+            accounts,
+            data: ResizeInstructionData { num_sectors }.pack().to_vec(),
+        }
+    }
+
+    pub fn shard(&self, shard: ShardIndex) -> Pubkey {
+        find_shard_address(&self.deque, shard).0
+    }
+
+    /// Every shard after the deque account itself.
+    pub fn shard_metas(&self, writable: bool) -> Vec<AccountMeta> {
+        (1..=self.last_shard)
+            .map(|shard| match writable {
+                true => AccountMeta::new(self.shard(shard), false),
+                false => AccountMeta::new_readonly(self.shard(shard), false),
+            })
+            .collect()
+    }
+
+    /// Appends a shard with `num_sectors` free sectors by writing the accounts directly, since the
+    /// program only creates one once shard 0 holds `SECTORS_PER_SHARD` sectors.
+    pub fn add_shard(&mut self, svm: &mut TestSvm, num_sectors: u32) -> Pubkey {
+        let shard = self.last_shard + 1;
+        let (key, bump) = find_shard_address(&self.deque, shard);
+        let mut deque_data = svm
+            .account(&self.deque)
+            .expect("Deque should exist")
+            .clone();
+        let mut shard_data =
+            vec![0; DEQUE_SHARD_HEADER_SIZE + num_sectors as usize * Deque::SECTOR_SIZE];
+        *from_slab_bytes_mut::<DequeShard>(&mut shard_data, 0).expect("Should cast") =
+            DequeShard::init(&self.deque, shard, bump);
+
+        {
+            let Deque { header, sectors } =
+                Deque::from_bytes(&mut deque_data.data).expect("Should be a deque");
+            header.last_shard = shard;
+            let mut shards = ShardedSectors::new();
+            shards.insert(0, sectors).expect("Should insert");
+            shards
+                .insert(shard, &mut shard_data[DEQUE_SHARD_HEADER_SIZE..])
+                .expect("Should insert");
+            ShardedDeque::from_parts(header, shards)
+                .expect("Should verify")
+                .extend_free_stack(
+                    shard_sector_idx(shard, 0),
+                    shard_sector_idx(shard, num_sectors),
+                )
+                .expect("Should extend");
+        }
+
+        svm.set_account(self.deque, deque_data);
+        svm.set_account(
+            key,
+            Account {
+                lamports: Rent::default().minimum_balance(shard_data.len()),
+                data: shard_data,
+                owner: deque::ID,
+                ..Default::default()
+            },
+        );
+        self.last_shard = shard;
+        key
+    }
+
+    pub fn defragment(&self, max_moves: u16) -> Instruction {
+        let mut accounts = vec![AccountMeta::new(self.deque, false)];
+        accounts.extend(self.shard_metas(true));
+        Instruction {
+            program_id: deque::ID,
+            accounts,
+            data: DefragmentInstructionData { max_moves }.pack().to_vec(),
+        }
+    }
+
+    /// The accounts of a deposit or withdrawal. Only deposits pass the trader's record.
+    fn trader_metas(
+        &self,
+        trader: &Pubkey,
+        choice: MarketChoice,
+        is_deposit: bool,
+    ) -> Vec<AccountMeta> {
+        let (mint, token_program) = self.mint(choice);
+        let mut accounts = self.event_emitter_metas(true);
+        accounts.extend([
+            AccountMeta::new(self.deque, false),
+            AccountMeta::new(*trader, true),
+            AccountMeta::new(self.ata(trader, choice), false),
+            AccountMeta::new_readonly(token_program, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new(self.vault(choice), false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]);
+        if is_deposit {
+            accounts.push(AccountMeta::new(self.trader_record(trader), false));
+        }
+        accounts.extend(self.shard_metas(true));
+        accounts
+    }
+
+    pub fn trader_record(&self, trader: &Pubkey) -> Pubkey {
+        find_trader_address(&self.deque, trader).0
+    }
+
+    pub fn deposit(&self, trader: &Pubkey, amount: u64, choice: MarketChoice) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            accounts: self.trader_metas(trader, choice, true),
+            data: DepositInstructionData::new(amount, choice).pack().to_vec(),
+        }
+    }
+
+    pub fn withdraw(&self, trader: &Pubkey, choice: MarketChoice) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            accounts: self.trader_metas(trader, choice, false),
+            data: WithdrawInstructionData::new(choice).pack().to_vec(),
+        }
+    }
+
+    pub fn close_trader_record(&self, trader: &Pubkey) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new(*trader, true),
+            AccountMeta::new_readonly(self.deque, false),
+            AccountMeta::new(self.trader_record(trader), false),
+        ];
+        accounts.extend(self.shard_metas(false));
+        Instruction {
+            program_id: deque::ID,
+            accounts,
+            data: CloseTraderRecordInstructionData {}.pack().to_vec(),
+        }
+    }
+
+    /// Points a deposit or withdrawal at the escrow in sector `hint`, if any, and passes only
+    /// `shards` after the fixed accounts, each as `(shard, writable)`.
+    pub fn with_shards(
+        &self,
+        mut ixn: Instruction,
+        hint: Option<SectorIndex>,
+        shards: &[(ShardIndex, bool)],
+    ) -> Instruction {
+        ixn.data = match DequeInstruction::unpack(&ixn.data).expect("Should unpack") {
+            DequeInstruction::Deposit(data) => data.with_escrow_hint(hint).pack().to_vec(),
+            DequeInstruction::Withdraw(data) => data.with_escrow_hint(hint).pack().to_vec(),
+            _ => panic!("Only deposits and withdrawals take an escrow hint"),
+        };
+        ixn.accounts
+            .truncate(ixn.accounts.len() - self.last_shard as usize);
+        ixn.accounts
+            .extend(shards.iter().map(|(shard, writable)| match writable {
+                true => AccountMeta::new(self.shard(*shard), false),
+                false => AccountMeta::new_readonly(self.shard(*shard), false),
+            }));
+        ixn
+    }
+
+    pub fn reconcile(&self, fail_on_deficit: bool) -> Instruction {
+        let mut accounts = self.event_emitter_metas(true);
+        accounts.extend([
+            AccountMeta::new_readonly(self.deque, false),
+            AccountMeta::new_readonly(self.vault(MarketChoice::Base), false),
+            AccountMeta::new_readonly(self.vault(MarketChoice::Quote), false),
+        ]);
+        accounts.extend(self.shard_metas(false));
+        Instruction {
+            program_id: deque::ID,
+            accounts,
+            data: ReconcileInstructionData { fail_on_deficit }.pack().to_vec(),
+        }
+    }
+
+    pub fn migrate_deque(&self) -> Instruction {
+        Instruction {
+            program_id: deque::ID,
+            accounts: vec![
+                AccountMeta::new(self.payer, true),
+                AccountMeta::new(self.deque, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new(find_event_history_address(&self.deque).0, false),
+            ],
+            data: MigrateDequeInstructionData {}.pack().to_vec(),
+        }
+    }
+
+    /// Creates a trader with `amount` tokens of both the base and the quote mint.
+    pub fn trader(&self, svm: &mut TestSvm, amount: u64) -> Pubkey {
+        let trader = Pubkey::new_unique();
+        svm.airdrop(&trader, PAYER_LAMPORTS);
+        svm.create_token_account(&self.base_mint, &trader, amount);
+        svm.create_token_account(&self.quote_mint, &trader, amount);
+        trader
+    }
+
+    /// Every escrow in the deque with the sector it's in, from front to back.
+    pub fn escrow_sectors(&self, svm: &TestSvm) -> Vec<(MarketEscrow, SectorIndex)> {
+        let mut data = svm
+            .account(&self.deque)
+            .expect("Deque should exist")
+            .data
+            .clone();
+        let mut shard_data: Vec<Vec<u8>> = (1..=self.last_shard)
+            .map(|shard| {
+                let account = svm.account(&self.shard(shard)).expect("Shard should exist");
+                account.data.clone()
+            })
+            .collect();
+
+        let Deque { header, sectors } = Deque::from_bytes(&mut data).expect("Should be a deque");
+        let mut shards = ShardedSectors::new();
+        shards.insert(0, sectors).expect("Should insert");
+        for (shard, data) in (1..).zip(shard_data.iter_mut()) {
+            shards
+                .insert(shard, &mut data[DEQUE_SHARD_HEADER_SIZE..])
+                .expect("Should insert");
+        }
+        let deque = ShardedDeque::from_parts(header, shards).expect("Should verify");
+        deque
+            .iter_nodes()
+            .map(|(escrow, idx)| (*escrow, idx))
+            .collect()
+    }
+
+    /// Every escrow in the deque, from front to back.
+    pub fn escrows(&self, svm: &TestSvm) -> Vec<MarketEscrow> {
+        self.escrow_sectors(svm)
+            .into_iter()
+            .map(|(escrow, _)| escrow)
+            .collect()
+    }
+}
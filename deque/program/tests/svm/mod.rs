@@ -0,0 +1,138 @@
+//! The deque program's instructions processed by a [`TestSvm`], with helpers for setting up
+//! accounts and decoding what each instruction emitted.
+//!
+//! The program is run natively by [`deque_svm`] by default. The `sbf-tests` crate runs these same
+//! tests against the built sBPF program with LiteSVM instead, by setting the `deque_sbf` cfg.
+
+#![allow(dead_code)]
+
+pub mod market;
+#[cfg(not(deque_sbf))]
+mod native;
+#[cfg(deque_sbf)]
+mod sbf;
+
+use deque::{
+    events::{split_event_record, DequeEvent},
+    shared::error_context::ErrorContext,
+};
+pub use deque_svm::Account;
+#[cfg(not(deque_sbf))]
+pub use native::TestSvm;
+#[cfg(deque_sbf)]
+pub use sbf::TestSvm;
+use solana_program::{
+    entrypoint::ProgramResult, program_error::ProgramError, program_pack::Pack, pubkey::Pubkey,
+    system_program,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token::state::Account as TokenAccount;
+
+/// The outcome of processing a single instruction.
+#[derive(Debug)]
+pub struct Processed {
+    pub result: ProgramResult,
+    pub logs: Vec<String>,
+    /// Everything logged with `sol_log_data`.
+    pub logged_data: Vec<Vec<u8>>,
+    /// The data of each `FlushEventLog` self-CPI, without its instruction tag.
+    pub event_payloads: Vec<Vec<u8>>,
+    /// The compute units the transaction consumed. Only the sBPF backend measures them.
+    pub compute_units: Option<u64>,
+}
+
+impl Processed {
+    /// Unwraps the result, printing the logs if the instruction failed.
+    pub fn unwrap(self) -> Self {
+        if let Err(err) = &self.result {
+            panic!("Instruction failed with {err:?}, logs: {:#?}", self.logs);
+        }
+        self
+    }
+
+    pub fn unwrap_err(self) -> ProgramError {
+        self.result.clone().expect_err("Instruction should fail")
+    }
+
+    /// Decodes every event emitted by the instruction.
+    pub fn events(&self) -> Vec<DequeEvent<'_>> {
+        // With `log-events` the events are logged instead of flushed with a self-CPI, next to the
+        // error context records.
+        #[cfg(feature = "log-events")]
+        let payloads = self
+            .logged_data
+            .iter()
+            .filter(|data| data.first() != Some(&deque::shared::error_context::ERROR_CONTEXT_TAG));
+        #[cfg(not(feature = "log-events"))]
+        let payloads = self.event_payloads.iter();
+
+        let mut events = vec![];
+        for payload in payloads {
+            let mut data = &payload[..];
+            while !data.is_empty() {
+                let (_, record, rest) = split_event_record(data).expect("Should split");
+                events.push(DequeEvent::unpack(record).expect("Should decode"));
+                data = rest;
+            }
+        }
+        events
+    }
+
+    /// Decodes the error context record the program logs when it fails.
+    pub fn error_context(&self) -> Option<ErrorContext> {
+        self.logged_data
+            .iter()
+            .find_map(|data| ErrorContext::unpack(data))
+    }
+}
+
+/// Helpers shared by both backends, built on their `account` and `set_account`.
+impl TestSvm {
+    pub fn lamports(&self, key: &Pubkey) -> u64 {
+        self.account(key).map_or(0, |account| account.lamports)
+    }
+
+    pub fn airdrop(&mut self, key: &Pubkey, lamports: u64) {
+        let mut account = self.account(key).unwrap_or(Account {
+            owner: system_program::ID,
+            ..Default::default()
+        });
+        account.lamports += lamports;
+        self.set_account(*key, account);
+    }
+
+    pub fn create_mint(&mut self, token_program: &Pubkey, decimals: u8) -> Pubkey {
+        let mint = Pubkey::new_unique();
+        self.set_account(mint, deque_svm::mint_account(token_program, decimals));
+        mint
+    }
+
+    /// Creates `owner`'s associated token account for `mint` holding `amount` tokens.
+    pub fn create_token_account(&mut self, mint: &Pubkey, owner: &Pubkey, amount: u64) -> Pubkey {
+        let token_program = self.account(mint).expect("Mint should exist").owner;
+        let key = get_associated_token_address_with_program_id(owner, mint, &token_program);
+        self.set_account(
+            key,
+            deque_svm::token_account(&token_program, mint, owner, amount),
+        );
+        key
+    }
+
+    pub fn token_balance(&self, key: &Pubkey) -> u64 {
+        let account = self.account(key).expect("Token account should exist");
+        TokenAccount::unpack(&account.data[..TokenAccount::LEN])
+            .expect("Should be a token account")
+            .amount
+    }
+
+    /// Overwrites a token account's balance, as if tokens had been moved in or out of it without
+    /// the deque program's involvement.
+    pub fn set_token_balance(&mut self, key: &Pubkey, amount: u64) {
+        let mut account = self.account(key).expect("Token account should exist");
+        let mut state = TokenAccount::unpack(&account.data[..TokenAccount::LEN])
+            .expect("Should be a token account");
+        state.amount = amount;
+        TokenAccount::pack(state, &mut account.data[..TokenAccount::LEN]).expect("Should pack");
+        self.set_account(*key, account);
+    }
+}
@@ -0,0 +1,79 @@
+//! Processes the deque program natively with [`deque_svm`]. This is the fast path, the `sbf-tests`
+//! crate runs the same tests against the built program.
+
+use std::collections::HashMap;
+
+use solana_program::{instruction::Instruction, pubkey::Pubkey, system_program, sysvar};
+
+use super::{Account, Processed};
+
+pub struct TestSvm {
+    accounts: HashMap<Pubkey, Account>,
+}
+
+impl TestSvm {
+    pub fn new() -> Self {
+        TestSvm {
+            accounts: deque_svm::PROGRAMS
+                .into_iter()
+                .map(|program| (program, deque_svm::program_account()))
+                .collect(),
+        }
+    }
+
+    pub fn set_slot(&mut self, slot: u64) {
+        deque_svm::set_clock(slot, deque_svm::clock().1);
+    }
+
+    pub fn set_clock(&mut self, slot: u64, unix_timestamp: i64) {
+        deque_svm::set_clock(slot, unix_timestamp);
+    }
+
+    pub fn set_account(&mut self, key: Pubkey, account: Account) {
+        self.accounts.insert(key, account);
+    }
+
+    pub fn account(&self, key: &Pubkey) -> Option<Account> {
+        self.accounts.get(key).cloned()
+    }
+
+    /// Processes `ixn` as the only instruction of a transaction. Account changes are only kept if
+    /// it succeeds.
+    pub fn process(&mut self, ixn: &Instruction) -> Processed {
+        let mut input = vec![];
+        deque_svm::serialize(ixn, |key| self.accounts.get(key), &mut input);
+        let executed = deque_svm::execute(&mut input, |accounts| {
+            for info in accounts {
+                if info.key == &sysvar::instructions::ID {
+                    continue;
+                }
+                let current = self.accounts.get(info.key).cloned().unwrap_or(Account {
+                    owner: system_program::ID,
+                    ..Default::default()
+                });
+                let updated = Account {
+                    lamports: info.lamports(),
+                    data: info.data.borrow().to_vec(),
+                    owner: *info.owner,
+                    ..current.clone()
+                };
+                if updated != current {
+                    assert!(info.is_writable, "Read-only account {} modified", info.key);
+                    // Like the runtime, accounts left without lamports are deleted.
+                    match updated.lamports {
+                        0 => self.accounts.remove(info.key),
+                        _ => self.accounts.insert(*info.key, updated),
+                    };
+                }
+            }
+        });
+
+        Processed {
+            result: executed.result,
+            logs: executed.logs,
+            logged_data: executed.logged_data,
+            event_payloads: executed.event_payloads,
+            compute_units: None,
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Processes the deque program built with `cargo build-sbf` in LiteSVM, next to the real SPL Token,
+//! Token-2022 and associated token account programs LiteSVM bundles. Only compiled by the
+//! `sbf-tests` crate, which depends on LiteSVM.
+
+use std::path::PathBuf;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use deque::instruction_enum::InstructionTag;
+use litesvm::LiteSVM;
+use solana_program::{
+    clock::Clock,
+    instruction::{Instruction, InstructionError},
+    message::Message,
+    native_token::LAMPORTS_PER_SOL,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use solana_transaction::Transaction;
+use solana_transaction_error::TransactionError;
+
+use super::{Account, Processed};
+
+pub struct TestSvm {
+    svm: LiteSVM,
+    /// Pays for each transaction, so the instruction's own accounts keep their balances.
+    fee_payer: Pubkey,
+}
+
+impl TestSvm {
+    pub fn new() -> Self {
+        // `cargo build-sbf` writes to the workspace's `target/deploy`, unless told otherwise.
+        let program = std::env::var_os("SBF_OUT_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../target/deploy"))
+            .join("deque.so");
+        let mut svm = LiteSVM::new()
+            .with_sigverify(false)
+            .with_blockhash_check(false)
+            // The same instruction is often sent twice, which would otherwise be rejected as a
+            // duplicate transaction.
+            .with_transaction_history(0);
+        svm.add_program_from_file(deque::ID, &program)
+            .unwrap_or_else(|err| {
+                panic!("Build the program with `cargo build-sbf` first, {program:?}: {err}")
+            });
+        let fee_payer = Pubkey::new_unique();
+        let mut test_svm = TestSvm { svm, fee_payer };
+        test_svm.airdrop(&fee_payer, 1_000 * LAMPORTS_PER_SOL);
+        // Match the native runner's clock.
+        test_svm.set_clock(1, 0);
+        test_svm
+    }
+
+    pub fn set_slot(&mut self, slot: u64) {
+        let unix_timestamp = self.svm.get_sysvar::<Clock>().unix_timestamp;
+        self.set_clock(slot, unix_timestamp);
+    }
+
+    pub fn set_clock(&mut self, slot: u64, unix_timestamp: i64) {
+        let clock = Clock {
+            slot,
+            unix_timestamp,
+            ..self.svm.get_sysvar::<Clock>()
+        };
+        self.svm.set_sysvar(&clock);
+    }
+
+    pub fn set_account(&mut self, key: Pubkey, account: Account) {
+        self.svm
+            .set_account(key, account)
+            .expect("Should set the account");
+    }
+
+    pub fn account(&self, key: &Pubkey) -> Option<Account> {
+        // LiteSVM keeps closed accounts around with no lamports.
+        self.svm
+            .get_account(key)
+            .filter(|account| account.lamports > 0)
+    }
+
+    /// Processes `ixn` as the only instruction of a transaction. Account changes are only kept if
+    /// it succeeds.
+    pub fn process(&mut self, ixn: &Instruction) -> Processed {
+        let message = Message::new(std::slice::from_ref(ixn), Some(&self.fee_payer));
+        let account_keys = message.account_keys.clone();
+        let (result, meta) = match self
+            .svm
+            .send_transaction(Transaction::new_unsigned(message))
+        {
+            Ok(meta) => (Ok(()), meta),
+            Err(failed) => (Err(program_error(failed.err)), failed.meta),
+        };
+
+        let flush_tag = InstructionTag::FlushEventLog as u8;
+        let event_payloads = meta
+            .inner_instructions
+            .iter()
+            .flatten()
+            .filter(|inner| {
+                account_keys[inner.instruction.program_id_index as usize] == deque::ID
+                    && inner.instruction.data.first() == Some(&flush_tag)
+            })
+            .map(|inner| inner.instruction.data[1..].to_vec())
+            .collect();
+        // `sol_log_data` is only ever called with a single slice, like the native runner assumes.
+        let logged_data = meta
+            .logs
+            .iter()
+            .filter_map(|log| log.strip_prefix("Program data: "))
+            .map(|data| STANDARD.decode(data).expect("Should be base64"))
+            .collect();
+
+        Processed {
+            result,
+            logs: meta.logs,
+            logged_data,
+            event_payloads,
+            compute_units: Some(meta.compute_units_consumed),
+        }
+    }
+}
+
+/// The program error an instruction failed with. Anything the runtime rejects without an
+/// equivalent program error is a bug in the test, so it panics.
+fn program_error(err: TransactionError) -> ProgramError {
+    match err {
+        TransactionError::InstructionError(_, InstructionError::Custom(code)) => {
+            ProgramError::Custom(code)
+        }
+        TransactionError::InstructionError(_, err) => ProgramError::try_from(err.clone())
+            .unwrap_or_else(|_| panic!("The runtime rejected the instruction with {err:?}")),
+        err => panic!("The runtime rejected the transaction with {err:?}"),
+    }
+}
@@ -0,0 +1,5 @@
+fn main() {
+    // Runs the program's tests in LiteSVM, see `program/tests/svm/mod.rs`.
+    println!("cargo::rustc-check-cfg=cfg(deque_sbf)");
+    println!("cargo::rustc-cfg=deque_sbf");
+}
@@ -0,0 +1,964 @@
+use alloc::{vec, vec::Vec};
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    error::{SlabError, SlabResult},
+    free_stack::{Stack, StackNode},
+    header::{DequeLinks, SlabHeader},
+    slab::{from_slab_bytes_mut, SectorIndex, Sectors, Slab, NIL},
+};
+
+#[derive(Clone, Copy, Debug, Zeroable)]
+#[repr(C)]
+pub struct DequeNode<T> {
+    // The inner payload bytes.
+    pub inner: T,
+    pub prev: SectorIndex,
+    pub next: SectorIndex,
+}
+
+unsafe impl<T: Pod> Pod for DequeNode<T> {}
+
+impl<T: Pod> Slab for DequeNode<T> {}
+
+pub struct Deque<'a, H: SlabHeader, S: Sectors = &'a mut [u8]> {
+    pub header: &'a mut H,
+    // Either StackNode<H::Item> or DequeNode<H::Item>
+    pub sectors: S,
+}
+
+impl<'a, H: SlabHeader, S: Sectors> Deque<'a, H, S> {
+    /// The size of a single sector, in bytes.
+    pub const SECTOR_SIZE: usize = size_of::<DequeNode<H::Item>>();
+
+    /// Build a Deque from a header and a sector store that were split apart by the caller, e.g.
+    /// because the sectors live in several buffers. The header is verified.
+    pub fn from_parts(header: &'a mut H, sectors: S) -> SlabResult<Self> {
+        header.verify()?;
+        Ok(Self { header, sectors })
+    }
+
+    /// Push the sectors in `[from, to)` to the free stack, e.g. after the buffer has grown.
+    pub fn extend_free_stack(&mut self, from: SectorIndex, to: SectorIndex) -> SlabResult {
+        let free_head = self.links().free_head;
+        let mut free = Stack::<H::Item, S>::new(&mut self.sectors, free_head);
+        for i in from..to {
+            free.push_to_free(i)?;
+        }
+        self.links_mut().free_head = free.get_head();
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn links(&self) -> &DequeLinks {
+        self.header.links()
+    }
+
+    #[inline(always)]
+    pub fn links_mut(&mut self) -> &mut DequeLinks {
+        self.header.links_mut()
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> u32 {
+        self.links().len
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether a push can succeed without growing the sector store first.
+    #[inline(always)]
+    pub fn has_free_sector(&self) -> bool {
+        self.links().free_head != NIL
+    }
+
+    pub fn push_front(&mut self, value: H::Item) -> SlabResult<SectorIndex> {
+        let new_idx = self.take_free_sector()?;
+
+        let head = self.links().deque_head;
+        let n: &mut DequeNode<H::Item> = self.sectors.sector_mut(new_idx)?;
+        *n = DequeNode {
+            inner: value,
+            prev: NIL,
+            next: head,
+        };
+
+        match head {
+            NIL => self.links_mut().deque_tail = new_idx,
+            head => self.node_mut(head)?.prev = new_idx,
+        }
+
+        let links = self.links_mut();
+        links.deque_head = new_idx;
+        links.len = links.len.saturating_add(1);
+        Ok(new_idx)
+    }
+
+    pub fn push_back(&mut self, value: H::Item) -> SlabResult<SectorIndex> {
+        let new_idx = self.take_free_sector()?;
+
+        let tail = self.links().deque_tail;
+        let n: &mut DequeNode<H::Item> = self.sectors.sector_mut(new_idx)?;
+        *n = DequeNode {
+            inner: value,
+            prev: tail,
+            next: NIL,
+        };
+
+        match tail {
+            NIL => self.links_mut().deque_head = new_idx,
+            tail => self.node_mut(tail)?.next = new_idx,
+        }
+
+        let links = self.links_mut();
+        links.deque_tail = new_idx;
+        links.len = links.len.saturating_add(1);
+        Ok(new_idx)
+    }
+
+    fn take_free_sector(&mut self) -> SlabResult<SectorIndex> {
+        let free_head = self.links().free_head;
+        let mut free = Stack::<H::Item, S>::new(&mut self.sectors, free_head);
+        let new_idx = free.remove_from_free()?;
+        self.links_mut().free_head = free.get_head();
+        if new_idx == NIL {
+            return Err(SlabError::NoFreeSectors);
+        }
+        Ok(new_idx)
+    }
+
+    /// Remove by an ordinal/logical index in the deque.
+    /// That is, remove at the *logical* index in the deque, not the *physical* index in memory.
+    pub fn remove_at_logical_idx(&mut self, logical_idx: u32) -> SlabResult<H::Item> {
+        let len = self.len();
+        if logical_idx >= len {
+            return Err(SlabError::OutOfBounds);
+        }
+
+        // Pick the closer direction, grab the sector index
+        let idx = if logical_idx <= len / 2 {
+            self.iter_indices().nth(logical_idx as usize)
+        } else {
+            self.iter_indices_rev()
+                .nth((len - 1 - logical_idx) as usize)
+        }
+        .ok_or(SlabError::MalformedSlab)?;
+
+        self.remove_at_sector_idx(idx)
+    }
+
+    pub fn remove_at_sector_idx(&mut self, idx: SectorIndex) -> SlabResult<H::Item> {
+        if idx == NIL {
+            return Err(SlabError::InvalidSectorIndex);
+        };
+
+        let (prev, next, inner) = {
+            let n = self.node(idx)?;
+            (n.prev, n.next, n.inner)
+        };
+
+        match prev {
+            NIL => self.links_mut().deque_head = next,
+            prev => self.node_mut(prev)?.next = next,
+        }
+
+        match next {
+            NIL => self.links_mut().deque_tail = prev,
+            next => self.node_mut(next)?.prev = prev,
+        }
+
+        let links = self.links_mut();
+        links.len = links.len.saturating_sub(1);
+        self.extend_free_stack(idx, idx + 1)?;
+        Ok(inner)
+    }
+
+    #[inline(always)]
+    pub fn node(&self, idx: SectorIndex) -> SlabResult<&DequeNode<H::Item>> {
+        self.sectors.sector::<DequeNode<H::Item>>(idx)
+    }
+
+    #[inline(always)]
+    pub fn node_mut(&mut self, idx: SectorIndex) -> SlabResult<&mut DequeNode<H::Item>> {
+        self.sectors.sector_mut::<DequeNode<H::Item>>(idx)
+    }
+
+    /// Walk the deque from the head and return the sector index of the first item matching
+    /// `pred`. Unlike [`Self::iter_nodes`], a link that can't be followed is an error instead of
+    /// the end of the walk, which matters when not every sector is loaded.
+    pub fn find_index(
+        &self,
+        mut pred: impl FnMut(&H::Item) -> bool,
+    ) -> SlabResult<Option<SectorIndex>> {
+        let mut idx = self.links().deque_head;
+        for _ in 0..self.len() {
+            let node = self.node(idx)?;
+            if pred(&node.inner) {
+                return Ok(Some(idx));
+            }
+            idx = node.next;
+        }
+        Ok(None)
+    }
+
+    pub fn iter_nodes(&self) -> impl Iterator<Item = (&H::Item, SectorIndex)> + '_ {
+        self.iter_indices()
+            .filter_map(move |i| self.node(i).ok().map(|node| (&node.inner, i)))
+    }
+
+    pub fn iter_indices(&self) -> impl Iterator<Item = SectorIndex> + '_ {
+        let start = (self.links().deque_head != NIL).then_some(self.links().deque_head);
+        core::iter::successors(start, move |&i| {
+            let node = self.node(i).ok()?;
+            (node.next != NIL).then_some(node.next)
+        })
+        .take(self.len() as usize)
+    }
+
+    pub fn iter_indices_rev(&self) -> impl Iterator<Item = SectorIndex> + '_ {
+        let start = (self.links().deque_tail != NIL).then_some(self.links().deque_tail);
+        core::iter::successors(start, move |&i| {
+            let node = self.node(i).ok()?;
+            (node.prev != NIL).then_some(node.prev)
+        })
+        .take(self.len() as usize)
+    }
+
+    /// Permute sectors so that the node at logical index `i` is stored at the `i`th sector of
+    /// [`Sectors::sector_runs`], i.e. at physical sector `i` for a single buffer.
+    ///
+    /// At most `max_moves` nodes are relocated per call, so large deques can be defragmented over
+    /// several transactions. Progress isn't stored anywhere: each call skips the prefix of nodes
+    /// that are already in place, which also means deposits and withdrawals can safely happen in
+    /// between calls. Once every node is in place, the free stack is rebuilt contiguously from
+    /// the `len`th sector to the last one.
+    ///
+    /// Every sector reachable from the deque's links must be in the store, so a sharded deque needs
+    /// all of its shards loaded.
+    pub fn defragment(&mut self, max_moves: u16) -> SlabResult<DefragmentProgress> {
+        let order = SectorOrder::new(self.sectors.sector_runs::<DequeNode<H::Item>>());
+        let capacity = order.len();
+
+        // Mark the free sectors so that moving a node into an empty sector can be told apart from
+        // swapping it with another node.
+        let mut free_sectors = SectorBitmap::new(capacity as usize);
+        let mut free = self.links().free_head;
+        for _ in 0..=capacity {
+            if free == NIL {
+                break;
+            }
+            let next = self.sectors.sector::<StackNode<H::Item>>(free)?.next;
+            free_sectors.set(order.position_of(free)?, true)?;
+            free = next;
+        }
+        if free != NIL {
+            return Err(SlabError::MalformedSlab);
+        }
+
+        // Free stack nodes that were moved this call, as (original sector, current sector) pairs.
+        let mut relocated: Vec<(SectorIndex, SectorIndex)> = vec![];
+        let (mut logical, mut idx, mut moves) = (0_u32, self.links().deque_head, 0_u16);
+
+        while idx != NIL {
+            if logical >= self.len() {
+                return Err(SlabError::MalformedSlab);
+            }
+
+            let target = order.sector_at(logical)?;
+            if idx != target {
+                if moves == max_moves {
+                    break;
+                }
+
+                let target_is_free = free_sectors.get(logical)?;
+                self.relocate_node(idx, target, target_is_free)?;
+
+                if target_is_free {
+                    free_sectors.set(logical, false)?;
+                    free_sectors.set(order.position_of(idx)?, true)?;
+                    match relocated.iter_mut().find(|(_, at)| *at == target) {
+                        Some((_, at)) => *at = idx,
+                        None => relocated.push((target, idx)),
+                    }
+                }
+
+                idx = target;
+                moves += 1;
+            }
+
+            idx = self.node(idx)?.next;
+            logical += 1;
+        }
+
+        let complete = idx == NIL;
+        if complete {
+            self.rebuild_free_stack_contiguous(&order)?;
+        } else if !relocated.is_empty() {
+            self.relink_relocated_free_nodes(&mut relocated, capacity)?;
+        }
+
+        Ok(DefragmentProgress {
+            moves,
+            in_place: if complete { self.len() } else { logical },
+            complete,
+        })
+    }
+
+    /// Swap the node at sector `from` into sector `to`, and whatever occupied `to` (either another
+    /// deque node or a free stack node) into `from`. All deque links are rewritten so that the
+    /// logical order is unchanged. Free stack links are *not* updated here.
+    fn relocate_node(
+        &mut self,
+        from: SectorIndex,
+        to: SectorIndex,
+        to_is_free: bool,
+    ) -> SlabResult {
+        self.swap_sectors(from, to)?;
+
+        let remap = |i: SectorIndex| match i {
+            i if i == from => to,
+            i if i == to => from,
+            i => i,
+        };
+
+        let moved_nodes: &[SectorIndex] = if to_is_free { &[to] } else { &[to, from] };
+        for &sector in moved_nodes {
+            let node = self.node_mut(sector)?;
+            node.prev = remap(node.prev);
+            node.next = remap(node.next);
+            let (prev, next) = (node.prev, node.next);
+
+            // Links between the two swapped nodes were already fixed by the remap above.
+            if prev != NIL && prev != from && prev != to {
+                self.node_mut(prev)?.next = sector;
+            }
+            if next != NIL && next != from && next != to {
+                self.node_mut(next)?.prev = sector;
+            }
+        }
+
+        let links = self.links_mut();
+        links.deque_head = remap(links.deque_head);
+        links.deque_tail = remap(links.deque_tail);
+        Ok(())
+    }
+
+    /// Swap the bytes of two sectors. Free stack nodes are the same size as deque nodes, so
+    /// copying either sector as a deque node moves it bit for bit.
+    fn swap_sectors(&mut self, a: SectorIndex, b: SectorIndex) -> SlabResult {
+        if a == b {
+            return Ok(());
+        }
+        let a_node = *self.node(a)?;
+        let b_node = core::mem::replace(self.node_mut(b)?, a_node);
+        *self.node_mut(a)? = b_node;
+        Ok(())
+    }
+
+    /// Point the free stack's links at the current location of every free node moved in a
+    /// partial defragmentation pass.
+    fn relink_relocated_free_nodes(
+        &mut self,
+        relocated: &mut [(SectorIndex, SectorIndex)],
+        capacity: u32,
+    ) -> SlabResult {
+        relocated.sort_unstable();
+        let current = |orig: SectorIndex| {
+            relocated
+                .binary_search_by_key(&orig, |(orig, _)| *orig)
+                .map_or(orig, |i| relocated[i].1)
+        };
+
+        // The stack's links still refer to the original sectors, so walk it by those. The walk is
+        // bounded since a sector that's both free and in the deque can make the links cycle.
+        let mut orig = self.links().free_head;
+        self.links_mut().free_head = current(orig);
+        for _ in 0..=capacity {
+            if orig == NIL {
+                return Ok(());
+            }
+            let node = self
+                .sectors
+                .sector_mut::<StackNode<H::Item>>(current(orig))?;
+            orig = node.next;
+            node.next = current(node.next);
+        }
+
+        Err(SlabError::MalformedSlab)
+    }
+
+    /// Rebuild the free stack from the `len`th sector to the last one, in order. Only valid once
+    /// every node in the deque is in place, since all free sectors are then at the end.
+    fn rebuild_free_stack_contiguous(&mut self, order: &SectorOrder) -> SlabResult {
+        let len = self.len();
+        for position in len..order.len() {
+            let next = match position + 1 {
+                next if next == order.len() => NIL,
+                next => order.sector_at(next)?,
+            };
+            self.sectors
+                .sector_mut::<StackNode<H::Item>>(order.sector_at(position)?)?
+                .next = next;
+        }
+        self.links_mut().free_head = match len {
+            len if len == order.len() => NIL,
+            len => order.sector_at(len)?,
+        };
+        Ok(())
+    }
+}
+
+impl<'a, H: SlabHeader> Deque<'a, H> {
+    /// Construct a new, empty Deque with allocated but uninitialized (zerod out) data.
+    /// Every sector after the header is pushed to the free stack.
+    pub fn init(zerod_data: &'a mut [u8], header: H) -> SlabResult<Self> {
+        let mut deque = Deque::from_bytes_unchecked(zerod_data)?;
+        *deque.header = header;
+        *deque.header.links_mut() = DequeLinks::empty();
+
+        debug_assert_eq!(deque.sectors.len() % Self::SECTOR_SIZE, 0);
+
+        deque.init_free_stack(deque.capacity() as usize)?;
+
+        Ok(deque)
+    }
+
+    pub fn init_free_stack(&mut self, num_sectors: usize) -> SlabResult {
+        let mut stack = Stack::<H::Item>::new(self.sectors, self.links().free_head);
+        for s in (0..num_sectors).rev() {
+            stack.push_to_free(s as SectorIndex)?;
+        }
+        self.links_mut().free_head = stack.get_head();
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> u32 {
+        (self.sectors.len() / Self::SECTOR_SIZE) as u32
+    }
+
+    /// Cast a byte vector to a Deque and verify the header.
+    pub fn from_bytes(data: &'a mut [u8]) -> SlabResult<Self> {
+        let deque = Self::from_bytes_unchecked(data)?;
+        deque.header.verify()?;
+        Ok(deque)
+    }
+
+    /// Cast a byte vector to a Deque without verifying the header.
+    pub fn from_bytes_unchecked(data: &'a mut [u8]) -> SlabResult<Self> {
+        if data.len() < size_of::<H>() {
+            return Err(SlabError::HeaderTooSmall);
+        }
+        let (header_slab, sectors) = data.split_at_mut(size_of::<H>());
+        let header = from_slab_bytes_mut::<H>(header_slab, 0_usize)?;
+        Ok(Self { header, sectors })
+    }
+
+    /// Returns the deque's nodes as a flat slice if the deque is fully defragmented, that is, if
+    /// every node at logical index `i` is stored at physical sector `i`.
+    pub fn contiguous_nodes(&self) -> Option<&[DequeNode<H::Item>]> {
+        let len = self.len() as usize;
+        let is_contiguous = self
+            .iter_indices()
+            .enumerate()
+            .all(|(i, idx)| i == idx as usize);
+        if !is_contiguous || self.iter_indices().count() != len {
+            return None;
+        }
+
+        let bytes = self.sectors.get(..len * Self::SECTOR_SIZE)?;
+        bytemuck::try_cast_slice(bytes).ok()
+    }
+}
+
+impl<H, S> core::fmt::Debug for Deque<'_, H, S>
+where
+    H: SlabHeader,
+    S: Sectors,
+    H::Item: core::fmt::Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let items: Vec<_> = self.iter_nodes().map(|(inner, _)| *inner).collect();
+        let links = self.links();
+
+        f.debug_struct("Deque")
+            .field("len", &links.len)
+            .field("deque_head", &links.deque_head)
+            .field("deque_tail", &links.deque_tail)
+            .field("free_head", &links.free_head)
+            .field("items", &items)
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DefragmentProgress {
+    /// The number of nodes relocated in this pass.
+    pub moves: u16,
+    /// The length of the prefix of nodes known to be in place.
+    pub in_place: u32,
+    /// Whether every node is in place and the free stack has been rebuilt.
+    pub complete: bool,
+}
+
+/// Maps between sector indices and their position in a store's [`Sectors::sector_runs`].
+struct SectorOrder {
+    runs: Vec<(SectorIndex, u32)>,
+}
+
+impl SectorOrder {
+    fn new(runs: Vec<(SectorIndex, u32)>) -> Self {
+        Self { runs }
+    }
+
+    /// The total number of sectors.
+    fn len(&self) -> u32 {
+        self.runs.iter().map(|(_, count)| count).sum()
+    }
+
+    fn sector_at(&self, mut position: u32) -> SlabResult<SectorIndex> {
+        for &(first, count) in &self.runs {
+            if position < count {
+                return Ok(first + position);
+            }
+            position -= count;
+        }
+        Err(SlabError::OutOfBounds)
+    }
+
+    fn position_of(&self, idx: SectorIndex) -> SlabResult<u32> {
+        let mut position = 0;
+        for &(first, count) in &self.runs {
+            if (first..first + count).contains(&idx) {
+                return Ok(position + idx - first);
+            }
+            position += count;
+        }
+        Err(SlabError::OutOfBounds)
+    }
+}
+
+/// One bit per sector in the deque. Indices come from links stored in the buffer, so they're
+/// bounds checked rather than trusted.
+struct SectorBitmap {
+    bits: Vec<u8>,
+    num_sectors: usize,
+}
+
+impl SectorBitmap {
+    fn new(num_sectors: usize) -> Self {
+        Self {
+            bits: vec![0; num_sectors.div_ceil(8)],
+            num_sectors,
+        }
+    }
+
+    fn get(&self, idx: SectorIndex) -> SlabResult<bool> {
+        let (byte, bit) = self.position(idx)?;
+        Ok(self.bits[byte] & bit != 0)
+    }
+
+    fn set(&mut self, idx: SectorIndex, value: bool) -> SlabResult {
+        let (byte, bit) = self.position(idx)?;
+        if value {
+            self.bits[byte] |= bit;
+        } else {
+            self.bits[byte] &= !bit;
+        }
+        Ok(())
+    }
+
+    fn position(&self, idx: SectorIndex) -> SlabResult<(usize, u8)> {
+        if idx as usize >= self.num_sectors {
+            return Err(SlabError::OutOfBounds);
+        }
+        Ok((idx as usize / 8, 1 << (idx % 8)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use bytemuck::{Pod, Zeroable};
+
+    use super::{Deque, DequeNode};
+    use crate::{
+        from_sector_idx, from_sector_idx_mut, from_slab_bytes_mut, shard_of, shard_sector_idx,
+        DequeLinks, SectorIndex, ShardedSectors, Slab, SlabError, SlabHeader, StackNode, NIL,
+    };
+
+    const MAGIC: u32 = 0xdeadbeef;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Pod, Zeroable)]
+    struct TestHeader {
+        magic: u32,
+        links: DequeLinks,
+        _padding: [u8; 4],
+    }
+
+    impl Slab for TestHeader {}
+
+    impl SlabHeader for TestHeader {
+        type Item = u64;
+
+        fn links(&self) -> &DequeLinks {
+            &self.links
+        }
+
+        fn links_mut(&mut self) -> &mut DequeLinks {
+            &mut self.links
+        }
+
+        fn verify(&self) -> crate::SlabResult {
+            match self.magic {
+                MAGIC => Ok(()),
+                _ => Err(SlabError::InvalidHeader),
+            }
+        }
+    }
+
+    type TestDeque<'a> = Deque<'a, TestHeader>;
+
+    /// A `u64` backed buffer so the header and sectors are always aligned.
+    fn buffer(num_sectors: usize) -> Vec<u64> {
+        let bytes = size_of::<TestHeader>() + num_sectors * TestDeque::SECTOR_SIZE;
+        vec![0u64; bytes / 8]
+    }
+
+    fn init(data: &mut [u8]) -> TestDeque<'_> {
+        let header = TestHeader {
+            magic: MAGIC,
+            links: DequeLinks::empty(),
+            _padding: [0; 4],
+        };
+        Deque::init(data, header).expect("Should init")
+    }
+
+    fn items(deque: &TestDeque) -> Vec<u64> {
+        deque.iter_nodes().map(|(item, _)| *item).collect()
+    }
+
+    fn free_sectors(deque: &TestDeque) -> Vec<SectorIndex> {
+        let mut res = vec![];
+        let mut idx = deque.links().free_head;
+        while idx != NIL {
+            res.push(idx);
+            idx = from_sector_idx::<StackNode<u64>>(deque.sectors, idx)
+                .expect("Should be a valid free node")
+                .next;
+        }
+        res
+    }
+
+    #[test]
+    fn push_and_remove() {
+        let mut buf = buffer(4);
+        let mut deque = init(bytemuck::cast_slice_mut(&mut buf));
+        assert_eq!(free_sectors(&deque), vec![0, 1, 2, 3]);
+
+        deque.push_back(2).expect("Should push");
+        deque.push_front(1).expect("Should push");
+        deque.push_back(3).expect("Should push");
+        deque.push_front(0).expect("Should push");
+        assert_eq!(items(&deque), vec![0, 1, 2, 3]);
+        assert_eq!(
+            deque.iter_indices_rev().collect::<Vec<_>>(),
+            deque
+                .iter_indices()
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(deque.push_back(4), Err(SlabError::NoFreeSectors));
+
+        assert_eq!(deque.remove_at_logical_idx(2), Ok(2));
+        assert_eq!(deque.remove_at_logical_idx(0), Ok(0));
+        assert_eq!(deque.remove_at_logical_idx(1), Ok(3));
+        assert_eq!(deque.remove_at_logical_idx(1), Err(SlabError::OutOfBounds));
+        assert_eq!(items(&deque), vec![1]);
+        assert_eq!(deque.len(), 1);
+        assert_eq!(free_sectors(&deque).len(), 3);
+
+        assert_eq!(deque.remove_at_logical_idx(0), Ok(1));
+        assert!(deque.is_empty());
+        assert_eq!(deque.links().deque_head, NIL);
+        assert_eq!(deque.links().deque_tail, NIL);
+        assert_eq!(free_sectors(&deque).len(), 4);
+    }
+
+    #[test]
+    fn grow_free_stack() {
+        let mut buf = buffer(8);
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+        let half = size_of::<TestHeader>() + 4 * TestDeque::SECTOR_SIZE;
+
+        let mut deque = init(&mut data[..half]);
+        for i in 0..4 {
+            deque.push_back(i).expect("Should push");
+        }
+        assert_eq!(deque.push_back(4), Err(SlabError::NoFreeSectors));
+
+        let mut deque = TestDeque::from_bytes(data).expect("Should cast");
+        assert_eq!(deque.capacity(), 8);
+        deque.extend_free_stack(4, 8).expect("Should extend");
+        for i in 4..8 {
+            deque.push_back(i).expect("Should push");
+        }
+        assert_eq!(items(&deque), (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn verify_header() {
+        let mut buf = buffer(1);
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+        assert_eq!(
+            TestDeque::from_bytes(data).err(),
+            Some(SlabError::InvalidHeader)
+        );
+        assert!(TestDeque::from_bytes_unchecked(data).is_ok());
+        assert_eq!(
+            TestDeque::from_bytes_unchecked(&mut data[..4]).err(),
+            Some(SlabError::HeaderTooSmall)
+        );
+    }
+
+    #[test]
+    fn defragment_in_multiple_passes() {
+        const NUM_SECTORS: usize = 20;
+        let mut buf = buffer(NUM_SECTORS);
+        let mut deque = init(bytemuck::cast_slice_mut(&mut buf));
+
+        // Scatter the nodes across the sectors by interleaving pushes on both ends and removals.
+        for i in 0..16 {
+            if i % 2 == 0 {
+                deque.push_front(i).expect("Should push");
+            } else {
+                deque.push_back(i).expect("Should push");
+            }
+        }
+        for logical_idx in [9, 4, 0, 6, 1] {
+            deque
+                .remove_at_logical_idx(logical_idx)
+                .expect("Should remove");
+        }
+        deque.push_back(100).expect("Should push");
+        deque.push_front(101).expect("Should push");
+
+        let expected = items(&deque);
+        assert!(deque.contiguous_nodes().is_none());
+
+        let mut passes = 0;
+        loop {
+            let progress = deque.defragment(3).expect("Should defragment");
+            passes += 1;
+
+            // The deque must stay fully usable between partial passes.
+            assert_eq!(items(&deque), expected);
+            assert_eq!(free_sectors(&deque).len(), NUM_SECTORS - expected.len());
+
+            if progress.complete {
+                break;
+            }
+            assert!(passes < 10);
+        }
+        assert!(passes > 1);
+
+        let nodes: Vec<u64> = deque
+            .contiguous_nodes()
+            .expect("Should be contiguous")
+            .iter()
+            .map(|node: &DequeNode<u64>| node.inner)
+            .collect();
+        assert_eq!(nodes, expected);
+
+        // The free stack is rebuilt as `len, len + 1, ..., capacity - 1`.
+        assert_eq!(
+            free_sectors(&deque),
+            (deque.len()..NUM_SECTORS as u32).collect::<Vec<_>>()
+        );
+
+        // Running it again on a defragmented deque is a no-op.
+        let progress = deque.defragment(3).expect("Should defragment");
+        assert_eq!(progress.moves, 0);
+        assert!(progress.complete);
+    }
+
+    #[test]
+    fn defragment_rejects_links_past_the_capacity() {
+        let mut buf = buffer(2);
+        let mut deque = init(bytemuck::cast_slice_mut(&mut buf));
+        deque.push_back(0).expect("Should push");
+        deque.push_back(1).expect("Should push");
+        // Corrupt the head so that it points past the end of the buffer.
+        deque.links_mut().deque_head = 7;
+
+        assert_eq!(deque.defragment(4), Err(SlabError::OutOfBounds));
+    }
+
+    #[test]
+    fn defragment_rejects_a_len_past_the_capacity() {
+        let mut buf = buffer(2);
+        let mut deque = init(bytemuck::cast_slice_mut(&mut buf));
+        deque.push_back(0).expect("Should push");
+        deque.push_back(1).expect("Should push");
+        // Corrupt the length and close the links into a cycle, so the walk runs past the last
+        // sector instead of stopping at the tail.
+        deque.links_mut().len = 5;
+        deque.node_mut(1).expect("Should be a node").next = 0;
+        deque.node_mut(0).expect("Should be a node").prev = 1;
+        deque.links_mut().deque_head = 1;
+
+        assert_eq!(deque.defragment(4), Err(SlabError::OutOfBounds));
+    }
+
+    #[test]
+    fn defragment_rejects_sectors_that_are_free_and_in_use() {
+        let mut buf = buffer(4);
+        let mut deque = init(bytemuck::cast_slice_mut(&mut buf));
+        for i in 0..4 {
+            deque.push_back(i).expect("Should push");
+        }
+        deque.remove_at_logical_idx(0).expect("Should remove");
+        deque.remove_at_logical_idx(0).expect("Should remove");
+        assert_eq!(deque.iter_indices().collect::<Vec<_>>(), vec![2, 3]);
+
+        // Corrupt the free stack so that it runs through the deque's head.
+        deque.links_mut().free_head = 0;
+        from_sector_idx_mut::<StackNode<u64>>(deque.sectors, 0)
+            .expect("Should be a free node")
+            .next = 2;
+
+        assert_eq!(deque.defragment(1), Err(SlabError::MalformedSlab));
+    }
+
+    #[test]
+    fn sharded_deque_spans_shards() {
+        let mut header_buf = buffer(0);
+        let header_bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut header_buf);
+        let header = from_slab_bytes_mut::<TestHeader>(header_bytes, 0).expect("Should cast");
+        *header = TestHeader {
+            magic: MAGIC,
+            links: DequeLinks::empty(),
+            _padding: [0; 4],
+        };
+
+        let (mut shard_0, mut shard_2) = (vec![0u64; 2 * 2], vec![0u64; 3 * 2]);
+        let mut sectors = ShardedSectors::new();
+        sectors
+            .insert(0, bytemuck::cast_slice_mut(&mut shard_0))
+            .expect("Should insert");
+        sectors
+            .insert(2, bytemuck::cast_slice_mut(&mut shard_2))
+            .expect("Should insert");
+        assert_eq!(
+            sectors.insert(2, &mut []).err(),
+            Some(SlabError::DuplicateShard)
+        );
+        assert_eq!(sectors.shard_capacity::<DequeNode<u64>>(2), Ok(3));
+
+        let mut deque = Deque::from_parts(header, sectors).expect("Should verify");
+        deque
+            .extend_free_stack(shard_sector_idx(0, 0), shard_sector_idx(0, 2))
+            .expect("Should extend");
+        deque
+            .extend_free_stack(shard_sector_idx(2, 0), shard_sector_idx(2, 3))
+            .expect("Should extend");
+
+        for i in 0..5 {
+            deque.push_back(i).expect("Should push");
+        }
+        assert!(!deque.has_free_sector());
+        assert_eq!(deque.push_back(5), Err(SlabError::NoFreeSectors));
+
+        let shards: Vec<_> = deque.iter_indices().map(shard_of).collect();
+        assert_eq!(shards, vec![2, 2, 2, 0, 0]);
+        assert_eq!(
+            deque.iter_nodes().map(|(i, _)| *i).collect::<Vec<_>>(),
+            (0..5).collect::<Vec<_>>()
+        );
+
+        // Removing a node in one shard relinks its neighbors in the other.
+        assert_eq!(deque.remove_at_logical_idx(3), Ok(3));
+        assert_eq!(
+            deque.iter_nodes().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![0, 1, 2, 4]
+        );
+        assert_eq!(
+            deque.find_index(|i| *i == 4),
+            Ok(Some(shard_sector_idx(0, 0)))
+        );
+        assert_eq!(deque.find_index(|i| *i == 3), Ok(None));
+
+        // Following a link into a shard that isn't loaded is an error, not the end of the deque.
+        let (header, _) = (deque.header, deque.sectors);
+        let mut sectors = ShardedSectors::new();
+        sectors
+            .insert(2, bytemuck::cast_slice_mut(&mut shard_2))
+            .expect("Should insert");
+        let deque = Deque::from_parts(header, sectors).expect("Should verify");
+        assert_eq!(deque.find_index(|i| *i == 4), Err(SlabError::MissingShard));
+        assert_eq!(deque.iter_nodes().count(), 3);
+    }
+
+    #[test]
+    fn defragment_packs_a_sharded_deque_across_shards() {
+        let mut header_buf = buffer(0);
+        let header_bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut header_buf);
+        let header = from_slab_bytes_mut::<TestHeader>(header_bytes, 0).expect("Should cast");
+        *header = TestHeader {
+            magic: MAGIC,
+            links: DequeLinks::empty(),
+            _padding: [0; 4],
+        };
+
+        let (mut shard_0, mut shard_1) = (vec![0u64; 2 * 2], vec![0u64; 3 * 2]);
+        let mut sectors = ShardedSectors::new();
+        sectors
+            .insert(0, bytemuck::cast_slice_mut(&mut shard_0))
+            .expect("Should insert");
+        sectors
+            .insert(1, bytemuck::cast_slice_mut(&mut shard_1))
+            .expect("Should insert");
+        let mut deque = Deque::from_parts(header, sectors).expect("Should verify");
+        deque
+            .extend_free_stack(shard_sector_idx(0, 0), shard_sector_idx(0, 2))
+            .expect("Should extend");
+        deque
+            .extend_free_stack(shard_sector_idx(1, 0), shard_sector_idx(1, 3))
+            .expect("Should extend");
+        for i in 0..5 {
+            deque.push_back(i).expect("Should push");
+        }
+        deque.remove_at_logical_idx(1).expect("Should remove");
+
+        // One move per pass, so the free stack is relinked across shards in between.
+        let mut passes = 0;
+        while !deque.defragment(1).expect("Should defragment").complete {
+            passes += 1;
+            assert!(passes < 10, "Should finish in a few passes");
+        }
+
+        assert_eq!(
+            deque.iter_indices().collect::<Vec<_>>(),
+            vec![
+                shard_sector_idx(0, 0),
+                shard_sector_idx(0, 1),
+                shard_sector_idx(1, 0),
+                shard_sector_idx(1, 1),
+            ]
+        );
+        assert_eq!(
+            deque.iter_nodes().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![0, 2, 3, 4]
+        );
+        assert_eq!(deque.links().free_head, shard_sector_idx(1, 2));
+        assert_eq!(deque.push_back(5), Ok(shard_sector_idx(1, 2)));
+        assert!(!deque.has_free_sector());
+    }
+}
@@ -0,0 +1,51 @@
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum SlabError {
+    /// The buffer is too small to hold the header.
+    HeaderTooSmall,
+    /// The header failed the validation in [`crate::SlabHeader::verify`].
+    InvalidHeader,
+    /// The bytes at an offset aren't properly sized or aligned for the slab type.
+    MalformedSlab,
+    /// A sector index was `NIL` where a real sector was expected.
+    InvalidSectorIndex,
+    /// Checked arithmetic on a byte offset overflowed.
+    ArithmeticError,
+    /// An index or byte offset is past the end of the buffer.
+    OutOfBounds,
+    /// There are no free sectors left to push to.
+    NoFreeSectors,
+    /// A sector index refers to a shard that wasn't loaded into the store.
+    MissingShard,
+    /// The same shard was loaded into the store twice.
+    DuplicateShard,
+    /// The shard index is reserved or past the maximum number of shards.
+    InvalidShard,
+}
+
+impl From<SlabError> for &'static str {
+    fn from(value: SlabError) -> Self {
+        match value {
+            SlabError::HeaderTooSmall => "Buffer is too small for the header",
+            SlabError::InvalidHeader => "Invalid header",
+            SlabError::MalformedSlab => "Malformed slab data",
+            SlabError::InvalidSectorIndex => "Invalid sector index",
+            SlabError::ArithmeticError => "Checked arithmetic failed",
+            SlabError::OutOfBounds => "Index is out of bounds",
+            SlabError::NoFreeSectors => "No free sectors left",
+            SlabError::MissingShard => "Shard isn't loaded",
+            SlabError::DuplicateShard => "Shard is loaded more than once",
+            SlabError::InvalidShard => "Invalid shard index",
+        }
+    }
+}
+
+impl core::fmt::Display for SlabError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str((*self).into())
+    }
+}
+
+impl core::error::Error for SlabError {}
+
+pub type SlabResult<T = ()> = Result<T, SlabError>;
@@ -1,17 +1,17 @@
 use bytemuck::{Pod, Zeroable};
-use solana_program::{entrypoint::ProgramResult, program_error::ProgramError};
 
 use crate::{
-    state::DequeNode,
-    utils::{from_sector_idx_mut, SectorIndex, Slab, NIL},
+    deque::DequeNode,
+    error::SlabResult,
+    slab::{SectorIndex, Sectors, Slab, NIL},
 };
 
 /// NIL/LAST are interchangeable within the context of the stack structure.
 const LAST: u32 = NIL;
-pub struct Stack<'a, T: Pod> {
+pub struct Stack<'a, T: Pod, S: Sectors + ?Sized = [u8]> {
     pub head: SectorIndex,
-    pub data: &'a mut [u8],
-    pub phantom: std::marker::PhantomData<&'a T>,
+    pub data: &'a mut S,
+    pub phantom: core::marker::PhantomData<&'a T>,
 }
 
 #[derive(Clone, Copy, Zeroable)]
@@ -28,20 +28,20 @@ unsafe impl<T: Pod> Pod for StackNode<T> {}
 
 impl<T: Pod> Slab for StackNode<T> {}
 
-impl<'a, T: Pod> Stack<'a, T> {
-    /// Initialize from a byte vector; it's expected that it's already well-formed.
-    pub fn new(data: &'a mut [u8], head: SectorIndex) -> Self {
+impl<'a, T: Pod, S: Sectors + ?Sized> Stack<'a, T, S> {
+    /// Initialize from a sector store; it's expected that it's already well-formed.
+    pub fn new(data: &'a mut S, head: SectorIndex) -> Self {
         debug_assert_eq!(size_of::<StackNode<T>>(), size_of::<DequeNode<T>>());
 
         Stack {
             head,
             data,
-            phantom: std::marker::PhantomData,
+            phantom: core::marker::PhantomData,
         }
     }
 
-    pub fn push_to_free(&mut self, idx: SectorIndex) -> ProgramResult {
-        let node: &mut StackNode<T> = from_sector_idx_mut::<StackNode<T>>(self.data, idx)?;
+    pub fn push_to_free(&mut self, idx: SectorIndex) -> SlabResult {
+        let node: &mut StackNode<T> = self.data.sector_mut(idx)?;
         node.inner = T::zeroed();
         node.next = self.head;
         self.head = idx;
@@ -49,13 +49,13 @@ impl<'a, T: Pod> Stack<'a, T> {
         Ok(())
     }
 
-    pub fn remove_from_free(&mut self) -> Result<SectorIndex, ProgramError> {
+    pub fn remove_from_free(&mut self) -> SlabResult<SectorIndex> {
         if self.head == LAST {
             return Ok(LAST);
         }
 
         let removed_idx = self.head;
-        let head = from_sector_idx_mut::<StackNode<T>>(self.data, removed_idx)?;
+        let head = self.data.sector_mut::<StackNode<T>>(removed_idx)?;
         self.head = head.next;
 
         // Fully zero out the node by setting `next` to 0.
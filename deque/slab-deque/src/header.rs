@@ -0,0 +1,52 @@
+use bytemuck::{Pod, Zeroable};
+use static_assertions::const_assert_eq;
+
+use crate::{
+    error::SlabResult,
+    slab::{SectorIndex, Slab, NIL},
+};
+
+/// The links needed to walk a deque and its free stack. Embed this in a [`SlabHeader`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Pod, Zeroable)]
+pub struct DequeLinks {
+    pub len: SectorIndex,
+    pub free_head: SectorIndex,
+    pub deque_head: SectorIndex,
+    pub deque_tail: SectorIndex,
+}
+
+const_assert_eq!(size_of::<DequeLinks>(), 16);
+
+impl DequeLinks {
+    pub const fn empty() -> Self {
+        DequeLinks {
+            len: 0,
+            free_head: NIL,
+            deque_head: NIL,
+            deque_tail: NIL,
+        }
+    }
+}
+
+impl Default for DequeLinks {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+/// The fixed-size header at the start of the buffer backing a [`crate::Deque`].
+pub trait SlabHeader: Slab {
+    /// The payload type stored in each deque node.
+    type Item: Pod;
+
+    fn links(&self) -> &DequeLinks;
+
+    fn links_mut(&mut self) -> &mut DequeLinks;
+
+    /// Validate the header when casting existing bytes, e.g. by checking a discriminant.
+    #[inline(always)]
+    fn verify(&self) -> SlabResult {
+        Ok(())
+    }
+}
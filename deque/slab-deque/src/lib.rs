@@ -0,0 +1,22 @@
+//! An account-backed, doubly linked deque laid out as a slab of fixed-size sectors.
+//!
+//! Free sectors are tracked with an intrusive stack that shares the same sector layout, so any
+//! `&mut [u8]` buffer (e.g. Solana account data) can back the structure without an allocator.
+//! A deque too large for one buffer can be spread across several with [`ShardedSectors`].
+#![no_std]
+
+extern crate alloc;
+
+pub mod deque;
+pub mod error;
+pub mod free_stack;
+pub mod header;
+pub mod sharded;
+pub mod slab;
+
+pub use deque::*;
+pub use error::*;
+pub use free_stack::*;
+pub use header::*;
+pub use sharded::*;
+pub use slab::*;
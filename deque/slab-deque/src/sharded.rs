@@ -0,0 +1,128 @@
+use alloc::vec::Vec;
+
+use crate::{
+    error::{SlabError, SlabResult},
+    slab::{from_sector_idx, from_sector_idx_mut, SectorIndex, Sectors, Slab, NIL},
+};
+
+/// The index of a buffer in a [`ShardedSectors`] store.
+pub type ShardIndex = u16;
+
+/// The low bits of a sharded [`SectorIndex`] are the slot within the shard, and the high bits are
+/// the shard index.
+pub const SHARD_SLOT_BITS: u32 = 16;
+
+/// The maximum number of sectors a single shard can hold.
+pub const SECTORS_PER_SHARD: u32 = 1 << SHARD_SLOT_BITS;
+
+/// The last shard index is reserved so that [`NIL`] never refers to a real sector.
+pub const MAX_SHARDS: u32 = ShardIndex::MAX as u32;
+
+/// Encode a shard and a slot within it as a [`SectorIndex`].
+///
+/// `slot == SECTORS_PER_SHARD` is allowed and yields the exclusive upper bound of the shard's
+/// range, i.e. the first sector of the next shard.
+#[inline(always)]
+pub const fn shard_sector_idx(shard: ShardIndex, slot: u32) -> SectorIndex {
+    ((shard as SectorIndex) << SHARD_SLOT_BITS) + slot
+}
+
+#[inline(always)]
+pub const fn shard_of(idx: SectorIndex) -> ShardIndex {
+    (idx >> SHARD_SLOT_BITS) as ShardIndex
+}
+
+#[inline(always)]
+pub const fn slot_of(idx: SectorIndex) -> SectorIndex {
+    idx & (SECTORS_PER_SHARD - 1)
+}
+
+/// A sector store spread across several buffers, e.g. one per account.
+///
+/// Each [`SectorIndex`] encodes the shard it lives in and its slot within that shard, so a deque's
+/// links can point across shards. Only the shards a caller needs have to be loaded; reaching a
+/// sector in a shard that isn't loaded is a [`SlabError::MissingShard`] error.
+///
+/// Shard 0 holding the first [`SECTORS_PER_SHARD`] sectors means a single buffer deque with at most
+/// that many sectors has the exact same indices as a sharded deque with only shard 0.
+#[derive(Default)]
+pub struct ShardedSectors<'a> {
+    shards: Vec<Option<&'a mut [u8]>>,
+}
+
+impl<'a> ShardedSectors<'a> {
+    pub fn new() -> Self {
+        Self { shards: Vec::new() }
+    }
+
+    /// Load the sector bytes for `shard`. Any bytes past the shard's maximum size are ignored.
+    pub fn insert(&mut self, shard: ShardIndex, sectors: &'a mut [u8]) -> SlabResult {
+        if shard as u32 >= MAX_SHARDS {
+            return Err(SlabError::InvalidShard);
+        }
+        let i = shard as usize;
+        if i >= self.shards.len() {
+            self.shards.resize_with(i + 1, || None);
+        }
+        match self.shards[i] {
+            Some(_) => Err(SlabError::DuplicateShard),
+            None => {
+                self.shards[i] = Some(sectors);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn contains(&self, shard: ShardIndex) -> bool {
+        matches!(self.shards.get(shard as usize), Some(Some(_)))
+    }
+
+    /// The number of whole sectors of type `T` in a loaded shard.
+    pub fn shard_capacity<T: Slab>(&self, shard: ShardIndex) -> SlabResult<u32> {
+        let bytes = self.shard(shard)?;
+        let capacity = bytes.len() / size_of::<T>();
+        Ok(capacity.min(SECTORS_PER_SHARD as usize) as u32)
+    }
+
+    fn shard(&self, shard: ShardIndex) -> SlabResult<&[u8]> {
+        match self.shards.get(shard as usize) {
+            Some(Some(bytes)) => Ok(bytes),
+            _ => Err(SlabError::MissingShard),
+        }
+    }
+
+    fn shard_mut(&mut self, shard: ShardIndex) -> SlabResult<&mut [u8]> {
+        match self.shards.get_mut(shard as usize) {
+            Some(Some(bytes)) => Ok(bytes),
+            _ => Err(SlabError::MissingShard),
+        }
+    }
+}
+
+impl Sectors for ShardedSectors<'_> {
+    #[inline(always)]
+    fn sector<T: Slab>(&self, idx: SectorIndex) -> SlabResult<&T> {
+        if idx == NIL {
+            return Err(SlabError::InvalidSectorIndex);
+        }
+        from_sector_idx(self.shard(shard_of(idx))?, slot_of(idx))
+    }
+
+    #[inline(always)]
+    fn sector_mut<T: Slab>(&mut self, idx: SectorIndex) -> SlabResult<&mut T> {
+        if idx == NIL {
+            return Err(SlabError::InvalidSectorIndex);
+        }
+        from_sector_idx_mut(self.shard_mut(shard_of(idx))?, slot_of(idx))
+    }
+
+    /// One run per loaded shard, in shard order. Shards that weren't loaded are skipped.
+    fn sector_runs<T: Slab>(&self) -> Vec<(SectorIndex, u32)> {
+        (0..self.shards.len() as ShardIndex)
+            .filter_map(|shard| {
+                let capacity = self.shard_capacity::<T>(shard).ok()?;
+                Some((shard_sector_idx(shard, 0), capacity))
+            })
+            .collect()
+    }
+}
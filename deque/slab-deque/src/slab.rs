@@ -0,0 +1,107 @@
+use alloc::{vec, vec::Vec};
+
+use crate::error::{SlabError, SlabResult};
+
+/// The physical `sector` index in the slab of bytes dedicated to inner data for a type.
+/// That is, to get the raw bytes offset, it is multiplied by the sector type's sector size.
+pub type SectorIndex = u32;
+pub const NIL: SectorIndex = SectorIndex::MAX;
+
+/// Marker trait to narrow an account data's Pod-based outer Node<T> type (used as a slice of
+/// mutable bytes: &mut [u8]) so that T can't also be passed.
+pub trait Slab: bytemuck::Pod {}
+
+#[inline(always)]
+pub fn from_slab_bytes<T: Slab>(data: &[u8], byte_offset: usize) -> SlabResult<&T> {
+    // TODO: Optimize this to use `get_unchecked` with a prior safety check on the data/buffer size.
+    let size = core::mem::size_of::<T>();
+    let end = byte_offset
+        .checked_add(size)
+        .ok_or(SlabError::ArithmeticError)?;
+    let bytes = data.get(byte_offset..end).ok_or(SlabError::OutOfBounds)?;
+    bytemuck::try_from_bytes(bytes).map_err(|_| SlabError::MalformedSlab)
+}
+
+#[inline(always)]
+pub fn from_slab_bytes_mut<T: Slab>(data: &mut [u8], byte_offset: usize) -> SlabResult<&mut T> {
+    // TODO: Optimize this to use `get_unchecked` with a prior safety check on the data/buffer size.
+    let size = core::mem::size_of::<T>();
+    let end = byte_offset
+        .checked_add(size)
+        .ok_or(SlabError::ArithmeticError)?;
+    let bytes = data
+        .get_mut(byte_offset..end)
+        .ok_or(SlabError::OutOfBounds)?;
+    bytemuck::try_from_bytes_mut(bytes).map_err(|_| SlabError::MalformedSlab)
+}
+
+#[inline(always)]
+pub fn from_sector_idx<T: Slab>(sectors: &[u8], idx: SectorIndex) -> SlabResult<&T> {
+    if idx == NIL {
+        return Err(SlabError::InvalidSectorIndex);
+    }
+    let stride = size_of::<T>();
+    let start = (idx as usize)
+        .checked_mul(stride)
+        .ok_or(SlabError::ArithmeticError)?;
+    from_slab_bytes(sectors, start)
+}
+
+#[inline(always)]
+pub fn from_sector_idx_mut<T: Slab>(sectors: &mut [u8], idx: SectorIndex) -> SlabResult<&mut T> {
+    if idx == NIL {
+        return Err(SlabError::InvalidSectorIndex);
+    }
+    let stride = size_of::<T>();
+    let start = (idx as usize)
+        .checked_mul(stride)
+        .ok_or(SlabError::ArithmeticError)?;
+    from_slab_bytes_mut(sectors, start)
+}
+
+/// Storage for the sectors backing a deque, addressed by [`SectorIndex`].
+///
+/// A plain byte slice is the simplest store, where a sector index is the offset in units of the
+/// sector size. See [`crate::ShardedSectors`] for a store that spans several buffers.
+pub trait Sectors {
+    fn sector<T: Slab>(&self, idx: SectorIndex) -> SlabResult<&T>;
+
+    fn sector_mut<T: Slab>(&mut self, idx: SectorIndex) -> SlabResult<&mut T>;
+
+    /// Every sector of type `T` in the store, as `(first, count)` runs of consecutive indices in
+    /// physical order. [`crate::Deque::defragment`] packs the deque's nodes into them front to back.
+    fn sector_runs<T: Slab>(&self) -> Vec<(SectorIndex, u32)>;
+}
+
+impl Sectors for [u8] {
+    #[inline(always)]
+    fn sector<T: Slab>(&self, idx: SectorIndex) -> SlabResult<&T> {
+        from_sector_idx(self, idx)
+    }
+
+    #[inline(always)]
+    fn sector_mut<T: Slab>(&mut self, idx: SectorIndex) -> SlabResult<&mut T> {
+        from_sector_idx_mut(self, idx)
+    }
+
+    fn sector_runs<T: Slab>(&self) -> Vec<(SectorIndex, u32)> {
+        vec![(0, (self.len() / size_of::<T>()) as u32)]
+    }
+}
+
+impl<S: Sectors + ?Sized> Sectors for &mut S {
+    #[inline(always)]
+    fn sector<T: Slab>(&self, idx: SectorIndex) -> SlabResult<&T> {
+        (**self).sector(idx)
+    }
+
+    #[inline(always)]
+    fn sector_mut<T: Slab>(&mut self, idx: SectorIndex) -> SlabResult<&mut T> {
+        (**self).sector_mut(idx)
+    }
+
+    #[inline(always)]
+    fn sector_runs<T: Slab>(&self) -> Vec<(SectorIndex, u32)> {
+        (**self).sector_runs::<T>()
+    }
+}
@@ -0,0 +1,229 @@
+//! Model-based tests that run random operation sequences against a byte-backed [`Deque`] and a
+//! [`VecDeque`], and check that the two agree after every step.
+
+use std::collections::{HashSet, VecDeque};
+
+use bytemuck::{Pod, Zeroable};
+use proptest::{prelude::*, sample::Index};
+use slab_deque::{
+    from_sector_idx, Deque, DequeLinks, DequeNode, SectorIndex, Slab, SlabError, SlabHeader,
+    SlabResult, Stack, StackNode, NIL,
+};
+
+const MAGIC: u32 = 0xdeadbeef;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct TestHeader {
+    magic: u32,
+    links: DequeLinks,
+    _padding: [u8; 4],
+}
+
+impl Slab for TestHeader {}
+
+impl SlabHeader for TestHeader {
+    type Item = u64;
+
+    fn links(&self) -> &DequeLinks {
+        &self.links
+    }
+
+    fn links_mut(&mut self) -> &mut DequeLinks {
+        &mut self.links
+    }
+
+    fn verify(&self) -> SlabResult {
+        match self.magic {
+            MAGIC => Ok(()),
+            _ => Err(SlabError::InvalidHeader),
+        }
+    }
+}
+
+type TestDeque<'a> = Deque<'a, TestHeader>;
+
+const WORDS_PER_SECTOR: usize = TestDeque::SECTOR_SIZE / 8;
+
+/// A `u64` backed buffer so the header and sectors are always aligned.
+fn buffer(num_sectors: usize) -> Vec<u64> {
+    vec![0u64; (size_of::<TestHeader>() + num_sectors * TestDeque::SECTOR_SIZE) / 8]
+}
+
+#[derive(Clone, Debug)]
+enum Op {
+    PushFront(u64),
+    PushBack(u64),
+    RemoveAtLogicalIdx(Index),
+    /// Remove the node holding the item at this logical index by its sector index.
+    RemoveAtSectorIdx(Index),
+    /// Grow the buffer like `inline_deque_resize` does and free the new sectors.
+    Grow(u8),
+    Defragment(u16),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => any::<u64>().prop_map(Op::PushFront),
+        4 => any::<u64>().prop_map(Op::PushBack),
+        3 => any::<Index>().prop_map(Op::RemoveAtLogicalIdx),
+        2 => any::<Index>().prop_map(Op::RemoveAtSectorIdx),
+        1 => (1..4u8).prop_map(Op::Grow),
+        1 => (0..6u16).prop_map(Op::Defragment),
+    ]
+}
+
+fn free_sectors(deque: &TestDeque) -> Vec<SectorIndex> {
+    let mut res = vec![];
+    let mut idx = deque.links().free_head;
+    while idx != NIL {
+        assert!(
+            res.len() < deque.capacity() as usize,
+            "Free stack has a cycle"
+        );
+        res.push(idx);
+        idx = from_sector_idx::<StackNode<u64>>(deque.sectors, idx)
+            .expect("Should be a valid free node")
+            .next;
+    }
+    res
+}
+
+/// Compares the deque to the model and checks that its links are well-formed.
+fn check(deque: &TestDeque, model: &VecDeque<u64>, capacity: u32) {
+    assert_eq!(deque.capacity(), capacity);
+    assert_eq!(deque.len() as usize, model.len());
+    assert_eq!(deque.is_empty(), model.is_empty());
+
+    let items: Vec<u64> = deque.iter_nodes().map(|(item, _)| *item).collect();
+    assert_eq!(items, model.iter().copied().collect::<Vec<_>>());
+
+    let indices: Vec<SectorIndex> = deque.iter_indices().collect();
+    let mut rev: Vec<SectorIndex> = deque.iter_indices_rev().collect();
+    rev.reverse();
+    assert_eq!(indices, rev);
+    assert_eq!(deque.links().deque_head, *indices.first().unwrap_or(&NIL));
+    assert_eq!(deque.links().deque_tail, *indices.last().unwrap_or(&NIL));
+    for (pos, idx) in indices.iter().enumerate() {
+        let node: &DequeNode<u64> = deque.node(*idx).expect("Should be a node");
+        let prev = pos.checked_sub(1).map_or(NIL, |p| indices[p]);
+        let next = indices.get(pos + 1).copied().unwrap_or(NIL);
+        assert_eq!((node.prev, node.next), (prev, next));
+    }
+
+    // Every sector is either in the deque or on the free stack, never both.
+    let free = free_sectors(deque);
+    assert_eq!(free.len() as u32, capacity - deque.len());
+    assert_eq!(deque.has_free_sector(), !free.is_empty());
+    let all: HashSet<SectorIndex> = indices.iter().chain(free.iter()).copied().collect();
+    assert_eq!(all, (0..capacity).collect());
+}
+
+fn apply(buf: &mut Vec<u64>, model: &mut VecDeque<u64>, capacity: &mut u32, op: Op) {
+    if let Op::Grow(n) = op {
+        buf.resize(buf.len() + n as usize * WORDS_PER_SECTOR, 0);
+        let mut deque = TestDeque::from_bytes(bytemuck::cast_slice_mut(buf)).unwrap();
+        deque
+            .extend_free_stack(*capacity, *capacity + n as u32)
+            .unwrap();
+        *capacity += n as u32;
+        return;
+    }
+
+    let mut deque = TestDeque::from_bytes(bytemuck::cast_slice_mut(buf)).unwrap();
+    let full = model.len() as u32 == *capacity;
+    match op {
+        Op::PushFront(value) => match full {
+            true => assert_eq!(deque.push_front(value), Err(SlabError::NoFreeSectors)),
+            false => {
+                deque.push_front(value).unwrap();
+                model.push_front(value);
+            }
+        },
+        Op::PushBack(value) => match full {
+            true => assert_eq!(deque.push_back(value), Err(SlabError::NoFreeSectors)),
+            false => {
+                deque.push_back(value).unwrap();
+                model.push_back(value);
+            }
+        },
+        Op::RemoveAtLogicalIdx(_) | Op::RemoveAtSectorIdx(_) if model.is_empty() => {
+            assert_eq!(deque.remove_at_logical_idx(0), Err(SlabError::OutOfBounds));
+        }
+        Op::RemoveAtLogicalIdx(idx) => {
+            let idx = idx.index(model.len());
+            assert_eq!(deque.remove_at_logical_idx(idx as u32), Ok(model[idx]));
+            model.remove(idx);
+        }
+        Op::RemoveAtSectorIdx(idx) => {
+            let idx = idx.index(model.len());
+            let sector = deque.iter_indices().nth(idx).unwrap();
+            assert_eq!(deque.remove_at_sector_idx(sector), Ok(model[idx]));
+            model.remove(idx);
+        }
+        Op::Defragment(max_moves) => {
+            let progress = deque.defragment(max_moves).unwrap();
+            assert!(progress.moves <= max_moves);
+            if progress.complete {
+                let nodes = deque.contiguous_nodes().expect("Should be contiguous");
+                let items: Vec<u64> = nodes.iter().map(|node| node.inner).collect();
+                assert_eq!(items, model.iter().copied().collect::<Vec<_>>());
+            }
+        }
+        Op::Grow(_) => unreachable!(),
+    }
+}
+
+proptest! {
+    #[test]
+    fn deque_matches_vec_deque(
+        initial_capacity in 0..6u32,
+        ops in proptest::collection::vec(op(), 1..200),
+    ) {
+        let mut buf = buffer(initial_capacity as usize);
+        let header = TestHeader {
+            magic: MAGIC,
+            links: DequeLinks::empty(),
+            _padding: [0; 4],
+        };
+        TestDeque::init(bytemuck::cast_slice_mut(&mut buf), header).unwrap();
+
+        let mut model = VecDeque::new();
+        let mut capacity = initial_capacity;
+        for op in ops {
+            apply(&mut buf, &mut model, &mut capacity, op);
+            let deque = TestDeque::from_bytes(bytemuck::cast_slice_mut(&mut buf)).unwrap();
+            check(&deque, &model, capacity);
+        }
+    }
+
+    #[test]
+    fn free_stack_matches_vec(
+        num_sectors in 1..16u32,
+        pushes in proptest::collection::vec(any::<bool>(), 1..100),
+    ) {
+        let mut buf = vec![0u64; num_sectors as usize * WORDS_PER_SECTOR];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buf);
+        let mut stack = Stack::<u64>::new(data, NIL);
+        let mut model: Vec<SectorIndex> = vec![];
+        let mut unused: Vec<SectorIndex> = (0..num_sectors).rev().collect();
+
+        for push in pushes {
+            match (push, unused.pop()) {
+                (true, Some(idx)) => {
+                    stack.push_to_free(idx).unwrap();
+                    model.push(idx);
+                }
+                (_, idx) => {
+                    unused.extend(idx);
+                    let removed = stack.remove_from_free().unwrap();
+                    prop_assert_eq!(removed, model.pop().unwrap_or(NIL));
+                    if removed != NIL {
+                        unused.push(removed);
+                    }
+                }
+            }
+            prop_assert_eq!(stack.get_head(), *model.last().unwrap_or(&NIL));
+        }
+    }
+}
@@ -0,0 +1,563 @@
+//! Runs the deque program natively against in-memory accounts, for the program's integration tests
+//! and the client's market simulator.
+//!
+//! Instructions are serialized into the same input buffer layout the runtime hands to a program's
+//! entrypoint, so account reallocs behave exactly as they do on-chain. CPIs are routed through
+//! `solana_program`'s syscall stubs to minimal implementations of the system, associated token,
+//! token and Token-2022 programs, and back into the deque program for the event log flush.
+//!
+//! The clock and everything captured while processing an instruction are thread local, so
+//! instructions can be processed in parallel on separate threads.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    mem::size_of,
+    sync::Once,
+};
+
+use deque::processor::process_instruction;
+pub use solana_account::Account;
+use solana_program::{
+    account_info::AccountInfo,
+    bpf_loader_upgradeable,
+    clock::Clock,
+    entrypoint::{
+        deserialize, ProgramResult, BPF_ALIGN_OF_U128, MAX_PERMITTED_DATA_INCREASE, NON_DUP_MARKER,
+    },
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    program_pack::Pack,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    pubkey::{Pubkey, PubkeyError},
+    rent::Rent,
+    system_program, sysvar,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token::state::{Account as TokenAccount, AccountState, Mint};
+
+thread_local! {
+    static STACK_HEIGHT: Cell<u64> = const { Cell::new(0) };
+    /// The slot and unix timestamp the clock sysvar returns.
+    static CLOCK: Cell<(u64, i64)> = const { Cell::new((1, 0)) };
+    static LOGS: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    static LOGGED_DATA: RefCell<Vec<Vec<u8>>> = const { RefCell::new(vec![]) };
+    /// The data of every instruction the deque program invoked itself with.
+    static SELF_CPIS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(vec![]) };
+    /// The deque program's PDAs by their signer seeds.
+    static PDAS: RefCell<HashMap<Vec<Vec<u8>>, Pubkey>> = RefCell::new(HashMap::new());
+}
+
+/// Every program the deque program's instructions reference.
+pub const PROGRAMS: [Pubkey; 5] = [
+    deque::ID,
+    system_program::ID,
+    spl_token::ID,
+    spl_token_2022::ID,
+    spl_associated_token_account::ID,
+];
+
+/// A deployed program's account, which is all the runtime checks before invoking it.
+pub fn program_account() -> Account {
+    Account {
+        lamports: 1,
+        executable: true,
+        owner: bpf_loader_upgradeable::ID,
+        ..Default::default()
+    }
+}
+
+/// Sets the slot and unix timestamp the program reads from the clock sysvar on this thread.
+pub fn set_clock(slot: u64, unix_timestamp: i64) {
+    CLOCK.set((slot, unix_timestamp));
+}
+
+/// The slot and unix timestamp the clock sysvar returns on this thread.
+pub fn clock() -> (u64, i64) {
+    CLOCK.get()
+}
+
+/// The outcome of processing a single instruction.
+#[derive(Debug)]
+pub struct Executed {
+    pub result: ProgramResult,
+    /// Everything logged with `sol_log`, prefixed like the runtime does.
+    pub logs: Vec<String>,
+    /// Everything logged with `sol_log_data`.
+    pub logged_data: Vec<Vec<u8>>,
+    /// The data of each `FlushEventLog` self-CPI, without its instruction tag.
+    pub event_payloads: Vec<Vec<u8>>,
+}
+
+/// Runs the deque program on an input serialized with [`serialize`]. `write_back` is only called
+/// when the instruction succeeds, with every account as the program left it.
+pub fn execute(input: &mut [u128], write_back: impl FnOnce(&[AccountInfo])) -> Executed {
+    static INSTALL_STUBS: Once = Once::new();
+    INSTALL_STUBS.call_once(|| {
+        set_syscall_stubs(Box::new(Stubs));
+    });
+    LOGS.take();
+    LOGGED_DATA.take();
+    SELF_CPIS.take();
+
+    // SAFETY: The input was serialized with the runtime's layout and outlives the accounts.
+    let (program_id, accounts, data) = unsafe { deserialize(input.as_mut_ptr() as *mut u8) };
+    assert_eq!(
+        program_id,
+        &deque::ID,
+        "Only the deque program can be invoked"
+    );
+
+    STACK_HEIGHT.set(1);
+    let result = process_instruction(program_id, &accounts, data);
+    STACK_HEIGHT.set(0);
+
+    if result.is_ok() {
+        write_back(&accounts);
+    }
+
+    Executed {
+        result,
+        logs: LOGS.take(),
+        logged_data: LOGGED_DATA.take(),
+        event_payloads: SELF_CPIS
+            .take()
+            .into_iter()
+            .map(|mut data| data.split_off(1))
+            .collect(),
+    }
+}
+
+/// The duplicate marker, signer, writable and executable flags, padding, key, owner, lamports and
+/// data length that precede each account's data.
+const SERIALIZED_ACCOUNT_HEADER_LEN: usize = 4 + size_of::<u32>() + 32 + 32 + 8 + 8;
+
+/// Serializes `ixn` into the aligned input buffer the runtime passes to programs, reusing `input`'s
+/// allocation. Accounts `account` doesn't know about are empty system accounts, and the
+/// instructions sysvar holds `ixn` as the transaction's only instruction.
+pub fn serialize<'a>(
+    ixn: &Instruction,
+    account: impl Fn(&Pubkey) -> Option<&'a Account>,
+    input: &mut Vec<u128>,
+) {
+    let mut unique: Vec<AccountMeta> = vec![];
+    for meta in &ixn.accounts {
+        match unique.iter_mut().find(|seen| seen.pubkey == meta.pubkey) {
+            Some(seen) => {
+                seen.is_signer |= meta.is_signer;
+                seen.is_writable |= meta.is_writable;
+            }
+            None => unique.push(meta.clone()),
+        }
+    }
+
+    let instructions_sysvar = instructions_sysvar(ixn);
+    let default_account = Account {
+        owner: system_program::ID,
+        ..Default::default()
+    };
+
+    // Each account is either a duplicate's index or the account itself, see `deserialize`.
+    let entries: Vec<Result<(&AccountMeta, &Account), u8>> = ixn
+        .accounts
+        .iter()
+        .enumerate()
+        .map(
+            |(i, meta)| match ixn.accounts.iter().position(|m| m.pubkey == meta.pubkey) {
+                Some(first) if first != i => Err(first as u8),
+                _ => {
+                    let meta = unique.iter().find(|m| m.pubkey == meta.pubkey).unwrap();
+                    let account = match meta.pubkey == sysvar::instructions::ID {
+                        true => &instructions_sysvar,
+                        false => account(&meta.pubkey).unwrap_or(&default_account),
+                    };
+                    Ok((meta, account))
+                }
+            },
+        )
+        .collect();
+
+    let accounts_len: usize = entries
+        .iter()
+        .map(|entry| match entry {
+            Err(_) => size_of::<u64>(),
+            Ok((_, account)) => {
+                (SERIALIZED_ACCOUNT_HEADER_LEN + account.data.len() + MAX_PERMITTED_DATA_INCREASE)
+                    .next_multiple_of(BPF_ALIGN_OF_U128)
+                    + size_of::<u64>()
+            }
+        })
+        .sum();
+    let len = size_of::<u64>() * 2 + accounts_len + ixn.data.len() + size_of::<Pubkey>();
+
+    // Zeroing the whole buffer also zeroes the space each account can grow into.
+    input.clear();
+    input.resize(len.div_ceil(size_of::<u128>()), 0);
+    let mut buf = Cursor {
+        bytes: bytemuck::cast_slice_mut(input),
+        pos: 0,
+    };
+    buf.put(&(ixn.accounts.len() as u64).to_le_bytes());
+    for entry in entries {
+        let (meta, account) = match entry {
+            Err(first) => {
+                buf.put(&[first]);
+                buf.skip(7);
+                continue;
+            }
+            Ok(entry) => entry,
+        };
+        buf.put(&[
+            NON_DUP_MARKER,
+            meta.is_signer as u8,
+            meta.is_writable as u8,
+            account.executable as u8,
+        ]);
+        buf.skip(size_of::<u32>());
+        buf.put(meta.pubkey.as_ref());
+        buf.put(account.owner.as_ref());
+        buf.put(&account.lamports.to_le_bytes());
+        buf.put(&(account.data.len() as u64).to_le_bytes());
+        buf.put(&account.data);
+        buf.skip(MAX_PERMITTED_DATA_INCREASE);
+        buf.pos = buf.pos.next_multiple_of(BPF_ALIGN_OF_U128);
+        buf.put(&u64::MAX.to_le_bytes());
+    }
+    buf.put(&(ixn.data.len() as u64).to_le_bytes());
+    buf.put(&ixn.data);
+    buf.put(ixn.program_id.as_ref());
+    debug_assert_eq!(buf.pos, len);
+}
+
+/// The instructions sysvar for a transaction with `ixn` as its only instruction.
+fn instructions_sysvar(ixn: &Instruction) -> Account {
+    #[allow(deprecated)]
+    use solana_program::sysvar::instructions::{
+        construct_instructions_data, BorrowedAccountMeta, BorrowedInstruction,
+    };
+
+    #[allow(deprecated)]
+    let data = construct_instructions_data(&[BorrowedInstruction {
+        program_id: &ixn.program_id,
+        accounts: ixn
+            .accounts
+            .iter()
+            .map(|meta| BorrowedAccountMeta {
+                pubkey: &meta.pubkey,
+                is_signer: meta.is_signer,
+                is_writable: meta.is_writable,
+            })
+            .collect(),
+        data: &ixn.data,
+    }]);
+    Account {
+        lamports: 1,
+        data,
+        owner: sysvar::ID,
+        ..Default::default()
+    }
+}
+
+struct Cursor<'a> {
+    bytes: &'a mut [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn put(&mut self, data: &[u8]) {
+        self.bytes[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+
+    /// Leaves the next `n` bytes zeroed.
+    fn skip(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+pub fn mint_account(token_program: &Pubkey, decimals: u8) -> Account {
+    let mut data = vec![0; Mint::LEN];
+    Mint::pack(
+        Mint {
+            decimals,
+            is_initialized: true,
+            ..Default::default()
+        },
+        &mut data,
+    )
+    .expect("Should pack");
+    Account {
+        lamports: Rent::default().minimum_balance(Mint::LEN),
+        data,
+        owner: *token_program,
+        ..Default::default()
+    }
+}
+
+pub fn token_account(
+    token_program: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+) -> Account {
+    let mut data = vec![0; TokenAccount::LEN];
+    TokenAccount::pack(token_account_state(mint, owner, amount), &mut data).expect("Should pack");
+    Account {
+        lamports: Rent::default().minimum_balance(TokenAccount::LEN),
+        data,
+        owner: *token_program,
+        ..Default::default()
+    }
+}
+
+pub fn token_account_state(mint: &Pubkey, owner: &Pubkey, amount: u64) -> TokenAccount {
+    TokenAccount {
+        mint: *mint,
+        owner: *owner,
+        amount,
+        state: AccountState::Initialized,
+        ..Default::default()
+    }
+}
+
+struct Stubs;
+
+impl SyscallStubs for Stubs {
+    fn sol_log(&self, message: &str) {
+        LOGS.with_borrow_mut(|logs| logs.push(format!("Program log: {message}")));
+    }
+
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        LOGGED_DATA.with_borrow_mut(|data| data.push(fields.concat()));
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        invoke(instruction, account_infos, signers_seeds)
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let (slot, unix_timestamp) = CLOCK.get();
+        let clock = Clock {
+            slot,
+            unix_timestamp,
+            ..Default::default()
+        };
+        // SAFETY: The caller passes a pointer to a `Clock`.
+        unsafe { *(var_addr as *mut Clock) = clock };
+        0
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        // SAFETY: The caller passes a pointer to a `Rent`.
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        0
+    }
+
+    fn sol_get_stack_height(&self) -> u64 {
+        STACK_HEIGHT.get()
+    }
+}
+
+/// Checks the CPI's privileges like the runtime does, then dispatches it to the callee.
+fn invoke(
+    instruction: &Instruction,
+    account_infos: &[AccountInfo],
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    // Only the deque program ever signs for PDAs.
+    let pda_signers = signers_seeds
+        .iter()
+        .map(|seeds| {
+            // The same few PDAs sign every CPI, and deriving them is relatively slow.
+            let key: Vec<Vec<u8>> = seeds.iter().map(|seed| seed.to_vec()).collect();
+            if let Some(pda) = PDAS.with_borrow(|pdas| pdas.get(&key).copied()) {
+                return Ok(pda);
+            }
+            let pda = Pubkey::create_program_address(seeds, &deque::ID)?;
+            PDAS.with_borrow_mut(|pdas| pdas.insert(key, pda));
+            Ok(pda)
+        })
+        .collect::<Result<Vec<_>, PubkeyError>>()
+        .map_err(|_| ProgramError::InvalidSeeds)?;
+
+    let accounts = instruction
+        .accounts
+        .iter()
+        .map(|meta| {
+            let info = account_infos
+                .iter()
+                .find(|info| info.key == &meta.pubkey)
+                .ok_or(ProgramError::NotEnoughAccountKeys)?;
+            if meta.is_signer && !info.is_signer && !pda_signers.contains(info.key) {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            if meta.is_writable && !info.is_writable {
+                return Err(ProgramError::InvalidArgument);
+            }
+            let mut info = info.clone();
+            info.is_signer = meta.is_signer;
+            info.is_writable = meta.is_writable;
+            Ok(info)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let height = STACK_HEIGHT.get();
+    STACK_HEIGHT.set(height + 1);
+    let result = match instruction.program_id {
+        id if id == system_program::ID => process_system(&accounts, &instruction.data),
+        id if id == spl_associated_token_account::ID => process_create_ata(&accounts),
+        id if id == spl_token::ID || id == spl_token_2022::ID => {
+            process_token(&id, &accounts, &instruction.data)
+        }
+        id if id == deque::ID => {
+            SELF_CPIS.with_borrow_mut(|cpis| cpis.push(instruction.data.clone()));
+            process_instruction(&deque::ID, &accounts, &instruction.data)
+        }
+        id => panic!("CPI to unsupported program {id}"),
+    };
+    STACK_HEIGHT.set(height);
+    result
+}
+
+fn transfer_lamports(from: &AccountInfo, to: &AccountInfo, lamports: u64) -> ProgramResult {
+    if !from.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let remaining = from
+        .lamports()
+        .checked_sub(lamports)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    **from.try_borrow_mut_lamports()? = remaining;
+    **to.try_borrow_mut_lamports()? += lamports;
+    Ok(())
+}
+
+/// Creates an account the same way the system program's `CreateAccount` does.
+fn create_account(
+    from: &AccountInfo,
+    to: &AccountInfo,
+    lamports: u64,
+    space: usize,
+    owner: &Pubkey,
+) -> ProgramResult {
+    if !to.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if to.lamports() > 0 || !to.data_is_empty() || to.owner != &system_program::ID {
+        // `SystemError::AccountAlreadyInUse`.
+        return Err(ProgramError::Custom(0));
+    }
+    transfer_lamports(from, to, lamports)?;
+    to.realloc(space, true)?;
+    to.assign(owner);
+    Ok(())
+}
+
+fn process_system(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    match u32::from_le_bytes(data[..4].try_into().unwrap()) {
+        // CreateAccount { lamports, space, owner }
+        0 => create_account(
+            &accounts[0],
+            &accounts[1],
+            u64_at(4),
+            u64_at(12) as usize,
+            &Pubkey::new_from_array(data[20..52].try_into().unwrap()),
+        ),
+        // Assign { owner }
+        1 => {
+            if !accounts[0].is_signer {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            if accounts[0].owner != &system_program::ID {
+                return Err(ProgramError::InvalidAccountOwner);
+            }
+            accounts[0].assign(&Pubkey::new_from_array(data[4..36].try_into().unwrap()));
+            Ok(())
+        }
+        // Transfer { lamports }
+        2 => {
+            if accounts[0].owner != &system_program::ID {
+                return Err(ProgramError::InvalidAccountOwner);
+            }
+            transfer_lamports(&accounts[0], &accounts[1], u64_at(4))
+        }
+        tag => panic!("Unsupported system instruction {tag}"),
+    }
+}
+
+/// `CreateAssociatedTokenAccount` with accounts `[payer, ata, wallet, mint, system, token]`.
+fn process_create_ata(accounts: &[AccountInfo]) -> ProgramResult {
+    let (payer, ata, wallet, mint, token_program) = (
+        &accounts[0],
+        &accounts[1],
+        &accounts[2],
+        &accounts[3],
+        &accounts[5],
+    );
+    if ata.key
+        != &get_associated_token_address_with_program_id(wallet.key, mint.key, token_program.key)
+        || mint.owner != token_program.key
+    {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    // The runtime would have the ATA program sign for the account.
+    let mut ata = ata.clone();
+    ata.is_signer = true;
+    let lamports = Rent::default().minimum_balance(TokenAccount::LEN);
+    create_account(payer, &ata, lamports, TokenAccount::LEN, token_program.key)?;
+    let mut data = ata.try_borrow_mut_data()?;
+    TokenAccount::pack(token_account_state(mint.key, wallet.key, 0), &mut data)
+}
+
+/// `Transfer` and `TransferChecked`, which are the same for both token programs.
+fn process_token(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let amount = u64::from_le_bytes(data[1..9].try_into().unwrap());
+    let (source, destination, authority) = match data[0] {
+        3 => (&accounts[0], &accounts[1], &accounts[2]),
+        12 => {
+            let mint = Mint::unpack(&accounts[1].try_borrow_data()?[..Mint::LEN])?;
+            if mint.decimals != data[9] {
+                // `TokenError::MintDecimalsMismatch`.
+                return Err(ProgramError::Custom(18));
+            }
+            (&accounts[0], &accounts[2], &accounts[3])
+        }
+        tag => panic!("Unsupported token instruction {tag}"),
+    };
+    if source.owner != program_id || destination.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut from = TokenAccount::unpack(&source.try_borrow_data()?[..TokenAccount::LEN])?;
+    let mut to = TokenAccount::unpack(&destination.try_borrow_data()?[..TokenAccount::LEN])?;
+    if from.mint != to.mint {
+        // `TokenError::MintMismatch`.
+        return Err(ProgramError::Custom(3));
+    }
+    if !authority.is_signer || &from.owner != authority.key {
+        // `TokenError::OwnerMismatch`.
+        return Err(ProgramError::Custom(4));
+    }
+    // `TokenError::InsufficientFunds`.
+    from.amount = from
+        .amount
+        .checked_sub(amount)
+        .ok_or(ProgramError::Custom(1))?;
+    to.amount += amount;
+
+    TokenAccount::pack(
+        from,
+        &mut source.try_borrow_mut_data()?[..TokenAccount::LEN],
+    )?;
+    TokenAccount::pack(
+        to,
+        &mut destination.try_borrow_mut_data()?[..TokenAccount::LEN],
+    )
+}